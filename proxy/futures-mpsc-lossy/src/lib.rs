@@ -17,15 +17,19 @@ use futures::sync::mpsc;
 pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
     let (tx, rx) = mpsc::unbounded();
     let capacity = Arc::new(AtomicUsize::new(capacity));
+    let rejected = Arc::new(AtomicUsize::new(0));
 
     let s = Sender {
         tx,
         capacity: capacity.clone(),
+        rejected: rejected.clone(),
     };
 
     let r = Receiver {
         rx,
+        bound: capacity.load(Ordering::SeqCst),
         capacity,
+        rejected,
     };
 
     (s, r)
@@ -33,12 +37,15 @@ pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
 
 pub struct Receiver<T> {
     rx: mpsc::UnboundedReceiver<T>,
+    bound: usize,
     capacity: Arc<AtomicUsize>,
+    rejected: Arc<AtomicUsize>,
 }
 
 pub struct Sender<T> {
     tx: mpsc::UnboundedSender<T>,
     capacity: Arc<AtomicUsize>,
+    rejected: Arc<AtomicUsize>,
 }
 
 /// Indicates that channel was not able to send an item. Subsequents items, however, may
@@ -51,6 +58,24 @@ pub enum SendError<T> {
 
 // ===== impl Receiver =====
 
+impl<T> Receiver<T> {
+    /// Returns the number of items that senders were unable to send because
+    /// the channel was at capacity since the last call to `take_rejected`.
+    pub fn take_rejected(&self) -> usize {
+        self.rejected.swap(0, Ordering::SeqCst)
+    }
+
+    /// Returns the number of items that have been sent but not yet received.
+    pub fn len(&self) -> usize {
+        self.bound - self.capacity.load(Ordering::SeqCst)
+    }
+
+    /// Returns true if every item that has been sent has been received.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
 impl<T> Stream for Receiver<T> {
     type Item = T;
     type Error = ();
@@ -70,7 +95,9 @@ impl<T> Stream for Receiver<T> {
 impl<T> fmt::Debug for Receiver<T> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.debug_struct("Receiver")
+            .field("bound", &self.bound)
             .field("capacity", &self.capacity)
+            .field("rejected", &self.rejected)
             .finish()
     }
 }
@@ -82,6 +109,7 @@ impl<T> Sender<T> {
         loop {
             let cap = self.capacity.load(Ordering::SeqCst);
             if cap == 0 {
+                self.rejected.fetch_add(1, Ordering::SeqCst);
                 return Err(SendError::Rejected(v));
             }
 
@@ -118,6 +146,7 @@ impl<T> Clone for Sender<T> {
         Sender {
             tx: self.tx.clone(),
             capacity: self.capacity.clone(),
+            rejected: self.rejected.clone(),
         }
     }
 }
@@ -127,6 +156,7 @@ impl<T> fmt::Debug for Sender<T> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.debug_struct("Sender")
             .field("capacity", &self.capacity)
+            .field("rejected", &self.rejected)
             .finish()
     }
 }
@@ -140,3 +170,43 @@ impl<T> SendError<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::{Async, Stream};
+
+    use super::*;
+
+    #[test]
+    fn counts_rejected_sends() {
+        let (tx, mut rx) = channel::<usize>(2);
+
+        assert_eq!(tx.lossy_send(1), Ok(()));
+        assert_eq!(tx.lossy_send(2), Ok(()));
+        assert_eq!(tx.lossy_send(3), Err(SendError::Rejected(3)));
+        assert_eq!(tx.lossy_send(4), Err(SendError::Rejected(4)));
+        assert_eq!(rx.take_rejected(), 2);
+        assert_eq!(rx.take_rejected(), 0);
+
+        // Receiving an item frees capacity for another send.
+        assert_eq!(rx.poll(), Ok(Async::Ready(Some(1))));
+        assert_eq!(tx.lossy_send(5), Ok(()));
+        assert_eq!(rx.take_rejected(), 0);
+    }
+
+    #[test]
+    fn counts_unreceived_items() {
+        let (tx, mut rx) = channel::<usize>(2);
+        assert!(rx.is_empty());
+
+        assert_eq!(tx.lossy_send(1), Ok(()));
+        assert_eq!(tx.lossy_send(2), Ok(()));
+        assert_eq!(tx.lossy_send(3), Err(SendError::Rejected(3)));
+        assert_eq!(rx.len(), 2);
+
+        assert_eq!(rx.poll(), Ok(Async::Ready(Some(1))));
+        assert_eq!(rx.len(), 1);
+        assert_eq!(rx.poll(), Ok(Async::Ready(Some(2))));
+        assert!(rx.is_empty());
+    }
+}
@@ -6,6 +6,7 @@ use std::path::PathBuf;
 use std::str::FromStr;
 use std::time::Duration;
 
+use deflate::Compression;
 use http;
 use indexmap::IndexSet;
 
@@ -53,6 +54,111 @@ pub struct Config {
     /// Event queue capacity.
     pub event_buffer_capacity: usize,
 
+    /// Interval after which queued events are recorded into the metrics
+    /// aggregate. If `None`, events are recorded as soon as they are received.
+    pub metrics_flush_interval: Option<Duration>,
+
+    /// If true, response latency is measured from the end of the request
+    /// stream, so that it excludes the time taken to send the request body.
+    pub metrics_latency_from_request_end: bool,
+
+    /// If true, the total size of each request's headers is recorded.
+    pub metrics_request_header_bytes: bool,
+
+    /// If true, the latencies of the first and last bytes of each response
+    /// are recorded.
+    pub metrics_response_byte_latency: bool,
+
+    /// If true, metrics responses include a `Server-Timing` header.
+    pub metrics_server_timing: bool,
+
+    /// How long a formatted scrape is served to concurrent scrapes with the
+    /// same query. If `None`, every scrape formats the metrics.
+    pub metrics_scrape_cache_ttl: Option<Duration>,
+
+    /// The maximum number of series written by each scrape. If `None`,
+    /// scrapes are not limited.
+    pub metrics_max_series: Option<usize>,
+
+    /// The upper bounds of the response latency histogram's buckets. If
+    /// `None`, the default latency buckets are used.
+    pub metrics_response_latency_bounds: Option<HistogramBounds>,
+
+    /// The upper bounds of the TCP connection duration histogram's buckets.
+    /// If `None`, the default latency buckets are used.
+    pub metrics_connection_duration_bounds: Option<HistogramBounds>,
+
+    /// How long the HTTP series for a destination are kept after they were
+    /// last updated. Series with an open stream are kept ten times as long.
+    /// If `None`, series are never evicted.
+    pub metrics_max_idle_age: Option<Duration>,
+
+    /// The maximum number of distinct authorities recorded for each
+    /// destination. If `None`, the default limit is used.
+    pub metrics_max_authorities: Option<usize>,
+
+    /// How hard gzipped scrapes are compressed: `fast`, `default`, or
+    /// `best`. If `None`, scrapes are compressed as fast as possible.
+    pub metrics_gzip_level: Option<Compression>,
+
+    /// The address of a StatsD server to which metrics are sent, in the
+    /// DogStatsD protocol, as they are recorded. If `None`, metrics are only
+    /// served to scrapes.
+    pub metrics_statsd_addr: Option<SocketAddr>,
+
+    /// Path templates, such as `/users/{id}`, by which HTTP metrics are
+    /// labeled with a `route` label. If `None`, metrics are not labeled by
+    /// route.
+    pub metrics_routes: Option<Vec<String>>,
+
+    /// A token which metrics requests must present in an
+    /// `Authorization: Bearer` header. If `None`, metrics are served to any
+    /// client.
+    pub metrics_bearer_token: Option<String>,
+
+    /// A namespace prepended to the name of every scraped metric, such as
+    /// `conduit` for `conduit_request_total`. If `None`, metrics are not
+    /// prefixed.
+    pub metrics_namespace: Option<String>,
+
+    /// The path at which metrics are scraped. If `None`, metrics are served
+    /// at `/metrics`.
+    pub metrics_path: Option<String>,
+
+    /// The number of recently recorded telemetry events which are kept and
+    /// served for debugging. If `None`, no events are kept.
+    pub metrics_recent_events: Option<usize>,
+
+    /// Labels, such as `pod` and `node`, added to every scraped series. If
+    /// `None`, no labels are added.
+    pub metrics_const_labels: Option<Vec<(String, String)>>,
+
+    /// The authority label recorded for requests without an authority. If
+    /// `None`, they are recorded as `__no_authority__`.
+    pub metrics_missing_authority: Option<String>,
+
+    /// If true, metrics may be reset by a `POST` to `<path>/reset`.
+    pub metrics_allow_reset: bool,
+
+    /// If true, HTTP metrics of gRPC requests are also labeled by the gRPC
+    /// method. At most 100 distinct methods are recorded for each authority.
+    pub metrics_grpc_method_labels: bool,
+
+    /// Authorities which are not recorded as label values, each either a host,
+    /// such as `db.internal`, or `*.` and a domain, such as `*.tenants.test`,
+    /// which matches any host within that domain. A matching authority is
+    /// recorded as the pattern it matches. If `None`, no authority is redacted.
+    pub metrics_redact_authorities: Option<Vec<String>>,
+
+    /// The quantiles of response latency, such as `0.5` and `0.99`, reported
+    /// over the responses completed in the last minute. If `None`, no
+    /// quantiles are recorded.
+    pub metrics_response_latency_quantiles: Option<Vec<f64>>,
+
+    /// If true, `request_by_authority_total` is also exported, summing the
+    /// request totals of each authority across both directions.
+    pub metrics_requests_by_authority: bool,
+
     /// Timeout after which to cancel binding a request.
     pub bind_timeout: Duration,
 
@@ -83,6 +189,16 @@ pub enum Error {
 pub enum ParseError {
     EnvironmentUnsupported,
     NotANumber,
+    NotABoolean,
+    NotACompressionLevel,
+    NotExponentialBounds,
+    NotASocketAddress,
+    NotAPathTemplate,
+    NotAMetricNamespace,
+    NotAMetricsPath,
+    NotAConstLabel,
+    NotAnAuthorityPattern,
+    NotAQuantile,
     HostIsNotAnIpAddress,
     NotUnicode,
     UrlError(UrlError),
@@ -106,6 +222,17 @@ pub enum UrlError {
     PathNotAllowed,
 }
 
+/// The upper bounds of a latency histogram's buckets.
+#[derive(Clone, Debug)]
+pub enum HistogramBounds {
+    /// Each of the bounds.
+    Millis(Vec<Duration>),
+
+    /// `count` bounds, the first of which is `start_ms` milliseconds and each
+    /// of which is `factor` times the one before it.
+    Exponential { start_ms: u64, factor: f64, count: usize },
+}
+
 /// The strings used to build a configuration.
 pub trait Strings {
     /// Retrieves the value for the key `key`.
@@ -123,6 +250,31 @@ pub struct TestEnv {
 
 // Environment variables to look at when loading the configuration
 const ENV_EVENT_BUFFER_CAPACITY: &str = "CONDUIT_PROXY_EVENT_BUFFER_CAPACITY";
+pub const ENV_METRICS_FLUSH_INTERVAL: &str = "CONDUIT_PROXY_METRICS_FLUSH_INTERVAL";
+pub const ENV_METRICS_LATENCY_FROM_REQUEST_END: &str = "CONDUIT_PROXY_METRICS_LATENCY_FROM_REQUEST_END";
+pub const ENV_METRICS_REQUEST_HEADER_BYTES: &str = "CONDUIT_PROXY_METRICS_REQUEST_HEADER_BYTES";
+pub const ENV_METRICS_SERVER_TIMING: &str = "CONDUIT_PROXY_METRICS_SERVER_TIMING";
+pub const ENV_METRICS_RESPONSE_BYTE_LATENCY: &str = "CONDUIT_PROXY_METRICS_RESPONSE_BYTE_LATENCY";
+pub const ENV_METRICS_SCRAPE_CACHE_TTL: &str = "CONDUIT_PROXY_METRICS_SCRAPE_CACHE_TTL";
+pub const ENV_METRICS_MAX_SERIES: &str = "CONDUIT_PROXY_METRICS_MAX_SERIES";
+pub const ENV_METRICS_RESPONSE_LATENCY_BOUNDS: &str = "CONDUIT_PROXY_METRICS_RESPONSE_LATENCY_BOUNDS";
+pub const ENV_METRICS_CONNECTION_DURATION_BOUNDS: &str = "CONDUIT_PROXY_METRICS_CONNECTION_DURATION_BOUNDS";
+pub const ENV_METRICS_MAX_IDLE_AGE: &str = "CONDUIT_PROXY_METRICS_MAX_IDLE_AGE";
+pub const ENV_METRICS_MAX_AUTHORITIES: &str = "CONDUIT_PROXY_METRICS_MAX_AUTHORITIES";
+pub const ENV_METRICS_GZIP_LEVEL: &str = "CONDUIT_PROXY_METRICS_GZIP_LEVEL";
+pub const ENV_METRICS_STATSD_ADDR: &str = "CONDUIT_PROXY_METRICS_STATSD_ADDR";
+pub const ENV_METRICS_ROUTES: &str = "CONDUIT_PROXY_METRICS_ROUTES";
+pub const ENV_METRICS_BEARER_TOKEN: &str = "CONDUIT_PROXY_METRICS_BEARER_TOKEN";
+pub const ENV_METRICS_NAMESPACE: &str = "CONDUIT_PROXY_METRICS_NAMESPACE";
+pub const ENV_METRICS_PATH: &str = "CONDUIT_PROXY_METRICS_PATH";
+pub const ENV_METRICS_RECENT_EVENTS: &str = "CONDUIT_PROXY_METRICS_RECENT_EVENTS";
+pub const ENV_METRICS_CONST_LABELS: &str = "CONDUIT_PROXY_METRICS_CONST_LABELS";
+pub const ENV_METRICS_MISSING_AUTHORITY: &str = "CONDUIT_PROXY_METRICS_MISSING_AUTHORITY";
+pub const ENV_METRICS_ALLOW_RESET: &str = "CONDUIT_PROXY_METRICS_ALLOW_RESET";
+pub const ENV_METRICS_GRPC_METHOD_LABELS: &str = "CONDUIT_PROXY_METRICS_GRPC_METHOD_LABELS";
+pub const ENV_METRICS_REDACT_AUTHORITIES: &str = "CONDUIT_PROXY_METRICS_REDACT_AUTHORITIES";
+pub const ENV_METRICS_RESPONSE_LATENCY_QUANTILES: &str = "CONDUIT_PROXY_METRICS_RESPONSE_LATENCY_QUANTILES";
+pub const ENV_METRICS_REQUESTS_BY_AUTHORITY: &str = "CONDUIT_PROXY_METRICS_REQUESTS_BY_AUTHORITY";
 pub const ENV_PRIVATE_LISTENER: &str = "CONDUIT_PROXY_PRIVATE_LISTENER";
 pub const ENV_PRIVATE_FORWARD: &str = "CONDUIT_PROXY_PRIVATE_FORWARD";
 pub const ENV_PUBLIC_LISTENER: &str = "CONDUIT_PROXY_PUBLIC_LISTENER";
@@ -182,6 +334,42 @@ impl<'a> TryFrom<&'a Strings> for Config {
         let bind_timeout = parse(strings, ENV_BIND_TIMEOUT, parse_number);
         let resolv_conf_path = strings.get(ENV_RESOLV_CONF);
         let event_buffer_capacity = parse(strings, ENV_EVENT_BUFFER_CAPACITY, parse_number);
+        let metrics_flush_interval = parse(strings, ENV_METRICS_FLUSH_INTERVAL, parse_number);
+        let metrics_latency_from_request_end =
+            parse(strings, ENV_METRICS_LATENCY_FROM_REQUEST_END, parse_bool);
+        let metrics_request_header_bytes =
+            parse(strings, ENV_METRICS_REQUEST_HEADER_BYTES, parse_bool);
+        let metrics_server_timing = parse(strings, ENV_METRICS_SERVER_TIMING, parse_bool);
+        let metrics_response_byte_latency =
+            parse(strings, ENV_METRICS_RESPONSE_BYTE_LATENCY, parse_bool);
+        let metrics_scrape_cache_ttl = parse(strings, ENV_METRICS_SCRAPE_CACHE_TTL, parse_number);
+        let metrics_max_series = parse(strings, ENV_METRICS_MAX_SERIES, parse_number);
+        let metrics_response_latency_bounds =
+            parse(strings, ENV_METRICS_RESPONSE_LATENCY_BOUNDS, parse_histogram_bounds);
+        let metrics_connection_duration_bounds =
+            parse(strings, ENV_METRICS_CONNECTION_DURATION_BOUNDS, parse_histogram_bounds);
+        let metrics_max_idle_age = parse(strings, ENV_METRICS_MAX_IDLE_AGE, parse_number);
+        let metrics_max_authorities = parse(strings, ENV_METRICS_MAX_AUTHORITIES, parse_number);
+        let metrics_gzip_level = parse(strings, ENV_METRICS_GZIP_LEVEL, parse_compression);
+        let metrics_statsd_addr = parse(strings, ENV_METRICS_STATSD_ADDR, parse_socket_addr);
+        let metrics_routes = parse(strings, ENV_METRICS_ROUTES, parse_path_template_list);
+        let metrics_bearer_token =
+            parse(strings, ENV_METRICS_BEARER_TOKEN, |s| Ok(s.to_owned()));
+        let metrics_namespace = parse(strings, ENV_METRICS_NAMESPACE, parse_metric_namespace);
+        let metrics_path = parse(strings, ENV_METRICS_PATH, parse_metrics_path);
+        let metrics_recent_events = parse(strings, ENV_METRICS_RECENT_EVENTS, parse_number);
+        let metrics_const_labels = parse(strings, ENV_METRICS_CONST_LABELS, parse_const_labels);
+        let metrics_missing_authority =
+            parse(strings, ENV_METRICS_MISSING_AUTHORITY, |s| Ok(s.to_owned()));
+        let metrics_allow_reset = parse(strings, ENV_METRICS_ALLOW_RESET, parse_bool);
+        let metrics_grpc_method_labels =
+            parse(strings, ENV_METRICS_GRPC_METHOD_LABELS, parse_bool);
+        let metrics_redact_authorities =
+            parse(strings, ENV_METRICS_REDACT_AUTHORITIES, parse_authority_patterns);
+        let metrics_response_latency_quantiles =
+            parse(strings, ENV_METRICS_RESPONSE_LATENCY_QUANTILES, parse_quantile_list);
+        let metrics_requests_by_authority =
+            parse(strings, ENV_METRICS_REQUESTS_BY_AUTHORITY, parse_bool);
         let pod_namespace = strings.get(ENV_POD_NAMESPACE).and_then(|maybe_value| {
             // There cannot be a default pod namespace, and the pod namespace is required.
             maybe_value.ok_or_else(|| {
@@ -236,6 +424,31 @@ impl<'a> TryFrom<&'a Strings> for Config {
             control_host_and_port: control_host_and_port?,
 
             event_buffer_capacity: event_buffer_capacity?.unwrap_or(DEFAULT_EVENT_BUFFER_CAPACITY),
+            metrics_flush_interval: metrics_flush_interval?.map(Duration::from_millis),
+            metrics_latency_from_request_end: metrics_latency_from_request_end?.unwrap_or(false),
+            metrics_request_header_bytes: metrics_request_header_bytes?.unwrap_or(false),
+            metrics_server_timing: metrics_server_timing?.unwrap_or(false),
+            metrics_response_byte_latency: metrics_response_byte_latency?.unwrap_or(false),
+            metrics_scrape_cache_ttl: metrics_scrape_cache_ttl?.map(Duration::from_millis),
+            metrics_max_series: metrics_max_series?,
+            metrics_response_latency_bounds: metrics_response_latency_bounds?,
+            metrics_connection_duration_bounds: metrics_connection_duration_bounds?,
+            metrics_max_idle_age: metrics_max_idle_age?.map(Duration::from_millis),
+            metrics_max_authorities: metrics_max_authorities?,
+            metrics_gzip_level: metrics_gzip_level?,
+            metrics_statsd_addr: metrics_statsd_addr?,
+            metrics_routes: metrics_routes?,
+            metrics_bearer_token: metrics_bearer_token?,
+            metrics_namespace: metrics_namespace?,
+            metrics_path: metrics_path?,
+            metrics_recent_events: metrics_recent_events?,
+            metrics_const_labels: metrics_const_labels?,
+            metrics_missing_authority: metrics_missing_authority?,
+            metrics_allow_reset: metrics_allow_reset?.unwrap_or(false),
+            metrics_grpc_method_labels: metrics_grpc_method_labels?.unwrap_or(false),
+            metrics_redact_authorities: metrics_redact_authorities?,
+            metrics_response_latency_quantiles: metrics_response_latency_quantiles?,
+            metrics_requests_by_authority: metrics_requests_by_authority?.unwrap_or(false),
             bind_timeout:
                 Duration::from_millis(bind_timeout?.unwrap_or(DEFAULT_BIND_TIMEOUT_MS)),
             pod_namespace: pod_namespace?,
@@ -308,6 +521,23 @@ fn parse_number<T>(s: &str) -> Result<T, ParseError> where T: FromStr {
     s.parse().map_err(|_| ParseError::NotANumber)
 }
 
+fn parse_bool(s: &str) -> Result<bool, ParseError> {
+    s.parse().map_err(|_| ParseError::NotABoolean)
+}
+
+fn parse_compression(s: &str) -> Result<Compression, ParseError> {
+    match s {
+        "fast" => Ok(Compression::Fast),
+        "default" => Ok(Compression::Default),
+        "best" => Ok(Compression::Best),
+        _ => Err(ParseError::NotACompressionLevel),
+    }
+}
+
+fn parse_socket_addr(s: &str) -> Result<SocketAddr, ParseError> {
+    s.parse().map_err(|_| ParseError::NotASocketAddress)
+}
+
 fn parse_url(s: &str) -> Result<HostAndPort, ParseError> {
     let url = s.parse::<http::Uri>().map_err(|_| ParseError::UrlError(UrlError::SyntaxError))?;
     if url.scheme_part().map(|s| s.as_str()) != Some("tcp") {
@@ -335,6 +565,139 @@ fn parse_port_set(s: &str) -> Result<IndexSet<u16>, ParseError> {
     Ok(set)
 }
 
+/// Parses a comma-separated list of durations, in milliseconds.
+fn parse_millis_list(s: &str) -> Result<Vec<Duration>, ParseError> {
+    s.split(',')
+        .map(|ms| parse_number(ms).map(Duration::from_millis))
+        .collect()
+}
+
+/// The most bounds that `exponential(START,FACTOR,COUNT)` may configure.
+const MAX_EXPONENTIAL_BOUNDS: usize = 64;
+
+/// Parses the bounds of a histogram's buckets, either as a comma-separated
+/// list of durations in milliseconds, or as `exponential(START,FACTOR,COUNT)`
+/// for `COUNT` bounds, the first of which is `START` milliseconds and each of
+/// which is `FACTOR` times the one before it.
+///
+/// `START` must be at least 1, and `COUNT` at most `MAX_EXPONENTIAL_BOUNDS`.
+fn parse_histogram_bounds(s: &str) -> Result<HistogramBounds, ParseError> {
+    const EXPONENTIAL: &str = "exponential(";
+    if !s.starts_with(EXPONENTIAL) {
+        return parse_millis_list(s).map(HistogramBounds::Millis);
+    }
+    if !s.ends_with(')') {
+        return Err(ParseError::NotExponentialBounds);
+    }
+
+    let args = s[EXPONENTIAL.len()..s.len() - 1].split(',').collect::<Vec<_>>();
+    if args.len() != 3 {
+        return Err(ParseError::NotExponentialBounds);
+    }
+    let start_ms = parse_number(args[0])?;
+    let factor = parse_number::<f64>(args[1])?;
+    let count = parse_number(args[2])?;
+    // The factor must be checked this way around, so that NaN is refused.
+    if start_ms == 0 || !(factor > 1.0) || count == 0 || count > MAX_EXPONENTIAL_BOUNDS {
+        return Err(ParseError::NotExponentialBounds);
+    }
+    Ok(HistogramBounds::Exponential { start_ms, factor, count })
+}
+
+/// Parses a comma-separated list of path templates, each of which must be
+/// an absolute path.
+fn parse_path_template_list(s: &str) -> Result<Vec<String>, ParseError> {
+    s.split(',')
+        .map(|template| {
+            if template.starts_with('/') {
+                Ok(template.to_owned())
+            } else {
+                Err(ParseError::NotAPathTemplate)
+            }
+        })
+        .collect()
+}
+
+/// Parses a namespace for metric names, which must itself be a valid metric
+/// name without colons.
+fn parse_metric_namespace(s: &str) -> Result<String, ParseError> {
+    let starts_well = s.chars().next()
+        .map(|c| c.is_ascii_alphabetic() || c == '_')
+        .unwrap_or(false);
+    if starts_well && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(s.to_owned())
+    } else {
+        Err(ParseError::NotAMetricNamespace)
+    }
+}
+
+/// Parses the path at which metrics are served, which must be absolute and
+/// must not end with a slash, as other endpoints are served beneath it.
+fn parse_metrics_path(s: &str) -> Result<String, ParseError> {
+    if s.starts_with('/') && !s.ends_with('/') && !s.contains('?') {
+        Ok(s.to_owned())
+    } else {
+        Err(ParseError::NotAMetricsPath)
+    }
+}
+
+/// Parses a comma-separated list of `key=value` labels, such as
+/// `pod=web-0,node=n1`. Each key must be a valid label name which is not
+/// reserved by Prometheus, as those beginning with `__` are.
+fn parse_const_labels(s: &str) -> Result<Vec<(String, String)>, ParseError> {
+    s.split(',')
+        .map(|label| {
+            let mut parts = label.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let value = parts.next().ok_or(ParseError::NotAConstLabel)?;
+            let starts_well = key.chars().next()
+                .map(|c| c.is_ascii_alphabetic() || c == '_')
+                .unwrap_or(false);
+            let valid = starts_well &&
+                !key.starts_with("__") &&
+                key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if valid {
+                Ok((key.to_owned(), value.to_owned()))
+            } else {
+                Err(ParseError::NotAConstLabel)
+            }
+        })
+        .collect()
+}
+
+/// Parses a comma-separated list of quantiles, each of which must be between
+/// 0 and 1.
+fn parse_quantile_list(s: &str) -> Result<Vec<f64>, ParseError> {
+    s.split(',')
+        .map(|q| {
+            let q = parse_number::<f64>(q)?;
+            if q >= 0.0 && q <= 1.0 {
+                Ok(q)
+            } else {
+                Err(ParseError::NotAQuantile)
+            }
+        })
+        .collect()
+}
+
+/// Parses a comma-separated list of authority patterns, each of which is
+/// either a host, such as `db.internal`, or `*.` followed by a domain, such
+/// as `*.tenants.test`.
+fn parse_authority_patterns(s: &str) -> Result<Vec<String>, ParseError> {
+    s.split(',')
+        .map(|pattern| {
+            let host = if pattern.starts_with("*.") { &pattern[2..] } else { pattern };
+            let valid = !host.is_empty() &&
+                host.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+            if valid {
+                Ok(pattern.to_ascii_lowercase())
+            } else {
+                Err(ParseError::NotAnAuthorityPattern)
+            }
+        })
+        .collect()
+}
+
 fn parse<T, Parse>(strings: &Strings, name: &str, parse: Parse) -> Result<Option<T>, Error>
     where Parse: FnOnce(&str) -> Result<T, ParseError> {
     match strings.get(name)? {
@@ -348,3 +711,55 @@ fn parse<T, Parse>(strings: &Strings, name: &str, parse: Parse) -> Result<Option
         None => Ok(None),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_histogram_bounds_exponential() {
+        match parse_histogram_bounds("exponential(1,2,20)") {
+            Ok(HistogramBounds::Exponential { start_ms, factor, count }) => {
+                assert_eq!(start_ms, 1);
+                assert_eq!(factor, 2.0);
+                assert_eq!(count, 20);
+            },
+            other => panic!("unexpected bounds: {:?}", other),
+        }
+
+        let count = format!("exponential(1,2,{})", MAX_EXPONENTIAL_BOUNDS);
+        assert!(parse_histogram_bounds(&count).is_ok());
+    }
+
+    #[test]
+    fn parse_histogram_bounds_millis() {
+        match parse_histogram_bounds("10,100,1000") {
+            Ok(HistogramBounds::Millis(bounds)) => assert_eq!(bounds, vec![
+                Duration::from_millis(10),
+                Duration::from_millis(100),
+                Duration::from_millis(1000),
+            ]),
+            other => panic!("unexpected bounds: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_histogram_bounds_refuses_invalid_exponential_bounds() {
+        let invalid = [
+            // A start of 0 stays 0, however often it is multiplied.
+            "exponential(0,2,10)",
+            // Too many bounds.
+            "exponential(1,2,65)",
+            "exponential(1,2,18446744073709551615)",
+            "exponential(1,1,10)",
+            "exponential(1,0.5,10)",
+            "exponential(1,NaN,10)",
+            "exponential(1,2,0)",
+            "exponential(1,2)",
+            "exponential(1,2,10",
+        ];
+        for s in invalid.iter() {
+            assert!(parse_histogram_bounds(s).is_err(), "{:?} should be refused", s);
+        }
+    }
+}
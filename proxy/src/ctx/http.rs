@@ -14,6 +14,14 @@ pub struct Request {
     pub uri: http::Uri,
     pub method: http::Method,
 
+    /// The total length of the request's header names and values, in bytes,
+    /// if it was measured.
+    pub header_bytes: Option<usize>,
+
+    /// Whether the request's content type is `application/grpc`, or one of
+    /// its `application/grpc+<format>` variants.
+    pub is_grpc: bool,
+
     /// Identifies the proxy server that received the request.
     pub server: Arc<ctx::transport::Server>,
 
@@ -42,11 +50,21 @@ impl Request {
         server: &Arc<ctx::transport::Server>,
         client: &Arc<ctx::transport::Client>,
         id: usize,
+        measure_header_bytes: bool,
     ) -> Arc<Self> {
+        let header_bytes = if measure_header_bytes {
+            Some(request.headers().iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum())
+        } else {
+            None
+        };
         let r = Self {
             id,
             uri: request.uri().clone(),
             method: request.method().clone(),
+            header_bytes,
+            is_grpc: is_grpc(request.headers()),
             server: Arc::clone(server),
             client: Arc::clone(client),
         };
@@ -59,6 +77,18 @@ impl Request {
     }
 }
 
+/// Returns true if the content type in `headers` is gRPC's.
+fn is_grpc(headers: &http::HeaderMap) -> bool {
+    headers.get(http::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map(|content_type| {
+            content_type == "application/grpc" ||
+                content_type.starts_with("application/grpc+") ||
+                content_type.starts_with("application/grpc;")
+        })
+        .unwrap_or(false)
+}
+
 impl Response {
     pub fn new<B>(response: &http::Response<B>, request: &Arc<Request>) -> Arc<Self> {
         let r = Self {
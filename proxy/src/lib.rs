@@ -50,7 +50,7 @@ use std::io;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 use indexmap::IndexSet;
 use tokio_core::reactor::{Core, Handle};
@@ -197,10 +197,7 @@ where
             config.outbound_ports_disable_protocol_detection,
         );
 
-        let (sensors, telemetry) = telemetry::new(
-            &process_ctx,
-            config.event_buffer_capacity,
-        );
+        let (sensors, telemetry) = telemetry::new(&process_ctx, &config);
 
         let dns_config = dns::Config::from_system_config()
             .unwrap_or_else(|e| {
@@ -210,6 +207,8 @@ where
 
         let (control, control_bg) = control::new(dns_config.clone(), config.pod_namespace.clone());
 
+        let mut shutdown_metrics = telemetry.metrics_aggregate();
+
         let executor = core.handle();
         let (drain_tx, drain_rx) = drain::channel();
 
@@ -315,7 +314,11 @@ where
         core.handle().spawn(fut);
         let shutdown_signal = shutdown_signal.and_then(move |()| {
             debug!("shutdown signaled");
-            drain_tx.drain()
+            let drain_started = Instant::now();
+            shutdown_metrics.record_drain_start(SystemTime::now());
+            drain_tx.drain().map(move |()| {
+                shutdown_metrics.record_drain_end(drain_started.elapsed());
+            })
         });
         core.run(shutdown_signal).expect("executor");
         debug!("shutdown complete");
@@ -1,13 +1,15 @@
 use std::io;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use futures::{future, Async, Future, Poll, Stream};
 use futures_mpsc_lossy::Receiver;
-use tokio_core::reactor::Handle;
+use tokio_core::reactor::{Handle, Interval};
 
 use super::event::Event;
 use super::metrics;
 use super::tap::Taps;
+use config::{Config, HistogramBounds};
 use connection;
 use ctx;
 
@@ -17,7 +19,23 @@ pub struct MakeControl {
     /// Receives events.
     rx: Receiver<Event>,
 
-    process_ctx: Arc<ctx::Process>,
+    /// Aggregates scrapable metrics.
+    metrics_aggregate: metrics::Aggregate,
+
+    /// Serves scrapable metrics.
+    metrics_service: metrics::Serve,
+
+    /// The maximum number of events that may be buffered between flushes.
+    capacity: usize,
+
+    /// How often buffered events are recorded into the metrics aggregate.
+    flush_interval: Option<Duration>,
+
+    /// How long HTTP series are kept after they were last updated.
+    max_idle_age: Option<Duration>,
+
+    /// Sends metrics to a StatsD server as events are received.
+    statsd: Option<metrics::StatsdSink>,
 }
 
 /// Handles the receipt of events.
@@ -27,7 +45,12 @@ pub struct MakeControl {
 ///
 /// As `Control` is polled, events are proceesed for the purposes of metrics export _as
 /// well as_ for Tap, which supports subscribing to a stream of events that match
-/// criteria.
+/// criteria. Tap observes events as soon as they are received. When a
+/// `flush_interval` is configured, events are buffered and recorded into the
+/// metrics aggregate once per interval (or sooner, if the buffer fills up), so that
+/// the metrics lock is acquired once per flush rather than once per event.
+/// Otherwise, the events received each time `Control` is polled are recorded
+/// together once no more are ready to be received.
 ///
 /// # TODO
 /// Limit the amount of memory that may be consumed for metrics aggregation.
@@ -39,9 +62,36 @@ pub struct Control {
     /// Serves scrapable metrics.
     metrics_service: metrics::Serve,
 
+    /// Sends metrics to a StatsD server as events are received.
+    statsd: Option<metrics::StatsdSink>,
+
     /// Receives telemetry events.
     rx: Option<Receiver<Event>>,
 
+    /// Events which have been received but not yet recorded by `metrics_aggregate`.
+    ///
+    /// Consecutive events which only increment the same counter are
+    /// coalesced into a single entry, along with the number of times the
+    /// event occurred.
+    pending: Vec<(Event, u64)>,
+
+    /// Events which have been received but not yet recorded by
+    /// `metrics_aggregate`, when no flush interval is configured. These are
+    /// recorded together once no more events are ready to be received.
+    received: Vec<Event>,
+
+    /// The maximum number of events that may be held in `pending` or
+    /// `received`.
+    capacity: usize,
+
+    /// Fires when `pending` events should be recorded. If `None`, events are
+    /// recorded as soon as no more are ready to be received.
+    flush_timer: Option<Interval>,
+
+    /// Fires when idle HTTP series should be evicted from the metrics
+    /// aggregate, along with how long a series may be idle.
+    evict_timer: Option<(Interval, Duration)>,
+
     /// Holds the current state of tap observations, as configured by an external source.
     taps: Option<Arc<Mutex<Taps>>>,
 
@@ -56,16 +106,99 @@ impl MakeControl {
     /// # Arguments
     /// - `rx`: the `Receiver` side of the channel on which events are sent.
     /// - `process_ctx`: runtime process metadata.
+    /// - `config`: the proxy's configuration. The event buffer capacity and
+    ///   the `metrics_*` settings configure how events are aggregated and
+    ///   how metrics are served; see `Config` for what each of them does.
     pub(super) fn new(
         rx: Receiver<Event>,
         process_ctx: &Arc<ctx::Process>,
+        config: &Config,
     ) -> Self {
+        let (metrics_aggregate, metrics_service) = metrics::new(process_ctx);
+        let mut metrics_aggregate = metrics_aggregate
+            .with_latency_from_request_end(config.metrics_latency_from_request_end)
+            .with_request_header_bytes(config.metrics_request_header_bytes)
+            .with_response_byte_latency(config.metrics_response_byte_latency)
+            .with_grpc_method_labels(config.metrics_grpc_method_labels)
+            .with_requests_by_authority(config.metrics_requests_by_authority);
+        metrics_aggregate = match config.metrics_response_latency_bounds {
+            Some(HistogramBounds::Millis(ref bounds)) =>
+                metrics_aggregate.with_response_latency_bounds(bounds),
+            Some(HistogramBounds::Exponential { start_ms, factor, count }) =>
+                metrics_aggregate.with_exponential_response_latency_bounds(start_ms, factor, count),
+            None => metrics_aggregate,
+        };
+        if let Some(ref quantiles) = config.metrics_response_latency_quantiles {
+            metrics_aggregate = metrics_aggregate.with_response_latency_quantiles(quantiles);
+        }
+        metrics_aggregate = match config.metrics_connection_duration_bounds {
+            Some(HistogramBounds::Millis(ref bounds)) =>
+                metrics_aggregate.with_connection_duration_bounds(bounds),
+            Some(HistogramBounds::Exponential { start_ms, factor, count }) => metrics_aggregate
+                .with_exponential_connection_duration_bounds(start_ms, factor, count),
+            None => metrics_aggregate,
+        };
+        if let Some(max) = config.metrics_max_authorities {
+            metrics_aggregate = metrics_aggregate.with_max_authorities(max);
+        }
+        if let Some(ref templates) = config.metrics_routes {
+            metrics_aggregate = metrics_aggregate.with_routes(templates);
+        }
+        if let Some(capacity) = config.metrics_recent_events {
+            metrics_aggregate = metrics_aggregate.with_recent_events(capacity);
+        }
+        if let Some(ref placeholder) = config.metrics_missing_authority {
+            metrics_aggregate = metrics_aggregate.with_missing_authority(placeholder);
+        }
+        if let Some(ref patterns) = config.metrics_redact_authorities {
+            let redactor = metrics::Redactor::matching(patterns);
+            metrics_aggregate = metrics_aggregate.with_authority_redactor(redactor);
+        }
+        let mut metrics_service = metrics_service
+            .with_server_timing(config.metrics_server_timing)
+            .with_scrape_cache_ttl(config.metrics_scrape_cache_ttl)
+            .with_max_series(config.metrics_max_series)
+            .with_reset(config.metrics_allow_reset);
+        if let Some(level) = config.metrics_gzip_level {
+            metrics_service = metrics_service.with_gzip_level(level);
+        }
+        if let Some(ref token) = config.metrics_bearer_token {
+            metrics_service = metrics_service.with_bearer_token(token);
+        }
+        if let Some(ref namespace) = config.metrics_namespace {
+            metrics_service = metrics_service.with_namespace(namespace);
+        }
+        if let Some(ref path) = config.metrics_path {
+            metrics_service = metrics_service.with_path(path);
+        }
+        if let Some(ref labels) = config.metrics_const_labels {
+            metrics_service = metrics_service.with_const_labels(labels);
+        }
+        let statsd = config.metrics_statsd_addr.and_then(|addr| {
+            metrics::StatsdSink::new(addr)
+                .map_err(|e| error!("failed to create statsd socket for {}: {}", addr, e))
+                .ok()
+        });
+        let capacity = config.event_buffer_capacity;
+        // The event queue is created with the same capacity as the buffer.
+        metrics_aggregate.record_queue_capacity(capacity);
         Self {
             rx,
-            process_ctx: Arc::clone(process_ctx),
+            metrics_aggregate,
+            metrics_service,
+            capacity,
+            flush_interval: config.metrics_flush_interval,
+            max_idle_age: config.metrics_max_idle_age,
+            statsd,
         }
     }
 
+    /// Returns a handle to the metrics aggregate, for recording metrics that
+    /// are not derived from telemetry events.
+    pub fn metrics_aggregate(&self) -> metrics::Aggregate {
+        self.metrics_aggregate.clone()
+    }
+
     /// Bind a `Control` with a reactor core.
     ///
     /// # Arguments
@@ -76,13 +209,26 @@ impl MakeControl {
     /// - `Ok(())` if the timeout was successfully created.
     /// - `Err(io::Error)` if the timeout could not be created.
     pub fn make_control(self, taps: &Arc<Mutex<Taps>>, handle: &Handle) -> io::Result<Control> {
-        let (metrics_aggregate, metrics_service) =
-            metrics::new(&self.process_ctx);
+        let flush_timer = match self.flush_interval {
+            Some(interval) => Some(Interval::new(interval, handle)?),
+            None => None,
+        };
+        // Idle series are checked for as often as they may become idle.
+        let evict_timer = match self.max_idle_age {
+            Some(max_idle) => Some((Interval::new(max_idle, handle)?, max_idle)),
+            None => None,
+        };
 
         Ok(Control {
-            metrics_aggregate,
-            metrics_service,
+            metrics_aggregate: self.metrics_aggregate,
+            metrics_service: self.metrics_service,
+            statsd: self.statsd,
             rx: Some(self.rx),
+            pending: Vec::new(),
+            received: Vec::new(),
+            capacity: self.capacity,
+            flush_timer,
+            evict_timer,
             taps: Some(taps.clone()),
             handle: handle.clone(),
         })
@@ -107,6 +253,84 @@ impl Control {
         }
     }
 
+    /// Buffers an event until it is recorded into the metrics aggregate,
+    /// either at the next flush if a flush interval is configured, or once no
+    /// more events are ready to be received.
+    fn record(&mut self, ev: Event) {
+        if self.flush_timer.is_none() {
+            self.received.push(ev);
+            if self.received.len() >= self.capacity {
+                self.record_received();
+            }
+            return;
+        }
+
+        if let Some(&mut (ref last, ref mut n)) = self.pending.last_mut() {
+            if self.metrics_aggregate.coalesces(last, &ev) {
+                *n += 1;
+                return;
+            }
+        }
+
+        self.pending.push((ev, 1));
+        if self.pending.len() >= self.capacity {
+            trace!("flushing {} events; buffer full", self.pending.len());
+            self.flush();
+        }
+    }
+
+    /// Records the events received since they were last recorded into the
+    /// metrics aggregate, when no flush interval is configured.
+    fn record_received(&mut self) {
+        if !self.received.is_empty() {
+            self.metrics_aggregate.record_batch(&self.received);
+            self.received.clear();
+        }
+    }
+
+    /// Records all buffered events into the metrics aggregate.
+    fn flush(&mut self) {
+        self.metrics_aggregate.record_repeated_events(&self.pending);
+        self.pending.clear();
+    }
+
+    /// Returns true if the flush interval has elapsed since it was last polled.
+    fn poll_flush_timer(&mut self) -> bool {
+        match self.flush_timer.as_mut() {
+            Some(timer) => poll_timer(timer, "flush"),
+            None => false,
+        }
+    }
+
+    /// Evicts idle HTTP series if the eviction interval has elapsed since it
+    /// was last polled.
+    fn poll_evict_timer(&mut self) {
+        if let Some((ref mut timer, max_idle)) = self.evict_timer {
+            if poll_timer(timer, "eviction") {
+                let evicted = self.metrics_aggregate.evict_idle(max_idle);
+                if evicted > 0 {
+                    debug!("evicted {} idle metric label sets", evicted);
+                }
+            }
+        }
+    }
+
+    /// Records the number of events waiting in the event queue, before any
+    /// of them are received.
+    fn record_queue_depth(&mut self) {
+        let depth = self.rx.as_ref().map(Receiver::len).unwrap_or(0);
+        self.metrics_aggregate.record_queue_depth(depth);
+    }
+
+    /// Records the number of events that were dropped because the event
+    /// queue was full.
+    fn record_dropped(&mut self) {
+        let dropped = self.rx.as_ref().map(Receiver::take_rejected).unwrap_or(0);
+        if dropped > 0 {
+            self.metrics_aggregate.record_events_dropped(dropped);
+        }
+    }
+
     pub fn serve_metrics(&self, bound_port: connection::BoundPort)
         -> Box<Future<Item = (), Error = io::Error> + 'static>
     {
@@ -137,22 +361,141 @@ impl Future for Control {
     type Error = ();
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.record_queue_depth();
+        self.record_dropped();
         loop {
-            match try_ready!(self.recv()) {
-                Some(ev) => {
+            match self.recv()? {
+                Async::Ready(Some(ev)) => {
                     if let Some(taps) = self.taps.as_mut() {
                         if let Ok(mut t) = taps.lock() {
                             t.inspect(&ev);
                         }
                     }
 
-                    self.metrics_aggregate.record_event(&ev);
+                    if let Some(statsd) = self.statsd.as_mut() {
+                        statsd.record_event(&ev);
+                    }
+
+                    self.record(ev);
                 }
-                None => {
+                Async::Ready(None) => {
                     debug!("events finished");
+                    self.record_received();
+                    self.flush();
                     return Ok(Async::Ready(()));
                 }
+                Async::NotReady => break,
             };
         }
+        self.record_received();
+
+        if self.poll_flush_timer() {
+            self.flush();
+        }
+        self.poll_evict_timer();
+
+        Ok(Async::NotReady)
+    }
+}
+
+/// Returns true if `timer` has fired since it was last polled.
+fn poll_timer(timer: &mut Interval, name: &str) -> bool {
+    let mut expired = false;
+    loop {
+        match timer.poll() {
+            Ok(Async::Ready(Some(()))) => expired = true,
+            Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+            Err(e) => {
+                warn!("metrics {} timer failed: {}", name, e);
+                break;
+            }
+        }
+    }
+    expired
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_mpsc_lossy::{self, Sender};
+    use tokio_core::reactor::Core;
+
+    use telemetry::metrics::test_util::*;
+    use super::*;
+
+    /// Returns a `Control` which receives events from a queue of `capacity`
+    /// events, along with the sending side of the queue and the metrics'
+    /// service.
+    fn new_control(process: &Arc<ctx::Process>, capacity: usize, handle: &Handle)
+        -> (Sender<Event>, Control, metrics::Serve)
+    {
+        let (tx, rx) = futures_mpsc_lossy::channel(capacity);
+        let (metrics_aggregate, metrics_service) = metrics::new(process);
+        let serve = metrics_service.clone();
+        let make = MakeControl {
+            rx,
+            metrics_aggregate,
+            metrics_service,
+            capacity,
+            flush_interval: None,
+            max_idle_age: None,
+            statsd: None,
+        };
+        let taps = Arc::new(Mutex::new(Taps::default()));
+        let control = make.make_control(&taps, handle).expect("make control");
+        (tx, control, serve)
+    }
+
+    /// Polls `control` once, from within a task.
+    fn poll_once(control: &mut Control) {
+        future::lazy(|| {
+            assert!(control.poll().expect("poll control").is_not_ready());
+            Ok::<_, ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn queue_depth_is_the_number_of_events_waiting_to_be_received() {
+        let core = Core::new().unwrap();
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (tx, mut control, serve) = new_control(&process, 4, &core.handle());
+
+        for _ in 0..3 {
+            let end = request_end(&request("http://foo.test/", &proxy));
+            assert!(tx.lossy_send(end).is_ok());
+        }
+        poll_once(&mut control);
+        let text = scrape(&serve);
+        assert!(text.contains("telemetry_queue_depth 3\n"));
+        assert!(text.contains(
+            "request_total{authority=\"foo.test\",direction=\"inbound\",method=\"GET\"} 3\n"
+        ));
+
+        // Every event was received by the previous poll.
+        poll_once(&mut control);
+        assert!(scrape(&serve).contains("telemetry_queue_depth 0\n"));
+    }
+
+    #[test]
+    fn events_sent_to_a_full_queue_are_counted_as_dropped() {
+        let core = Core::new().unwrap();
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (tx, mut control, serve) = new_control(&process, 2, &core.handle());
+
+        for _ in 0..5 {
+            let _ = tx.lossy_send(request_end(&request("http://foo.test/", &proxy)));
+        }
+        poll_once(&mut control);
+        let text = scrape(&serve);
+        assert!(text.contains("telemetry_events_dropped_total 3\n"));
+        assert!(text.contains(
+            "request_total{authority=\"foo.test\",direction=\"inbound\",method=\"GET\"} 2\n"
+        ));
+
+        // Once the queue has been drained, events are no longer dropped.
+        assert!(tx.lossy_send(request_end(&request("http://foo.test/", &proxy))).is_ok());
+        poll_once(&mut control);
+        assert!(scrape(&serve).contains("telemetry_events_dropped_total 3\n"));
     }
 }
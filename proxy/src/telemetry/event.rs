@@ -7,6 +7,7 @@ use ctx;
 
 #[derive(Clone, Debug)]
 pub enum Event {
+    TransportAcceptFail(Arc<ctx::Proxy>, TransportAcceptFail),
     TransportOpen(Arc<ctx::transport::Ctx>),
     TransportClose(Arc<ctx::transport::Ctx>, TransportClose),
 
@@ -19,6 +20,14 @@ pub enum Event {
     StreamResponseEnd(Arc<ctx::http::Response>, StreamResponseEnd),
 }
 
+/// Describes a connection that was accepted, but failed before it could be
+/// opened as a transport.
+#[derive(Clone, Debug)]
+pub struct TransportAcceptFail {
+    /// Why the connection failed.
+    pub reason: &'static str,
+}
+
 #[derive(Clone, Debug)]
 pub struct TransportClose {
     /// Indicates that the transport was closed without error.
@@ -35,11 +44,15 @@ pub struct TransportClose {
 pub struct StreamRequestFail {
     pub since_request_open: Duration,
     pub error: h2::Reason,
+    /// The number of request body bytes sent before the request failed.
+    pub bytes_sent: u64,
 }
 
 #[derive(Clone, Debug)]
 pub struct StreamRequestEnd {
     pub since_request_open: Duration,
+    /// The number of request body bytes sent.
+    pub bytes_sent: u64,
 }
 
 #[derive(Clone, Debug)]
@@ -50,6 +63,8 @@ pub struct StreamResponseOpen {
 #[derive(Clone, Debug)]
 pub struct StreamResponseFail {
     pub since_request_open: Duration,
+    /// The time since the request stream ended, if it had ended.
+    pub since_request_end: Option<Duration>,
     pub since_response_open: Duration,
     pub error: h2::Reason,
     pub bytes_sent: u64,
@@ -60,6 +75,8 @@ pub struct StreamResponseFail {
 pub struct StreamResponseEnd {
     pub grpc_status: Option<u32>,
     pub since_request_open: Duration,
+    /// The time since the request stream ended, if it had ended.
+    pub since_request_end: Option<Duration>,
     pub since_response_open: Duration,
     pub bytes_sent: u64,
     pub frames_sent: u32,
@@ -89,6 +106,7 @@ impl Event {
 
     pub fn proxy(&self) -> &Arc<ctx::Proxy> {
         match *self {
+            Event::TransportAcceptFail(ref proxy, _) => proxy,
             Event::TransportOpen(ref ctx) | Event::TransportClose(ref ctx, _) => ctx.proxy(),
             Event::StreamRequestOpen(ref req) |
             Event::StreamRequestFail(ref req, _) |
@@ -0,0 +1,663 @@
+//! Records telemetry events into the metrics shards.
+
+use std::borrow::Borrow;
+use std::{ptr, time};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ctx;
+use telemetry::event::Event;
+use super::{
+    latency_bounds,
+    lock,
+    Metrics,
+    ResponseByteLatency,
+    ResponseLatencyQuantiles,
+    Scalar,
+    Shards,
+};
+use super::labels::{
+    escape_label_value,
+    AcceptErrorLabels,
+    NO_AUTHORITY,
+    Redactor,
+    RequestLabelsRef,
+    ResponseLabels,
+    TransportLabels,
+    TransportCloseLabels,
+};
+use super::latency::Histogram;
+use super::limit::AuthorityLimit;
+use super::recent::RecentEvents;
+use super::route::RouteMatcher;
+use super::size::SizeHistogram;
+
+/// Tracks Prometheus metrics
+#[derive(Debug, Clone)]
+pub struct Aggregate {
+    pub(super) shards: Arc<Shards>,
+
+    /// If true, response latency is measured from the end of the request
+    /// stream, rather than from when the request was opened.
+    latency_from_request_end: bool,
+
+    /// If true, the latencies of the first and last bytes of each response
+    /// are recorded.
+    response_byte_latency: bool,
+
+    /// Rewrites authorities before they are recorded as label values.
+    redactor: Option<Redactor>,
+
+    /// The authority recorded for requests without one, already escaped as
+    /// a label value.
+    missing_authority: Arc<str>,
+
+    /// If set, HTTP series are also labeled by the path template matching
+    /// each request's path.
+    routes: Option<RouteMatcher>,
+
+    /// If true, HTTP series of gRPC requests are also labeled by the gRPC
+    /// method.
+    grpc_method_labels: bool,
+
+    /// If true, each event is also kept in the shards' recent events.
+    recent_events: bool,
+}
+
+// ===== impl Aggregate =====
+
+impl Aggregate {
+
+    pub(super) fn new(shards: &Arc<Shards>) -> Self {
+        Aggregate {
+            shards: shards.clone(),
+            latency_from_request_end: false,
+            response_byte_latency: false,
+            redactor: None,
+            missing_authority: NO_AUTHORITY.into(),
+            routes: None,
+            grpc_method_labels: false,
+            recent_events: false,
+        }
+    }
+
+    /// Measure response latency from the end of the request stream, so that
+    /// the time taken to send the request body is not included.
+    ///
+    /// If the response completes before the request stream has ended, its
+    /// latency is still measured from when the request was opened.
+    pub fn with_latency_from_request_end(self, latency_from_request_end: bool) -> Self {
+        Aggregate {
+            latency_from_request_end,
+            ..self
+        }
+    }
+
+    /// Configures whether the total size of each request's headers is
+    /// recorded in the `request_header_bytes` histogram.
+    ///
+    /// This is disabled by default, as it adds an observation for every
+    /// request when it is opened. When disabled, the histogram is not
+    /// exported. Only requests whose header size was measured when they were
+    /// opened are recorded.
+    pub fn with_request_header_bytes(self, request_header_bytes: bool) -> Self {
+        self.shards.each(|metrics| {
+            metrics.request_header_bytes = if request_header_bytes {
+                Some(Scalar::<SizeHistogram>::new(
+                    "request_header_bytes",
+                    "A histogram of the total size of each request's header \
+                    names and values, in bytes.",
+                ))
+            } else {
+                None
+            };
+        });
+        self
+    }
+
+    /// Configures whether the `request_by_authority_total` counter is
+    /// exported, summing `request_total` for each authority across both
+    /// directions.
+    ///
+    /// This is disabled by default, as it adds a series for every authority.
+    /// It is computed when the metrics are formatted, so enabling it doesn't
+    /// add any work when requests are recorded.
+    pub fn with_requests_by_authority(self, requests_by_authority: bool) -> Self {
+        self.shards.each(|metrics| {
+            metrics.requests_by_authority = requests_by_authority;
+        });
+        self
+    }
+
+    /// Configures whether the latencies of the first and last bytes of each
+    /// response are recorded, in the `response_first_byte_latency_ms` and
+    /// `response_last_byte_latency_ms` histograms.
+    ///
+    /// These are disabled by default. When disabled, the histograms are not
+    /// exported.
+    pub fn with_response_byte_latency(self, response_byte_latency: bool) -> Self {
+        self.shards.each(|metrics| {
+            metrics.response_byte_latency = if response_byte_latency {
+                Some(ResponseByteLatency::new())
+            } else {
+                None
+            };
+        });
+
+        Aggregate {
+            response_byte_latency,
+            ..self
+        }
+    }
+
+    /// Configures the quantiles of response latency reported by the
+    /// `response_latency_quantiles_ms` summary, over the responses completed
+    /// in the last minute.
+    ///
+    /// By default, no quantiles are recorded and the summary is not exported.
+    pub fn with_response_latency_quantiles(self, quantiles: &[f64]) -> Self {
+        self.shards.each(|metrics| {
+            metrics.response_latency_quantiles = Some(ResponseLatencyQuantiles::new(quantiles));
+        });
+        self
+    }
+
+    /// Configures the upper bounds of the response latency histogram's
+    /// buckets.
+    ///
+    /// By default, every latency histogram uses the same bounds. Responses
+    /// which have already been recorded are moved into the new buckets.
+    pub fn with_response_latency_bounds(self, bounds: &[Duration]) -> Self {
+        self.with_response_latency_histogram(Histogram::new(&latency_bounds(bounds)))
+    }
+
+    /// Configures the response latency histogram to have `count`
+    /// exponentially-spaced buckets, as `Histogram::exponential` does.
+    pub fn with_exponential_response_latency_bounds(
+        self,
+        start_ms: u64,
+        factor: f64,
+        count: usize,
+    ) -> Self {
+        self.with_response_latency_histogram(Histogram::exponential(start_ms, factor, count))
+    }
+
+    /// Configures the response latency histogram's series to have the
+    /// bounds of `empty`, from which new series are cloned.
+    fn with_response_latency_histogram(self, empty: Histogram) -> Self {
+        self.shards.each(|metrics| {
+            for histogram in metrics.response_latency.values.values_mut() {
+                histogram.reconfigure(empty.bounds());
+            }
+            metrics.empty_response_latency = empty.clone();
+        });
+        self
+    }
+
+    /// Configures the upper bounds of the TCP connection duration
+    /// histogram's buckets.
+    ///
+    /// By default, every latency histogram uses the same bounds, which are
+    /// too fine-grained for connections that last for minutes. Connections
+    /// which have already been recorded are moved into the new buckets.
+    pub fn with_connection_duration_bounds(self, bounds: &[Duration]) -> Self {
+        self.with_connection_duration_histogram(Histogram::new(&latency_bounds(bounds)))
+    }
+
+    /// Configures the TCP connection duration histogram to have `count`
+    /// exponentially-spaced buckets, as `Histogram::exponential` does.
+    pub fn with_exponential_connection_duration_bounds(
+        self,
+        start_ms: u64,
+        factor: f64,
+        count: usize,
+    ) -> Self {
+        self.with_connection_duration_histogram(Histogram::exponential(start_ms, factor, count))
+    }
+
+    /// Configures the TCP connection duration histogram's series to have
+    /// the bounds of `empty`, from which new series are cloned.
+    fn with_connection_duration_histogram(self, empty: Histogram) -> Self {
+        self.shards.each(|metrics| {
+            for histogram in metrics.tcp.connection_duration.values.values_mut() {
+                histogram.reconfigure(empty.bounds());
+            }
+            metrics.tcp.empty_connection_duration = empty.clone();
+        });
+        self
+    }
+
+    /// Configures the maximum number of distinct authorities recorded for
+    /// each destination. Requests with any other authority are recorded
+    /// with the authority `__other__`.
+    ///
+    /// Defaults to 1000.
+    pub fn with_max_authorities(self, max: usize) -> Self {
+        self.shards.each(|metrics| {
+            metrics.authorities = AuthorityLimit::new(max);
+        });
+        self
+    }
+
+    /// Configures a `Redactor` which rewrites the authority of each request
+    /// before it is recorded as a label value.
+    pub fn with_authority_redactor(self, redactor: Redactor) -> Self {
+        Aggregate {
+            redactor: Some(redactor),
+            ..self
+        }
+    }
+
+    /// Configures the authority recorded for requests without one, such as
+    /// HTTP/1.0 requests without a `Host` header.
+    ///
+    /// Defaults to `__no_authority__`.
+    pub fn with_missing_authority(self, placeholder: &str) -> Self {
+        Aggregate {
+            missing_authority: (&*escape_label_value(placeholder)).into(),
+            ..self
+        }
+    }
+
+    /// Labels HTTP series by the route matching each request's path, as
+    /// well as by its authority.
+    ///
+    /// `templates` are paths such as `/users/{id}`, whose `{param}` segments
+    /// match any single segment. See `RouteMatcher` for how a template is
+    /// chosen when several match. Requests matching none of them are
+    /// labeled `route="__unmatched__"`.
+    pub fn with_routes<T: AsRef<str>>(self, templates: &[T]) -> Self {
+        Aggregate {
+            routes: Some(RouteMatcher::new(templates)),
+            ..self
+        }
+    }
+
+    /// Configures whether HTTP series of gRPC requests are also labeled by
+    /// the request's gRPC method, such as `grpc_method="/pkg.Service/Method"`.
+    ///
+    /// This is disabled by default, as the method is taken from the path
+    /// chosen by the client. When enabled, at most 100 distinct methods are
+    /// recorded for each authority; requests with any other method are
+    /// labeled `grpc_method="__other__"`.
+    pub fn with_grpc_method_labels(self, grpc_method_labels: bool) -> Self {
+        Aggregate {
+            grpc_method_labels,
+            ..self
+        }
+    }
+
+    /// Keeps the `capacity` most recently recorded events, which are served
+    /// as JSON at `<path>/events` for debugging.
+    ///
+    /// This is disabled by default, in which case nothing is kept and the
+    /// events aren't served.
+    pub fn with_recent_events(self, capacity: usize) -> Self {
+        *lock(&self.shards.recent_events, "recent events") = Some(RecentEvents::new(capacity));
+        Aggregate {
+            recent_events: true,
+            ..self
+        }
+    }
+
+    /// Keeps `n` copies of each `(event, n)` in the recent events, if they
+    /// are being kept.
+    fn record_recent<E: Borrow<Event>>(&self, events: &[(E, u64)]) {
+        if !self.recent_events {
+            return;
+        }
+        let now = time::SystemTime::now();
+        let mut recent_events = lock(&self.shards.recent_events, "recent events");
+        if let Some(ref mut recent) = *recent_events {
+            for &(ref event, n) in events {
+                for _ in 0..n {
+                    recent.push(now, event.borrow());
+                }
+            }
+        }
+    }
+
+    /// Returns true if observing `a` and then `b` only increments the same
+    /// counters by the same amounts twice, so that they may be recorded
+    /// together with `record_repeated_events`.
+    pub fn coalesces(&self, a: &Event, b: &Event) -> bool {
+        // Request failures also record their latency, so they can't be
+        // coalesced.
+        fn request(ev: &Event) -> Option<(&ctx::http::Request, u64)> {
+            match *ev {
+                Event::StreamRequestEnd(ref req, ref end) => Some((req, end.bytes_sent)),
+                _ => None,
+            }
+        }
+
+        match (request(a), request(b)) {
+            (Some((a, a_bytes)), Some((b, b_bytes))) if a_bytes == b_bytes => {
+                let routes = self.routes.as_ref();
+                let grpc = self.grpc_method_labels;
+                RequestLabelsRef::new(a).route(routes, a).with_grpc_method(grpc, a) ==
+                    RequestLabelsRef::new(b).route(routes, b).with_grpc_method(grpc, b)
+            },
+            _ => false,
+        }
+    }
+
+    /// Returns the latency to record for a response, given the time since
+    /// the request was opened and the time since the request ended.
+    fn response_latency(&self, since_open: Duration, since_end: Option<Duration>) -> Duration {
+        if self.latency_from_request_end {
+            since_end.unwrap_or(since_open)
+        } else {
+            since_open
+        }
+    }
+
+    /// Updates the process metrics.
+    #[inline]
+    fn update<F: FnOnce(&mut Metrics)>(&mut self, f: F) {
+        let mut metrics = lock(self.shards.process(), "metrics");
+        f(&mut *metrics);
+    }
+
+    /// Record that `n` telemetry events were dropped before they could be
+    /// observed.
+    pub fn record_events_dropped(&mut self, n: usize) {
+        self.update(|metrics| {
+            metrics.events_dropped_total.value += n as u64;
+        })
+    }
+
+    /// Record that an accepted connection failed for `reason` before it
+    /// could be tracked as a transport.
+    pub fn record_accept_error(&mut self, reason: &'static str) {
+        self.update(|metrics| Aggregate::record_accept_error_locked(metrics, reason))
+    }
+
+    /// Record an accept error in `metrics`, the locked process metrics.
+    fn record_accept_error_locked(metrics: &mut Metrics, reason: &'static str) {
+        metrics.tcp().accept_errors_total(AcceptErrorLabels::new(reason)).incr();
+    }
+
+    /// Evict the HTTP series which have not been updated for `max_idle`,
+    /// returning the number of sets of request labels which were evicted.
+    ///
+    /// Series with an open stream are kept for several times as long, in
+    /// case the event closing the stream was dropped.
+    pub fn evict_idle(&mut self, max_idle: Duration) -> usize {
+        let now = Instant::now();
+        let mut evicted = 0;
+        self.shards.each(|metrics| {
+            evicted += metrics.evict_idle(max_idle, now);
+        });
+        evicted
+    }
+
+    /// Record the number of events waiting in the event queue to be
+    /// received.
+    pub fn record_queue_depth(&mut self, depth: usize) {
+        self.update(|metrics| {
+            metrics.queue_depth.value.set(depth as u64);
+        })
+    }
+
+    /// Record the maximum number of events that may wait in the event queue.
+    pub fn record_queue_capacity(&mut self, capacity: usize) {
+        self.update(|metrics| {
+            metrics.queue_capacity.value.set(capacity as u64);
+        })
+    }
+
+    /// Record that the proxy began draining connections at `at`.
+    pub fn record_drain_start(&mut self, at: time::SystemTime) {
+        let secs = at.duration_since(time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.update(|metrics| {
+            metrics.shutdown_initiated_time.value.set(secs);
+        })
+    }
+
+    /// Record that the proxy finished draining connections after `duration`.
+    pub fn record_drain_end(&mut self, duration: Duration) {
+        self.update(|metrics| {
+            metrics.drain_duration.value += duration;
+        })
+    }
+
+    /// Observe each `(event, n)` of `events` in order, as `n` consecutive
+    /// occurrences of the event.
+    ///
+    /// If an event only increments a counter, the counter is incremented by
+    /// `n` with a single update, which has the same result as observing the
+    /// event `n` times.
+    ///
+    /// Each shard of the metrics is locked at most once for all of the
+    /// events, rather than once for each event.
+    pub fn record_repeated_events(&mut self, events: &[(Event, u64)]) {
+        self.record_all(events);
+    }
+
+    /// Observe each of `events` in order, with the same result as observing
+    /// each of them in turn.
+    ///
+    /// Each shard of the metrics is locked at most once for all of the
+    /// events, rather than once for each event.
+    pub fn record_batch(&mut self, events: &[Event]) {
+        let events = events.iter().map(|event| (event, 1)).collect::<Vec<_>>();
+        self.record_all(&events);
+    }
+
+    /// Observe each `(event, n)` of `events`, as `n` consecutive occurrences
+    /// of the event, holding the lock of each shard while all of the events
+    /// recorded in it are observed.
+    ///
+    /// Each shard only records the events of its own traffic, so observing
+    /// the events of one shard before those of the other has the same result
+    /// as observing every event in order.
+    fn record_all<E: Borrow<Event>>(&mut self, events: &[(E, u64)]) {
+        self.record_recent(events);
+        for shard in &[&self.shards.inbound, &self.shards.outbound] {
+            let mut locked = None;
+            for &(ref event, n) in events {
+                let event = event.borrow();
+                if !ptr::eq(self.shards.recording(event), *shard) {
+                    continue;
+                }
+                let metrics = locked.get_or_insert_with(|| lock(shard, "metrics"));
+                self.record_locked(metrics, event, n);
+            }
+        }
+    }
+
+    /// Observe `n` consecutive occurrences of the given event in `metrics`,
+    /// the locked shard in which it is recorded.
+    fn record_locked(&self, metrics: &mut Metrics, event: &Event, n: u64) {
+        trace!("Metrics::record({:?})", event);
+        match *event {
+
+            Event::StreamRequestEnd(ref req, ref end) => {
+                let labels = RequestLabelsRef::new(req)
+                    .redact(self.redactor.as_ref())
+                    .route(self.routes.as_ref(), req)
+                    .with_grpc_method(self.grpc_method_labels, req)
+                    .with_missing_authority(&self.missing_authority);
+                let labels = metrics.limit_request_labels(labels);
+                *metrics.request_total(&labels) += n;
+                *metrics.request_body_bytes_total(&labels) += end.bytes_sent.saturating_mul(n);
+                metrics.request_activity(&labels).touch();
+            },
+
+            _ => for _ in 0..n {
+                self.record_once_locked(metrics, event);
+            },
+        }
+    }
+
+    /// Observe the given event in `metrics`, the locked shard in which it is
+    /// recorded.
+    fn record_once_locked(&self, metrics: &mut Metrics, event: &Event) {
+        match *event {
+
+            Event::StreamRequestOpen(ref req) => {
+                // Request and response totals are recorded when the stream
+                // *finishes*, but the size of the request's headers is
+                // known as soon as it is opened.
+                let labels = RequestLabelsRef::new(req)
+                    .redact(self.redactor.as_ref())
+                    .route(self.routes.as_ref(), req)
+                    .with_grpc_method(self.grpc_method_labels, req)
+                    .with_missing_authority(&self.missing_authority);
+                let labels = metrics.limit_request_labels(labels);
+                metrics.request_activity(&labels).stream_opened();
+                metrics.request_opened(req.id, &labels, Instant::now());
+                if let Some(header_bytes) = req.header_bytes {
+                    if let Some(ref mut histogram) = metrics.request_header_bytes {
+                        histogram.value += header_bytes;
+                    }
+                }
+            },
+
+            Event::StreamResponseOpen(ref res, ref open) => {
+                // Most metrics for the response are recorded when the stream
+                // *finishes*, but its first-byte latency is known as soon as
+                // it is opened. Whether it has a gRPC status isn't known
+                // yet, so it is labeled by its HTTP status alone.
+                if self.response_byte_latency {
+                    let labels = Arc::new(ResponseLabels::new(
+                        res,
+                        None,
+                        self.redactor.as_ref(),
+                        self.routes.as_ref(),
+                    ).with_missing_authority(&self.missing_authority)
+                        .with_grpc_method(self.grpc_method_labels, &res.request));
+                    let labels = metrics.limit_response_labels(labels);
+                    metrics.response_activity(&labels).touch();
+                    if let Some(ref mut byte_latency) = metrics.response_byte_latency {
+                        byte_latency.record_first_byte(&labels, open.since_request_open);
+                    }
+                }
+            },
+
+            Event::StreamRequestFail(ref req, ref fail) => {
+                // The request failed before a response was received, so its
+                // latency is not a response latency.
+                let labels = RequestLabelsRef::new(req)
+                    .redact(self.redactor.as_ref())
+                    .route(self.routes.as_ref(), req)
+                    .with_grpc_method(self.grpc_method_labels, req)
+                    .with_missing_authority(&self.missing_authority);
+                let labels = metrics.limit_request_labels(labels);
+                metrics.request_total(&labels).incr();
+                *metrics.request_failure_latency(&labels) += fail.since_request_open;
+                *metrics.request_body_bytes_total(&labels) += fail.bytes_sent;
+                metrics.request_activity(&labels).stream_closed();
+                metrics.request_closed(req.id);
+            },
+
+            Event::StreamRequestEnd(ref req, ref end) => {
+                let labels = RequestLabelsRef::new(req)
+                    .redact(self.redactor.as_ref())
+                    .route(self.routes.as_ref(), req)
+                    .with_grpc_method(self.grpc_method_labels, req)
+                    .with_missing_authority(&self.missing_authority);
+                let labels = metrics.limit_request_labels(labels);
+                metrics.request_total(&labels).incr();
+                *metrics.request_body_bytes_total(&labels) += end.bytes_sent;
+                metrics.request_activity(&labels).touch();
+            },
+
+            Event::StreamResponseEnd(ref res, ref end) => {
+                let labels = Arc::new(ResponseLabels::new(
+                    res,
+                    end.grpc_status,
+                    self.redactor.as_ref(),
+                    self.routes.as_ref(),
+                ).with_missing_authority(&self.missing_authority)
+                    .with_grpc_method(self.grpc_method_labels, &res.request));
+                let latency = self.response_latency(end.since_request_open, end.since_request_end);
+                let labels = metrics.limit_response_labels(labels);
+                metrics.response_total(&labels).incr();
+                *metrics.response_latency(&labels) += latency;
+                *metrics.response_body_bytes_total(&labels) += end.bytes_sent;
+                metrics.response_activity(&labels).stream_closed();
+                metrics.request_closed(res.request.id);
+                if let Some(ref mut byte_latency) = metrics.response_byte_latency {
+                    byte_latency.record_last_byte(&labels, end.since_request_open);
+                }
+                if let Some(ref mut quantiles) = metrics.response_latency_quantiles {
+                    quantiles.record(&labels, latency);
+                }
+            },
+
+            Event::StreamResponseFail(ref res, ref fail) => {
+                let labels = Arc::new(ResponseLabels::fail(
+                    res,
+                    fail.error,
+                    self.redactor.as_ref(),
+                    self.routes.as_ref(),
+                ).with_missing_authority(&self.missing_authority)
+                    .with_grpc_method(self.grpc_method_labels, &res.request));
+                let latency = self.response_latency(fail.since_request_open, fail.since_request_end);
+                let labels = metrics.limit_response_labels(labels);
+                metrics.response_total(&labels).incr();
+                *metrics.response_latency(&labels) += latency;
+                *metrics.response_body_bytes_total(&labels) += fail.bytes_sent;
+                metrics.response_activity(&labels).stream_closed();
+                metrics.request_closed(res.request.id);
+                if let Some(ref mut byte_latency) = metrics.response_byte_latency {
+                    byte_latency.record_last_byte(&labels, fail.since_request_open);
+                }
+                if let Some(ref mut quantiles) = metrics.response_latency_quantiles {
+                    quantiles.record(&labels, latency);
+                }
+            },
+
+            Event::TransportOpen(ref ctx) => {
+                let labels = Arc::new(TransportLabels::new(ctx));
+                metrics.tcp().open_total(&labels).incr();
+                metrics.tcp().open_connections(&labels).incr();
+            },
+
+            Event::TransportAcceptFail(_, ref fail) => {
+                Aggregate::record_accept_error_locked(metrics, fail.reason);
+            },
+
+            Event::TransportClose(ref ctx, ref close) => {
+                let labels = Arc::new(TransportLabels::new(ctx));
+                let close_labels = Arc::new(TransportCloseLabels::new(ctx, close));
+                *metrics.tcp().write_bytes_total(&labels) += close.tx_bytes as u64;
+                *metrics.tcp().read_bytes_total(&labels) += close.rx_bytes as u64;
+
+                *metrics.tcp().connection_duration(&close_labels) += close.duration;
+                metrics.tcp().close_total(&close_labels).incr();
+
+                let metrics = metrics.tcp().open_connections.values.get_mut(&labels);
+                debug_assert!(metrics.is_some());
+                match metrics {
+                    Some(m) => {
+                        m.decr();
+                    }
+                    None => {
+                        error!("Closed transport missing from metrics registry: {{{}}}", labels);
+                    }
+                }
+            },
+        };
+    }
+}
+
+#[cfg(test)]
+impl Aggregate {
+    /// Observe the given event.
+    pub fn record_event(&mut self, event: &Event) {
+        self.record_all(&[(event, 1)]);
+    }
+
+    /// Observe `n` consecutive occurrences of the given event.
+    ///
+    /// If the event only increments a counter, the counter is incremented
+    /// by `n` with a single update, which has the same result as observing
+    /// the event `n` times.
+    pub fn record_repeated_event(&mut self, event: &Event, n: u64) {
+        self.record_all(&[(event, n)]);
+    }
+}
@@ -1,12 +1,16 @@
-use std::{fmt, ops};
-use std::num::Wrapping;
+use std::{cmp, fmt, ops};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-/// A Prometheus counter is represented by a `Wrapping` unsigned 64-bit int.
+use super::FloatValue;
+
+/// A Prometheus counter is represented by an unsigned 64-bit int.
 ///
-/// Counters always explicitly wrap on overflows rather than panicking in
-/// debug builds. Prometheus' [`rate()`] and [`irate()`] queries handle breaks
-/// in monotonicity gracefully  (see also [`resets()`]), so wrapping is less
-/// problematic than panicking in this case.
+/// Like a `Gauge`, a counter saturates at `u64::MAX` rather than wrapping or
+/// panicking in debug builds, and logs a warning when it does. Prometheus'
+/// [`rate()`] and [`irate()`] queries would handle a wrapped counter as if it
+/// had been reset (see also [`resets()`]), but a counter which stops
+/// increasing is less misleading than one which appears to have counted
+/// everything again.
 ///
 /// Note, however, that Prometheus represents counters using 64-bit
 /// floating-point numbers. The correct semantics are to ensure the counter
@@ -17,44 +21,229 @@ use std::num::Wrapping;
 /// [`irate()`]: https://prometheus.io/docs/prometheus/latest/querying/functions/#irate()
 /// [`resets()`]: https://prometheus.io/docs/prometheus/latest/querying/functions/#resets
 ///
+/// Each counter also records when it was created, which OpenMetrics scrapes
+/// expose as a `_created` sample, so that consumers can tell that a counter
+/// was reset even if its value never decreased.
+///
 // TODO: Implement Prometheus reset semantics correctly, taking into
 //       consideration that Prometheus models counters as `f64` and so
 //       there are only 52 significant bits.
-#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
-pub struct Counter(Wrapping<u64>);
+#[derive(Copy, Clone, Debug)]
+pub struct Counter {
+    value: u64,
+
+    /// When the counter was created, in milliseconds since the Unix epoch.
+    created_ms: u64,
+}
+
+/// A Prometheus counter of fractional values, such as weighted costs.
+///
+/// Unlike `Counter`, values are accumulated as an `f64`, so they are not
+/// truncated, but precision is lost once the total is large enough.
+// Not yet used by any metric.
+#[allow(dead_code)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CounterF64(f64);
 
 // ===== impl Counter =====
 
 impl Counter {
     /// Increment the counter by one.
-    ///
-    /// This function wraps on overflows.
     pub fn incr(&mut self) {
-        (*self).0 += Wrapping(1);
+        self.add(1)
+    }
+
+    /// Increase the counter by `n`.
+    ///
+    /// If that would overflow, the counter is set to `u64::MAX` instead.
+    pub fn add(&mut self, n: u64) {
+        match self.value.checked_add(n) {
+            Some(value) => self.value = value,
+            None => {
+                warn!("Counter overflow");
+                self.value = u64::max_value();
+            },
+        }
+    }
+
+    /// Resets the counter to zero, as if it had just been created.
+    pub fn reset(&mut self) {
+        *self = Counter::default();
+    }
+
+    /// Returns when the counter was created, in seconds since the Unix
+    /// epoch.
+    pub fn created_secs(&self) -> f64 {
+        self.created_ms as f64 / 1_000.0
     }
 }
 
+impl Default for Counter {
+    fn default() -> Self {
+        let created_ms = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() * 1_000 + u64::from(d.subsec_nanos() / 1_000_000))
+            .unwrap_or(0);
+        Counter {
+            value: 0,
+            created_ms,
+        }
+    }
+}
+
+/// Counters are equal if they hold the same value, regardless of when they
+/// were created.
+impl PartialEq for Counter {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+impl Eq for Counter {}
+
 impl Into<u64> for Counter {
     fn into(self) -> u64 {
-        (self.0).0
+        self.value
     }
 }
 
+/// The sum of two counters saturates as `Counter::add` does, and was created
+/// when the older of them was.
 impl ops::Add for Counter {
     type Output = Self;
-    fn add(self, Counter(rhs): Self) -> Self::Output {
-        Counter(self.0 + rhs)
+    fn add(self, rhs: Self) -> Self::Output {
+        let mut sum = Counter {
+            value: self.value,
+            created_ms: cmp::min(self.created_ms, rhs.created_ms),
+        };
+        sum += rhs.value;
+        sum
+    }
+}
+
+/// The difference between two counters is the increase from `rhs` to `self`.
+///
+/// If `rhs` is larger, such as if the counter has been reset since, the
+/// difference is zero rather than wrapping to a huge increase.
+impl ops::Sub for Counter {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self::Output {
+        Counter {
+            value: self.value.saturating_sub(rhs.value),
+            created_ms: self.created_ms,
+        }
     }
 }
 
 impl ops::AddAssign<u64> for Counter {
     fn add_assign(&mut self, rhs: u64) {
-        (*self).0 += Wrapping(rhs)
+        self.add(rhs)
     }
 }
 
 impl fmt::Display for Counter {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        self.0.fmt(f)
+        self.value.fmt(f)
+    }
+}
+
+// ===== impl CounterF64 =====
+
+// Not yet used by any metric.
+#[allow(dead_code)]
+impl CounterF64 {
+    /// Increase the counter by `n`.
+    ///
+    /// Counters may only increase, so negative and non-finite values are
+    /// ignored.
+    pub fn add(&mut self, n: f64) {
+        if n >= 0.0 && n.is_finite() {
+            (*self).0 += n;
+        } else {
+            warn!("CounterF64 cannot be increased by {}", n);
+        }
+    }
+}
+
+impl Into<f64> for CounterF64 {
+    fn into(self) -> f64 {
+        self.0
+    }
+}
+
+impl fmt::Display for CounterF64 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        FloatValue(self.0).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{f64, u64};
+    use super::*;
+
+    #[test]
+    fn counter_saturates_at_max() {
+        let mut counter = Counter::default();
+        counter.add(u64::MAX - 1);
+        counter.incr();
+        assert_eq!(Into::<u64>::into(counter), u64::MAX);
+
+        counter.incr();
+        assert_eq!(Into::<u64>::into(counter), u64::MAX);
+
+        let mut counter = Counter::default();
+        counter += u64::MAX - 1;
+        counter += 2;
+        assert_eq!(Into::<u64>::into(counter), u64::MAX);
+
+        let mut half = Counter::default();
+        half.add(u64::MAX / 2 + 1);
+        assert_eq!(Into::<u64>::into(half + half), u64::MAX);
+    }
+
+    #[test]
+    fn counter_resets_to_zero() {
+        let mut counter = Counter::default();
+        counter.add(u64::MAX);
+        counter.reset();
+        assert_eq!(Into::<u64>::into(counter), 0);
+        counter.incr();
+        assert_eq!(Into::<u64>::into(counter), 1);
+    }
+
+    #[test]
+    fn counter_difference_does_not_wrap() {
+        let mut prev = Counter::default();
+        prev.add(3);
+        let mut counter = prev;
+        counter.add(2);
+        assert_eq!(Into::<u64>::into(counter - prev), 2);
+
+        counter.reset();
+        counter.incr();
+        assert_eq!(Into::<u64>::into(counter - prev), 0);
+    }
+
+    #[test]
+    fn counter_f64_is_not_truncated() {
+        let mut counter = CounterF64::default();
+        counter.add(1.0);
+        counter.add(0.5);
+        assert_eq!(counter.to_string(), "1.5");
+
+        // Written as Prometheus parses it, like every other float value.
+        counter.add(999_998.5);
+        assert_eq!(counter.to_string(), "1000000");
+        assert_eq!(counter.to_string().parse::<f64>(), Ok(1e+06));
+    }
+
+    #[test]
+    fn counter_f64_ignores_negative_and_nan() {
+        let mut counter = CounterF64::default();
+        counter.add(2.25);
+        counter.add(-1.0);
+        counter.add(f64::NAN);
+        counter.add(f64::INFINITY);
+        assert_eq!(Into::<f64>::into(counter), 2.25);
     }
 }
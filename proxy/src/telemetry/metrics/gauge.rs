@@ -7,21 +7,36 @@ pub struct Gauge(u64);
 impl Gauge {
     /// Increment the gauge by one.
     pub fn incr(&mut self) {
-        if let Some(new_value) = self.0.checked_add(1) {
+        self.add(1)
+    }
+
+    /// Decrement the gauge by one.
+    pub fn decr(&mut self) {
+        self.sub(1)
+    }
+
+    /// Increase the gauge by `n`.
+    pub fn add(&mut self, n: u64) {
+        if let Some(new_value) = self.0.checked_add(n) {
             (*self).0 = new_value;
         } else {
             warn!("Gauge overflow");
         }
     }
 
-    /// Decrement the gauge by one.
-    pub fn decr(&mut self) {
-        if let Some(new_value) = self.0.checked_sub(1) {
+    /// Decrease the gauge by `n`.
+    pub fn sub(&mut self, n: u64) {
+        if let Some(new_value) = self.0.checked_sub(n) {
             (*self).0 = new_value;
         } else {
             warn!("Gauge underflow");
         }
     }
+
+    /// Set the gauge to `value`.
+    pub fn set(&mut self, value: u64) {
+        (*self).0 = value;
+    }
 }
 
 impl From<u64> for Gauge {
@@ -41,3 +56,26 @@ impl fmt::Display for Gauge {
         self.0.fmt(f)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::u64;
+    use super::*;
+
+    #[test]
+    fn add_and_sub_do_not_overflow() {
+        let mut gauge = Gauge::default();
+        gauge.add(5);
+        gauge.sub(2);
+        assert_eq!(Into::<u64>::into(gauge), 3);
+
+        gauge.sub(4);
+        assert_eq!(Into::<u64>::into(gauge), 3);
+
+        gauge.set(u64::MAX - 1);
+        gauge.add(2);
+        assert_eq!(Into::<u64>::into(gauge), u64::MAX - 1);
+        gauge.incr();
+        assert_eq!(Into::<u64>::into(gauge), u64::MAX);
+    }
+}
@@ -1,12 +1,28 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt::{self, Write};
 use std::hash;
 use std::sync::Arc;
 
+use h2;
 use http;
+use indexmap::Equivalent;
 
 use ctx;
 use telemetry::event;
+use super::route::RouteMatcher;
+
+/// The authority recorded for requests whose authority would exceed the
+/// maximum number of distinct authorities for their destination.
+pub const OTHER_AUTHORITY: &str = "__other__";
+
+/// The authority recorded by default for requests without an authority, so
+/// that they are distinct from requests with an empty one.
+pub const NO_AUTHORITY: &str = "__no_authority__";
+
+/// The gRPC method recorded for requests whose method would exceed the
+/// maximum number of distinct gRPC methods for their authority.
+pub const OTHER_GRPC_METHOD: &str = "__other__";
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct RequestLabels {
@@ -21,8 +37,46 @@ pub struct RequestLabels {
     /// The value of the `:authority` (HTTP/2) or `Host` (HTTP/1.1) header of
     /// the request.
     authority: String,
+
+    /// The request method, or `UNKNOWN` if it isn't a standard method.
+    method: &'static str,
+
+    /// The path template matching the request's path, if routes are
+    /// configured.
+    route: Option<Arc<str>>,
+
+    /// The fully-qualified method of a gRPC request, such as
+    /// `/pkg.Service/Method`, if gRPC methods are labeled.
+    grpc_method: Option<String>,
+}
+
+/// A borrowed view of the labels of a request, used to look up existing
+/// request metrics without allocating a new `RequestLabels`.
+///
+/// This must hash identically to the equivalent `RequestLabels`, so its
+/// labels are hashed in the same order and the same way.
+#[derive(Debug, Eq, PartialEq)]
+pub struct RequestLabelsRef<'a> {
+    direction: Direction,
+    outbound_labels: Option<DstLabels>,
+    authority: Cow<'a, str>,
+    method: &'static str,
+    route: Option<Arc<str>>,
+    grpc_method: Option<&'a str>,
+
+    /// Was the authority escaped to be used as a label value?
+    authority_sanitized: bool,
 }
 
+/// Rewrites authority label values before they are recorded, so that
+/// sensitive authorities are not exposed by scrapes.
+///
+/// The function returns the value to record instead of the authority, or
+/// `None` to record the authority unchanged. An authority which is redacted
+/// to an empty string is recorded as `"unknown"`.
+#[derive(Clone)]
+pub struct Redactor(Arc<Fn(&str) -> Option<String> + Send + Sync>);
+
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct ResponseLabels {
 
@@ -33,7 +87,7 @@ pub struct ResponseLabels {
 
     /// The value of the grpc-status trailer. Only applicable to response
     /// metrics for gRPC responses.
-    grpc_status_code: Option<u32>,
+    grpc_status_code: Option<GrpcStatus>,
 
     /// Was the response a success or failure?
     classification: Classification,
@@ -51,6 +105,16 @@ pub struct TransportLabels {
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub enum Peer { Src, Dst }
 
+/// A gRPC status code, as used in the `grpc_status_code` label.
+///
+/// Codes outside the range defined by gRPC are all labeled `unknown`, so
+/// that a peer sending junk status codes cannot create unbounded series.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+enum GrpcStatus {
+    Code(u32),
+    Unknown,
+}
+
 /// Labels describing the end of a TCP connection
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct TransportCloseLabels {
@@ -61,10 +125,37 @@ pub struct TransportCloseLabels {
     classification: Classification,
 }
 
+/// A type of labels which can list the label keys it may carry.
+pub trait LabelKeys {
+    /// The keys of the labels that a series with these labels may carry.
+    ///
+    /// Destination labels, whose keys depend on the destination, are listed
+    /// as `dst_*`.
+    const KEYS: &'static [&'static str];
+}
+
+/// Labels identifying the build of the proxy.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct BuildInfoLabels {
+    version: &'static str,
+    git_sha: &'static str,
+    rust_version: &'static str,
+}
+
+/// Labels describing a connection that failed before it could be tracked as
+/// a transport.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct AcceptErrorLabels {
+    /// Why the connection failed.
+    reason: &'static str,
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 enum Classification {
     Success,
     Failure,
+    /// The stream was canceled or refused, rather than failed by the server.
+    ClientCancel,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
@@ -79,10 +170,76 @@ pub struct DstLabels {
     original: Arc<HashMap<String, String>>,
 }
 
+/// Matches destination labels against a set of required `dst_*` label
+/// values, so that a scrape can be scoped to a subset of destinations.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DstMatcher {
+    /// The required label values, keyed by label name without the `dst_`
+    /// prefix.
+    labels: Vec<(String, String)>,
+}
+
 // ===== impl RequestLabels =====
 
-impl<'a> RequestLabels {
+impl RequestLabels {
     pub fn new(req: &ctx::http::Request) -> Self {
+        RequestLabelsRef::new(req).to_labels()
+    }
+
+    pub fn dst_labels(&self) -> Option<&DstLabels> {
+        self.outbound_labels.as_ref()
+    }
+
+    pub fn authority(&self) -> &str {
+        &self.authority
+    }
+
+    pub fn grpc_method(&self) -> Option<&str> {
+        self.grpc_method.as_ref().map(String::as_str)
+    }
+}
+
+impl fmt::Display for RequestLabels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "authority=\"{}\",{},method=\"{}\"",
+            self.authority,
+            self.direction,
+            self.method,
+        )?;
+
+        if let Some(ref route) = self.route {
+            write!(f, ",route=\"{}\"", route)?;
+        }
+
+        if let Some(ref grpc_method) = self.grpc_method {
+            write!(f, ",grpc_method=\"{}\"", grpc_method)?;
+        }
+
+        if let Some(ref outbound) = self.outbound_labels {
+            // leading comma added between the method label and the
+            // destination labels, if there are destination labels.
+            write!(f, ",{}", outbound)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl LabelKeys for RequestLabels {
+    const KEYS: &'static [&'static str] = &[
+        "authority",
+        "direction",
+        "method",
+        "route",
+        "grpc_method",
+        "dst_*",
+    ];
+}
+
+// ===== impl RequestLabelsRef =====
+
+impl<'a> RequestLabelsRef<'a> {
+    pub fn new(req: &'a ctx::http::Request) -> Self {
         let direction = Direction::from_context(req.server.proxy.as_ref());
 
         let outbound_labels = req.dst_labels()
@@ -90,28 +247,241 @@ impl<'a> RequestLabels {
 
         let authority = req.uri
             .authority_part()
-            .map(http::uri::Authority::to_string)
-            .unwrap_or_else(String::new);
-
-        RequestLabels {
+            .map(http::uri::Authority::as_str)
+            .map(escape_label_value)
+            .unwrap_or(Cow::Borrowed(""));
+        let authority_sanitized = match authority {
+            Cow::Owned(_) => true,
+            Cow::Borrowed(_) => false,
+        };
+
+        RequestLabelsRef {
             direction,
             outbound_labels,
             authority,
+            method: method_label(&req.method),
+            route: None,
+            grpc_method: None,
+            authority_sanitized,
         }
     }
-}
 
-impl fmt::Display for RequestLabels {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "authority=\"{}\",{}", self.authority, self.direction)?;
+    /// Applies `redactor`, if there is one, to the authority.
+    pub fn redact(self, redactor: Option<&Redactor>) -> Self {
+        let authority = match redactor.and_then(|r| r.redact(&self.authority)) {
+            Some(ref redacted) if redacted.is_empty() => Cow::Borrowed("unknown"),
+            Some(redacted) => escape_label_value(&redacted).into_owned().into(),
+            None => return self,
+        };
+        RequestLabelsRef { authority, ..self }
+    }
 
-        if let Some(ref outbound) = self.outbound_labels {
-            // leading comma added between the direction label and the
-            // destination labels, if there are destination labels.
-            write!(f, ",{}", outbound)?;
+    /// Labels the request with the route matching its path, if there are
+    /// routes.
+    pub fn route(self, routes: Option<&RouteMatcher>, req: &ctx::http::Request) -> Self {
+        match routes {
+            Some(routes) => RequestLabelsRef {
+                route: Some(routes.route(req.uri.path())),
+                ..self
+            },
+            None => self,
         }
+    }
 
-        Ok(())
+    /// Labels the request with its gRPC method, if `enabled` and the
+    /// request is gRPC.
+    pub fn with_grpc_method(self, enabled: bool, req: &'a ctx::http::Request) -> Self {
+        if enabled {
+            RequestLabelsRef { grpc_method: grpc_method_label(req), ..self }
+        } else {
+            self
+        }
+    }
+
+    /// Records the request under `OTHER_AUTHORITY` rather than its own
+    /// authority.
+    pub fn with_other_authority(self) -> Self {
+        RequestLabelsRef { authority: Cow::Borrowed(OTHER_AUTHORITY), ..self }
+    }
+
+    /// Records the request under `placeholder` if it has no authority.
+    pub fn with_missing_authority(self, placeholder: &'a str) -> Self {
+        if self.authority.is_empty() {
+            RequestLabelsRef { authority: Cow::Borrowed(placeholder), ..self }
+        } else {
+            self
+        }
+    }
+
+    pub fn dst_labels(&self) -> Option<&DstLabels> {
+        self.outbound_labels.as_ref()
+    }
+
+    pub fn authority(&self) -> &str {
+        &self.authority
+    }
+
+    pub fn grpc_method(&self) -> Option<&str> {
+        self.grpc_method
+    }
+
+    /// Records the request under `OTHER_GRPC_METHOD` rather than its own
+    /// gRPC method, if it has one.
+    pub fn with_other_grpc_method(self) -> Self {
+        let grpc_method = self.grpc_method.map(|_| OTHER_GRPC_METHOD);
+        RequestLabelsRef { grpc_method, ..self }
+    }
+
+    /// Returns owned `RequestLabels` equivalent to these labels.
+    pub fn to_labels(&self) -> RequestLabels {
+        RequestLabels {
+            direction: self.direction,
+            outbound_labels: self.outbound_labels.clone(),
+            authority: self.authority.clone().into_owned(),
+            method: self.method,
+            route: self.route.clone(),
+            grpc_method: self.grpc_method.map(str::to_owned),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_authority(self, authority: Cow<'a, str>) -> Self {
+        let authority_sanitized = match authority {
+            Cow::Owned(_) => true,
+            Cow::Borrowed(_) => false,
+        };
+        RequestLabelsRef { authority, authority_sanitized, ..self }
+    }
+
+    /// Returns true if the request's authority had to be altered to be used
+    /// as a label value.
+    pub fn authority_sanitized(&self) -> bool {
+        self.authority_sanitized
+    }
+}
+
+impl<'a> hash::Hash for RequestLabelsRef<'a> {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.direction.hash(state);
+        self.outbound_labels.hash(state);
+        self.authority.hash(state);
+        self.method.hash(state);
+        self.route.hash(state);
+        self.grpc_method.hash(state);
+    }
+}
+
+/// The request methods which are recorded by name.
+const METHODS: &[&str] = &[
+    "GET",
+    "HEAD",
+    "POST",
+    "PUT",
+    "DELETE",
+    "CONNECT",
+    "OPTIONS",
+    "TRACE",
+    "PATCH",
+];
+
+/// Returns the label value for a request method.
+///
+/// Any method other than the standard ones is recorded as `UNKNOWN`, so that
+/// malformed requests can't create arbitrarily many series.
+fn method_label(method: &http::Method) -> &'static str {
+    METHODS.iter()
+        .find(|&&m| m == method.as_str())
+        .cloned()
+        .unwrap_or("UNKNOWN")
+}
+
+/// Returns the label value for the method of a gRPC request, or `None` if
+/// the request isn't gRPC.
+///
+/// The method is taken from the request's path, which gRPC requires to be
+/// `/<package>.<Service>/<Method>`. A path that isn't in that form, or
+/// contains characters that can't be in a service or method name, isn't
+/// labeled, rather than being recorded as a method.
+fn grpc_method_label(req: &ctx::http::Request) -> Option<&str> {
+    fn is_name(s: &str, dots: bool) -> bool {
+        !s.is_empty() && s.chars().all(|c| {
+            c.is_ascii_alphanumeric() || c == '_' || (dots && c == '.')
+        })
+    }
+
+    if !req.is_grpc {
+        return None;
+    }
+
+    let path = req.uri.path();
+    let mut parts = path.splitn(3, '/');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(""), Some(service), Some(method))
+            if is_name(service, true) && is_name(method, false) => Some(path),
+        _ => None,
+    }
+}
+
+/// Returns the host of `authority`, without any user information or port.
+fn authority_host(authority: &str) -> &str {
+    let host = authority.rsplit('@').next().unwrap_or(authority);
+    match host.rfind(':') {
+        Some(i) if host[i + 1..].chars().all(|c| c.is_ascii_digit()) => &host[..i],
+        _ => host,
+    }
+}
+
+// ===== impl Redactor =====
+
+impl Redactor {
+    pub fn new<F>(redact: F) -> Self
+    where
+        F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+    {
+        Redactor(Arc::new(redact))
+    }
+
+    /// Returns a `Redactor` which records each authority whose host matches
+    /// one of `patterns` as the pattern it matches.
+    ///
+    /// A pattern is either a host, which matches only that host, or `*.`
+    /// followed by a domain, which matches any host within that domain. Hosts
+    /// are compared without their port and regardless of case.
+    pub fn matching<T: AsRef<str>>(patterns: &[T]) -> Self {
+        let patterns = patterns.iter()
+            .map(|p| p.as_ref().to_ascii_lowercase())
+            .collect::<Vec<_>>();
+        Redactor::new(move |authority| {
+            let host = authority_host(authority).to_ascii_lowercase();
+            patterns.iter()
+                .find(|pattern| if pattern.starts_with("*.") {
+                    host.ends_with(&pattern[1..])
+                } else {
+                    host == **pattern
+                })
+                .cloned()
+        })
+    }
+
+    fn redact(&self, authority: &str) -> Option<String> {
+        (self.0)(authority)
+    }
+}
+
+impl fmt::Debug for Redactor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Redactor").finish()
+    }
+}
+
+impl<'a> Equivalent<Arc<RequestLabels>> for RequestLabelsRef<'a> {
+    fn equivalent(&self, labels: &Arc<RequestLabels>) -> bool {
+        self.direction == labels.direction &&
+        *self.authority == *labels.authority &&
+        self.method == labels.method &&
+        self.route == labels.route &&
+        self.grpc_method == labels.grpc_method.as_ref().map(String::as_str) &&
+        self.outbound_labels == labels.outbound_labels
     }
 }
 
@@ -119,28 +489,88 @@ impl fmt::Display for RequestLabels {
 
 impl ResponseLabels {
 
-    pub fn new(rsp: &ctx::http::Response, grpc_status_code: Option<u32>) -> Self {
-        let request_labels = RequestLabels::new(&rsp.request);
+    pub fn new(
+        rsp: &ctx::http::Response,
+        grpc_status_code: Option<u32>,
+        redactor: Option<&Redactor>,
+        routes: Option<&RouteMatcher>,
+    ) -> Self {
+        let request_labels = RequestLabelsRef::new(&rsp.request)
+            .redact(redactor)
+            .route(routes, &rsp.request)
+            .to_labels();
         let classification = Classification::classify(rsp, grpc_status_code);
         ResponseLabels {
             request_labels,
             status_code: rsp.status.as_u16(),
-            grpc_status_code,
+            grpc_status_code: grpc_status_code.map(GrpcStatus::from),
             classification,
         }
     }
 
-    /// Called when the response stream has failed.
-    pub fn fail(rsp: &ctx::http::Response) -> Self {
-        let request_labels = RequestLabels::new(&rsp.request);
+    /// Called when the response stream has failed with `reason`.
+    pub fn fail(
+        rsp: &ctx::http::Response,
+        reason: h2::Reason,
+        redactor: Option<&Redactor>,
+        routes: Option<&RouteMatcher>,
+    ) -> Self {
+        let request_labels = RequestLabelsRef::new(&rsp.request)
+            .redact(redactor)
+            .route(routes, &rsp.request)
+            .to_labels();
         ResponseLabels {
             request_labels,
             // TODO: is it correct to always treat this as 500?
             // Alternatively, the status_code field could be made optional...
             status_code: 500,
             grpc_status_code: None,
-            classification: Classification::Failure,
+            classification: Classification::stream_fail(reason),
+        }
+    }
+
+    pub fn dst_labels(&self) -> Option<&DstLabels> {
+        self.request_labels.dst_labels()
+    }
+
+    pub fn request_labels(&self) -> &RequestLabels {
+        &self.request_labels
+    }
+
+    /// Returns these labels with the request recorded under
+    /// `OTHER_AUTHORITY` rather than its own authority.
+    pub fn with_other_authority(&self) -> Self {
+        let mut labels = self.clone();
+        labels.request_labels.authority = OTHER_AUTHORITY.to_owned();
+        labels
+    }
+
+    /// Returns these labels with the request recorded under `placeholder`
+    /// if it has no authority.
+    pub fn with_missing_authority(mut self, placeholder: &str) -> Self {
+        if self.request_labels.authority.is_empty() {
+            self.request_labels.authority = placeholder.to_owned();
+        }
+        self
+    }
+
+    /// Returns these labels with the request labeled by its gRPC method, if
+    /// `enabled` and the request is gRPC.
+    pub fn with_grpc_method(mut self, enabled: bool, req: &ctx::http::Request) -> Self {
+        if enabled {
+            self.request_labels.grpc_method = grpc_method_label(req).map(str::to_owned);
+        }
+        self
+    }
+
+    /// Returns these labels with the request recorded under
+    /// `OTHER_GRPC_METHOD` rather than its own gRPC method.
+    pub fn with_other_grpc_method(&self) -> Self {
+        let mut labels = self.clone();
+        if labels.request_labels.grpc_method.is_some() {
+            labels.request_labels.grpc_method = Some(OTHER_GRPC_METHOD.to_owned());
         }
+        labels
     }
 }
 
@@ -162,6 +592,46 @@ impl fmt::Display for ResponseLabels {
     }
 }
 
+impl LabelKeys for ResponseLabels {
+    const KEYS: &'static [&'static str] = &[
+        "authority",
+        "direction",
+        "method",
+        "route",
+        "grpc_method",
+        "dst_*",
+        "classification",
+        "status_code",
+        "grpc_status_code",
+    ];
+}
+
+// ===== impl GrpcStatus =====
+
+impl GrpcStatus {
+    /// The largest status code defined by gRPC (`UNAUTHENTICATED`).
+    const MAX_CODE: u32 = 16;
+}
+
+impl From<u32> for GrpcStatus {
+    fn from(code: u32) -> Self {
+        if code <= GrpcStatus::MAX_CODE {
+            GrpcStatus::Code(code)
+        } else {
+            GrpcStatus::Unknown
+        }
+    }
+}
+
+impl fmt::Display for GrpcStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GrpcStatus::Code(code) => code.fmt(f),
+            GrpcStatus::Unknown => f.pad("unknown"),
+        }
+    }
+}
+
 // ===== impl Classification =====
 
 impl Classification {
@@ -184,9 +654,28 @@ impl Classification {
         }
     }
 
+    /// Classifies a response by its HTTP status and, if it has one, its gRPC
+    /// status.
+    ///
+    /// A gRPC response fails if either status indicates a failure, so that
+    /// a server error is never classified as a success just because a gRPC
+    /// status of `OK` was sent.
     fn classify(rsp: &ctx::http::Response, grpc_status: Option<u32>) -> Self {
-        grpc_status.map(Classification::grpc_status)
-            .unwrap_or_else(|| Classification::http_status(&rsp.status))
+        match (Classification::http_status(&rsp.status), grpc_status) {
+            (Classification::Success, Some(code)) => Classification::grpc_status(code),
+            (http, _) => http,
+        }
+    }
+
+    /// Classifies a stream which failed with `reason`.
+    ///
+    /// Streams which were canceled or refused are classified separately, so
+    /// that clients abandoning requests are not counted as server failures.
+    fn stream_fail(reason: h2::Reason) -> Self {
+        match reason {
+            h2::Reason::CANCEL | h2::Reason::REFUSED_STREAM => Classification::ClientCancel,
+            _ => Classification::Failure,
+        }
     }
 
     fn transport_close(close: &event::TransportClose) -> Self {
@@ -204,6 +693,7 @@ impl fmt::Display for Classification {
         match self {
             &Classification::Success => f.pad("classification=\"success\""),
             &Classification::Failure => f.pad("classification=\"failure\""),
+            &Classification::ClientCancel => f.pad("classification=\"client_cancel\""),
         }
     }
 }
@@ -232,6 +722,11 @@ impl fmt::Display for Direction {
 // ===== impl DstLabels ====
 
 impl DstLabels {
+    /// Formats the given destination labels.
+    ///
+    /// Keys are sanitized and values are escaped in the formatted labels, as
+    /// they come from the controller rather than the proxy; `as_map` returns
+    /// them as they were given. See `sanitize_label_key`.
     pub fn new<I, S>(labels: I) -> Option<Self>
     where
         I: IntoIterator<Item=(S, S)>,
@@ -244,15 +739,17 @@ impl DstLabels {
 
             // Format the first label pair without a leading comma, since we
             // don't know where it is in the output labels at this point.
-            let mut s = format!("dst_{}=\"{}\"", k, v);
-            original.insert(format!("{}", k), format!("{}", v));
+            let (k, v) = (k.to_string(), v.to_string());
+            let mut s = format!("dst_{}=\"{}\"", sanitize_label_key(&k), escape_label_value(&v));
+            original.insert(k, v);
 
             // Format subsequent label pairs with leading commas, since
             // we know that we already formatted the first label pair.
             for (k, v) in labels {
-                write!(s, ",dst_{}=\"{}\"", k, v)
+                let (k, v) = (k.to_string(), v.to_string());
+                write!(s, ",dst_{}=\"{}\"", sanitize_label_key(&k), escape_label_value(&v))
                     .expect("writing to string should not fail");
-                original.insert(format!("{}", k), format!("{}", v));
+                original.insert(k, v);
             }
 
             Some(DstLabels {
@@ -287,6 +784,135 @@ impl fmt::Display for DstLabels {
     }
 }
 
+/// Escapes `value` so that it may be used as a Prometheus label value.
+///
+/// Backslashes, double quotes, and newlines are escaped, and any other
+/// control characters are replaced with `_`. The value is only copied if it
+/// had to be altered.
+///
+/// `http::uri::Authority` does not currently accept any of these characters,
+/// but the authority is the only label taken directly from a request, so it
+/// should not be trusted to be a valid label value.
+pub fn escape_label_value(value: &str) -> Cow<str> {
+    let needs_escape = |c: char| c == '\\' || c == '"' || c.is_control();
+    if !value.contains(needs_escape) {
+        return Cow::Borrowed(value);
+    }
+
+    let mut escaped = String::with_capacity(value.len() + 1);
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c if c.is_control() => escaped.push('_'),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Sanitizes `key` so that it may be used in a Prometheus label name.
+///
+/// Any character other than an ASCII letter, digit, or underscore is
+/// replaced with `_`, and the key is prefixed with `_` if it would otherwise
+/// start with a digit, so that `my-label` becomes `my_label` and `1st`
+/// becomes `_1st`. The key is only copied if it had to be altered.
+pub fn sanitize_label_key(key: &str) -> Cow<str> {
+    let is_valid = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let starts_with_digit = key.starts_with(|c: char| c.is_ascii_digit());
+    if key.chars().all(is_valid) && !starts_with_digit {
+        return Cow::Borrowed(key);
+    }
+
+    let mut sanitized = String::with_capacity(key.len() + 1);
+    if starts_with_digit {
+        sanitized.push('_');
+    }
+    sanitized.extend(key.chars().map(|c| if is_valid(c) { c } else { '_' }));
+    Cow::Owned(sanitized)
+}
+
+/// Parses labels as they are formatted in scrapes, such as
+/// `direction="inbound",peer="src"`, into their keys and unescaped values.
+///
+/// This lets consumers other than scrapes use the same label formatting.
+pub fn label_pairs(formatted: &str) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let mut chars = formatted.chars();
+    loop {
+        let key = chars.by_ref().take_while(|&c| c != '=').collect::<String>();
+        if key.is_empty() || chars.next() != Some('"') {
+            break;
+        }
+
+        let mut value = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some(escaped) => value.push(escaped),
+                    None => break,
+                },
+                c => value.push(c),
+            }
+        }
+        pairs.push((key, value));
+
+        // Skip the comma that separates the label from the next one.
+        if chars.next() != Some(',') {
+            break;
+        }
+    }
+    pairs
+}
+
+// ===== impl DstMatcher =====
+
+impl DstMatcher {
+    /// Parses a matcher from the `dst_<key>=<value>` parameters of a query
+    /// string. Parameters without the `dst_` prefix are ignored.
+    pub fn from_query(query: &str) -> Self {
+        let labels = query.split('&')
+            .filter_map(|param| {
+                let mut kv = param.splitn(2, '=');
+                let key = kv.next()?;
+                let value = kv.next().unwrap_or("");
+                if key.starts_with("dst_") && key.len() > "dst_".len() {
+                    Some((key["dst_".len()..].to_owned(), value.to_owned()))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        DstMatcher { labels }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Returns true if `dst` has every label required by this matcher.
+    ///
+    /// An empty matcher matches everything, including series without
+    /// destination labels.
+    pub fn matches(&self, dst: Option<&DstLabels>) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+
+        match dst {
+            Some(dst) => {
+                let map = dst.as_map();
+                self.labels.iter().all(|&(ref k, ref v)| {
+                    map.get(k).map(|actual| actual == v).unwrap_or(false)
+                })
+            },
+            None => false,
+        }
+    }
+}
 
 // ===== impl TransportLabels =====
 
@@ -312,6 +938,56 @@ impl fmt::Display for TransportLabels {
     }
 }
 
+impl LabelKeys for TransportLabels {
+    const KEYS: &'static [&'static str] = &["direction", "peer"];
+}
+
+// ===== impl BuildInfoLabels =====
+
+impl BuildInfoLabels {
+    pub fn new(version: &'static str, git_sha: &'static str, rust_version: &'static str) -> Self {
+        BuildInfoLabels {
+            version,
+            git_sha,
+            rust_version,
+        }
+    }
+}
+
+impl fmt::Display for BuildInfoLabels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "version=\"{}\",git_sha=\"{}\",rust_version=\"{}\"",
+            escape_label_value(self.version),
+            escape_label_value(self.git_sha),
+            escape_label_value(self.rust_version),
+        )
+    }
+}
+
+impl LabelKeys for BuildInfoLabels {
+    const KEYS: &'static [&'static str] = &["version", "git_sha", "rust_version"];
+}
+
+// ===== impl AcceptErrorLabels =====
+
+impl AcceptErrorLabels {
+    pub fn new(reason: &'static str) -> Self {
+        AcceptErrorLabels { reason }
+    }
+}
+
+impl fmt::Display for AcceptErrorLabels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "reason=\"{}\"", self.reason)
+    }
+}
+
+impl LabelKeys for AcceptErrorLabels {
+    const KEYS: &'static [&'static str] = &["reason"];
+}
+
 // ===== impl TransportCloseLabels =====
 
 impl TransportCloseLabels {
@@ -331,3 +1007,11 @@ impl fmt::Display for TransportCloseLabels {
     }
 }
 
+impl LabelKeys for TransportCloseLabels {
+    const KEYS: &'static [&'static str] = &["direction", "peer", "classification"];
+}
+
+impl<L: LabelKeys> LabelKeys for Arc<L> {
+    const KEYS: &'static [&'static str] = L::KEYS;
+}
+
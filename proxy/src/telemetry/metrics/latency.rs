@@ -1,6 +1,7 @@
 #![deny(missing_docs)]
 use std::{fmt, iter, ops, slice, u32};
 use std::num::Wrapping;
+use std::sync::Arc;
 use std::time::Duration;
 use super::Counter;
 
@@ -57,13 +58,21 @@ pub const BUCKET_BOUNDS: [Latency; NUM_BUCKETS] = [
 ];
 
 /// A series of latency values and counts.
-#[derive(Debug, Default, Clone)]
+///
+/// Histograms are equal if they have the same bounds, bucket counts, and sum.
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Histogram {
 
-    /// Array of buckets in which to count latencies.
+    /// The upper bounds of each bucket, in increasing order.
     ///
-    /// The upper bound of a given bucket `i` is given in `BUCKET_BOUNDS[i]`.
-    buckets: [Counter; NUM_BUCKETS],
+    /// The last bound is always `u32::MAX`, so that every latency falls
+    /// into some bucket.
+    bounds: Arc<[Latency]>,
+
+    /// Buckets in which to count latencies.
+    ///
+    /// The upper bound of a given bucket `i` is given in `bounds[i]`.
+    buckets: Vec<Counter>,
 
     /// The total sum of all observed latency values.
     ///
@@ -97,13 +106,119 @@ pub struct Latency(u32);
 
 impl Histogram {
 
+    /// Construct a histogram with the given bucket upper bounds.
+    ///
+    /// The bounds are sorted and deduplicated, and a `u32::MAX` bound is
+    /// added if it is not already present.
+    pub fn new(bounds: &[Latency]) -> Self {
+        let bounds = Self::normalize_bounds(bounds);
+        Histogram {
+            buckets: vec![Counter::default(); bounds.len()],
+            bounds,
+            sum: Wrapping(0),
+        }
+    }
+
+    /// Construct a histogram with `count` exponentially-spaced bucket
+    /// upper bounds, the first of which is `start` milliseconds and each of
+    /// which is `factor` times the one before it.
+    ///
+    /// As with Prometheus' `ExponentialBuckets`, `exponential(1, 2.0, 4)`
+    /// has bounds of 1, 2, 4, and 8 milliseconds, followed by the
+    /// `u32::MAX` bound. Bounds are rounded to the nearest tenth of a
+    /// millisecond, and any which are too large to represent are replaced
+    /// by the `u32::MAX` bound.
+    ///
+    /// # Panics
+    ///
+    /// If `factor` is not greater than 1, or `count` is zero.
+    pub fn exponential(start: u64, factor: f64, count: usize) -> Self {
+        assert!(factor > 1.0, "exponential buckets require a factor greater than 1");
+        assert!(count > 0, "exponential buckets require at least one bucket");
+        let mut bounds = Vec::new();
+        let mut tenths_of_ms = start as f64 * f64::from(MS_TO_TENTHS_OF_MS);
+        for _ in 0..count {
+            let bound = tenths_of_ms.round();
+            if bound >= f64::from(u32::MAX) {
+                break;
+            }
+            bounds.push(Latency(bound as u32));
+            tenths_of_ms *= factor;
+        }
+        Self::new(&bounds)
+    }
+
+    fn normalize_bounds(bounds: &[Latency]) -> Arc<[Latency]> {
+        let mut bounds = bounds.to_vec();
+        bounds.sort();
+        bounds.dedup();
+        if bounds.last() != Some(&Latency(u32::MAX)) {
+            bounds.push(Latency(u32::MAX));
+        }
+        Arc::from(bounds)
+    }
+
+    /// Returns the upper bound of each bucket in this histogram.
+    pub fn bounds(&self) -> &[Latency] {
+        &self.bounds
+    }
+
+    /// Change the bucket bounds of this histogram, redistributing the
+    /// existing counts into the new buckets.
+    ///
+    /// The exact latencies of observed values are not known, only the
+    /// bucket that they fell into, so each existing bucket's count is moved
+    /// into the lowest new bucket whose upper bound is at least the old
+    /// bucket's upper bound. This may overstate, but never understates,
+    /// the observed latencies. The total count and the sum are unchanged.
+    pub fn reconfigure(&mut self, bounds: &[Latency]) {
+        let bounds = Self::normalize_bounds(bounds);
+        let mut buckets = vec![Counter::default(); bounds.len()];
+        for (old_bound, count) in self.bounds.iter().zip(self.buckets.iter()) {
+            let i = bounds.iter()
+                .position(|max| old_bound <= max)
+                .expect("bounds must end with u32::MAX");
+            buckets[i] = buckets[i] + *count;
+        }
+        self.bounds = bounds;
+        self.buckets = buckets;
+    }
+
+    /// Returns a histogram of the observations made since `prev`, an earlier
+    /// snapshot of this histogram.
+    ///
+    /// Each bucket's count and the sum are the increase since `prev`. If a
+    /// count or the sum is lower than it was in `prev`, such as if the
+    /// histogram was reset in between, its increase is zero rather than
+    /// wrapping.
+    ///
+    /// # Panics
+    ///
+    /// If `prev` has different bounds than this histogram.
+    // There is no delta scrape mode yet.
+    #[allow(dead_code)]
+    pub fn diff(&self, prev: &Histogram) -> Histogram {
+        assert_eq!(
+            self.bounds(), prev.bounds(),
+            "histograms must have the same bounds to be diffed"
+        );
+        Histogram {
+            bounds: self.bounds.clone(),
+            buckets: self.buckets.iter()
+                .zip(prev.buckets.iter())
+                .map(|(&count, &prev)| count - prev)
+                .collect(),
+            sum: Wrapping(self.sum.0.saturating_sub(prev.sum.0)),
+        }
+    }
+
     /// Observe a measurement
     pub fn observe<I>(&mut self, measurement: I)
     where
         I: Into<Latency>,
     {
         let measurement = measurement.into();
-        let i = BUCKET_BOUNDS.iter()
+        let i = self.bounds.iter()
             .position(|max| &measurement <= max)
             .expect("latency value greater than u32::MAX; this shouldn't be \
                      possible.");
@@ -120,6 +235,53 @@ impl Histogram {
         self.sum.0 as f64 / MS_TO_TENTHS_OF_MS as f64
     }
 
+    /// Estimates the `q`-quantile of the observed latencies, such as the
+    /// median for `q = 0.5`.
+    ///
+    /// As with Prometheus' `histogram_quantile()`, the latencies in the
+    /// bucket containing the quantile are assumed to be evenly distributed
+    /// between its lower and upper bounds, and a quantile in the `+Inf`
+    /// bucket is the highest finite bound. `q` is clamped to `[0, 1]`.
+    ///
+    /// Returns zero if nothing has been observed.
+    // Not yet used by any consumer in the proxy itself.
+    #[allow(dead_code)]
+    pub fn quantile(&self, q: f64) -> Duration {
+        let q = q.max(0.0).min(1.0);
+        let total: u64 = self.into_iter().sum();
+        if total == 0 {
+            return Duration::default();
+        }
+
+        let rank = q * total as f64;
+        let mut lower = 0;
+        let mut below = 0;
+        let mut tenths_of_ms = 0.0;
+        for (&Latency(upper), count) in self.bounds.iter().zip(self) {
+            if count > 0 && (below + count) as f64 >= rank {
+                tenths_of_ms = if upper == u32::MAX {
+                    f64::from(lower)
+                } else {
+                    let fraction = (rank - below as f64) / count as f64;
+                    f64::from(lower) + f64::from(upper - lower) * fraction
+                };
+                break;
+            }
+            lower = upper;
+            below += count;
+        }
+
+        let nanos = (tenths_of_ms * f64::from(TENTHS_OF_MS_TO_NS)).round() as u64;
+        let nanos_per_sec = u64::from(SEC_TO_MS * MS_TO_NS);
+        Duration::new(nanos / nanos_per_sec, (nanos % nanos_per_sec) as u32)
+    }
+
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new(&BUCKET_BOUNDS)
+    }
 }
 
 impl<I> ops::AddAssign<I> for Histogram
@@ -152,7 +314,8 @@ impl<'a> IntoIterator for &'a Histogram {
 const SEC_TO_MS: u32 = 1_000;
 const SEC_TO_TENTHS_OF_A_MS: u32 = SEC_TO_MS * 10;
 const TENTHS_OF_MS_TO_NS: u32 =  MS_TO_NS / 10;
-const MS_TO_TENTHS_OF_MS: u32 = 10;
+/// Conversion ratio from milliseconds to tenths of a millisecond.
+pub const MS_TO_TENTHS_OF_MS: u32 = 10;
 /// Conversion ratio from milliseconds to nanoseconds.
 pub const MS_TO_NS: u32 = 1_000_000;
 
@@ -206,6 +369,18 @@ impl Into<u32> for Latency {
     }
 }
 
+impl Into<f64> for Latency {
+    /// Converts the latency to milliseconds, with the maximum latency
+    /// converted to infinity.
+    fn into(self) -> f64 {
+        if self.0 == u32::MAX {
+            ::std::f64::INFINITY
+        } else {
+            f64::from(self.0) / f64::from(MS_TO_TENTHS_OF_MS)
+        }
+    }
+}
+
 impl fmt::Display for Latency {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         if self.0 == u32::MAX {
@@ -220,10 +395,217 @@ impl fmt::Display for Latency {
             // the formatting for u32::MAX.
             write!(f, "+Inf")
         } else {
-            // NOTE: if bucket values are changed so that they're no longer
-            //       evenly divisible by ten, we may want to ensure that there's
-            //       a reasonable cap on precision here.
-            write!(f, "{}", self.0 / MS_TO_TENTHS_OF_MS)
+            // Bucket bounds are used as `le` label values, so each latency
+            // must always be formatted identically for Prometheus to
+            // identify the bucket across scrapes. Whole milliseconds are
+            // written without a fractional part, and anything else with
+            // exactly one decimal place, so bounds which aren't evenly
+            // divisible by ten aren't truncated into their neighbors.
+            let ms = self.0 / MS_TO_TENTHS_OF_MS;
+            match self.0 % MS_TO_TENTHS_OF_MS {
+                0 => write!(f, "{}", ms),
+                tenths => write!(f, "{}.{}", ms, tenths),
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cumulative(h: &Histogram) -> Vec<u64> {
+        h.into_iter()
+            .scan(0, |total, count| {
+                *total += count;
+                Some(*total)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bounds_format_canonically() {
+        let one_ms = [
+            Latency(10),
+            Latency::from(Duration::from_millis(1)),
+            Latency::from(Duration::new(0, 1_000_500)),
+        ];
+        for latency in &one_ms {
+            assert_eq!(latency.to_string(), "1");
+        }
+
+        assert_eq!(Latency(0).to_string(), "0");
+        assert_eq!(Latency(5).to_string(), "0.5");
+        assert_eq!(Latency(15).to_string(), "1.5");
+        assert_eq!(Latency::from(Duration::new(0, 1_500_000)).to_string(), "1.5");
+        assert_eq!(Latency(1_000_000).to_string(), "100000");
+        assert_eq!(Latency(u32::MAX).to_string(), "+Inf");
+
+        // Every bound of a histogram has a distinct `le` value.
+        let h = Histogram::new(&[Latency(10), Latency(15), Latency(19), Latency(20)]);
+        let mut les = h.bounds().iter().map(Latency::to_string).collect::<Vec<_>>();
+        assert_eq!(les, vec!["1", "1.5", "1.9", "2", "+Inf"]);
+        les.dedup();
+        assert_eq!(les.len(), h.bounds().len());
+    }
+
+    #[test]
+    fn reconfigure_preserves_count_and_sum() {
+        let mut h = Histogram::default();
+        for ms in &[1, 3, 7, 15, 25, 45, 150, 2_500, 70_000] {
+            h += Duration::from_millis(*ms);
+        }
+        let count: u64 = h.into_iter().sum();
+        let sum = h.sum_in_ms();
+
+        h.reconfigure(&[Latency(100), Latency(1_000), Latency(10_000)]);
+
+        assert_eq!(h.bounds(), &[
+            Latency(100),
+            Latency(1_000),
+            Latency(10_000),
+            Latency(u32::MAX),
+        ]);
+        assert_eq!(h.into_iter().sum::<u64>(), count);
+        assert_eq!(h.sum_in_ms(), sum);
+
+        // Each observation is counted in a bucket at least as large as the
+        // one it was originally counted in, and the buckets stay cumulative.
+        let c = cumulative(&h);
+        assert_eq!(c, vec![3, 6, 7, 9]);
+        assert!(c.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn histograms_with_the_same_observations_are_equal() {
+        let mut a = Histogram::default();
+        let mut b = Histogram::default();
+        assert_eq!(a, b);
+
+        for ms in &[1, 7, 150] {
+            a += Duration::from_millis(*ms);
+            b += Duration::from_millis(*ms);
+        }
+        assert_eq!(a, b);
+
+        b += Duration::from_millis(1);
+        assert_ne!(a, b);
+
+        // Histograms with different bounds are not equal.
+        assert_ne!(Histogram::new(&[Latency(10)]), Histogram::new(&[Latency(100)]));
+    }
+
+    #[test]
+    fn diff_counts_observations_since_snapshot() {
+        let mut h = Histogram::default();
+        for ms in &[1, 7, 150] {
+            h += Duration::from_millis(*ms);
+        }
+        let prev = h.clone();
+        for ms in &[1, 2_500, 2_500] {
+            h += Duration::from_millis(*ms);
+        }
+
+        let mut expected = Histogram::default();
+        for ms in &[1, 2_500, 2_500] {
+            expected += Duration::from_millis(*ms);
+        }
+        let diff = h.diff(&prev);
+        assert_eq!(diff, expected);
+        assert_eq!(diff.sum_in_ms(), 5_001.0);
+        assert_eq!(h.diff(&h), Histogram::default());
+
+        // If the histogram has been reset since `prev`, its counts are lower,
+        // and the diff is empty rather than wrapping.
+        assert_eq!(prev.diff(&h), Histogram::default());
+    }
+
+    #[test]
+    #[should_panic]
+    fn diff_requires_the_same_bounds() {
+        let h = Histogram::new(&[Latency(10)]);
+        h.diff(&Histogram::default());
+    }
+
+    #[test]
+    fn quantiles_are_interpolated_within_buckets() {
+        let mut h = Histogram::new(&[Latency(100), Latency(200), Latency(400)]);
+        assert_eq!(h.quantile(0.5), Duration::from_millis(0));
+
+        for _ in 0..10 {
+            h += Duration::from_millis(5);
+            h += Duration::from_millis(15);
+        }
+        for _ in 0..20 {
+            h += Duration::from_millis(30);
+        }
+
+        assert_eq!(h.quantile(0.0), Duration::from_millis(0));
+        assert_eq!(h.quantile(0.25), Duration::from_millis(10));
+        assert_eq!(h.quantile(0.5), Duration::from_millis(20));
+        assert_eq!(h.quantile(0.75), Duration::from_millis(30));
+        assert_eq!(h.quantile(0.9), Duration::from_millis(36));
+        assert_eq!(h.quantile(1.0), Duration::from_millis(40));
+
+        // Out-of-range quantiles are clamped.
+        assert_eq!(h.quantile(-1.0), h.quantile(0.0));
+        assert_eq!(h.quantile(1.5), h.quantile(1.0));
+
+        // Latencies beyond the last finite bound are estimated as that
+        // bound.
+        for _ in 0..60 {
+            h += Duration::from_secs(1);
+        }
+        assert_eq!(h.quantile(0.99), Duration::from_millis(40));
+    }
+
+    #[test]
+    fn exponential_bounds_are_multiplied_by_factor() {
+        let h = Histogram::exponential(1, 2.0, 4);
+        assert_eq!(h.bounds(), &[
+            Latency(10),
+            Latency(20),
+            Latency(40),
+            Latency(80),
+            Latency(u32::MAX),
+        ]);
+
+        let h = Histogram::exponential(5, 1.5, 3);
+        let les = h.bounds().iter().map(Latency::to_string).collect::<Vec<_>>();
+        assert_eq!(les, vec!["5", "7.5", "11.3", "+Inf"]);
+
+        // Spans milliseconds to minutes.
+        let h = Histogram::exponential(1, 2.0, 20);
+        assert_eq!(h.bounds().len(), 21);
+        assert_eq!(h.bounds()[19], Latency::from(Duration::from_millis(524_288)));
+
+        // Bounds too large to represent are replaced by the `u32::MAX` bound.
+        let h = Histogram::exponential(100_000, 10.0, 5);
+        assert_eq!(h.bounds(), &[
+            Latency(1_000_000),
+            Latency(10_000_000),
+            Latency(100_000_000),
+            Latency(1_000_000_000),
+            Latency(u32::MAX),
+        ]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn exponential_requires_factor_greater_than_one() {
+        Histogram::exponential(1, 1.0, 5);
+    }
+
+    #[test]
+    #[should_panic]
+    fn exponential_requires_a_bucket() {
+        Histogram::exponential(1, 2.0, 0);
+    }
+
+    #[test]
+    fn new_adds_max_bound() {
+        let h = Histogram::new(&[Latency(50), Latency(10), Latency(50)]);
+        assert_eq!(h.bounds(), &[Latency(10), Latency(50), Latency(u32::MAX)]);
+        assert_eq!(h.into_iter().count(), 3);
+    }
+}
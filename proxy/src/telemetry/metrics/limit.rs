@@ -0,0 +1,112 @@
+//! Limits the distinct label values recorded for a destination or an
+//! authority, so that a client which sends many distinct values cannot create unbounded series.
+
+use std::collections::HashSet;
+
+use indexmap::IndexMap;
+
+use super::labels::{DstLabels, OTHER_AUTHORITY, OTHER_GRPC_METHOD};
+
+/// The distinct authorities which have been recorded for each set of
+/// destination labels.
+///
+/// Once a destination has `max` authorities, requests with any other
+/// authority are recorded under `OTHER_AUTHORITY`, so that a client which
+/// sends many distinct authorities cannot create unbounded series.
+#[derive(Debug, Clone)]
+pub struct AuthorityLimit {
+    max: usize,
+    by_dst: IndexMap<Option<DstLabels>, HashSet<String>>,
+}
+
+/// The default maximum number of distinct authorities recorded for each
+/// destination.
+pub const DEFAULT_MAX_AUTHORITIES: usize = 1000;
+
+/// The distinct gRPC methods which have been recorded for each authority.
+///
+/// The method is taken from the request's path, which is chosen by the
+/// client. Once an authority has `max` methods, requests with any other
+/// method are recorded under `OTHER_GRPC_METHOD`.
+#[derive(Debug, Clone)]
+pub struct GrpcMethodLimit {
+    max: usize,
+    by_authority: IndexMap<String, HashSet<String>>,
+}
+
+/// The default maximum number of distinct gRPC methods recorded for each
+/// authority.
+pub const DEFAULT_MAX_GRPC_METHODS: usize = 100;
+
+// ===== impl AuthorityLimit =====
+
+impl AuthorityLimit {
+    pub fn new(max: usize) -> Self {
+        AuthorityLimit {
+            max,
+            by_dst: IndexMap::new(),
+        }
+    }
+
+    /// Returns true if `authority` may be recorded for `dst`, noting it as
+    /// one of the destination's authorities if it has not been recorded
+    /// before.
+    pub fn allows(&mut self, dst: Option<&DstLabels>, authority: &str) -> bool {
+        if authority == OTHER_AUTHORITY {
+            return true;
+        }
+        let max = self.max;
+        let authorities = self.by_dst
+            .entry(dst.cloned())
+            .or_insert_with(HashSet::new);
+        if authorities.contains(authority) {
+            return true;
+        }
+        if authorities.len() >= max {
+            return false;
+        }
+        authorities.insert(authority.to_owned());
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.by_dst.clear();
+    }
+}
+
+// ===== impl GrpcMethodLimit =====
+
+impl GrpcMethodLimit {
+    pub fn new(max: usize) -> Self {
+        GrpcMethodLimit {
+            max,
+            by_authority: IndexMap::new(),
+        }
+    }
+
+    /// Returns true if `method` may be recorded for `authority`, noting it
+    /// as one of the authority's methods if it has not been recorded before.
+    pub fn allows(&mut self, authority: &str, method: &str) -> bool {
+        if method == OTHER_GRPC_METHOD {
+            return true;
+        }
+        let max = self.max;
+        if !self.by_authority.contains_key(authority) {
+            self.by_authority.insert(authority.to_owned(), HashSet::new());
+        }
+        let methods = self.by_authority.get_mut(authority)
+            .expect("authority's methods must exist");
+        if methods.contains(method) {
+            return true;
+        }
+        if methods.len() >= max {
+            return false;
+        }
+        methods.insert(method.to_owned());
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.by_authority.clear();
+    }
+}
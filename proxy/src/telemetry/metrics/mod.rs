@@ -26,53 +26,200 @@
 //! labels, we can add new labels or modify the existing ones without having
 //! to worry about missing commas, double commas, or trailing commas at the
 //! end of the label set (all of which will make Prometheus angry).
+use std::collections::HashMap;
 use std::default::Default;
 use std::{fmt, time};
+use std::time::{Duration, Instant};
 use std::hash::Hash;
-use std::sync::{Arc, Mutex};
-use std::io::Write;
-
-use deflate::CompressionOptions;
-use deflate::write::GzEncoder;
-use futures::future::{self, FutureResult};
-use hyper::{self, Body, StatusCode};
-use hyper::header::{AcceptEncoding, ContentEncoding, ContentType, Encoding, QualityItem};
-use hyper::server::{
-    Response as HyperResponse,
-    Request as HyperRequest,
-    Service as HyperService,
-};
+use std::sync::{Arc, Mutex, MutexGuard};
+
 use indexmap::{IndexMap};
 
 use ctx;
 use telemetry::event::Event;
 
+mod aggregate;
 mod counter;
 mod gauge;
 mod labels;
 mod latency;
+mod limit;
+mod recent;
+mod route;
+mod scrape;
+mod serve;
+mod size;
+mod snapshot;
+mod statsd;
+mod summary;
 
 use self::counter::Counter;
 use self::gauge::Gauge;
 use self::labels::{
+    label_pairs,
+    AcceptErrorLabels,
+    BuildInfoLabels,
+    LabelKeys,
+    DstMatcher,
     RequestLabels,
+    RequestLabelsRef,
     ResponseLabels,
     TransportLabels,
     TransportCloseLabels
 };
-use self::latency::{BUCKET_BOUNDS, Histogram};
-pub use self::labels::DstLabels;
+use self::latency::{Histogram, Latency};
+use self::limit::{AuthorityLimit, GrpcMethodLimit, DEFAULT_MAX_AUTHORITIES, DEFAULT_MAX_GRPC_METHODS};
+use self::recent::RecentEvents;
+use self::scrape::{MetricParts, ScrapeOptions, SeriesLimit, SCRAPE_CHUNK_SIZE};
+use self::size::SizeHistogram;
+use self::summary::Summary;
+pub use self::aggregate::Aggregate;
+pub use self::labels::{DstLabels, Redactor};
+pub use self::snapshot::{
+    HistogramSnapshot,
+    MetricSnapshot,
+    SeriesSnapshot,
+    Snapshot,
+    SummarySnapshot,
+    Value,
+};
+pub use self::serve::Serve;
+pub use self::statsd::StatsdSink;
 
 #[derive(Debug, Clone)]
 struct Metrics {
     request_total: Metric<Counter, Arc<RequestLabels>>,
+    request_failure_latency: Metric<Histogram, Arc<RequestLabels>>,
+    request_body_bytes_total: Metric<Counter, Arc<RequestLabels>>,
+    requests_active: Metric<Gauge, Arc<RequestLabels>>,
+
+    /// The labels under which each active request is counted by
+    /// `requests_active`, keyed by the request's ID, so that each request
+    /// is only uncounted once, however many of its events complete it.
+    active_requests: HashMap<usize, ActiveRequest>,
+
+    /// When `active_requests` was last swept for requests which have been
+    /// active for longer than `MAX_ACTIVE_REQUEST_AGE_SECS`.
+    active_requests_swept: Instant,
 
     response_total: Metric<Counter, Arc<ResponseLabels>>,
     response_latency: Metric<Histogram, Arc<ResponseLabels>>,
+    response_body_bytes_total: Metric<Counter, Arc<ResponseLabels>>,
+
+    /// An empty histogram with the bucket bounds of each response latency
+    /// series. New series are cloned from it, so that they share its bounds.
+    empty_response_latency: Histogram,
+
+    /// Only present if response first- and last-byte latencies are being
+    /// recorded.
+    response_byte_latency: Option<ResponseByteLatency>,
+
+    /// Only present if quantiles of response latency are being recorded.
+    response_latency_quantiles: Option<ResponseLatencyQuantiles>,
+
+    /// Only present if request header sizes are being recorded.
+    request_header_bytes: Option<Scalar<SizeHistogram>>,
+
+    /// If true, `request_by_authority_total` is formatted from
+    /// `request_total`.
+    requests_by_authority: bool,
 
     tcp: TcpMetrics,
 
-    start_time: u64,
+    events_dropped_total: Scalar<Counter>,
+    queue_depth: Scalar<Gauge>,
+    queue_capacity: Scalar<Gauge>,
+
+    authority_sanitized_total: Scalar<Counter>,
+
+    scrapes_total: Scalar<Counter>,
+    scrape_errors_total: Scalar<Counter>,
+    scrape_lock_hold: Scalar<Histogram>,
+    scrape_duration: Scalar<Histogram>,
+
+    /// The number of series of the HTTP and TCP metrics, counted when the
+    /// metrics are formatted rather than as series are created.
+    series: Scalar<Gauge>,
+
+    shutdown_initiated_time: Scalar<Gauge>,
+    drain_duration: Scalar<Histogram>,
+
+    start_time: Scalar<Gauge>,
+    build_timestamp: Scalar<Gauge>,
+
+    /// Always 1, labeled with the version of the proxy.
+    build_info: Metric<Gauge, BuildInfoLabels>,
+
+    /// Set to 1 if the last scrape was truncated because it would have
+    /// exceeded the maximum number of series.
+    series_truncated: Scalar<Gauge>,
+
+    /// Tracks the activity of the request and response series for each set
+    /// of request labels, so that series which are no longer updated can be
+    /// evicted.
+    request_activity: IndexMap<Arc<RequestLabels>, Activity>,
+
+    /// Limits the number of distinct authorities recorded for each
+    /// destination.
+    authorities: AuthorityLimit,
+
+    /// Limits the number of distinct gRPC methods recorded for each
+    /// authority.
+    grpc_methods: GrpcMethodLimit,
+}
+
+/// The metrics, sharded by the direction of the traffic that they describe,
+/// so that inbound and outbound traffic are recorded under separate locks.
+///
+/// Each series of the HTTP and TCP metrics is labeled by its direction, so
+/// the shards never record the same series. The metrics which describe the
+/// proxy process rather than its traffic are only recorded in the inbound
+/// shard.
+#[derive(Debug)]
+struct Shards {
+    inbound: Mutex<Metrics>,
+    outbound: Mutex<Metrics>,
+
+    /// The most recently recorded events, if they are being kept. They are
+    /// locked separately from the metrics, so that reading them never delays
+    /// recording.
+    recent_events: Mutex<Option<RecentEvents>>,
+}
+
+/// A request which is counted by `requests_active`.
+#[derive(Debug, Clone)]
+struct ActiveRequest {
+    labels: Arc<RequestLabels>,
+    opened_at: Instant,
+}
+
+/// The activity of the series for a set of request labels.
+#[derive(Debug, Clone)]
+struct Activity {
+    /// When a series with these labels was last updated.
+    last_updated: Instant,
+
+    /// The number of streams with these labels which have been opened but
+    /// have not yet completed.
+    open_streams: u64,
+}
+
+/// The latencies of the first and last bytes of each response, which differ
+/// greatly for streaming responses.
+#[derive(Debug, Clone)]
+struct ResponseByteLatency {
+    first_byte: Metric<Histogram, Arc<ResponseLabels>>,
+    last_byte: Metric<Histogram, Arc<ResponseLabels>>,
+}
+
+/// Quantiles of the latency of each response, over a sliding window.
+#[derive(Debug, Clone)]
+struct ResponseLatencyQuantiles {
+    summary: Metric<Summary, Arc<ResponseLabels>>,
+
+    /// An empty summary with the window and quantiles of each series. New
+    /// series are cloned from it.
+    empty: Summary,
 }
 
 #[derive(Debug, Clone)]
@@ -83,8 +230,14 @@ struct TcpMetrics {
     connection_duration: Metric<Histogram, Arc<TransportCloseLabels>>,
     open_connections: Metric<Gauge, Arc<TransportLabels>>,
 
+    /// An empty histogram with the bucket bounds of each connection duration
+    /// series. New series are cloned from it, so that they share its bounds.
+    empty_connection_duration: Histogram,
+
     write_bytes_total: Metric<Counter, Arc<TransportLabels>>,
     read_bytes_total: Metric<Counter, Arc<TransportLabels>>,
+
+    accept_errors_total: Metric<Counter, AcceptErrorLabels>,
 }
 
 #[derive(Debug, Clone)]
@@ -94,16 +247,117 @@ struct Metric<M, L: Hash + Eq> {
     values: IndexMap<L, M>
 }
 
-/// Tracks Prometheus metrics
+/// Formats the total number of requests to each authority, summed across
+/// both directions.
+///
+/// This is computed from the request totals when the metrics are formatted.
 #[derive(Debug)]
-pub struct Aggregate {
-    metrics: Arc<Mutex<Metrics>>,
-}
+struct RequestsByAuthority<'a>(&'a Metric<Counter, Arc<RequestLabels>>);
 
-/// Serve Prometheues metrics.
+/// A metric which is not partitioned by any labels.
 #[derive(Debug, Clone)]
-pub struct Serve {
-    metrics: Arc<Mutex<Metrics>>,
+struct Scalar<M> {
+    name: &'static str,
+    help: &'static str,
+    value: M,
+}
+
+/// Describes a metric family, for tooling which generates dashboards and
+/// documentation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MetricDescriptor {
+    pub name: &'static str,
+
+    /// The Prometheus metric type: `counter`, `gauge`, `histogram`, or
+    /// `summary`.
+    pub kind: &'static str,
+
+    pub help: &'static str,
+
+    /// The unit of the metric's values, if it has one.
+    pub unit: Option<&'static str>,
+
+    /// The keys of the labels that the metric's series may carry.
+    pub label_keys: Vec<&'static str>,
+}
+
+/// A family of metrics which is exported by a scrape.
+trait Family: FmtMetric {
+    /// Describes each metric in the family.
+    fn describe(&self) -> Vec<MetricDescriptor>;
+
+    /// Copies the current value of each series of each metric in the
+    /// family.
+    fn snapshot(&self) -> Vec<MetricSnapshot>;
+}
+
+/// Metrics which are written to a scrape.
+trait FmtMetric {
+    /// Writes each of the metrics to `w`, formatted according to `opts`.
+    fn fmt_metric(&self, opts: ScrapeOptions, w: &mut fmt::Write) -> fmt::Result;
+}
+
+/// A type of metric value, which determines the metric's Prometheus type.
+trait Kind {
+    const KIND: &'static str;
+
+    /// Label keys which every series of this kind of metric carries.
+    const LABEL_KEYS: &'static [&'static str] = &[];
+
+    /// Copies the current value.
+    fn snapshot(&self) -> Value;
+}
+
+/// The time at which the proxy was built, in seconds since the Unix epoch, as
+/// set by the build environment.
+const BUILD_TIMESTAMP: Option<&str> = option_env!("CONDUIT_PROXY_BUILD_TIMESTAMP");
+
+/// The git commit from which the proxy was built, as set by the build
+/// environment.
+const BUILD_GIT_SHA: Option<&str> = option_env!("CONDUIT_PROXY_GIT_SHA");
+
+/// The version of the Rust compiler with which the proxy was built, as set by
+/// the build environment.
+const BUILD_RUST_VERSION: Option<&str> = option_env!("CONDUIT_PROXY_RUST_VERSION");
+
+/// How long a request is counted as active if no event completes it, in
+/// seconds.
+///
+/// Events are dropped when the event queue is full, so the event completing
+/// a request may never be recorded. Requests are uncounted once they have
+/// been active for this long, so that a dropped event doesn't leave the
+/// `http_requests_active` gauge raised, and its series unevictable, forever.
+const MAX_ACTIVE_REQUEST_AGE_SECS: u64 = 60 * 60;
+
+/// How often the active requests are checked for any which have been active
+/// for longer than `MAX_ACTIVE_REQUEST_AGE_SECS`, in seconds.
+const ACTIVE_REQUEST_SWEEP_INTERVAL_SECS: u64 = 60;
+
+/// How many times longer than the maximum idle age the series with an open
+/// stream are kept after they were last updated.
+///
+/// The event closing a stream may be dropped when the event queue is full,
+/// so a stream which appears to be open may have long since closed. Its
+/// series are kept for longer than others, but not forever.
+const OPEN_STREAM_IDLE_FACTOR: u32 = 10;
+
+/// How long each response latency is included in the quantiles of
+/// `response_latency_quantiles_ms`, in seconds.
+const RESPONSE_LATENCY_QUANTILES_WINDOW_SECS: u64 = 60;
+
+/// Parses the build timestamp, returning 0 if it is missing or invalid.
+fn parse_build_timestamp(timestamp: Option<&str>) -> u64 {
+    match timestamp.map(str::parse) {
+        Some(Ok(secs)) => secs,
+        Some(Err(e)) => {
+            warn!("invalid build timestamp {:?}: {}", timestamp, e);
+            0
+        },
+        None => {
+            warn!("build timestamp was not set at build time");
+            0
+        },
+    }
 }
 
 /// Construct the Prometheus metrics.
@@ -111,10 +365,10 @@ pub struct Serve {
 /// Returns the `Aggregate` and `Serve` sides. The `Serve` side
 /// is a Hyper service which can be used to create the server for the
 /// scrape endpoint, while the `Aggregate` side can receive updates to the
-/// metrics by calling `record_event`.
+/// metrics by calling `record_batch`.
 pub fn new(process: &Arc<ctx::Process>) -> (Aggregate, Serve){
-    let metrics = Arc::new(Mutex::new(Metrics::new(process)));
-    (Aggregate::new(&metrics), Serve::new(&metrics))
+    let shards = Arc::new(Shards::new(process));
+    (Aggregate::new(&shards), Serve::new(&shards))
 }
 
 // ===== impl Metrics =====
@@ -123,19 +377,42 @@ impl Metrics {
 
     pub fn new(process: &Arc<ctx::Process>) -> Self {
 
-        let start_time = process.start_time
+        let mut start_time = Scalar::<Gauge>::new(
+            "process_start_time_seconds",
+            "Time that the process started, in seconds since the Unix epoch.",
+        );
+        start_time.value = process.start_time
             .duration_since(time::UNIX_EPOCH)
             .expect(
                 "process start time should not be before the beginning \
                  of the Unix epoch"
             )
-            .as_secs();
+            .as_secs()
+            .into();
 
         let request_total = Metric::<Counter, Arc<RequestLabels>>::new(
             "request_total",
             "A counter of the number of requests the proxy has received.",
         );
 
+        let request_failure_latency = Metric::<Histogram, Arc<RequestLabels>>::new(
+            "request_failure_latency_ms",
+            "A histogram of the time from when a request was received until \
+            it failed before a response was received, in milliseconds.",
+        );
+
+        let request_body_bytes_total = Metric::<Counter, Arc<RequestLabels>>::new(
+            "request_body_bytes_total",
+            "A counter of the number of bytes of request bodies the proxy \
+            has sent, including those of requests which failed.",
+        );
+
+        let requests_active = Metric::<Gauge, Arc<RequestLabels>>::new(
+            "http_requests_active",
+            "A gauge of the number of requests the proxy has received whose \
+            streams have neither completed nor failed.",
+        );
+
         let response_total = Metric::<Counter, Arc<ResponseLabels>>::new(
             "response_total",
             "A counter of the number of responses the proxy has received.",
@@ -148,202 +425,1104 @@ impl Metrics {
             stream has completed.",
         );
 
+        let response_body_bytes_total = Metric::<Counter, Arc<ResponseLabels>>::new(
+            "response_body_bytes_total",
+            "A counter of the number of bytes of response bodies the proxy \
+            has sent, including those of responses which failed.",
+        );
+
+        let events_dropped_total = Scalar::<Counter>::new(
+            "telemetry_events_dropped_total",
+            "A counter of the number of telemetry events that were dropped \
+            because the event queue was full.",
+        );
+
+        let queue_depth = Scalar::<Gauge>::new(
+            "telemetry_queue_depth",
+            "The number of telemetry events that were waiting in the event \
+            queue to be received when it was last polled.",
+        );
+
+        let queue_capacity = Scalar::<Gauge>::new(
+            "telemetry_queue_capacity",
+            "The maximum number of telemetry events that may wait in the \
+            event queue; events sent while it is full are dropped.",
+        );
+
+        let authority_sanitized_total = Scalar::<Counter>::new(
+            "authority_sanitized_total",
+            "A counter of the number of requests whose authority had to be \
+            escaped to be used as a label value.",
+        );
+
+        let scrapes_total = Scalar::<Counter>::new(
+            "metrics_scrapes_total",
+            "A counter of the number of times the metrics were formatted to \
+            serve a scrape.",
+        );
+
+        let scrape_errors_total = Scalar::<Counter>::new(
+            "metrics_scrape_errors_total",
+            "A counter of the number of scrapes that were truncated because \
+            the metrics could not be formatted.",
+        );
+
+        let scrape_lock_hold = Scalar::<Histogram>::new(
+            "metrics_scrape_lock_hold_ms",
+            "A histogram of the amount of time the metrics locks were held \
+            while serving a scrape, in milliseconds like the proxy's other \
+            latency histograms, to a tenth of a millisecond.",
+        );
+
+        let scrape_duration = Scalar::<Histogram>::new(
+            "metrics_scrape_duration_ms",
+            "A histogram of the amount of time taken to format each scrape, \
+            in milliseconds.",
+        );
+
+        let shutdown_initiated_time = Scalar::<Gauge>::new(
+            "shutdown_initiated_time_seconds",
+            "The time at which the proxy began draining connections for a \
+            graceful shutdown, in seconds since the Unix epoch.",
+        );
+
+        let drain_duration = Scalar::<Histogram>::new(
+            "drain_duration_ms",
+            "A histogram of the amount of time taken to drain connections \
+            during a graceful shutdown, in milliseconds.",
+        );
+
+        let mut build_timestamp = Scalar::<Gauge>::new(
+            "build_timestamp_seconds",
+            "Time that the proxy was built, in seconds since the Unix epoch.",
+        );
+        build_timestamp.value = parse_build_timestamp(BUILD_TIMESTAMP).into();
+
+        let mut build_info = Metric::<Gauge, BuildInfoLabels>::new(
+            "proxy_build_info",
+            "A gauge which is always 1, labeled with the version of the proxy, \
+            the git commit from which it was built, and the version of Rust \
+            with which it was built.",
+        );
+        let build_info_labels = BuildInfoLabels::new(
+            env!("CARGO_PKG_VERSION"),
+            BUILD_GIT_SHA.unwrap_or("unknown"),
+            BUILD_RUST_VERSION.unwrap_or("unknown"),
+        );
+        build_info.values.insert(build_info_labels, 1.into());
+
+        let series = Scalar::<Gauge>::new(
+            "metrics_series_total",
+            "The number of series of the HTTP and TCP metrics that the proxy \
+            was tracking as of the last scrape.",
+        );
+
+        let series_truncated = Scalar::<Gauge>::new(
+            "metrics_series_truncated",
+            "Set to 1 if the last scrape omitted metrics because it would \
+            have exceeded the maximum number of series, and 0 otherwise.",
+        );
+
         Metrics {
             request_total,
+            request_failure_latency,
+            request_body_bytes_total,
+            requests_active,
+            active_requests: HashMap::new(),
+            active_requests_swept: Instant::now(),
             response_total,
             response_latency,
+            response_body_bytes_total,
+            empty_response_latency: Histogram::default(),
+            response_byte_latency: None,
+            response_latency_quantiles: None,
+            request_header_bytes: None,
+            requests_by_authority: false,
             tcp: TcpMetrics::new(),
+            events_dropped_total,
+            queue_depth,
+            queue_capacity,
+            authority_sanitized_total,
+            scrapes_total,
+            scrape_errors_total,
+            scrape_lock_hold,
+            scrape_duration,
+            series,
+            shutdown_initiated_time,
+            drain_duration,
             start_time,
+            build_timestamp,
+            build_info,
+            series_truncated,
+            request_activity: IndexMap::new(),
+            authorities: AuthorityLimit::new(DEFAULT_MAX_AUTHORITIES),
+            grpc_methods: GrpcMethodLimit::new(DEFAULT_MAX_GRPC_METHODS),
         }
     }
 
-    fn request_total(&mut self,
-                     labels: &Arc<RequestLabels>)
-                     -> &mut Counter {
-        self.request_total.values
-            .entry(labels.clone())
-            .or_insert_with(Counter::default)
+    /// Returns `labels`, or the labels to record instead if its authority
+    /// would exceed the maximum number of authorities for its destination,
+    /// or its gRPC method the maximum number of methods for its authority.
+    fn limit_request_labels<'a>(&mut self, labels: RequestLabelsRef<'a>) -> RequestLabelsRef<'a> {
+        let labels = if self.authorities.allows(labels.dst_labels(), labels.authority()) {
+            labels
+        } else {
+            labels.with_other_authority()
+        };
+        let allowed = match labels.grpc_method() {
+            Some(method) => self.grpc_methods.allows(labels.authority(), method),
+            None => true,
+        };
+        if allowed {
+            labels
+        } else {
+            labels.with_other_grpc_method()
+        }
     }
 
-    fn response_latency(&mut self,
-                        labels: &Arc<ResponseLabels>)
-                        -> &mut Histogram {
-        self.response_latency.values
-            .entry(labels.clone())
-            .or_insert_with(Histogram::default)
+    /// Returns `labels`, or the labels to record instead if the authority
+    /// of its request would exceed the maximum number of authorities for its
+    /// destination, or its gRPC method the maximum number of methods for its
+    /// authority.
+    fn limit_response_labels(&mut self, labels: Arc<ResponseLabels>) -> Arc<ResponseLabels> {
+        let allowed = {
+            let request_labels = labels.request_labels();
+            self.authorities.allows(request_labels.dst_labels(), request_labels.authority())
+        };
+        let labels = if allowed {
+            labels
+        } else {
+            Arc::new(labels.with_other_authority())
+        };
+        let allowed = {
+            let request_labels = labels.request_labels();
+            match request_labels.grpc_method() {
+                Some(method) => self.grpc_methods.allows(request_labels.authority(), method),
+                None => true,
+            }
+        };
+        if allowed {
+            labels
+        } else {
+            Arc::new(labels.with_other_grpc_method())
+        }
     }
 
-    fn response_total(&mut self,
-                      labels: &Arc<ResponseLabels>)
-                      -> &mut Counter {
-        self.response_total.values
-            .entry(labels.clone())
-            .or_insert_with(Counter::default)
+    fn request_total(&mut self,
+                     labels: &RequestLabelsRef)
+                     -> &mut Counter {
+        if labels.authority_sanitized() {
+            self.authority_sanitized_total.value.incr();
+        }
+
+        request_series(&mut self.request_total.values, labels)
     }
 
-    fn tcp(&mut self) -> &mut TcpMetrics {
-        &mut self.tcp
+    fn request_failure_latency(&mut self,
+                               labels: &RequestLabelsRef)
+                               -> &mut Histogram {
+        request_series(&mut self.request_failure_latency.values, labels)
     }
-}
 
-impl fmt::Display for Metrics {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "{}", self.request_total)?;
-        writeln!(f, "{}", self.response_total)?;
-        writeln!(f, "{}", self.response_latency)?;
-        writeln!(f, "{}", self.tcp)?;
+    fn request_body_bytes_total(&mut self,
+                                labels: &RequestLabelsRef)
+                                -> &mut Counter {
+        request_series(&mut self.request_body_bytes_total.values, labels)
+    }
 
-        writeln!(f, "process_start_time_seconds {}", self.start_time)?;
-        Ok(())
+    fn request_activity(&mut self,
+                        labels: &RequestLabelsRef)
+                        -> &mut Activity {
+        request_series(&mut self.request_activity, labels)
     }
-}
 
-// ===== impl TcpMetrics =====
+    /// Counts the request with the given ID, opened at `now`, as active
+    /// until it is closed or has been active for longer than
+    /// `MAX_ACTIVE_REQUEST_AGE_SECS`.
+    fn request_opened(&mut self, id: usize, labels: &RequestLabelsRef, now: Instant) {
+        let sweep_interval = Duration::from_secs(ACTIVE_REQUEST_SWEEP_INTERVAL_SECS);
+        if now >= self.active_requests_swept + sweep_interval {
+            self.expire_active_requests(Duration::from_secs(MAX_ACTIVE_REQUEST_AGE_SECS), now);
+        }
 
-impl TcpMetrics {
-    pub fn new() -> TcpMetrics {
-        let open_total = Metric::<Counter, Arc<TransportLabels>>::new(
-            "tcp_open_total",
-            "A counter of the total number of transport connections.",
-        );
+        request_series(&mut self.requests_active.values, labels).incr();
+        let labels = self.requests_active.values.get_full(labels)
+            .map(|(_, labels, _)| labels.clone())
+            .expect("request series must exist");
+        self.active_requests.insert(id, ActiveRequest {
+            labels,
+            opened_at: now,
+        });
+    }
 
-        let close_total = Metric::<Counter, Arc<TransportCloseLabels>>::new(
-            "tcp_close_total",
-            "A counter of the total number of transport connections.",
-        );
+    /// Stops counting the request with the given ID as active, if it is
+    /// still counted.
+    fn request_closed(&mut self, id: usize) {
+        if let Some(request) = self.active_requests.remove(&id) {
+            if let Some(active) = self.requests_active.values.get_mut(&request.labels) {
+                active.decr();
+            }
+        }
+    }
 
-        let connection_duration = Metric::<Histogram, Arc<TransportCloseLabels>>::new(
-            "tcp_connection_duration_ms",
-            "A histogram of the duration of the lifetime of connections, in milliseconds",
-        );
+    /// Stops counting the requests which were opened more than `max_age`
+    /// before `now` as active, returning the number of requests that are no
+    /// longer counted.
+    ///
+    /// These requests are assumed to have completed without the event that
+    /// completed them being recorded. If one of them does complete later, it
+    /// is not uncounted again.
+    fn expire_active_requests(&mut self, max_age: Duration, now: Instant) -> usize {
+        self.active_requests_swept = now;
+        let before = self.active_requests.len();
+        let requests_active = &mut self.requests_active.values;
+        self.active_requests.retain(|_, request| {
+            if request.opened_at + max_age > now {
+                return true;
+            }
+            if let Some(active) = requests_active.get_mut(&request.labels) {
+                active.decr();
+            }
+            false
+        });
+        let expired = before - self.active_requests.len();
+        if expired > 0 {
+            debug!(
+                "no longer counting {} requests active for over {:?}",
+                expired,
+                max_age,
+            );
+        }
+        expired
+    }
 
-        let open_connections = Metric::<Gauge, Arc<TransportLabels>>::new(
-            "tcp_open_connections",
-            "A gauge of the number of transport connections currently open.",
-        );
+    /// Returns the activity of the series for the request labels of a
+    /// response.
+    fn response_activity(&mut self, labels: &ResponseLabels) -> &mut Activity {
+        let request_labels = labels.request_labels();
+        if !self.request_activity.contains_key(request_labels) {
+            self.request_activity
+                .insert(Arc::new(request_labels.clone()), Activity::default());
+        }
+        self.request_activity.get_mut(request_labels)
+            .expect("request activity must exist")
+    }
 
-        let read_bytes_total = Metric::<Counter, Arc<TransportLabels>>::new(
-            "tcp_read_bytes_total",
-            "A counter of the total number of recieved bytes."
-        );
+    /// Evicts the request and response series which have not been updated
+    /// within `max_idle` of `now`, so that destinations which are no longer
+    /// used don't accumulate series forever.
+    ///
+    /// Series with a stream that is still open are kept for
+    /// `OPEN_STREAM_IDLE_FACTOR` times as long. The TCP metrics, which are
+    /// not labeled by destination, are never evicted, so that the number of
+    /// open connections is never lost.
+    fn evict_idle(&mut self, max_idle: Duration, now: Instant) -> usize {
+        let before = self.request_activity.len();
+        self.request_activity
+            .retain(|_, activity| !activity.is_idle(max_idle, now));
+        let evicted = before - self.request_activity.len();
+        if evicted == 0 {
+            return 0;
+        }
 
-        let write_bytes_total = Metric::<Counter, Arc<TransportLabels>>::new(
-            "tcp_write_bytes_total",
-            "A counter of the total number of sent bytes."
-        );
+        // Evicted authorities and gRPC methods no longer count towards
+        // their limits.
+        self.authorities.clear();
+        self.grpc_methods.clear();
+        for labels in self.request_activity.keys() {
+            self.authorities.allows(labels.dst_labels(), labels.authority());
+            if let Some(method) = labels.grpc_method() {
+                self.grpc_methods.allows(labels.authority(), method);
+            }
+        }
 
-         Self {
-            open_total,
-            close_total,
-            connection_duration,
-            open_connections,
-            read_bytes_total,
-            write_bytes_total,
+        let activity = &self.request_activity;
+        self.request_total.values
+            .retain(|labels, _| activity.contains_key(&**labels));
+        self.request_failure_latency.values
+            .retain(|labels, _| activity.contains_key(&**labels));
+        self.request_body_bytes_total.values
+            .retain(|labels, _| activity.contains_key(&**labels));
+        // A request may be active even though its activity was closed by
+        // another event, so series of active requests are always kept.
+        self.requests_active.values
+            .retain(|labels, active| {
+                activity.contains_key(&**labels) || *active != Gauge::default()
+            });
+        self.response_total.values
+            .retain(|labels, _| activity.contains_key(labels.request_labels()));
+        self.response_latency.values
+            .retain(|labels, _| activity.contains_key(labels.request_labels()));
+        self.response_body_bytes_total.values
+            .retain(|labels, _| activity.contains_key(labels.request_labels()));
+        if let Some(ref mut byte_latency) = self.response_byte_latency {
+            byte_latency.first_byte.values
+                .retain(|labels, _| activity.contains_key(labels.request_labels()));
+            byte_latency.last_byte.values
+                .retain(|labels, _| activity.contains_key(labels.request_labels()));
         }
+        if let Some(ref mut quantiles) = self.response_latency_quantiles {
+            quantiles.summary.values
+                .retain(|labels, _| activity.contains_key(labels.request_labels()));
+        }
+        evicted
     }
 
-    fn open_total(&mut self, labels: &Arc<TransportLabels>) -> &mut Counter {
-        self.open_total.values
+    fn response_latency(&mut self,
+                        labels: &Arc<ResponseLabels>)
+                        -> &mut Histogram {
+        let empty = &self.empty_response_latency;
+        self.response_latency.values
             .entry(labels.clone())
-            .or_insert_with(Default::default)
+            .or_insert_with(|| empty.clone())
     }
 
-    fn close_total(&mut self, labels: &Arc<TransportCloseLabels>) -> &mut Counter {
-        self.close_total.values
+    fn response_total(&mut self,
+                      labels: &Arc<ResponseLabels>)
+                      -> &mut Counter {
+        self.response_total.values
             .entry(labels.clone())
             .or_insert_with(Counter::default)
     }
 
-    fn connection_duration(&mut self, labels: &Arc<TransportCloseLabels>) -> &mut Histogram {
-        self.connection_duration.values
+    fn response_body_bytes_total(&mut self,
+                                 labels: &Arc<ResponseLabels>)
+                                 -> &mut Counter {
+        self.response_body_bytes_total.values
             .entry(labels.clone())
-            .or_insert_with(Histogram::default)
+            .or_insert_with(Counter::default)
     }
 
-    fn open_connections(&mut self, labels: &Arc<TransportLabels>) -> &mut Gauge {
-        self.open_connections.values
-            .entry(labels.clone())
-            .or_insert_with(Gauge::default)
+    fn tcp(&mut self) -> &mut TcpMetrics {
+        &mut self.tcp
     }
 
-    fn write_bytes_total(&mut self, labels: &Arc<TransportLabels>) -> &mut Counter {
-        self.write_bytes_total.values
-            .entry(labels.clone())
-            .or_insert_with(Counter::default)
+    /// Resets every metric to its initial value, as if nothing had been
+    /// recorded.
+    ///
+    /// The configuration of the metrics, such as which optional metrics are
+    /// recorded and the bounds of their histograms, is kept. So are the
+    /// metrics which describe the process rather than what it has recorded:
+    /// the start time, build timestamp, event queue capacity, and shutdown
+    /// time.
+    fn reset(&mut self) {
+        self.request_total.values.clear();
+        self.request_failure_latency.values.clear();
+        self.request_body_bytes_total.values.clear();
+        self.response_total.values.clear();
+        self.response_latency.values.clear();
+        self.response_body_bytes_total.values.clear();
+        if let Some(ref mut byte_latency) = self.response_byte_latency {
+            byte_latency.first_byte.values.clear();
+            byte_latency.last_byte.values.clear();
+        }
+        if let Some(ref mut quantiles) = self.response_latency_quantiles {
+            quantiles.summary.values.clear();
+        }
+        if let Some(ref mut request_header_bytes) = self.request_header_bytes {
+            request_header_bytes.value = SizeHistogram::default();
+        }
+        self.tcp.reset();
+        self.authorities.clear();
+        self.grpc_methods.clear();
+
+        self.events_dropped_total.value.reset();
+        self.queue_depth.value.set(0);
+        self.authority_sanitized_total.value.reset();
+        self.scrapes_total.value.reset();
+        self.scrape_errors_total.value.reset();
+        self.scrape_lock_hold.value = Histogram::default();
+        self.scrape_duration.value = Histogram::default();
+        self.drain_duration.value = Histogram::default();
+        self.series_truncated.value.set(0);
     }
 
-    fn read_bytes_total(&mut self, labels: &Arc<TransportLabels>) -> &mut Counter {
-        self.read_bytes_total.values
-            .entry(labels.clone())
-            .or_insert_with(Counter::default)
+    /// Returns a copy of these metrics in which the per-destination HTTP
+    /// metrics only include series whose destination labels match `dst`.
+    fn matching_dst(&self, dst: &DstMatcher) -> Metrics {
+        let mut metrics = self.clone();
+        metrics.request_total.values
+            .retain(|labels, _| dst.matches(labels.dst_labels()));
+        metrics.request_failure_latency.values
+            .retain(|labels, _| dst.matches(labels.dst_labels()));
+        metrics.request_body_bytes_total.values
+            .retain(|labels, _| dst.matches(labels.dst_labels()));
+        metrics.requests_active.values
+            .retain(|labels, _| dst.matches(labels.dst_labels()));
+        metrics.response_total.values
+            .retain(|labels, _| dst.matches(labels.dst_labels()));
+        metrics.response_latency.values
+            .retain(|labels, _| dst.matches(labels.dst_labels()));
+        metrics.response_body_bytes_total.values
+            .retain(|labels, _| dst.matches(labels.dst_labels()));
+        if let Some(ref mut byte_latency) = metrics.response_byte_latency {
+            byte_latency.first_byte.values
+                .retain(|labels, _| dst.matches(labels.dst_labels()));
+            byte_latency.last_byte.values
+                .retain(|labels, _| dst.matches(labels.dst_labels()));
+        }
+        if let Some(ref mut quantiles) = metrics.response_latency_quantiles {
+            quantiles.summary.values
+                .retain(|labels, _| dst.matches(labels.dst_labels()));
+        }
+        metrics
     }
-}
 
-impl fmt::Display for TcpMetrics {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "{}", self.open_total)?;
-        writeln!(f, "{}", self.close_total)?;
-        writeln!(f, "{}", self.connection_duration)?;
-        writeln!(f, "{}", self.open_connections)?;
-        writeln!(f, "{}", self.write_bytes_total)?;
-        writeln!(f, "{}", self.read_bytes_total)?;
+    /// Adds the traffic recorded by another shard to these metrics.
+    ///
+    /// The process metrics of `other` are not merged, as they are only
+    /// recorded in the inbound shard.
+    fn merge(&mut self, other: Metrics) {
+        self.request_total.values.extend(other.request_total.values);
+        self.request_failure_latency.values.extend(other.request_failure_latency.values);
+        self.request_body_bytes_total.values.extend(other.request_body_bytes_total.values);
+        self.requests_active.values.extend(other.requests_active.values);
+        self.active_requests.extend(other.active_requests);
+        self.response_total.values.extend(other.response_total.values);
+        self.response_latency.values.extend(other.response_latency.values);
+        self.response_body_bytes_total.values.extend(other.response_body_bytes_total.values);
+        if let Some(ref mut byte_latency) = self.response_byte_latency {
+            if let Some(other) = other.response_byte_latency {
+                byte_latency.first_byte.values.extend(other.first_byte.values);
+                byte_latency.last_byte.values.extend(other.last_byte.values);
+            }
+        }
+        if let Some(ref mut quantiles) = self.response_latency_quantiles {
+            if let Some(other) = other.response_latency_quantiles {
+                quantiles.summary.values.extend(other.summary.values);
+            }
+        }
+        if let Some(ref mut request_header_bytes) = self.request_header_bytes {
+            if let Some(ref other) = other.request_header_bytes {
+                request_header_bytes.value.merge(&other.value);
+            }
+        }
+        self.tcp.merge(other.tcp);
+        self.request_activity.extend(other.request_activity);
 
-        Ok(())
+        self.authority_sanitized_total.value =
+            self.authority_sanitized_total.value + other.authority_sanitized_total.value;
     }
-}
-
-// ===== impl Metric =====
 
-impl<M, L: Hash + Eq> Metric<M, L> {
-
-    pub fn new(name: &'static str, help: &'static str) -> Self {
-        Metric {
-            name,
-            help,
-            values: IndexMap::new(),
+    /// Counts the series of the HTTP and TCP metrics, setting
+    /// `metrics_series_total`.
+    fn count_series(&mut self) {
+        let mut count = self.request_total.values.len()
+            + self.request_failure_latency.values.len()
+            + self.request_body_bytes_total.values.len()
+            + self.requests_active.values.len()
+            + self.response_total.values.len()
+            + self.response_latency.values.len()
+            + self.response_body_bytes_total.values.len();
+        if let Some(ref byte_latency) = self.response_byte_latency {
+            count += byte_latency.first_byte.values.len() + byte_latency.last_byte.values.len();
+        }
+        if let Some(ref quantiles) = self.response_latency_quantiles {
+            count += quantiles.summary.values.len();
         }
+        if self.request_header_bytes.is_some() {
+            count += 1;
+        }
+        count += self.tcp.series_count();
+        self.series.value.set(count as u64);
     }
 
+    /// Records a scrape which was formatted from `formatted`, a copy of the
+    /// metrics which took `lock_hold` to make, in `duration`.
+    ///
+    /// `failed` is true if the scrape could not be formatted in full.
+    fn record_scrape(
+        &mut self,
+        formatted: &Metrics,
+        failed: bool,
+        lock_hold: Duration,
+        duration: Duration,
+    ) {
+        self.scrapes_total.value.incr();
+        if failed {
+            self.scrape_errors_total.value.incr();
+        }
+        self.series_truncated.value = formatted.series_truncated.value;
+        self.scrape_lock_hold.value += lock_hold;
+        self.scrape_duration.value += duration;
+    }
 }
 
-impl<L> fmt::Display for Metric<Counter, L>
-where
+impl Metrics {
+    /// Writes each metric to `w`.
+    fn fmt_metrics<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        let opts = ScrapeOptions::from_query("");
+        self.fmt_application_metrics(opts, w)?;
+        self.fmt_process_metrics(opts, w)?;
+        fmt_each(&[&self.series_truncated], opts, w)
+    }
+
+    /// Writes the HTTP and TCP metrics describing the proxied traffic to `w`,
+    /// formatted according to `opts`.
+    fn fmt_application_metrics<W>(&self, opts: ScrapeOptions, w: &mut W) -> fmt::Result
+    where
+        W: fmt::Write,
+    {
+        self.with_application_families(|families| fmt_each(families, opts, w))
+    }
+
+    /// Writes the metrics describing the proxy process itself to `w`,
+    /// formatted according to `opts`.
+    fn fmt_process_metrics<W>(&self, opts: ScrapeOptions, w: &mut W) -> fmt::Result
+    where
+        W: fmt::Write,
+    {
+        self.with_process_families(|families| fmt_each(families, opts, w))
+    }
+
+    /// Calls `f` with the families of HTTP and TCP metrics describing the
+    /// proxied traffic, in the order in which they are written.
+    fn with_application_families<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&[&Family]) -> T,
+    {
+        let by_authority = RequestsByAuthority(&self.request_total);
+        let mut families: Vec<&Family> = vec![
+            &self.request_total,
+            &self.request_failure_latency,
+            &self.request_body_bytes_total,
+            &self.requests_active,
+            &self.response_total,
+            &self.response_latency,
+            &self.response_body_bytes_total,
+        ];
+
+        // The requests by authority follow the request totals that they sum.
+        if self.requests_by_authority {
+            families.insert(1, &by_authority);
+        }
+
+        if let Some(ref byte_latency) = self.response_byte_latency {
+            families.push(&byte_latency.first_byte);
+            families.push(&byte_latency.last_byte);
+        }
+
+        if let Some(ref quantiles) = self.response_latency_quantiles {
+            families.push(&quantiles.summary);
+        }
+
+        if let Some(ref request_header_bytes) = self.request_header_bytes {
+            families.push(request_header_bytes);
+        }
+
+        // Each TCP metric is its own family, so that scrapes may select them
+        // by name.
+        families.extend(self.tcp.families().iter().cloned());
+        f(&families)
+    }
+
+    /// Calls `f` with the families of metrics describing the proxy process
+    /// itself, in the order in which they are written.
+    fn with_process_families<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&[&Family]) -> T,
+    {
+        f(&[
+            &self.events_dropped_total,
+            &self.queue_depth,
+            &self.queue_capacity,
+            &self.authority_sanitized_total,
+            &self.scrapes_total,
+            &self.scrape_errors_total,
+            &self.scrape_lock_hold,
+            &self.scrape_duration,
+            &self.series,
+            &self.shutdown_initiated_time,
+            &self.drain_duration,
+            &self.start_time,
+            &self.build_timestamp,
+            &self.build_info,
+        ])
+    }
+
+    /// Describes every metric that a scrape would include.
+    fn describe(&self) -> Vec<MetricDescriptor> {
+        fn describe_all(families: &[&Family]) -> Vec<MetricDescriptor> {
+            families.iter().flat_map(|family| family.describe()).collect()
+        }
+
+        let mut descriptors = self.with_application_families(describe_all);
+        descriptors.extend(self.with_process_families(describe_all));
+        descriptors.extend(self.series_truncated.describe());
+        descriptors
+    }
+
+    /// Copies the value of every metric that a scrape would include.
+    fn snapshot(&self) -> Snapshot {
+        fn snapshot_all(families: &[&Family]) -> Vec<MetricSnapshot> {
+            families.iter().flat_map(|family| family.snapshot()).collect()
+        }
+
+        let mut metrics = self.with_application_families(snapshot_all);
+        metrics.extend(self.with_process_families(snapshot_all));
+        metrics.extend(self.series_truncated.snapshot());
+        Snapshot { metrics }
+    }
+
+    /// Writes the metrics to be served by a scrape to `w`, only including
+    /// per-destination metrics that match `dst`, and formatted according to
+    /// `opts`.
+    ///
+    /// If formatting fails, the scrape is truncated after the last metric
+    /// that was written and the failure is counted.
+    ///
+    /// If writing a metric would exceed `opts.max_series`, it and every
+    /// later metric are omitted, and `metrics_series_truncated` is set.
+    ///
+    /// Returns false if formatting failed.
+    fn fmt_scrape<W>(&mut self, dst: &DstMatcher, opts: ScrapeOptions, w: &mut W) -> bool
+    where
+        W: fmt::Write,
+    {
+        self.scrapes_total.value.incr();
+        // Every tracked series is counted, even if the scrape only includes
+        // some destinations.
+        self.count_series();
+
+        let (result, truncated) = {
+            let matching;
+            let metrics = if dst.is_empty() {
+                &*self
+            } else {
+                matching = self.matching_dst(dst);
+                &matching
+            };
+
+            let mut w = SeriesLimit::new(w, opts.max_series);
+            let result = if opts.federate {
+                metrics.fmt_application_metrics(opts, &mut w)
+            } else {
+                metrics.fmt_application_metrics(opts, &mut w)
+                    .and_then(|_| metrics.fmt_process_metrics(opts, &mut w))
+            };
+            (result, w.truncated)
+        };
+
+        if truncated {
+            warn!(
+                "scrape exceeded the maximum of {} series; omitting remaining metrics",
+                opts.max_series.unwrap_or(0),
+            );
+        }
+        self.series_truncated.value.set(truncated as u64);
+
+        // The truncation marker is always written, so that it isn't omitted
+        // from the truncated scrape it describes.
+        let result = result.and_then(|_| fmt_each(&[&self.series_truncated], opts, w));
+        if result.is_err() {
+            warn!("failed to format metrics; scrape is incomplete");
+            self.scrape_errors_total.value.incr();
+        }
+        result.is_ok()
+    }
+}
+
+/// Writes each of `metrics` to `w`.
+///
+/// Each metric is formatted in full before it is written, so that if
+/// formatting fails, the metrics that have already been written are
+/// complete and nothing of the failed metric is written. However, unless
+/// `opts.max_series` requires that a metric be omitted whole, a metric that
+/// is longer than `SCRAPE_CHUNK_SIZE` is written in parts of whole lines, so
+/// that a streamed scrape never holds much more than a chunk of it at once.
+fn fmt_each<W>(metrics: &[&Family], opts: ScrapeOptions, w: &mut W) -> fmt::Result
+where
+    W: fmt::Write,
+{
+    let part_size = if opts.max_series.is_some() { None } else { Some(SCRAPE_CHUNK_SIZE) };
+    let mut buf = MetricParts::new(w, part_size);
+    for metric in metrics.iter().filter(|metric| opts.includes(**metric)) {
+        metric.fmt_metric(opts, &mut buf)?;
+        fmt::Write::write_str(&mut buf, "\n")?;
+        buf.finish()?;
+    }
+
+    Ok(())
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_metrics(f)
+    }
+}
+
+/// Converts histogram bucket bounds to latencies.
+fn latency_bounds(bounds: &[Duration]) -> Vec<Latency> {
+    bounds.iter().map(|&bound| Latency::from(bound)).collect()
+}
+
+/// Returns the series of `metric` with the given request labels, inserting
+/// it if it doesn't exist.
+///
+/// The series is looked up by reference, so that the labels are only
+/// allocated the first time a series is recorded.
+fn request_series<'a, M: Default>(
+    values: &'a mut IndexMap<Arc<RequestLabels>, M>,
+    labels: &RequestLabelsRef,
+) -> &'a mut M {
+    let existing = values.get_full(labels).map(|(i, _, _)| i);
+    let i = match existing {
+        Some(i) => i,
+        None => {
+            values.insert(Arc::new(labels.to_labels()), M::default());
+            values.len() - 1
+        }
+    };
+    values.get_index_mut(i)
+        .expect("request series must exist")
+        .1
+}
+
+// ===== impl Activity =====
+
+impl Activity {
+    /// Records that a series with these labels was updated.
+    fn touch(&mut self) {
+        self.last_updated = Instant::now();
+    }
+
+    fn stream_opened(&mut self) {
+        self.open_streams += 1;
+        self.touch();
+    }
+
+    fn stream_closed(&mut self) {
+        // The count may already be 0 if the stream was opened before the
+        // metrics were reset.
+        self.open_streams = self.open_streams.saturating_sub(1);
+        self.touch();
+    }
+
+    /// Returns true if the series with these labels have not been updated
+    /// within `max_idle` of `now`, or within `OPEN_STREAM_IDLE_FACTOR` times
+    /// that if any of their streams are open.
+    fn is_idle(&self, max_idle: Duration, now: Instant) -> bool {
+        let max_idle = if self.open_streams == 0 {
+            max_idle
+        } else {
+            max_idle * OPEN_STREAM_IDLE_FACTOR
+        };
+        self.last_updated + max_idle <= now
+    }
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Activity {
+            last_updated: Instant::now(),
+            open_streams: 0,
+        }
+    }
+}
+
+// ===== impl ResponseByteLatency =====
+
+impl ResponseByteLatency {
+    fn new() -> Self {
+        ResponseByteLatency {
+            first_byte: Metric::<Histogram, Arc<ResponseLabels>>::new(
+                "response_first_byte_latency_ms",
+                "A histogram of the time from when the request headers are \
+                received to when the response headers are received, in \
+                milliseconds.",
+            ),
+            last_byte: Metric::<Histogram, Arc<ResponseLabels>>::new(
+                "response_last_byte_latency_ms",
+                "A histogram of the time from when the request headers are \
+                received to when the response stream has completed, in \
+                milliseconds.",
+            ),
+        }
+    }
+
+    /// Records the first-byte latency of a response whose headers were
+    /// received `since_request_open` after the request was opened.
+    ///
+    /// This is recorded when the response is opened, so that it is not lost
+    /// if the response stream never completes.
+    fn record_first_byte(&mut self, labels: &Arc<ResponseLabels>, since_request_open: Duration) {
+        *self.first_byte.values
+            .entry(labels.clone())
+            .or_insert_with(Histogram::default) += since_request_open;
+    }
+
+    /// Records the last-byte latency of a response that completed
+    /// `since_request_open` after the request was opened.
+    fn record_last_byte(&mut self, labels: &Arc<ResponseLabels>, since_request_open: Duration) {
+        *self.last_byte.values
+            .entry(labels.clone())
+            .or_insert_with(Histogram::default) += since_request_open;
+    }
+}
+
+// ===== impl ResponseLatencyQuantiles =====
+
+impl ResponseLatencyQuantiles {
+    fn new(quantiles: &[f64]) -> Self {
+        ResponseLatencyQuantiles {
+            summary: Metric::<Summary, Arc<ResponseLabels>>::new(
+                "response_latency_quantiles_ms",
+                "A summary of the total latency of the responses completed in \
+                the last minute, in milliseconds.",
+            ),
+            empty: Summary::new(
+                Duration::from_secs(RESPONSE_LATENCY_QUANTILES_WINDOW_SECS),
+                quantiles,
+            ),
+        }
+    }
+
+    /// Records the total latency of a response.
+    fn record(&mut self, labels: &Arc<ResponseLabels>, latency: Duration) {
+        let empty = &self.empty;
+        self.summary.values
+            .entry(labels.clone())
+            .or_insert_with(|| empty.clone())
+            .observe(latency);
+    }
+}
+
+// ===== impl TcpMetrics =====
+
+impl TcpMetrics {
+    pub fn new() -> TcpMetrics {
+        let open_total = Metric::<Counter, Arc<TransportLabels>>::new(
+            "tcp_open_total",
+            "A counter of the total number of transport connections.",
+        );
+
+        let close_total = Metric::<Counter, Arc<TransportCloseLabels>>::new(
+            "tcp_close_total",
+            "A counter of the total number of transport connections.",
+        );
+
+        let connection_duration = Metric::<Histogram, Arc<TransportCloseLabels>>::new(
+            "tcp_connection_duration_ms",
+            "A histogram of the duration of the lifetime of connections, in milliseconds",
+        );
+
+        let open_connections = Metric::<Gauge, Arc<TransportLabels>>::new(
+            "tcp_open_connections",
+            "A gauge of the number of transport connections currently open.",
+        );
+
+        let read_bytes_total = Metric::<Counter, Arc<TransportLabels>>::new(
+            "tcp_read_bytes_total",
+            "A counter of the total number of recieved bytes."
+        );
+
+        let write_bytes_total = Metric::<Counter, Arc<TransportLabels>>::new(
+            "tcp_write_bytes_total",
+            "A counter of the total number of sent bytes."
+        );
+
+        let accept_errors_total = Metric::<Counter, AcceptErrorLabels>::new(
+            "tcp_accept_errors_total",
+            "A counter of the number of accepted connections that failed \
+            before they could be tracked as transports.",
+        );
+
+         Self {
+            open_total,
+            close_total,
+            connection_duration,
+            open_connections,
+            empty_connection_duration: Histogram::default(),
+            read_bytes_total,
+            write_bytes_total,
+            accept_errors_total,
+        }
+    }
+
+    /// Resets every TCP metric to its initial value.
+    ///
+    /// The open connections gauge is kept, since those connections are
+    /// still open and will be closed later.
+    fn reset(&mut self) {
+        self.open_total.values.clear();
+        self.close_total.values.clear();
+        self.connection_duration.values.clear();
+        self.write_bytes_total.values.clear();
+        self.read_bytes_total.values.clear();
+        self.accept_errors_total.values.clear();
+    }
+
+    /// Adds the transports recorded by another shard to these metrics.
+    ///
+    /// Accept errors are not merged, as they are only recorded in the
+    /// inbound shard.
+    fn merge(&mut self, other: TcpMetrics) {
+        self.open_total.values.extend(other.open_total.values);
+        self.close_total.values.extend(other.close_total.values);
+        self.connection_duration.values.extend(other.connection_duration.values);
+        self.open_connections.values.extend(other.open_connections.values);
+        self.write_bytes_total.values.extend(other.write_bytes_total.values);
+        self.read_bytes_total.values.extend(other.read_bytes_total.values);
+    }
+
+    /// Returns the number of series of the TCP metrics.
+    fn series_count(&self) -> usize {
+        self.open_total.values.len()
+            + self.close_total.values.len()
+            + self.connection_duration.values.len()
+            + self.open_connections.values.len()
+            + self.write_bytes_total.values.len()
+            + self.read_bytes_total.values.len()
+            + self.accept_errors_total.values.len()
+    }
+
+    /// Returns each of the TCP metric families, in the order in which they
+    /// are written.
+    fn families(&self) -> [&Family; 7] {
+        [
+            &self.open_total,
+            &self.close_total,
+            &self.connection_duration,
+            &self.open_connections,
+            &self.write_bytes_total,
+            &self.read_bytes_total,
+            &self.accept_errors_total,
+        ]
+    }
+
+    fn open_total(&mut self, labels: &Arc<TransportLabels>) -> &mut Counter {
+        self.open_total.values
+            .entry(labels.clone())
+            .or_insert_with(Default::default)
+    }
+
+    fn accept_errors_total(&mut self, labels: AcceptErrorLabels) -> &mut Counter {
+        self.accept_errors_total.values
+            .entry(labels)
+            .or_insert_with(Counter::default)
+    }
+
+    fn close_total(&mut self, labels: &Arc<TransportCloseLabels>) -> &mut Counter {
+        self.close_total.values
+            .entry(labels.clone())
+            .or_insert_with(Counter::default)
+    }
+
+    fn connection_duration(&mut self, labels: &Arc<TransportCloseLabels>) -> &mut Histogram {
+        let empty = &self.empty_connection_duration;
+        self.connection_duration.values
+            .entry(labels.clone())
+            .or_insert_with(|| empty.clone())
+    }
+
+    fn open_connections(&mut self, labels: &Arc<TransportLabels>) -> &mut Gauge {
+        self.open_connections.values
+            .entry(labels.clone())
+            .or_insert_with(Gauge::default)
+    }
+
+    fn write_bytes_total(&mut self, labels: &Arc<TransportLabels>) -> &mut Counter {
+        self.write_bytes_total.values
+            .entry(labels.clone())
+            .or_insert_with(Counter::default)
+    }
+
+    fn read_bytes_total(&mut self, labels: &Arc<TransportLabels>) -> &mut Counter {
+        self.read_bytes_total.values
+            .entry(labels.clone())
+            .or_insert_with(Counter::default)
+    }
+}
+
+
+/// Divides `numerator` by `denominator`, returning zero rather than `NaN` or
+/// an infinity when the denominator is zero.
+// Currently only used by tests, until a computed gauge needs it.
+#[allow(dead_code)]
+fn ratio(numerator: f64, denominator: f64) -> f64 {
+    if denominator == 0.0 {
+        return 0.0;
+    }
+    numerator / denominator
+}
+
+/// Formats a sample value using the spellings Prometheus accepts for
+/// non-finite values (`NaN`, `+Inf` and `-Inf`), which differ from those
+/// used by `f64`'s `Display` implementation.
+#[derive(Copy, Clone, Debug)]
+struct FloatValue(f64);
+
+impl fmt::Display for FloatValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_nan() {
+            f.write_str("NaN")
+        } else if self.0.is_infinite() {
+            f.write_str(if self.0 > 0.0 { "+Inf" } else { "-Inf" })
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+// ===== impl Metric =====
+
+impl<M, L: Hash + Eq> Metric<M, L> {
+
+    pub fn new(name: &'static str, help: &'static str) -> Self {
+        Metric {
+            name,
+            help,
+            values: IndexMap::new(),
+        }
+    }
+
+}
+
+impl<L> FmtMetric for Metric<Counter, L>
+where
     L: fmt::Display,
     L: Hash + Eq,
 {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f,
+    fn fmt_metric(&self, opts: ScrapeOptions, w: &mut fmt::Write) -> fmt::Result {
+        write!(w,
             "# HELP {name} {help}\n# TYPE {name} counter\n",
             name = self.name,
             help = self.help,
         )?;
 
         for (labels, value) in &self.values {
-            write!(f, "{name}{{{labels}}} {value}\n",
+            write!(w, "{name}{{{labels}}} {value}\n",
                 name = self.name,
                 labels = labels,
                 value = value,
             )?;
+            if opts.created {
+                write!(w, "{name}_created{{{labels}}} {created}\n",
+                    name = counter_family(self.name),
+                    labels = labels,
+                    created = value.created_secs(),
+                )?;
+            }
         }
 
         Ok(())
     }
 }
 
-impl<L> fmt::Display for Metric<Gauge, L>
+impl<L> FmtMetric for Metric<Gauge, L>
 where
     L: fmt::Display,
     L: Hash + Eq,
 {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f,
+    fn fmt_metric(&self, _: ScrapeOptions, w: &mut fmt::Write) -> fmt::Result {
+        write!(w,
             "# HELP {name} {help}\n# TYPE {name} gauge\n",
             name = self.name,
             help = self.help,
         )?;
 
         for (labels, value) in &self.values {
-            write!(f, "{name}{{{labels}}} {value}\n",
+            write!(w, "{name}{{{labels}}} {value}\n",
                 name = self.name,
                 labels = labels,
                 value = value,
@@ -354,48 +1533,26 @@ where
     }
 }
 
-impl<L> fmt::Display for Metric<Histogram, L> where
+impl<L> FmtMetric for Metric<Histogram, L> where
     L: fmt::Display,
     L: Hash + Eq,
 {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f,
-            "# HELP {name} {help}\n# TYPE {name} histogram\n",
+    fn fmt_metric(&self, opts: ScrapeOptions, w: &mut fmt::Write) -> fmt::Result {
+        write!(w,
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n",
             name = self.name,
             help = self.help,
+            kind = histogram_kind(opts),
         )?;
 
         for (labels, histogram) in &self.values {
-            // Look up the bucket numbers against the BUCKET_BOUNDS array
-            // to turn them into upper bounds.
-            let bounds_and_counts = histogram.into_iter()
-                .enumerate()
-                .map(|(num, count)| (BUCKET_BOUNDS[num], count));
-
-            // Since Prometheus expects each bucket's value to be the sum of
-            // the number of values in this bucket and all lower buckets,
-            // track the total count here.
-            let mut total_count = 0;
-            for (le, count) in bounds_and_counts {
-                // Add this bucket's count to the total count.
-                total_count += count;
-                write!(f, "{name}_bucket{{{labels},le=\"{le}\"}} {count}\n",
-                    name = self.name,
-                    labels = labels,
-                    le = le,
-                    // Print the total count *as of this iteration*.
-                    count = total_count,
-                )?;
-            }
-
-            // Print the total count and histogram sum stats.
-            write!(f,
-                "{name}_count{{{labels}}} {count}\n\
-                 {name}_sum{{{labels}}} {sum}\n",
-                name = self.name,
-                labels = labels,
-                count = total_count,
-                sum = histogram.sum_in_ms(),
+            fmt_histogram(
+                w,
+                opts.histogram_buckets,
+                self.name,
+                Some(labels),
+                histogram.bounds().iter().zip(histogram),
+                &FloatValue(histogram.sum_in_ms()),
             )?;
         }
 
@@ -403,160 +1560,2120 @@ impl<L> fmt::Display for Metric<Histogram, L> where
     }
 }
 
-// ===== impl Aggregate =====
-
-impl Aggregate {
+impl<L> FmtMetric for Metric<Summary, L> where
+    L: fmt::Display,
+    L: Hash + Eq,
+{
+    fn fmt_metric(&self, _: ScrapeOptions, w: &mut fmt::Write) -> fmt::Result {
+        write!(w,
+            "# HELP {name} {help}\n# TYPE {name} summary\n",
+            name = self.name,
+            help = self.help,
+        )?;
 
-    fn new(metrics: &Arc<Mutex<Metrics>>) -> Self {
-        Aggregate {
-            metrics: metrics.clone(),
+        let now = Instant::now();
+        for (labels, summary) in &self.values {
+            fmt_summary(w, self.name, Some(labels), summary, now)?;
         }
-    }
 
-    #[inline]
-    fn update<F: Fn(&mut Metrics)>(&mut self, f: F) {
-        let mut lock = self.metrics.lock()
-            .expect("metrics lock poisoned");
-        f(&mut *lock);
+        Ok(())
     }
+}
 
-    /// Observe the given event.
-    pub fn record_event(&mut self, event: &Event) {
-        trace!("Metrics::record({:?})", event);
-        match *event {
-
-            Event::StreamRequestOpen(_) | Event::StreamResponseOpen(_, _) => {
-                // Do nothing; we'll record metrics for the request or response
-                //  when the stream *finishes*.
-            },
-
-            Event::StreamRequestFail(ref req, _) => {
-                let labels = Arc::new(RequestLabels::new(req));
-                self.update(|metrics| {
-                    metrics.request_total(&labels).incr();
-                })
-            },
-
-            Event::StreamRequestEnd(ref req, _) => {
-                let labels = Arc::new(RequestLabels::new(req));
-                self.update(|metrics| {
-                    metrics.request_total(&labels).incr();
-                })
-            },
-
-            Event::StreamResponseEnd(ref res, ref end) => {
-                let labels = Arc::new(ResponseLabels::new(
-                    res,
-                    end.grpc_status,
-                ));
-                self.update(|metrics| {
-                    metrics.response_total(&labels).incr();
-                    *metrics.response_latency(&labels) += end.since_request_open;
-                });
-            },
-
-            Event::StreamResponseFail(ref res, ref fail) => {
-                // TODO: do we care about the failure's error code here?
-                let labels = Arc::new(ResponseLabels::fail(res));
-                self.update(|metrics| {
-                    metrics.response_total(&labels).incr();
-                    *metrics.response_latency(&labels) += fail.since_request_open;
-                });
-            },
-
-            Event::TransportOpen(ref ctx) => {
-                let labels = Arc::new(TransportLabels::new(ctx));
-                self.update(|metrics| {
-                    metrics.tcp().open_total(&labels).incr();
-                    metrics.tcp().open_connections(&labels).incr();
-                })
-            },
-
-            Event::TransportClose(ref ctx, ref close) => {
-                let labels = Arc::new(TransportLabels::new(ctx));
-                let close_labels = Arc::new(TransportCloseLabels::new(ctx, close));
-                self.update(|metrics| {
-                    *metrics.tcp().write_bytes_total(&labels) += close.tx_bytes as u64;
-                    *metrics.tcp().read_bytes_total(&labels) += close.rx_bytes as u64;
-
-                    *metrics.tcp().connection_duration(&close_labels) += close.duration;
-                    metrics.tcp().close_total(&close_labels).incr();
-
-                    let metrics = metrics.tcp().open_connections.values.get_mut(&labels);
-                    debug_assert!(metrics.is_some());
-                    match metrics {
-                        Some(m) => {
-                            m.decr();
-                        }
-                        None => {
-                            error!("Closed transport missing from metrics registry: {{{}}}", labels);
-                        }
-                    }
-                })
-            },
-        };
+/// Returns the OpenMetrics name of a counter family, which is its samples'
+/// name without the `_total` suffix.
+fn counter_family(name: &str) -> &str {
+    const TOTAL: &str = "_total";
+    if name.ends_with(TOTAL) {
+        &name[..name.len() - TOTAL.len()]
+    } else {
+        name
     }
 }
 
+/// Returns the Prometheus type of a histogram formatted according to `opts`.
+///
+/// When `opts.histogram_buckets` is false, histograms are written without
+/// their buckets, which Prometheus describes as a summary without any
+/// quantiles.
+fn histogram_kind(opts: ScrapeOptions) -> &'static str {
+    if opts.histogram_buckets {
+        "histogram"
+    } else {
+        "summary"
+    }
+}
 
-// ===== impl Serve =====
-
-impl Serve {
-    fn new(metrics: &Arc<Mutex<Metrics>>) -> Self {
-        Serve {
-            metrics: metrics.clone(),
+/// Writes the buckets, count, and sum of a histogram, given the upper bound
+/// and count of each of its buckets.
+///
+/// If `buckets` is false, only the count and sum are written.
+///
+/// If `labels` are provided, they are written before the `le` label of each
+/// bucket, and on the count and sum.
+fn fmt_histogram<B, I>(
+    w: &mut fmt::Write,
+    buckets: bool,
+    name: &str,
+    labels: Option<&fmt::Display>,
+    bounds_and_counts: I,
+    sum: &fmt::Display,
+) -> fmt::Result
+where
+    B: fmt::Display,
+    I: IntoIterator<Item = (B, u64)>,
+{
+    // Since Prometheus expects each bucket's value to be the sum of
+    // the number of values in this bucket and all lower buckets,
+    // track the total count here.
+    let mut total_count = 0;
+    for (le, count) in bounds_and_counts {
+        // Add this bucket's count to the total count.
+        total_count += count;
+        if !buckets {
+            continue;
+        }
+        match labels {
+            Some(labels) => write!(w, "{name}_bucket{{{labels},le=\"{le}\"}} {count}\n",
+                name = name,
+                labels = labels,
+                le = le,
+                // Print the total count *as of this iteration*.
+                count = total_count,
+            )?,
+            None => write!(w, "{name}_bucket{{le=\"{le}\"}} {count}\n",
+                name = name,
+                le = le,
+                count = total_count,
+            )?,
         }
     }
+
+    // Print the total count and histogram sum stats.
+    fmt_count_and_sum(w, name, labels, total_count, sum)
 }
 
-fn is_gzip(req: &HyperRequest) -> bool {
-    if let Some(accept_encodings) = req
-        .headers()
-        .get::<AcceptEncoding>()
-    {
-        return accept_encodings
-            .iter()
-            .any(|&QualityItem { ref item, .. }| item == &Encoding::Gzip)
-    }
-    false
-}
-
-impl HyperService for Serve {
-    type Request = HyperRequest;
-    type Response = HyperResponse;
-    type Error = hyper::Error;
-    type Future = FutureResult<Self::Response, Self::Error>;
-
-    fn call(&self, req: Self::Request) -> Self::Future {
-        if req.path() != "/metrics" {
-            return future::ok(HyperResponse::new()
-                .with_status(StatusCode::NotFound));
-        }
-
-        let metrics = self.metrics.lock()
-            .expect("metrics lock poisoned");
-
-        let resp = if is_gzip(&req) {
-            trace!("gzipping metrics");
-            let mut writer = GzEncoder::new(Vec::<u8>::new(), CompressionOptions::fast());
-            write!(&mut writer, "{}", *metrics)
-                .and_then(|_| writer.finish())
-                .map(|body| {
-                    HyperResponse::new()
-                        .with_header(ContentEncoding(vec![Encoding::Gzip]))
-                        .with_header(ContentType::plaintext())
-                        .with_body(Body::from(body))
-                })
+/// Writes the quantiles, count, and sum of a summary, with the quantiles of
+/// the values observed within the window before `now`.
+///
+/// If `labels` are provided, they are written before the `quantile` label of
+/// each quantile, and on the count and sum.
+fn fmt_summary(
+    w: &mut fmt::Write,
+    name: &str,
+    labels: Option<&fmt::Display>,
+    summary: &Summary,
+    now: Instant,
+) -> fmt::Result {
+    for (quantile, value) in summary.quantiles_at(now) {
+        match labels {
+            Some(labels) => write!(w, "{name}{{{labels},quantile=\"{quantile}\"}} {value}\n",
+                name = name,
+                labels = labels,
+                quantile = quantile,
+                value = FloatValue(value),
+            )?,
+            None => write!(w, "{name}{{quantile=\"{quantile}\"}} {value}\n",
+                name = name,
+                quantile = quantile,
+                value = FloatValue(value),
+            )?,
+        }
+    }
+
+    fmt_count_and_sum(w, name, labels, summary.count(), &FloatValue(summary.sum_in_ms()))
+}
+
+/// Writes the `_count` and `_sum` samples of a histogram or summary.
+fn fmt_count_and_sum(
+    w: &mut fmt::Write,
+    name: &str,
+    labels: Option<&fmt::Display>,
+    total_count: u64,
+    sum: &fmt::Display,
+) -> fmt::Result {
+    match labels {
+        Some(labels) => write!(w,
+            "{name}_count{{{labels}}} {count}\n\
+             {name}_sum{{{labels}}} {sum}\n",
+            name = name,
+            labels = labels,
+            count = total_count,
+            sum = sum,
+        ),
+        None => write!(w,
+            "{name}_count {count}\n\
+             {name}_sum {sum}\n",
+            name = name,
+            count = total_count,
+            sum = sum,
+        ),
+    }
+}
+
+impl<M, L> Family for Metric<M, L>
+where
+    Metric<M, L>: FmtMetric,
+    M: Kind,
+    L: LabelKeys + Hash + Eq + fmt::Display,
+{
+    fn describe(&self) -> Vec<MetricDescriptor> {
+        let mut label_keys = L::KEYS.to_vec();
+        label_keys.extend_from_slice(M::LABEL_KEYS);
+        vec![MetricDescriptor {
+            name: self.name,
+            kind: M::KIND,
+            help: self.help,
+            unit: unit(self.name),
+            label_keys,
+        }]
+    }
+
+    fn snapshot(&self) -> Vec<MetricSnapshot> {
+        let series = self.values.iter()
+            .map(|(labels, value)| SeriesSnapshot {
+                labels: label_pairs(&labels.to_string()),
+                value: value.snapshot(),
+            })
+            .collect();
+        vec![MetricSnapshot { name: self.name, series }]
+    }
+}
+
+/// Returns the unit of the metric named `name`, as indicated by the name's
+/// suffix.
+fn unit(name: &str) -> Option<&'static str> {
+    if name.ends_with("_ms") {
+        Some("milliseconds")
+    } else if name.ends_with("_seconds") {
+        Some("seconds")
+    } else if name.ends_with("_bytes") || name.ends_with("_bytes_total") {
+        Some("bytes")
+    } else {
+        None
+    }
+}
+
+/// Returns the suffix of the metric family named `name` which names its
+/// unit, if it has one.
+fn unit_suffix(name: &str) -> Option<&'static str> {
+    ["ms", "seconds", "bytes"].iter()
+        .find(|unit| {
+            name.len() > unit.len() + 1
+                && name.ends_with(*unit)
+                && name[..name.len() - unit.len()].ends_with('_')
+        })
+        .cloned()
+}
+
+impl Kind for Counter {
+    const KIND: &'static str = "counter";
+
+    fn snapshot(&self) -> Value {
+        Value::Counter((*self).into())
+    }
+}
+
+impl Kind for Gauge {
+    const KIND: &'static str = "gauge";
+
+    fn snapshot(&self) -> Value {
+        Value::Gauge((*self).into())
+    }
+}
+
+impl Kind for Histogram {
+    const KIND: &'static str = "histogram";
+    const LABEL_KEYS: &'static [&'static str] = &["le"];
+
+    fn snapshot(&self) -> Value {
+        Value::Histogram(HistogramSnapshot {
+            buckets: self.bounds().iter()
+                .map(|&bound| bound.into())
+                .zip(self)
+                .collect(),
+            sum: self.sum_in_ms(),
+        })
+    }
+}
+
+impl Kind for Summary {
+    const KIND: &'static str = "summary";
+    const LABEL_KEYS: &'static [&'static str] = &["quantile"];
+
+    fn snapshot(&self) -> Value {
+        Value::Summary(SummarySnapshot {
+            quantiles: self.quantiles_at(Instant::now()),
+            count: self.count(),
+            sum: self.sum_in_ms(),
+        })
+    }
+}
+
+impl Kind for SizeHistogram {
+    const KIND: &'static str = "histogram";
+    const LABEL_KEYS: &'static [&'static str] = &["le"];
+
+    fn snapshot(&self) -> Value {
+        Value::Histogram(HistogramSnapshot {
+            buckets: self.bounds().iter()
+                .map(|&bound| bound.into())
+                .zip(self)
+                .collect(),
+            sum: self.sum() as f64,
+        })
+    }
+}
+
+// ===== impl RequestsByAuthority =====
+
+impl<'a> RequestsByAuthority<'a> {
+    const NAME: &'static str = "request_by_authority_total";
+    const HELP: &'static str = "A counter of the number of requests the \
+        proxy has received for each authority, in either direction.";
+
+    /// Sums the request totals for each authority.
+    fn totals(&self) -> IndexMap<&'a str, Counter> {
+        let mut totals = IndexMap::<&str, Counter>::new();
+        for (labels, &count) in &self.0.values {
+            let total = totals.entry(labels.authority()).or_insert_with(Counter::default);
+            *total = *total + count;
+        }
+        totals
+    }
+}
+
+impl<'a> FmtMetric for RequestsByAuthority<'a> {
+    fn fmt_metric(&self, opts: ScrapeOptions, w: &mut fmt::Write) -> fmt::Result {
+        let name = Self::NAME;
+        write!(w,
+            "# HELP {name} {help}\n# TYPE {name} counter\n",
+            name = name,
+            help = Self::HELP,
+        )?;
+
+        for (authority, total) in self.totals() {
+            write!(w, "{name}{{authority=\"{authority}\"}} {value}\n",
+                name = name,
+                authority = authority,
+                value = total,
+            )?;
+            if opts.created {
+                write!(w, "{name}_created{{authority=\"{authority}\"}} {created}\n",
+                    name = counter_family(name),
+                    authority = authority,
+                    created = total.created_secs(),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> Family for RequestsByAuthority<'a> {
+    fn describe(&self) -> Vec<MetricDescriptor> {
+        vec![MetricDescriptor {
+            name: Self::NAME,
+            kind: Counter::KIND,
+            help: Self::HELP,
+            unit: None,
+            label_keys: vec!["authority"],
+        }]
+    }
+
+    fn snapshot(&self) -> Vec<MetricSnapshot> {
+        let series = self.totals().into_iter()
+            .map(|(authority, total)| SeriesSnapshot {
+                labels: vec![("authority".to_owned(), authority.to_owned())],
+                value: total.snapshot(),
+            })
+            .collect();
+        vec![MetricSnapshot { name: Self::NAME, series }]
+    }
+}
+
+// ===== impl Scalar =====
+
+impl<M: Default> Scalar<M> {
+
+    pub fn new(name: &'static str, help: &'static str) -> Self {
+        Scalar {
+            name,
+            help,
+            value: M::default(),
+        }
+    }
+
+}
+
+impl<M> Family for Scalar<M>
+where
+    Scalar<M>: FmtMetric,
+    M: Kind,
+{
+    fn describe(&self) -> Vec<MetricDescriptor> {
+        vec![MetricDescriptor {
+            name: self.name,
+            kind: M::KIND,
+            help: self.help,
+            unit: unit(self.name),
+            label_keys: M::LABEL_KEYS.to_vec(),
+        }]
+    }
+
+    fn snapshot(&self) -> Vec<MetricSnapshot> {
+        vec![MetricSnapshot {
+            name: self.name,
+            series: vec![SeriesSnapshot {
+                labels: Vec::new(),
+                value: self.value.snapshot(),
+            }],
+        }]
+    }
+}
+
+impl FmtMetric for Scalar<Counter> {
+    fn fmt_metric(&self, opts: ScrapeOptions, w: &mut fmt::Write) -> fmt::Result {
+        write!(w,
+            "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}\n",
+            name = self.name,
+            help = self.help,
+            value = self.value,
+        )?;
+        if opts.created {
+            write!(w, "{name}_created {created}\n",
+                name = counter_family(self.name),
+                created = self.value.created_secs(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FmtMetric for Scalar<Gauge> {
+    fn fmt_metric(&self, _: ScrapeOptions, w: &mut fmt::Write) -> fmt::Result {
+        write!(w,
+            "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n",
+            name = self.name,
+            help = self.help,
+            value = self.value,
+        )
+    }
+}
+
+impl FmtMetric for Scalar<Histogram> {
+    fn fmt_metric(&self, opts: ScrapeOptions, w: &mut fmt::Write) -> fmt::Result {
+        write!(w,
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n",
+            name = self.name,
+            help = self.help,
+            kind = histogram_kind(opts),
+        )?;
+
+        fmt_histogram(
+            w,
+            opts.histogram_buckets,
+            self.name,
+            None,
+            self.value.bounds().iter().zip(&self.value),
+            &FloatValue(self.value.sum_in_ms()),
+        )
+    }
+}
+
+impl FmtMetric for Scalar<SizeHistogram> {
+    fn fmt_metric(&self, opts: ScrapeOptions, w: &mut fmt::Write) -> fmt::Result {
+        write!(w,
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n",
+            name = self.name,
+            help = self.help,
+            kind = histogram_kind(opts),
+        )?;
+
+        fmt_histogram(
+            w,
+            opts.histogram_buckets,
+            self.name,
+            None,
+            self.value.bounds().iter().zip(&self.value),
+            &self.value.sum(),
+        )
+    }
+}
+
+impl FmtMetric for Scalar<Summary> {
+    fn fmt_metric(&self, _: ScrapeOptions, w: &mut fmt::Write) -> fmt::Result {
+        write!(w,
+            "# HELP {name} {help}\n# TYPE {name} summary\n",
+            name = self.name,
+            help = self.help,
+        )?;
+
+        fmt_summary(w, self.name, None, &self.value, Instant::now())
+    }
+}
+
+// ===== impl Shards =====
+
+impl Shards {
+    fn new(process: &Arc<ctx::Process>) -> Self {
+        Shards {
+            inbound: Mutex::new(Metrics::new(process)),
+            outbound: Mutex::new(Metrics::new(process)),
+            recent_events: Mutex::new(None),
+        }
+    }
+
+    /// Returns the shard in which traffic through `proxy` is recorded.
+    fn traffic(&self, proxy: &ctx::Proxy) -> &Mutex<Metrics> {
+        if proxy.is_inbound() {
+            &self.inbound
         } else {
-            let mut writer = Vec::<u8>::new();
-            write!(&mut writer, "{}", *metrics)
-                .map(|_| {
-                    HyperResponse::new()
-                        .with_header(ContentType::plaintext())
-                        .with_body(Body::from(writer))
+            &self.outbound
+        }
+    }
+
+    /// Returns the shard in which `event` is recorded.
+    fn recording(&self, event: &Event) -> &Mutex<Metrics> {
+        match *event {
+            // Accept errors precede any transport, so they are recorded
+            // with the process metrics.
+            Event::TransportAcceptFail(..) => self.process(),
+            _ => self.traffic(event.proxy()),
+        }
+    }
+
+    /// Returns the shard in which the process metrics are recorded.
+    fn process(&self) -> &Mutex<Metrics> {
+        &self.inbound
+    }
+
+    /// Calls `f` with each shard in turn, only holding the lock of the shard
+    /// that it is called with.
+    fn each<F: FnMut(&mut Metrics)>(&self, mut f: F) {
+        for shard in &[&self.inbound, &self.outbound] {
+            let mut metrics = lock(shard, "metrics");
+            f(&mut *metrics);
+        }
+    }
+
+    /// Copies each shard in turn and merges the copies.
+    ///
+    /// Returns the merged copy and the total time for which the shards'
+    /// locks were held.
+    fn merged(&self) -> (Metrics, Duration) {
+        let mut lock_hold = Duration::default();
+        let mut copies = Vec::with_capacity(2);
+        self.each(|metrics| {
+            let locked_at = Instant::now();
+            copies.push(metrics.clone());
+            lock_hold += locked_at.elapsed();
+        });
+
+        let mut copies = copies.into_iter();
+        let mut merged = copies.next().expect("there is always an inbound shard");
+        for copy in copies {
+            merged.merge(copy);
+        }
+        (merged, lock_hold)
+    }
+}
+
+/// Locks `mutex`, which holds the `what`, recovering its contents if a thread
+/// panicked while holding it.
+///
+/// The metrics are only ever updated in place, so a panic can at worst leave
+/// some of them partially updated. Recovering them means that a single panic
+/// skews the metrics a little, rather than failing every event that is
+/// recorded and every scrape that is served from then on.
+fn lock<'a, T>(mutex: &'a Mutex<T>, what: &str) -> MutexGuard<'a, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        warn!("{} lock poisoned; recovering", what);
+        poisoned.into_inner()
+    })
+}
+
+/// Helpers shared by the tests of the metrics modules.
+#[cfg(test)]
+pub mod test_util {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    use std::time::Duration;
+
+    use http;
+    use hyper::{Method, StatusCode};
+    use hyper::server::Request as HyperRequest;
+
+    use conduit_proxy_controller_grpc::common::Protocol;
+    use futures_watch;
+    use telemetry::event;
+    use super::*;
+
+    pub fn server(proxy: &Arc<ctx::Proxy>) -> Arc<ctx::transport::Server> {
+        let local: SocketAddr = "127.0.0.1:4143".parse().unwrap();
+        let remote: SocketAddr = "10.1.1.1:41000".parse().unwrap();
+        ctx::transport::Server::new(proxy, &local, &remote, &None, Protocol::Http)
+    }
+
+    pub fn client<L>(proxy: &Arc<ctx::Proxy>, dst_labels: L) -> Arc<ctx::transport::Client>
+    where
+        L: IntoIterator<Item=(&'static str, &'static str)>,
+    {
+        let remote: SocketAddr = "10.2.2.2:8080".parse().unwrap();
+        let (dst_labels, _) = futures_watch::Watch::new(DstLabels::new(dst_labels));
+        ctx::transport::Client::new(proxy, &remote, Protocol::Http, Some(dst_labels))
+    }
+
+    pub fn request(uri: &str, proxy: &Arc<ctx::Proxy>) -> Arc<ctx::http::Request> {
+        dst_request(uri, proxy, vec![])
+    }
+
+    pub fn dst_request<L>(uri: &str, proxy: &Arc<ctx::Proxy>, dst_labels: L)
+        -> Arc<ctx::http::Request>
+    where
+        L: IntoIterator<Item=(&'static str, &'static str)>,
+    {
+        // Each request is given its own ID, as the proxy gives them.
+        static NEXT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let req = http::Request::get(uri).body(()).unwrap();
+        ctx::http::Request::new(&req, &server(proxy), &client(proxy, dst_labels), id, true)
+    }
+
+    pub fn request_end(req: &Arc<ctx::http::Request>) -> Event {
+        Event::StreamRequestEnd(Arc::clone(req), event::StreamRequestEnd {
+            since_request_open: Duration::from_millis(10),
+            bytes_sent: 0,
+        })
+    }
+
+    pub fn response_end(req: &Arc<ctx::http::Request>, grpc_status: Option<u32>) -> Event {
+        let rsp = http::Response::builder().status(200).body(()).unwrap();
+        let rsp = ctx::http::Response::new(&rsp, req);
+        Event::StreamResponseEnd(rsp, event::StreamResponseEnd {
+            grpc_status,
+            since_request_open: Duration::from_millis(10),
+            since_request_end: Some(Duration::from_millis(8)),
+            since_response_open: Duration::from_millis(5),
+            bytes_sent: 0,
+            frames_sent: 0,
+        })
+    }
+
+    pub fn scrape(serve: &Serve) -> String {
+        scrape_uri(serve, "/metrics")
+    }
+
+    pub fn scrape_uri(serve: &Serve, uri: &str) -> String {
+        let (status, body, _) = serve.scrape_sync(get(uri));
+        assert_eq!(status, StatusCode::Ok);
+        String::from_utf8(body).expect("scrape should be utf-8")
+    }
+
+    pub fn get(uri: &str) -> HyperRequest {
+        HyperRequest::new(Method::Get, uri.parse().unwrap())
+    }
+
+    /// Checks that `scrape` is valid Prometheus text, returning the name of
+    /// each sample (without labels) and its value.
+    pub fn parse_samples(scrape: &str) -> Vec<(String, String)> {
+        let mut typed = Vec::new();
+        let mut samples = Vec::new();
+        // The value of the previous bucket of the histogram series being
+        // parsed, if the previous sample was a bucket.
+        let mut last_bucket = None;
+        for line in scrape.lines() {
+            if line.is_empty() || line.starts_with("# HELP ") {
+                continue;
+            }
+
+            if line.starts_with("# TYPE ") {
+                let mut parts = line["# TYPE ".len()..].split(' ');
+                let name = parts.next().expect("TYPE line has a name");
+                let kind = parts.next().expect("TYPE line has a type");
+                assert!(
+                    ["counter", "gauge", "histogram", "summary"].contains(&kind),
+                    "unexpected type in {:?}", line
+                );
+                assert_eq!(parts.next(), None, "malformed line {:?}", line);
+                typed.push(name.to_owned());
+                continue;
+            }
+
+            assert!(!line.starts_with('#'), "unexpected comment {:?}", line);
+            let value_at = line.rfind(' ').expect("sample has a value");
+            let (series, value) = (&line[..value_at], &line[value_at + 1..]);
+            assert!(
+                ["NaN", "+Inf", "-Inf"].contains(&value) || (
+                    value.parse::<f64>().map(f64::is_finite).unwrap_or(false)
+                ),
+                "invalid value in {:?}", line
+            );
+            let name = match series.find('{') {
+                Some(i) => {
+                    assert!(series.ends_with('}'), "malformed labels in {:?}", line);
+                    &series[..i]
+                },
+                None => series,
+            };
+            assert!(
+                typed.iter().any(|t| name == t || (
+                    name.starts_with(t.as_str()) &&
+                    ["_bucket", "_count", "_sum"].contains(&&name[t.len()..])
+                )),
+                "sample {:?} has no TYPE", line
+            );
+
+            // Prometheus requires each bucket to count the values in all
+            // lower buckets too, and the `+Inf` bucket to count every value.
+            if name.ends_with("_bucket") {
+                let count = value.parse::<u64>().expect("bucket counts are integers");
+                if let Some((last, _)) = last_bucket {
+                    assert!(count >= last, "bucket {:?} is not cumulative", line);
+                }
+                last_bucket = Some((count, series.ends_with(",le=\"+Inf\"}") ||
+                    series.ends_with("{le=\"+Inf\"}")));
+            } else {
+                if let Some((last, inf)) = last_bucket.take() {
+                    assert!(inf, "histogram ending before {:?} has no +Inf bucket", line);
+                    assert!(name.ends_with("_count"), "{:?} does not follow buckets", line);
+                    assert_eq!(value, last.to_string(), "{:?} isn't the +Inf bucket", line);
+                }
+            }
+            samples.push((name.to_owned(), value.to_owned()));
+        }
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use h2;
+    use http;
+
+    use telemetry::event;
+    use super::*;
+    use super::labels::NO_AUTHORITY;
+    use super::test_util::*;
+
+    #[test]
+    fn float_values_use_prometheus_spellings() {
+        use std::f64;
+
+        assert_eq!(FloatValue(f64::NAN).to_string(), "NaN");
+        assert_eq!(FloatValue(f64::INFINITY).to_string(), "+Inf");
+        assert_eq!(FloatValue(f64::NEG_INFINITY).to_string(), "-Inf");
+        assert_eq!(FloatValue(1.5).to_string(), "1.5");
+        assert_eq!(FloatValue(0.0).to_string(), "0");
+    }
+
+    #[test]
+    fn ratios_with_a_zero_denominator_are_zero() {
+        assert_eq!(ratio(0.0, 0.0), 0.0);
+        assert_eq!(ratio(1.0, 0.0), 0.0);
+        assert_eq!(ratio(3.0, 2.0), 1.5);
+
+        let sample = format!("ratio {}", FloatValue(ratio(0.0, 0.0)));
+        assert_eq!(sample, "ratio 0");
+        parse_samples(&format!("# TYPE ratio gauge\n{}\n", sample));
+    }
+
+    #[test]
+    fn idle_series_are_evicted() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::outbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let idle = request("http://idle.test/", &proxy);
+        let open = request("http://open.test/", &proxy);
+        let ctx = Arc::new(ctx::transport::Ctx::Client(client(&proxy, None)));
+        aggregate.record_event(&Event::TransportOpen(Arc::clone(&ctx)));
+        for req in &[&idle, &open] {
+            aggregate.record_event(&Event::StreamRequestOpen(Arc::clone(req)));
+            aggregate.record_event(&request_end(req));
+        }
+        aggregate.record_event(&response_end(&idle, None));
+
+        let max_idle = Duration::from_secs(60);
+        let evict = |aggregate: &Aggregate, since: Duration| {
+            aggregate.shards.outbound.lock().unwrap().evict_idle(max_idle, Instant::now() + since)
+        };
+        assert_eq!(evict(&aggregate, Duration::from_secs(0)), 0);
+        assert_eq!(evict(&aggregate, Duration::from_secs(61)), 1);
+
+        let scrape = scrape(&serve);
+        assert!(!scrape.contains("authority=\"idle.test\""));
+        assert!(scrape.contains("request_total{authority=\"open.test\""));
+        assert!(scrape.contains("tcp_open_connections{direction=\"outbound\",peer=\"dst\"} 1\n"));
+
+        // Once its stream completes, the remaining series may be evicted.
+        aggregate.record_event(&response_end(&open, None));
+        assert_eq!(evict(&aggregate, Duration::from_secs(0)), 0);
+        assert_eq!(evict(&aggregate, Duration::from_secs(61)), 1);
+        assert!(aggregate.shards.outbound.lock().unwrap().response_total.values.is_empty());
+    }
+
+    #[test]
+    fn series_with_an_unclosed_stream_are_eventually_evicted() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::outbound(&process);
+        let (mut aggregate, serve) = new(&process);
+
+        // The events closing this request's stream are dropped, so it
+        // appears to be open forever.
+        let req = request("http://dropped.test/", &proxy);
+        aggregate.record_event(&Event::StreamRequestOpen(Arc::clone(&req)));
+        aggregate.record_event(&request_end(&req));
+
+        let max_idle = Duration::from_secs(60);
+        let evict = |aggregate: &Aggregate, since: Duration| {
+            aggregate.shards.outbound.lock().unwrap().evict_idle(max_idle, Instant::now() + since)
+        };
+        assert_eq!(evict(&aggregate, Duration::from_secs(61)), 0);
+        assert!(scrape(&serve).contains("request_total{authority=\"dropped.test\""));
+
+        let kept = max_idle * OPEN_STREAM_IDLE_FACTOR;
+        assert_eq!(evict(&aggregate, kept + Duration::from_secs(1)), 1);
+        assert!(!scrape(&serve).contains("request_total{authority=\"dropped.test\""));
+    }
+
+    #[test]
+    fn authorities_are_limited_per_destination() {
+        const MAX: usize = 3;
+
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (aggregate, serve) = new(&process);
+        let mut aggregate = aggregate.with_max_authorities(MAX);
+        for i in 0..MAX + 5 {
+            let req = request(&format!("http://foo{}.test/", i), &proxy);
+            aggregate.record_event(&request_end(&req));
+            aggregate.record_event(&response_end(&req, None));
+        }
+
+        let scrape = scrape(&serve);
+        let series = |prefix: &str| {
+            scrape.lines().filter(|l| l.starts_with(prefix)).count()
+        };
+        assert_eq!(series("request_total{"), MAX + 1);
+        assert_eq!(series("response_total{"), MAX + 1);
+        assert!(scrape.contains("request_total{authority=\"foo0.test\""));
+        assert!(!scrape.contains(&format!("authority=\"foo{}.test\"", MAX)));
+        assert!(scrape.contains(
+            "request_total{authority=\"__other__\",direction=\"inbound\",method=\"GET\"} 5\n"
+        ));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn request_labels_ref_hashes_like_request_labels() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::Hasher;
+
+        fn hash<H: Hash>(h: &H) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            h.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let process = ctx::Process::test("test");
+        let req = request("http://foo.test:8080/bar", &ctx::Proxy::outbound(&process));
+
+        let labels = RequestLabels::new(&req);
+        let labels_ref = RequestLabelsRef::new(&req);
+        assert_eq!(hash(&labels), hash(&labels_ref));
+    }
+
+    #[test]
+    fn request_total_reuses_series() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+
+        aggregate.record_event(&request_end(&request("http://foo.test/", &proxy)));
+        aggregate.record_event(&request_end(&request("http://foo.test/bar", &proxy)));
+        aggregate.record_event(&request_end(&request("http://bar.test/", &proxy)));
+
+        let scrape = scrape(&serve);
+        assert!(scrape.contains(
+            "request_total{authority=\"foo.test\",direction=\"inbound\",method=\"GET\"} 2\n"));
+        assert!(scrape.contains(
+            "request_total{authority=\"bar.test\",direction=\"inbound\",method=\"GET\"} 1\n"));
+    }
+
+    #[test]
+    fn scrape_matches_dst_labels() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::outbound(&process);
+        let (mut aggregate, serve) = new(&process);
+
+        let checkout = dst_request(
+            "http://checkout.test/", &proxy, vec![("app", "checkout"), ("ns", "shop")]);
+        let users = dst_request(
+            "http://users.test/", &proxy, vec![("app", "users"), ("ns", "shop")]);
+
+        aggregate.record_event(&request_end(&checkout));
+        aggregate.record_event(&request_end(&users));
+
+        let all = scrape(&serve);
+        assert!(all.contains("authority=\"checkout.test\""));
+        assert!(all.contains("authority=\"users.test\""));
+
+        let matched = scrape_uri(&serve, "/metrics?dst_app=checkout&dst_ns=shop");
+        assert!(matched.contains("authority=\"checkout.test\""));
+        assert!(!matched.contains("authority=\"users.test\""));
+
+        let none = scrape_uri(&serve, "/metrics?dst_app=checkout&dst_ns=other");
+        assert!(!none.contains("authority=\"checkout.test\""));
+        assert!(!none.contains("authority=\"users.test\""));
+    }
+
+    #[test]
+    fn tracked_series_are_counted_when_scraped() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::outbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let tracked = |scrape: &str| {
+            parse_samples(scrape).into_iter()
+                .find(|&(ref name, _)| name == "metrics_series_total")
+                .map(|(_, value)| value.parse::<u64>().unwrap())
+                .expect("scrape should count its series")
+        };
+
+        assert_eq!(tracked(&scrape(&serve)), 0);
+
+        aggregate.record_event(&request_end(&dst_request(
+            "http://checkout.test/", &proxy, vec![("app", "checkout")])));
+        let one = tracked(&scrape(&serve));
+        assert!(one > 0);
+
+        aggregate.record_event(&request_end(&dst_request(
+            "http://users.test/", &proxy, vec![("app", "users")])));
+        assert_eq!(tracked(&scrape(&serve)), 2 * one);
+
+        // Series which a scrape omits are still tracked.
+        assert_eq!(tracked(&scrape_uri(&serve, "/metrics?dst_app=checkout")), 2 * one);
+    }
+
+    #[test]
+    fn directions_are_recorded_in_separate_shards() {
+        let process = ctx::Process::test("test");
+        let inbound = ctx::Proxy::inbound(&process);
+        let outbound = ctx::Proxy::outbound(&process);
+        let (mut aggregate, serve) = new(&process);
+
+        aggregate.record_event(&request_end(&request("http://foo.test/", &outbound)));
+        {
+            // Inbound traffic is recorded without waiting for the outbound
+            // shard's lock.
+            let shards = Arc::clone(&aggregate.shards);
+            let _outbound = shards.outbound.lock().unwrap();
+            aggregate.record_event(&request_end(&request("http://foo.test/", &inbound)));
+        }
+
+        let scrape = scrape(&serve);
+        assert_eq!(scrape.matches("# TYPE request_total counter\n").count(), 1);
+        assert!(scrape.contains(
+            "request_total{authority=\"foo.test\",direction=\"inbound\",method=\"GET\"} 1\n"));
+        assert!(scrape.contains(
+            "request_total{authority=\"foo.test\",direction=\"outbound\",method=\"GET\"} 1\n"));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn poisoned_shards_are_recovered() {
+        use std::thread;
+
+        let process = ctx::Process::test("test");
+        let outbound = ctx::Proxy::outbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let req = request("http://foo.test/", &outbound);
+        aggregate.record_event(&request_end(&req));
+
+        let shards = Arc::clone(&aggregate.shards);
+        let panicked = thread::spawn(move || {
+            let _outbound = shards.outbound.lock().unwrap();
+            panic!("panicking while holding the outbound shard's lock");
+        }).join();
+        assert!(panicked.is_err());
+        assert!(aggregate.shards.outbound.is_poisoned());
+
+        // Both recording and scraping carry on with the recovered metrics.
+        aggregate.record_event(&request_end(&req));
+        let scrape = scrape(&serve);
+        assert!(scrape.contains(
+            "request_total{authority=\"foo.test\",direction=\"outbound\",method=\"GET\"} 2\n"));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn summaries_are_formatted_with_quantiles() {
+        let mut summary = Summary::new(Duration::from_secs(60), &[0.5, 0.99]);
+        for ms in 1..101 {
+            summary.observe(Duration::from_millis(ms));
+        }
+        let mut metric = Metric::<Summary, AcceptErrorLabels>::new(
+            "test_latency_ms",
+            "A test summary.",
+        );
+        metric.values.insert(AcceptErrorLabels::new("test"), summary);
+
+        let format = |metric: &FmtMetric| {
+            let mut formatted = String::new();
+            metric.fmt_metric(ScrapeOptions::from_query(""), &mut formatted).expect("format");
+            formatted
+        };
+        let formatted = format(&metric);
+        assert_eq!(formatted, "\
+            # HELP test_latency_ms A test summary.\n\
+            # TYPE test_latency_ms summary\n\
+            test_latency_ms{reason=\"test\",quantile=\"0.5\"} 50\n\
+            test_latency_ms{reason=\"test\",quantile=\"0.99\"} 99\n\
+            test_latency_ms_count{reason=\"test\"} 100\n\
+            test_latency_ms_sum{reason=\"test\"} 5050\n\
+        ");
+        parse_samples(&formatted);
+
+        let descriptor = &metric.describe()[0];
+        assert_eq!(descriptor.kind, "summary");
+        assert_eq!(descriptor.label_keys, vec!["reason", "quantile"]);
+
+        // Until a value is observed, its quantiles are unknown.
+        let empty = Scalar {
+            name: "test_latency_ms",
+            help: "A test summary.",
+            value: Summary::new(Duration::from_secs(60), &[0.5]),
+        };
+        let formatted = format(&empty);
+        assert!(formatted.contains("test_latency_ms{quantile=\"0.5\"} NaN\n"));
+        assert!(formatted.contains("test_latency_ms_count 0\n"));
+        parse_samples(&formatted);
+    }
+
+    #[test]
+    fn dst_label_values_are_escaped() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::outbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let req = dst_request("http://foo.test/", &proxy, vec![("pod", "a\"b\\\nc")]);
+        aggregate.record_event(&request_end(&req));
+
+        let dst_labels = DstLabels::new(vec![("pod", "a\"b\\\nc")]).expect("dst labels");
+        assert_eq!(dst_labels.as_map()["pod"], "a\"b\\\nc");
+        assert_eq!(dst_labels.as_str(), "dst_pod=\"a\\\"b\\\\\\nc\"");
+
+        let scrape = scrape(&serve);
+        parse_samples(&scrape);
+        let line = scrape.lines()
+            .find(|line| line.starts_with("request_total{"))
+            .expect("request_total sample");
+        let labels = &line["request_total{".len()..line.rfind("} ").unwrap()];
+        assert!(label_pairs(labels).contains(&("dst_pod".to_owned(), "a\"b\\\nc".to_owned())));
+    }
+
+    #[test]
+    fn dst_label_keys_are_sanitized() {
+        let dst_labels = DstLabels::new(vec![
+            ("my-label", "a"),
+            ("1st", "b"),
+            ("weird.key", "c"),
+            ("fine_key2", "d"),
+        ]).expect("dst labels");
+        assert_eq!(
+            dst_labels.as_str(),
+            "dst_my_label=\"a\",dst__1st=\"b\",dst_weird_key=\"c\",dst_fine_key2=\"d\""
+        );
+        assert_eq!(dst_labels.as_map()["my-label"], "a");
+        assert_eq!(dst_labels.as_map()["1st"], "b");
+        assert_eq!(dst_labels.as_map()["weird.key"], "c");
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let req = request("http://foo.test/", &proxy);
+        for &ms in &[1, 3, 30, 30, 700, 60_000] {
+            let rsp = http::Response::builder().status(200).body(()).unwrap();
+            let rsp = ctx::http::Response::new(&rsp, &req);
+            aggregate.record_event(&Event::StreamResponseEnd(rsp, event::StreamResponseEnd {
+                grpc_status: None,
+                since_request_open: Duration::from_millis(ms),
+                since_request_end: None,
+                since_response_open: Duration::from_millis(ms),
+                bytes_sent: 0,
+                frames_sent: 0,
+            }));
+        }
+
+        let scrape = scrape(&serve);
+        let buckets = parse_samples(&scrape).into_iter()
+            .filter(|&(ref name, _)| name == "response_latency_ms_bucket")
+            .map(|(_, value)| value.parse::<u64>().unwrap())
+            .collect::<Vec<_>>();
+        assert!(buckets.windows(2).all(|w| w[0] <= w[1]), "{:?}", buckets);
+        assert_eq!(buckets.first(), Some(&1));
+        assert_eq!(buckets.last(), Some(&6));
+        assert!(scrape.contains("le=\"+Inf\"} 6\n"));
+        assert!(scrape.contains("response_latency_ms_count{"));
+    }
+
+    #[test]
+    fn queue_depth_and_capacity_are_recorded() {
+        let process = ctx::Process::test("test");
+        let (mut aggregate, serve) = new(&process);
+
+        aggregate.record_queue_capacity(100);
+        aggregate.record_queue_depth(42);
+
+        let scrape = scrape(&serve);
+        assert!(scrape.contains("telemetry_queue_depth 42\n"));
+        assert!(scrape.contains("telemetry_queue_capacity 100\n"));
+    }
+
+    #[test]
+    fn request_header_bytes_are_recorded_when_enabled() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+
+        let (mut disabled, serve) = new(&process);
+        disabled.record_event(&event::Event::StreamRequestOpen(
+            request("http://foo.test/", &proxy)));
+        assert!(!scrape(&serve).contains("request_header_bytes"));
+
+        let (aggregate, serve) = new(&process);
+        let mut aggregate = aggregate.with_request_header_bytes(true);
+        for &len in &[10, 300, 5_000] {
+            let req = http::Request::get("http://foo.test/")
+                .header("x-pad", "a".repeat(len).as_str())
+                .body(())
+                .unwrap();
+            let req = ctx::http::Request::new(
+                &req, &server(&proxy), &client(&proxy, vec![]), 0, true);
+            assert_eq!(req.header_bytes, Some("x-pad".len() + len));
+            aggregate.record_event(&event::Event::StreamRequestOpen(req));
+        }
+
+        // Requests whose header size wasn't measured aren't recorded.
+        let req = http::Request::get("http://foo.test/").body(()).unwrap();
+        let req = ctx::http::Request::new(&req, &server(&proxy), &client(&proxy, vec![]), 0, false);
+        assert_eq!(req.header_bytes, None);
+        aggregate.record_event(&event::Event::StreamRequestOpen(req));
+
+        let scrape = scrape(&serve);
+        assert!(scrape.contains("request_header_bytes_bucket{le=\"256\"} 1\n"));
+        assert!(scrape.contains("request_header_bytes_bucket{le=\"512\"} 2\n"));
+        assert!(scrape.contains("request_header_bytes_bucket{le=\"4096\"} 2\n"));
+        assert!(scrape.contains("request_header_bytes_bucket{le=\"8192\"} 3\n"));
+        assert!(scrape.contains("request_header_bytes_bucket{le=\"+Inf\"} 3\n"));
+        assert!(scrape.contains("request_header_bytes_count 3\n"));
+        assert!(scrape.contains("request_header_bytes_sum 5325\n"));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn accept_errors_are_counted_separately_from_opens() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+
+        aggregate.record_event(&event::Event::TransportAcceptFail(
+            proxy.clone(),
+            event::TransportAcceptFail { reason: "peek" },
+        ));
+        aggregate.record_accept_error("peek");
+
+        let scrape = scrape(&serve);
+        assert!(scrape.contains("tcp_accept_errors_total{reason=\"peek\"} 2\n"));
+        assert!(!scrape.contains("tcp_open_total{"));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn streaming_response_first_and_last_byte_latency() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let req = request("http://foo.test/", &proxy);
+        let rsp = http::Response::builder().status(200).body(()).unwrap();
+        let rsp = ctx::http::Response::new(&rsp, &req);
+        let open = Event::StreamResponseOpen(Arc::clone(&rsp), event::StreamResponseOpen {
+            since_request_open: Duration::from_millis(3),
+        });
+        let end = Event::StreamResponseEnd(rsp, event::StreamResponseEnd {
+            grpc_status: None,
+            since_request_open: Duration::from_secs(120),
+            since_request_end: None,
+            since_response_open: Duration::from_secs(120) - Duration::from_millis(3),
+            bytes_sent: 0,
+            frames_sent: 0,
+        });
+
+        let (mut disabled, serve) = new(&process);
+        disabled.record_event(&open);
+        disabled.record_event(&end);
+        assert!(!scrape(&serve).contains("byte_latency_ms"));
+
+        let (aggregate, serve) = new(&process);
+        let mut aggregate = aggregate.with_response_byte_latency(true);
+        aggregate.record_event(&open);
+        aggregate.record_event(&end);
+
+        let labels = "authority=\"foo.test\",direction=\"inbound\",method=\"GET\",\
+                      classification=\"success\",status_code=\"200\"";
+        let scrape = scrape(&serve);
+        assert!(scrape.contains(&format!(
+            "response_first_byte_latency_ms_bucket{{{},le=\"3\"}} 1\n", labels)));
+        assert!(scrape.contains(&format!(
+            "response_first_byte_latency_ms_sum{{{}}} 3\n", labels)));
+        assert!(scrape.contains(&format!(
+            "response_last_byte_latency_ms_bucket{{{},le=\"50000\"}} 0\n", labels)));
+        assert!(scrape.contains(&format!(
+            "response_last_byte_latency_ms_bucket{{{},le=\"+Inf\"}} 1\n", labels)));
+        assert!(scrape.contains(&format!(
+            "response_last_byte_latency_ms_sum{{{}}} 120000\n", labels)));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn response_latency_quantiles_are_recorded_when_configured() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let req = request("http://foo.test/", &proxy);
+        let rsp = http::Response::builder().status(200).body(()).unwrap();
+        let rsp = ctx::http::Response::new(&rsp, &req);
+        let end = |ms| Event::StreamResponseEnd(Arc::clone(&rsp), event::StreamResponseEnd {
+            grpc_status: None,
+            since_request_open: Duration::from_millis(ms),
+            since_request_end: None,
+            since_response_open: Duration::from_millis(ms),
+            bytes_sent: 0,
+            frames_sent: 0,
+        });
+
+        let (mut disabled, serve) = new(&process);
+        disabled.record_event(&end(1));
+        assert!(!scrape(&serve).contains("response_latency_quantiles_ms"));
+
+        let (aggregate, serve) = new(&process);
+        let mut aggregate = aggregate.with_response_latency_quantiles(&[0.99, 0.5]);
+        for ms in 1..101 {
+            aggregate.record_event(&end(ms));
+        }
+
+        let labels = "authority=\"foo.test\",direction=\"inbound\",method=\"GET\",\
+                      classification=\"success\",status_code=\"200\"";
+        let scrape = scrape(&serve);
+        assert!(scrape.contains("# TYPE response_latency_quantiles_ms summary\n"));
+        assert!(scrape.contains(&format!(
+            "response_latency_quantiles_ms{{{},quantile=\"0.5\"}} 50\n", labels)));
+        assert!(scrape.contains(&format!(
+            "response_latency_quantiles_ms{{{},quantile=\"0.99\"}} 99\n", labels)));
+        assert!(scrape.contains(&format!(
+            "response_latency_quantiles_ms_count{{{}}} 100\n", labels)));
+        assert!(scrape.contains(&format!(
+            "response_latency_quantiles_ms_sum{{{}}} 5050\n", labels)));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn first_byte_latency_is_recorded_when_the_response_opens() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (aggregate, serve) = new(&process);
+        let mut aggregate = aggregate.with_response_byte_latency(true);
+        let req = request("http://foo.test/", &proxy);
+        let rsp = http::Response::builder().status(200).body(()).unwrap();
+        let rsp = ctx::http::Response::new(&rsp, &req);
+
+        // The response never completes, such as when the client disconnects
+        // while it is streamed.
+        aggregate.record_event(&Event::StreamResponseOpen(rsp, event::StreamResponseOpen {
+            since_request_open: Duration::from_millis(7),
+        }));
+
+        let labels = "authority=\"foo.test\",direction=\"inbound\",method=\"GET\",\
+                      classification=\"success\",status_code=\"200\"";
+        let scrape = scrape(&serve);
+        assert!(scrape.contains(&format!(
+            "response_first_byte_latency_ms_count{{{}}} 1\n", labels)));
+        assert!(scrape.contains(&format!(
+            "response_first_byte_latency_ms_sum{{{}}} 7\n", labels)));
+        assert!(!scrape.contains("response_last_byte_latency_ms_count{"));
+        assert!(!scrape.contains("response_total{"));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn redacted_authorities_are_not_recorded() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::outbound(&process);
+        let (aggregate, serve) = new(&process);
+        let mut aggregate = aggregate.with_authority_redactor(Redactor::new(|authority| {
+            if authority.ends_with(".secret.test") {
+                Some("redacted.secret.test".to_owned())
+            } else if authority.starts_with("token-") {
+                Some(String::new())
+            } else {
+                None
+            }
+        }));
+
+        let tenant = request("http://tenant-1234.secret.test/", &proxy);
+        aggregate.record_event(&request_end(&tenant));
+        aggregate.record_event(&response_end(&tenant, None));
+        aggregate.record_event(&request_end(&request("http://token-abcd.test/", &proxy)));
+        aggregate.record_event(&request_end(&request("http://foo.test/", &proxy)));
+
+        let scrape = scrape(&serve);
+        assert!(!scrape.contains("tenant-1234"));
+        assert!(!scrape.contains("token-abcd"));
+        assert!(scrape.contains(
+            "request_total{authority=\"redacted.secret.test\",\
+                direction=\"outbound\",method=\"GET\"} 1\n"));
+        assert!(scrape.contains(
+            "response_total{authority=\"redacted.secret.test\",\
+                direction=\"outbound\",method=\"GET\","));
+        assert!(scrape.contains(
+            "request_total{authority=\"unknown\",direction=\"outbound\",method=\"GET\"} 1\n"));
+        assert!(scrape.contains(
+            "request_total{authority=\"foo.test\",direction=\"outbound\",method=\"GET\"} 1\n"));
+        assert!(scrape.contains("authority_sanitized_total 0\n"));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn authorities_matching_redaction_patterns_are_redacted() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::outbound(&process);
+        let (aggregate, serve) = new(&process);
+        let mut aggregate = aggregate
+            .with_authority_redactor(Redactor::matching(&["*.secret.test", "db.internal"]));
+
+        aggregate.record_event(&request_end(&request("http://tenant-1.secret.test/", &proxy)));
+        aggregate.record_event(&request_end(&request("http://Tenant-2.Secret.test:8080/", &proxy)));
+        aggregate.record_event(&request_end(&request("http://db.internal:5432/", &proxy)));
+        aggregate.record_event(&request_end(&request("http://secret.test/", &proxy)));
+        aggregate.record_event(&request_end(&request("http://db.internal.test/", &proxy)));
+
+        let scrape = scrape(&serve);
+        assert!(!scrape.contains("tenant-"));
+        assert!(scrape.contains(
+            "request_total{authority=\"*.secret.test\",\
+                direction=\"outbound\",method=\"GET\"} 2\n"));
+        assert!(scrape.contains(
+            "request_total{authority=\"db.internal\",\
+                direction=\"outbound\",method=\"GET\"} 1\n"));
+        assert!(scrape.contains(
+            "request_total{authority=\"secret.test\",\
+                direction=\"outbound\",method=\"GET\"} 1\n"));
+        assert!(scrape.contains(
+            "request_total{authority=\"db.internal.test\",\
+                direction=\"outbound\",method=\"GET\"} 1\n"));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn requests_are_labeled_by_route() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (aggregate, serve) = new(&process);
+        let mut aggregate = aggregate.with_routes(&["/users/{id}", "/users/me"]);
+
+        let user = request("http://foo.test/users/42", &proxy);
+        aggregate.record_event(&request_end(&user));
+        aggregate.record_event(&response_end(&user, None));
+        aggregate.record_event(&request_end(&request("http://foo.test/users/7", &proxy)));
+        aggregate.record_event(&request_end(&request("http://foo.test/users/me", &proxy)));
+        aggregate.record_event(&request_end(&request("http://foo.test/posts/1", &proxy)));
+
+        // Requests with different routes are recorded separately, so they
+        // can't be coalesced.
+        let me = request_end(&request("http://foo.test/users/me", &proxy));
+        assert!(aggregate.coalesces(&request_end(&user), &request_end(&user)));
+        assert!(!aggregate.coalesces(&request_end(&user), &me));
+
+        let scrape = scrape(&serve);
+        let labels = "authority=\"foo.test\",direction=\"inbound\",method=\"GET\"";
+        assert!(scrape.contains(&format!(
+            "request_total{{{},route=\"/users/{{id}}\"}} 2\n", labels)));
+        assert!(scrape.contains(&format!(
+            "request_total{{{},route=\"/users/me\"}} 1\n", labels)));
+        assert!(scrape.contains(&format!(
+            "request_total{{{},route=\"__unmatched__\"}} 1\n", labels)));
+        assert!(scrape.contains(&format!(
+            "response_total{{{},route=\"/users/{{id}}\",", labels)));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn sanitized_authority_is_counted() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let req = request("http://foo.test/", &proxy);
+        let mut metrics = Metrics::new(&process);
+
+        metrics.request_total(&RequestLabelsRef::new(&req)).incr();
+        assert_eq!(Into::<u64>::into(metrics.authority_sanitized_total.value), 0);
+
+        // `http::Uri` won't parse an authority that needs escaping, so test
+        // the escaped labels directly.
+        let labels = RequestLabelsRef::new(&req)
+            .with_authority(labels::escape_label_value("foo\u{7}\"bar\".test"));
+        metrics.request_total(&labels).incr();
+        assert_eq!(Into::<u64>::into(metrics.authority_sanitized_total.value), 1);
+
+        let scrape = format!("{}", metrics);
+        assert!(scrape.contains(
+            "request_total{authority=\"foo_\\\"bar\\\".test\",\
+                direction=\"inbound\",method=\"GET\"} 1\n"));
+        assert!(scrape.contains("authority_sanitized_total 1\n"));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn request_total_by_authority() {
+        let process = ctx::Process::test("test");
+        let inbound = ctx::Proxy::inbound(&process);
+        let outbound = ctx::Proxy::outbound(&process);
+        let (mut aggregate, serve) = new(&process);
+
+        aggregate.record_event(&request_end(&request("http://foo.test/", &inbound)));
+        aggregate.record_event(&request_end(&request("http://foo.test/", &inbound)));
+        aggregate.record_event(&request_end(&request("http://foo.test/", &outbound)));
+        aggregate.record_event(&request_end(&request("http://bar.test/", &outbound)));
+
+        // The sums are only exported when they are enabled.
+        assert!(!scrape(&serve).contains("request_by_authority_total"));
+        let _aggregate = aggregate.with_requests_by_authority(true);
+
+        let scrape = scrape(&serve);
+        assert!(scrape.contains(
+            "request_total{authority=\"foo.test\",direction=\"inbound\",method=\"GET\"} 2\n"));
+        assert!(scrape.contains(
+            "request_total{authority=\"foo.test\",direction=\"outbound\",method=\"GET\"} 1\n"));
+        assert!(scrape.contains("request_by_authority_total{authority=\"foo.test\"} 3\n"));
+        assert!(scrape.contains("request_by_authority_total{authority=\"bar.test\"} 1\n"));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn request_total_by_method() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (aggregate, serve) = new(&process);
+        let mut aggregate = aggregate.with_requests_by_authority(true);
+        let method_request = |method: &str| {
+            let req = http::Request::builder()
+                .method(method)
+                .uri("http://foo.test/")
+                .body(())
+                .unwrap();
+            ctx::http::Request::new(&req, &server(&proxy), &client(&proxy, vec![]), 0, false)
+        };
+
+        aggregate.record_event(&request_end(&request("http://foo.test/", &proxy)));
+        aggregate.record_event(&request_end(&method_request("POST")));
+        aggregate.record_event(&request_end(&method_request("POST")));
+        aggregate.record_event(&request_end(&method_request("PROPFIND")));
+        aggregate.record_event(&request_end(&method_request("BREW")));
+
+        let scrape = scrape(&serve);
+        let labels = "authority=\"foo.test\",direction=\"inbound\"";
+        assert!(scrape.contains(&format!("request_total{{{},method=\"GET\"}} 1\n", labels)));
+        assert!(scrape.contains(&format!("request_total{{{},method=\"POST\"}} 2\n", labels)));
+        assert!(scrape.contains(&format!("request_total{{{},method=\"UNKNOWN\"}} 2\n", labels)));
+        assert!(!scrape.contains("PROPFIND"));
+        assert!(scrape.contains("request_by_authority_total{authority=\"foo.test\"} 5\n"));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn missing_authority_is_recorded_as_placeholder() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (aggregate, serve) = new(&process);
+        let mut aggregate = aggregate.with_missing_authority("no \"host\"");
+
+        let req = request("/foo", &proxy);
+        aggregate.record_event(&request_end(&req));
+        aggregate.record_event(&response_end(&req, None));
+        aggregate.record_event(&request_end(&request("http://foo.test/", &proxy)));
+
+        let labels = "authority=\"no \\\"host\\\"\",direction=\"inbound\",method=\"GET\"";
+        let scrape = scrape(&serve);
+        assert!(scrape.contains(&format!("request_total{{{}}} 1\n", labels)));
+        assert!(scrape.contains(&format!("response_total{{{},\
+            classification=\"success\",status_code=\"200\"}} 1\n", labels)));
+        assert!(scrape.contains("request_total{authority=\"foo.test\","));
+        assert!(!scrape.contains("authority=\"\""));
+        assert!(!scrape.contains(NO_AUTHORITY));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn request_failure_latency_is_not_response_latency() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+
+        let req = request("http://foo.test/", &proxy);
+        let fail = Event::StreamRequestFail(Arc::clone(&req), event::StreamRequestFail {
+            since_request_open: Duration::from_millis(25),
+            error: h2::Reason::INTERNAL_ERROR,
+            bytes_sent: 0,
+        });
+        assert!(!aggregate.coalesces(&fail, &fail));
+        aggregate.record_event(&fail);
+
+        let scrape = scrape(&serve);
+        assert!(scrape.contains(
+            "request_total{authority=\"foo.test\",direction=\"inbound\",method=\"GET\"} 1\n"));
+        assert!(scrape.contains(
+            "request_failure_latency_ms_bucket{authority=\"foo.test\",\
+                direction=\"inbound\",method=\"GET\",le=\"20\"} 0\n"));
+        assert!(scrape.contains(
+            "request_failure_latency_ms_bucket{authority=\"foo.test\",\
+                direction=\"inbound\",method=\"GET\",le=\"30\"} 1\n"));
+        assert!(scrape.contains(
+            "request_failure_latency_ms_sum{authority=\"foo.test\",\
+                direction=\"inbound\",method=\"GET\"} 25\n"));
+        assert!(!scrape.contains("response_latency_ms_bucket{"));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn body_bytes_are_counted() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let req = request("http://foo.test/", &proxy);
+        let request_end = |bytes_sent| {
+            Event::StreamRequestEnd(Arc::clone(&req), event::StreamRequestEnd {
+                since_request_open: Duration::from_millis(10),
+                bytes_sent,
+            })
+        };
+        let rsp = http::Response::builder().status(200).body(()).unwrap();
+        let rsp = ctx::http::Response::new(&rsp, &req);
+
+        aggregate.record_event(&request_end(100));
+        let fail = event::StreamRequestFail {
+            since_request_open: Duration::from_millis(10),
+            error: h2::Reason::INTERNAL_ERROR,
+            bytes_sent: 20,
+        };
+        aggregate.record_event(&Event::StreamRequestFail(Arc::clone(&req), fail));
+        let end = event::StreamResponseEnd {
+            grpc_status: None,
+            since_request_open: Duration::from_millis(10),
+            since_request_end: None,
+            since_response_open: Duration::from_millis(5),
+            bytes_sent: 1_000,
+            frames_sent: 2,
+        };
+        aggregate.record_event(&Event::StreamResponseEnd(Arc::clone(&rsp), end));
+        aggregate.record_event(&Event::StreamResponseFail(rsp, event::StreamResponseFail {
+            since_request_open: Duration::from_millis(10),
+            since_request_end: None,
+            since_response_open: Duration::from_millis(5),
+            error: h2::Reason::INTERNAL_ERROR,
+            bytes_sent: 300,
+            frames_sent: 1,
+        }));
+
+        // Requests with different body sizes can't be recorded together.
+        assert!(aggregate.coalesces(&request_end(7), &request_end(7)));
+        assert!(!aggregate.coalesces(&request_end(7), &request_end(8)));
+        aggregate.record_repeated_event(&request_end(7), 3);
+
+        let labels = "authority=\"foo.test\",direction=\"inbound\",method=\"GET\"";
+        let scrape = scrape(&serve);
+        assert!(scrape.contains(&format!("request_body_bytes_total{{{}}} 141\n", labels)));
+        assert!(scrape.contains(&format!("response_body_bytes_total{{{},\
+            classification=\"success\",status_code=\"200\"}} 1000\n", labels)));
+        assert!(scrape.contains(&format!("response_body_bytes_total{{{},\
+            classification=\"failure\",status_code=\"500\"}} 300\n", labels)));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn repeated_body_bytes_saturate() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let req = request("http://foo.test/", &proxy);
+        let end = Event::StreamRequestEnd(Arc::clone(&req), event::StreamRequestEnd {
+            since_request_open: Duration::from_millis(10),
+            bytes_sent: u64::max_value() / 2,
+        });
+
+        aggregate.record_repeated_event(&end, 3);
+
+        let labels = "authority=\"foo.test\",direction=\"inbound\",method=\"GET\"";
+        let scrape = scrape(&serve);
+        assert!(scrape.contains(&format!("request_total{{{}}} 3\n", labels)));
+        assert!(scrape.contains(&format!(
+            "request_body_bytes_total{{{}}} {}\n", labels, u64::max_value())));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn canceled_responses_are_not_failures() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let req = request("http://foo.test/", &proxy);
+        let rsp = http::Response::builder().status(200).body(()).unwrap();
+        let rsp = ctx::http::Response::new(&rsp, &req);
+        let fail = |error| {
+            Event::StreamResponseFail(Arc::clone(&rsp), event::StreamResponseFail {
+                since_request_open: Duration::from_millis(10),
+                since_request_end: None,
+                since_response_open: Duration::from_millis(5),
+                error,
+                bytes_sent: 0,
+                frames_sent: 0,
+            })
+        };
+
+        aggregate.record_event(&fail(h2::Reason::CANCEL));
+        aggregate.record_event(&fail(h2::Reason::CANCEL));
+        aggregate.record_event(&fail(h2::Reason::REFUSED_STREAM));
+        aggregate.record_event(&fail(h2::Reason::INTERNAL_ERROR));
+
+        let labels = "authority=\"foo.test\",direction=\"inbound\",method=\"GET\"";
+        let scrape = scrape(&serve);
+        assert!(scrape.contains(&format!("response_total{{{},\
+            classification=\"client_cancel\",status_code=\"500\"}} 3\n", labels)));
+        assert!(scrape.contains(&format!("response_total{{{},\
+            classification=\"failure\",status_code=\"500\"}} 1\n", labels)));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn repeated_events_match_individual_events() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut individual, individual_serve) = new(&process);
+        let (mut repeated, repeated_serve) = new(&process);
+
+        let end = request_end(&request("http://foo.test/", &proxy));
+        let other = request_end(&request("http://bar.test/", &proxy));
+        assert!(individual.coalesces(&end, &request_end(&request("http://foo.test/baz", &proxy))));
+        assert!(!individual.coalesces(&end, &other));
+
+        for _ in 0..100 {
+            individual.record_event(&end);
+        }
+        individual.record_event(&other);
+        repeated.record_repeated_event(&end, 100);
+        repeated.record_repeated_event(&other, 1);
+
+        let cut = |scrape: String| {
+            let end = scrape.find("# HELP metrics_scrape_lock_hold_ms").unwrap();
+            scrape[..end].to_owned()
+        };
+        let individual = cut(scrape(&individual_serve));
+        assert!(individual.contains(
+            "request_total{authority=\"foo.test\",direction=\"inbound\",method=\"GET\"} 100\n"));
+        assert_eq!(individual, cut(scrape(&repeated_serve)));
+    }
+
+    #[test]
+    fn active_requests_are_uncounted_once() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let request_fail = |req: &Arc<ctx::http::Request>| {
+            Event::StreamRequestFail(Arc::clone(req), event::StreamRequestFail {
+                since_request_open: Duration::from_millis(10),
+                error: h2::Reason::INTERNAL_ERROR,
+                bytes_sent: 0,
+            })
+        };
+        let active = |serve: &Serve| {
+            parse_samples(&scrape(serve)).into_iter()
+                .find(|&(ref name, _)| name == "http_requests_active")
+                .map(|(_, value)| value)
+                .expect("scrape should count active requests")
+        };
+
+        let ended = request("http://foo.test/", &proxy);
+        let failed = request("http://foo.test/", &proxy);
+        let open = request("http://foo.test/", &proxy);
+        for req in &[&ended, &failed, &open] {
+            aggregate.record_event(&Event::StreamRequestOpen(Arc::clone(req)));
+        }
+        assert!(scrape(&serve).contains("http_requests_active{authority=\"foo.test\",\
+            direction=\"inbound\",method=\"GET\"} 3\n"));
+
+        // A request remains active until its response completes, and is
+        // only uncounted once, however many events complete it.
+        aggregate.record_event(&request_end(&ended));
+        assert_eq!(active(&serve), "3");
+        aggregate.record_event(&response_end(&ended, None));
+        aggregate.record_event(&request_fail(&ended));
+        aggregate.record_event(&request_fail(&failed));
+        aggregate.record_event(&request_fail(&failed));
+        assert_eq!(active(&serve), "1");
+
+        // Requests that were never counted are not uncounted.
+        aggregate.record_event(&request_fail(&request("http://foo.test/", &proxy)));
+        assert_eq!(active(&serve), "1");
+        aggregate.record_event(&response_end(&open, None));
+        assert_eq!(active(&serve), "0");
+    }
+
+    #[test]
+    fn active_requests_expire_if_never_completed() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let active = |serve: &Serve| {
+            parse_samples(&scrape(serve)).into_iter()
+                .find(|&(ref name, _)| name == "http_requests_active")
+                .map(|(_, value)| value)
+                .expect("scrape should count active requests")
+        };
+
+        // The events completing this request are dropped, so only its open
+        // is recorded.
+        let dropped = request("http://foo.test/", &proxy);
+        aggregate.record_event(&Event::StreamRequestOpen(Arc::clone(&dropped)));
+        assert_eq!(active(&serve), "1");
+
+        // Once it has been active for too long, the next request to be
+        // opened sweeps it from the active requests.
+        let max_age = Duration::from_secs(MAX_ACTIVE_REQUEST_AGE_SECS);
+        let later = Instant::now() + max_age + Duration::from_secs(1);
+        let next = request("http://foo.test/", &proxy);
+        aggregate.shards.inbound.lock().unwrap()
+            .request_opened(next.id, &RequestLabelsRef::new(&next), later);
+        assert_eq!(active(&serve), "1");
+        {
+            let metrics = aggregate.shards.inbound.lock().unwrap();
+            assert_eq!(metrics.active_requests.len(), 1);
+            assert!(metrics.active_requests.contains_key(&next.id));
+        }
+
+        // If the expired request does complete, it isn't uncounted again.
+        aggregate.record_event(&response_end(&dropped, None));
+        assert_eq!(active(&serve), "1");
+        aggregate.record_event(&response_end(&next, None));
+        assert_eq!(active(&serve), "0");
+        assert!(aggregate.shards.inbound.lock().unwrap().active_requests.is_empty());
+    }
+
+    #[test]
+    fn batched_events_match_individual_events() {
+        let process = ctx::Process::test("test");
+        let inbound = ctx::Proxy::inbound(&process);
+        let outbound = ctx::Proxy::outbound(&process);
+        let (mut individual, individual_serve) = new(&process);
+        let (mut batched, batched_serve) = new(&process);
+
+        let req = request("http://foo.test/", &inbound);
+        let ctx = Arc::new(ctx::transport::Ctx::Server(server(&outbound)));
+        let events = vec![
+            Event::TransportOpen(Arc::clone(&ctx)),
+            request_end(&req),
+            request_end(&request("http://bar.test/", &outbound)),
+            event::Event::TransportAcceptFail(
+                outbound.clone(),
+                event::TransportAcceptFail { reason: "peek" },
+            ),
+            response_end(&req, None),
+            Event::TransportClose(ctx, event::TransportClose {
+                clean: true,
+                duration: Duration::from_millis(15),
+                rx_bytes: 10,
+                tx_bytes: 20,
+            }),
+        ];
+
+        for event in &events {
+            individual.record_event(event);
+        }
+        batched.record_batch(&events);
+
+        let cut = |scrape: String| {
+            let end = scrape.find("# HELP metrics_scrape_lock_hold_ms").unwrap();
+            scrape[..end].to_owned()
+        };
+        let individual = cut(scrape(&individual_serve));
+        assert!(individual.contains("tcp_accept_errors_total{reason=\"peek\"} 1\n"));
+        assert!(individual.contains("authority=\"bar.test\",direction=\"outbound\""));
+        assert_eq!(individual, cut(scrape(&batched_serve)));
+    }
+
+    #[test]
+    fn out_of_range_grpc_status_is_unknown() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+
+        let req = request("http://foo.test/", &proxy);
+        aggregate.record_event(&response_end(&req, Some(16)));
+        aggregate.record_event(&response_end(&req, Some(9999)));
+        aggregate.record_event(&response_end(&req, Some(17)));
+
+        let scrape = scrape(&serve);
+        assert!(scrape.contains(
+            "response_total{authority=\"foo.test\",direction=\"inbound\",method=\"GET\",\
+            classification=\"failure\",status_code=\"200\",grpc_status_code=\"16\"} 1\n"));
+        assert!(scrape.contains(
+            "response_total{authority=\"foo.test\",direction=\"inbound\",method=\"GET\",\
+            classification=\"failure\",status_code=\"200\",grpc_status_code=\"unknown\"} 2\n"));
+        assert!(!scrape.contains("9999"));
+    }
+
+    #[test]
+    fn grpc_responses_are_classified_by_both_statuses() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let req = request("http://foo.test/", &proxy);
+        let grpc_response_end = |status: u16, grpc_status: u32| {
+            let rsp = http::Response::builder().status(status).body(()).unwrap();
+            let rsp = ctx::http::Response::new(&rsp, &req);
+            Event::StreamResponseEnd(rsp, event::StreamResponseEnd {
+                grpc_status: Some(grpc_status),
+                since_request_open: Duration::from_millis(10),
+                since_request_end: Some(Duration::from_millis(8)),
+                since_response_open: Duration::from_millis(5),
+                bytes_sent: 0,
+                frames_sent: 0,
+            })
+        };
+
+        aggregate.record_event(&grpc_response_end(200, 0));
+        aggregate.record_event(&grpc_response_end(200, 13));
+        aggregate.record_event(&grpc_response_end(503, 0));
+
+        let labels = "authority=\"foo.test\",direction=\"inbound\",method=\"GET\"";
+        let scrape = scrape(&serve);
+        assert!(scrape.contains(&format!("response_total{{{},\
+            classification=\"success\",status_code=\"200\",grpc_status_code=\"0\"}} 1\n",
+            labels)));
+        assert!(scrape.contains(&format!("response_total{{{},\
+            classification=\"failure\",status_code=\"200\",grpc_status_code=\"13\"}} 1\n",
+            labels)));
+        assert!(scrape.contains(&format!("response_total{{{},\
+            classification=\"failure\",status_code=\"503\",grpc_status_code=\"0\"}} 1\n",
+            labels)));
+    }
+
+    #[test]
+    fn grpc_requests_are_labeled_by_method() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (aggregate, serve) = new(&process);
+        let mut aggregate = aggregate.with_grpc_method_labels(true);
+        let post = |path: &str, content_type: &str| {
+            let req = http::Request::post(format!("http://foo.test{}", path).as_str())
+                .header("content-type", content_type)
+                .body(())
+                .unwrap();
+            ctx::http::Request::new(&req, &server(&proxy), &client(&proxy, vec![]), 0, false)
+        };
+
+        let grpc = post("/pkg.v1.Service/Get_Thing", "application/grpc");
+        aggregate.record_event(&request_end(&grpc));
+        aggregate.record_event(&response_end(&grpc, Some(0)));
+        let proto = post("/pkg.v1.Service/Get_Thing", "application/grpc+proto");
+        aggregate.record_event(&request_end(&proto));
+        // Requests which aren't gRPC, or whose path isn't a gRPC method, are
+        // not labeled.
+        let json = post("/pkg.v1.Service/Get_Thing", "application/json");
+        aggregate.record_event(&request_end(&json));
+        for path in &["/", "/pkg.v1.Service", "/pkg.v1.Service/", "/pkg.v1.Service/Get/Thing",
+                      "//Get", "/pkg.v1.Service/Get.Thing", "/pkg%20Service/Get"] {
+            aggregate.record_event(&request_end(&post(path, "application/grpc")));
+        }
+
+        let labels = "authority=\"foo.test\",direction=\"inbound\",method=\"POST\"";
+        let scrape = scrape(&serve);
+        assert!(scrape.contains(&format!(
+            "request_total{{{},grpc_method=\"/pkg.v1.Service/Get_Thing\"}} 2\n", labels)));
+        assert!(scrape.contains(&format!(
+            "response_total{{{},grpc_method=\"/pkg.v1.Service/Get_Thing\",\
+                classification=\"success\",status_code=\"200\",grpc_status_code=\"0\"}} 1\n",
+            labels)));
+        assert!(scrape.contains(&format!("request_total{{{}}} 8\n", labels)));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn grpc_methods_are_not_labeled_by_default() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let req = http::Request::post("http://foo.test/pkg.v1.Service/Get")
+            .header("content-type", "application/grpc")
+            .body(())
+            .unwrap();
+        let req = ctx::http::Request::new(&req, &server(&proxy), &client(&proxy, vec![]), 0, false);
+        aggregate.record_event(&request_end(&req));
+        aggregate.record_event(&response_end(&req, Some(0)));
+
+        let scrape = scrape(&serve);
+        assert!(scrape.contains("request_total{authority=\"foo.test\",direction=\"inbound\",\
+            method=\"POST\"} 1\n"));
+        assert!(!scrape.contains("grpc_method="));
+    }
+
+    #[test]
+    fn grpc_methods_are_limited_per_authority() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (aggregate, serve) = new(&process);
+        let mut aggregate = aggregate.with_grpc_method_labels(true);
+        let post = |authority: &str, method: usize| {
+            let uri = format!("http://{}/pkg.v1.Service/Method{}", authority, method);
+            let req = http::Request::post(uri.as_str())
+                .header("content-type", "application/grpc")
+                .body(())
+                .unwrap();
+            ctx::http::Request::new(&req, &server(&proxy), &client(&proxy, vec![]), 0, false)
+        };
+
+        // A client sending many distinct methods to one authority doesn't
+        // limit the methods recorded for another.
+        for method in 0..DEFAULT_MAX_GRPC_METHODS + 50 {
+            let req = post("flood.test", method);
+            aggregate.record_event(&request_end(&req));
+            aggregate.record_event(&response_end(&req, Some(0)));
+        }
+        let req = post("other.test", DEFAULT_MAX_GRPC_METHODS + 1);
+        aggregate.record_event(&request_end(&req));
+
+        let scrape = scrape(&serve);
+        let series = |prefix: &str| {
+            scrape.lines().filter(|l| l.starts_with(prefix)).count()
+        };
+        let flood = "{authority=\"flood.test\",direction=\"inbound\",method=\"POST\"";
+        assert_eq!(series(&format!("request_total{}", flood)), DEFAULT_MAX_GRPC_METHODS + 1);
+        assert_eq!(series(&format!("response_total{}", flood)), DEFAULT_MAX_GRPC_METHODS + 1);
+        assert!(scrape.contains(&format!(
+            "request_total{},grpc_method=\"/pkg.v1.Service/Method0\"}} 1\n", flood)));
+        assert!(scrape.contains(&format!(
+            "request_total{},grpc_method=\"__other__\"}} 50\n", flood)));
+        assert!(scrape.contains(&format!(
+            "grpc_method=\"/pkg.v1.Service/Method{}\"", DEFAULT_MAX_GRPC_METHODS + 1)));
+        parse_samples(&scrape);
+    }
+
+    #[test]
+    fn response_latency_is_described() {
+        let process = ctx::Process::test("test");
+        let (_, serve) = new(&process);
+
+        let descriptors = serve.describe();
+        let latency = descriptors.iter()
+            .find(|d| d.name == "response_latency_ms")
+            .expect("response_latency_ms should be described");
+        assert_eq!(latency.kind, "histogram");
+        assert_eq!(latency.unit, Some("milliseconds"));
+        for key in &[
+            "le",
+            "authority",
+            "direction",
+            "classification",
+            "status_code",
+            "grpc_status_code",
+        ] {
+            assert!(
+                latency.label_keys.contains(key),
+                "response_latency_ms should have a {} label", key
+            );
+        }
+    }
+
+    #[test]
+    fn latency_histograms_have_configurable_bounds() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (aggregate, serve) = new(&process);
+        let req = request("http://foo.test/", &proxy);
+        let ctx = Arc::new(ctx::transport::Ctx::Server(server(&proxy)));
+        let open = Event::TransportOpen(Arc::clone(&ctx));
+        let close = Event::TransportClose(ctx, event::TransportClose {
+            clean: true,
+            duration: Duration::from_secs(90),
+            rx_bytes: 0,
+            tx_bytes: 0,
+        });
+
+        // Series recorded before the bounds are configured are moved into
+        // the new buckets.
+        let mut aggregate = aggregate;
+        aggregate.record_event(&response_end(&req, None));
+        aggregate.record_event(&open);
+        aggregate.record_event(&close);
+        let mut aggregate = aggregate
+            .with_response_latency_bounds(&[
+                Duration::from_millis(5),
+                Duration::from_millis(50),
+            ])
+            .with_connection_duration_bounds(&[
+                Duration::from_secs(60),
+                Duration::from_secs(600),
+            ]);
+        aggregate.record_event(&response_end(&req, None));
+        aggregate.record_event(&open);
+        aggregate.record_event(&close);
+
+        let scrape = scrape(&serve);
+        parse_samples(&scrape);
+        let les = |name: &str| {
+            let prefix = format!("{}_bucket{{", name);
+            scrape.lines()
+                .filter(|l| l.starts_with(&prefix))
+                .map(|l| {
+                    let le = &l[l.find("le=\"").unwrap() + 4..];
+                    le[..le.find('"').unwrap()].to_owned()
                 })
+                .collect::<Vec<_>>()
         };
+        assert_eq!(les("response_latency_ms"), vec!["5", "50", "+Inf"]);
+        assert_eq!(les("tcp_connection_duration_ms"), vec!["60000", "600000", "+Inf"]);
+        assert!(scrape.contains("response_latency_ms_bucket{authority=\"foo.test\",\
+            direction=\"inbound\",method=\"GET\",\
+            classification=\"success\",status_code=\"200\",le=\"50\"} 2\n"));
+        assert!(scrape.contains("response_latency_ms_count{"));
+        assert!(scrape.contains("tcp_connection_duration_ms_count{"));
+
+        // Other latency histograms keep the default bounds.
+        assert_eq!(les("request_failure_latency_ms").len(), 0);
+        assert!(scrape.contains("metrics_scrape_lock_hold_ms_bucket{le=\"1\"}"));
+    }
+
+    #[test]
+    fn latency_histograms_have_configurable_exponential_bounds() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (aggregate, serve) = new(&process);
+        let req = request("http://foo.test/", &proxy);
+        let ctx = Arc::new(ctx::transport::Ctx::Server(server(&proxy)));
+        let close = Event::TransportClose(Arc::clone(&ctx), event::TransportClose {
+            clean: true,
+            duration: Duration::from_secs(90),
+            rx_bytes: 0,
+            tx_bytes: 0,
+        });
+
+        let mut aggregate = aggregate
+            .with_exponential_response_latency_bounds(5, 2.0, 3)
+            .with_exponential_connection_duration_bounds(1_000, 10.0, 3);
+        aggregate.record_event(&response_end(&req, None));
+        aggregate.record_event(&Event::TransportOpen(ctx));
+        aggregate.record_event(&close);
+
+        let scrape = scrape(&serve);
+        parse_samples(&scrape);
+        let les = |name: &str| {
+            let prefix = format!("{}_bucket{{", name);
+            scrape.lines()
+                .filter(|l| l.starts_with(&prefix))
+                .map(|l| {
+                    let le = &l[l.find("le=\"").unwrap() + 4..];
+                    le[..le.find('"').unwrap()].to_owned()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(les("response_latency_ms"), vec!["5", "10", "20", "+Inf"]);
+        assert_eq!(les("tcp_connection_duration_ms"), vec!["1000", "10000", "100000", "+Inf"]);
+        assert!(scrape.contains("response_latency_ms_bucket{authority=\"foo.test\",\
+            direction=\"inbound\",method=\"GET\",\
+            classification=\"success\",status_code=\"200\",le=\"10\"} 1\n"));
+        assert!(scrape.contains("tcp_connection_duration_ms_bucket{direction=\"inbound\",\
+            peer=\"src\",classification=\"success\",le=\"100000\"} 1\n"));
+    }
+
+    #[test]
+    fn latency_from_request_end() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (from_open, from_open_serve) = new(&process);
+        let (from_end, from_end_serve) = new(&process);
+        let mut from_open = from_open.with_latency_from_request_end(false);
+        let mut from_end = from_end.with_latency_from_request_end(true);
+
+        // The client took 2 seconds to send the request body, and the
+        // response took 5 milliseconds after that.
+        let req = request("http://foo.test/", &proxy);
+        let rsp = ctx::http::Response::new(
+            &http::Response::builder().status(200).body(()).unwrap(),
+            &req,
+        );
+        let end = Event::StreamResponseEnd(rsp, event::StreamResponseEnd {
+            grpc_status: None,
+            since_request_open: Duration::from_millis(2_005),
+            since_request_end: Some(Duration::from_millis(5)),
+            since_response_open: Duration::from_millis(1),
+            bytes_sent: 0,
+            frames_sent: 0,
+        });
+        from_open.record_event(&end);
+        from_end.record_event(&end);
+
+        assert!(scrape(&from_open_serve).contains("response_latency_ms_sum{\
+            authority=\"foo.test\",direction=\"inbound\",method=\"GET\",classification=\"success\",\
+            status_code=\"200\"} 2005\n"));
+        assert!(scrape(&from_end_serve).contains("response_latency_ms_sum{\
+            authority=\"foo.test\",direction=\"inbound\",method=\"GET\",classification=\"success\",\
+            status_code=\"200\"} 5\n"));
+    }
+
+    #[test]
+    fn build_timestamp() {
+        assert_eq!(parse_build_timestamp(Some("1524000000")), 1524000000);
+        assert_eq!(parse_build_timestamp(Some("yesterday")), 0);
+        assert_eq!(parse_build_timestamp(None), 0);
+
+        let process = ctx::Process::test("test");
+        let (_, serve) = new(&process);
+        let expected = parse_build_timestamp(BUILD_TIMESTAMP);
+        assert!(scrape(&serve).contains(&format!("build_timestamp_seconds {}\n", expected)));
+    }
+
+    #[test]
+    fn build_info() {
+        let process = ctx::Process::test("test");
+        let (_, serve) = new(&process);
+        let scrape = scrape(&serve);
+
+        let expected = format!(
+            "proxy_build_info{{version=\"{}\",git_sha=\"{}\",rust_version=\"{}\"}} 1\n",
+            env!("CARGO_PKG_VERSION"),
+            BUILD_GIT_SHA.unwrap_or("unknown"),
+            BUILD_RUST_VERSION.unwrap_or("unknown"),
+        );
+        assert!(scrape.contains(&expected));
+        assert_eq!(scrape.matches("proxy_build_info{").count(), 1);
+        assert!(scrape.contains("# TYPE proxy_build_info gauge\n"));
+    }
+
+    #[test]
+    fn records_drain_start_and_end() {
+        let process = ctx::Process::test("test");
+        let (mut aggregate, serve) = new(&process);
+
+        let scrape1 = scrape(&serve);
+        assert!(scrape1.contains("shutdown_initiated_time_seconds 0\n"));
+        assert!(scrape1.contains("drain_duration_ms_count 0\n"));
+
+        let started = time::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        aggregate.record_drain_start(started);
+        aggregate.record_drain_end(Duration::from_millis(250));
 
-        future::result(resp.map_err(hyper::Error::Io))
+        let scrape2 = scrape(&serve);
+        assert!(scrape2.contains("shutdown_initiated_time_seconds 1500000000\n"));
+        assert!(scrape2.contains("drain_duration_ms_bucket{le=\"300\"} 1\n"));
+        assert!(scrape2.contains("drain_duration_ms_count 1\n"));
+        assert!(scrape2.contains("drain_duration_ms_sum 250\n"));
     }
 }
@@ -0,0 +1,176 @@
+//! Keeps the most recently recorded telemetry events, so that metrics which
+//! look wrong can be compared with the events that produced them.
+
+use std::collections::VecDeque;
+use std::fmt::{self, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use h2;
+
+use telemetry::event::Event;
+use super::labels::{
+    label_pairs,
+    AcceptErrorLabels,
+    RequestLabels,
+    ResponseLabels,
+    TransportLabels,
+    TransportCloseLabels,
+};
+use super::snapshot::{push_key, push_number, push_string};
+
+/// A bounded buffer of the most recently recorded events, oldest first.
+///
+/// Once the buffer is full, each event that is pushed discards the oldest.
+#[derive(Debug)]
+pub struct RecentEvents {
+    capacity: usize,
+    events: VecDeque<(SystemTime, Event)>,
+}
+
+// ===== impl RecentEvents =====
+
+impl RecentEvents {
+    pub fn new(capacity: usize) -> Self {
+        RecentEvents {
+            capacity,
+            events: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Adds an event which was recorded at `at`.
+    pub fn push(&mut self, at: SystemTime, event: &Event) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back((at, event.clone()));
+    }
+
+    /// Copies the buffered events, so that they can be serialized without
+    /// holding the buffer's lock.
+    ///
+    /// Events only hold their contexts by reference, so this is cheap.
+    pub fn to_vec(&self) -> Vec<(SystemTime, Event)> {
+        self.events.iter().cloned().collect()
+    }
+}
+
+/// Serializes events as a JSON array, oldest first, such as:
+///
+/// ```json
+/// [{"at":1500000000.25,"event":"stream_request_end",
+///   "labels":{"authority":"web.test","direction":"outbound"},
+///   "since_request_open_ms":12.5,"bytes_sent":0}]
+/// ```
+///
+/// Each event is labeled as its metrics would be, except that authorities
+/// are not redacted. Durations are in milliseconds, and times are in seconds
+/// since the Unix epoch.
+pub fn to_json(events: &[(SystemTime, Event)]) -> String {
+    let mut json = String::new();
+    json.push('[');
+    for (i, &(at, ref event)) in events.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let at = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        json.push_str("{\"at\":");
+        push_number(&mut json, secs(at));
+        push_event(&mut json, event);
+        json.push('}');
+    }
+    json.push(']');
+    json
+}
+
+/// Appends the name, labels, and fields of `event` to the object being
+/// written to `json`.
+fn push_event(json: &mut String, event: &Event) {
+    match *event {
+        Event::TransportAcceptFail(_, ref fail) => {
+            push_header(json, "transport_accept_fail", &AcceptErrorLabels::new(fail.reason));
+        },
+        Event::TransportOpen(ref ctx) => {
+            push_header(json, "transport_open", &TransportLabels::new(ctx));
+        },
+        Event::TransportClose(ref ctx, ref close) => {
+            push_header(json, "transport_close", &TransportCloseLabels::new(ctx, close));
+            push_field(json, "clean", close.clean);
+            push_duration(json, "duration_ms", close.duration);
+            push_field(json, "rx_bytes", close.rx_bytes);
+            push_field(json, "tx_bytes", close.tx_bytes);
+        },
+        Event::StreamRequestOpen(ref req) => {
+            push_header(json, "stream_request_open", &RequestLabels::new(req));
+        },
+        Event::StreamRequestFail(ref req, ref fail) => {
+            push_header(json, "stream_request_fail", &RequestLabels::new(req));
+            push_duration(json, "since_request_open_ms", fail.since_request_open);
+            push_error(json, fail.error);
+            push_field(json, "bytes_sent", fail.bytes_sent);
+        },
+        Event::StreamRequestEnd(ref req, ref end) => {
+            push_header(json, "stream_request_end", &RequestLabels::new(req));
+            push_duration(json, "since_request_open_ms", end.since_request_open);
+            push_field(json, "bytes_sent", end.bytes_sent);
+        },
+        Event::StreamResponseOpen(ref rsp, ref open) => {
+            let labels = ResponseLabels::new(rsp, None, None, None);
+            push_header(json, "stream_response_open", &labels);
+            push_duration(json, "since_request_open_ms", open.since_request_open);
+        },
+        Event::StreamResponseFail(ref rsp, ref fail) => {
+            let labels = ResponseLabels::fail(rsp, fail.error, None, None);
+            push_header(json, "stream_response_fail", &labels);
+            push_duration(json, "since_request_open_ms", fail.since_request_open);
+            push_duration(json, "since_response_open_ms", fail.since_response_open);
+            push_error(json, fail.error);
+            push_field(json, "bytes_sent", fail.bytes_sent);
+            push_field(json, "frames_sent", fail.frames_sent);
+        },
+        Event::StreamResponseEnd(ref rsp, ref end) => {
+            let labels = ResponseLabels::new(rsp, end.grpc_status, None, None);
+            push_header(json, "stream_response_end", &labels);
+            push_duration(json, "since_request_open_ms", end.since_request_open);
+            push_duration(json, "since_response_open_ms", end.since_response_open);
+            push_field(json, "bytes_sent", end.bytes_sent);
+            push_field(json, "frames_sent", end.frames_sent);
+        },
+    }
+}
+
+/// Appends the `event` name and `labels` of an event.
+fn push_header(json: &mut String, name: &str, labels: &fmt::Display) {
+    json.push_str(",\"event\":");
+    push_string(json, name);
+    json.push_str(",\"labels\":{");
+    for (i, (key, value)) in label_pairs(&labels.to_string()).into_iter().enumerate() {
+        push_key(json, i, &key);
+        push_string(json, &value);
+    }
+    json.push('}');
+}
+
+/// Appends a field of an event.
+///
+/// Fields always follow `at`, so they are never the object's first key.
+fn push_field<T: fmt::Display>(json: &mut String, key: &str, value: T) {
+    push_key(json, 1, key);
+    write!(json, "{}", value).expect("writing to a String cannot fail");
+}
+
+fn push_duration(json: &mut String, key: &str, duration: Duration) {
+    push_key(json, 1, key);
+    push_number(json, secs(duration) * 1_000.0);
+}
+
+fn push_error(json: &mut String, error: h2::Reason) {
+    push_key(json, 1, "error");
+    push_string(json, &format!("{:?}", error));
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1_000_000_000.0
+}
@@ -0,0 +1,172 @@
+//! Matches request paths against configured path templates, so that HTTP
+//! metrics can be labeled by route without creating a series for every
+//! distinct path.
+
+use std::{iter, str};
+use std::sync::Arc;
+
+use super::labels::escape_label_value;
+
+/// The route recorded for requests whose path matches none of the
+/// configured templates.
+pub const UNMATCHED_ROUTE: &str = "__unmatched__";
+
+/// Matches request paths against a set of path templates, such as
+/// `/users/{id}/posts`.
+///
+/// Each segment of a template is either a literal, which must equal the
+/// corresponding segment of the path, or a `{param}`, which matches any
+/// single non-empty segment. Empty segments, including those left by a
+/// trailing slash, are ignored in both templates and paths.
+///
+/// When several templates match a path, the most specific one is used: at
+/// the first segment where two templates differ, a literal takes precedence
+/// over a parameter. Templates which are equally specific are tried in the
+/// order in which they were configured.
+#[derive(Clone, Debug)]
+pub struct RouteMatcher {
+    /// The templates, ordered from most to least specific.
+    routes: Vec<Route>,
+
+    unmatched: Arc<str>,
+}
+
+#[derive(Clone, Debug)]
+struct Route {
+    /// The template as it is recorded as a label value.
+    label: Arc<str>,
+
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param,
+}
+
+// ===== impl RouteMatcher =====
+
+impl RouteMatcher {
+    pub fn new<I, T>(templates: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>,
+    {
+        let mut routes = templates.into_iter()
+            .map(|template| Route::new(template.as_ref()))
+            .collect::<Vec<_>>();
+        // `sort_by_key` is stable, so equally specific templates keep their
+        // configured order.
+        routes.sort_by_key(|route| {
+            route.segments.iter()
+                .map(|segment| *segment == Segment::Param)
+                .collect::<Vec<_>>()
+        });
+        RouteMatcher {
+            routes,
+            unmatched: UNMATCHED_ROUTE.into(),
+        }
+    }
+
+    /// Returns the label value for the route matching `path`, or
+    /// `UNMATCHED_ROUTE` if no template matches it.
+    pub fn route(&self, path: &str) -> Arc<str> {
+        self.routes.iter()
+            .find(|route| route.matches(path))
+            .map(|route| &route.label)
+            .unwrap_or(&self.unmatched)
+            .clone()
+    }
+}
+
+// ===== impl Route =====
+
+impl Route {
+    fn new(template: &str) -> Self {
+        let segments = segments(template)
+            .map(|segment| {
+                if segment.starts_with('{') && segment.ends_with('}') {
+                    Segment::Param
+                } else {
+                    Segment::Literal(segment.to_owned())
+                }
+            })
+            .collect();
+        Route {
+            label: escape_label_value(template).into_owned().into(),
+            segments,
+        }
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        let mut path = segments(path);
+        for expected in &self.segments {
+            match (expected, path.next()) {
+                (&Segment::Literal(ref literal), Some(segment)) if literal == segment => {},
+                (&Segment::Param, Some(_)) => {},
+                _ => return false,
+            }
+        }
+        path.next().is_none()
+    }
+}
+
+/// Returns the non-empty segments of a path.
+fn segments(path: &str) -> iter::Filter<str::Split<char>, fn(&&str) -> bool> {
+    fn is_not_empty(segment: &&str) -> bool {
+        !segment.is_empty()
+    }
+    path.split('/').filter(is_not_empty as fn(&&str) -> bool)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literals_take_precedence_over_params() {
+        let routes = RouteMatcher::new(&[
+            "/users/{id}",
+            "/users/{id}/posts",
+            "/users/me",
+            "/{kind}/me",
+            "/",
+        ]);
+
+        assert_eq!(&*routes.route("/users/42"), "/users/{id}");
+        assert_eq!(&*routes.route("/users/me"), "/users/me");
+        assert_eq!(&*routes.route("/users/me/"), "/users/me");
+        assert_eq!(&*routes.route("/teams/me"), "/{kind}/me");
+        assert_eq!(&*routes.route("/users/42/posts"), "/users/{id}/posts");
+        assert_eq!(&*routes.route("/"), "/");
+        assert_eq!(&*routes.route(""), "/");
+    }
+
+    #[test]
+    fn equally_specific_templates_keep_their_order() {
+        let routes = RouteMatcher::new(&["/{a}/x", "/{b}/{c}", "/{d}/x"]);
+        assert_eq!(&*routes.route("/foo/x"), "/{a}/x");
+        assert_eq!(&*routes.route("/foo/y"), "/{b}/{c}");
+
+        let routes = RouteMatcher::new(&["/{d}/x", "/{a}/x"]);
+        assert_eq!(&*routes.route("/foo/x"), "/{d}/x");
+    }
+
+    #[test]
+    fn unmatched_paths_are_labeled_unmatched() {
+        let routes = RouteMatcher::new(&["/users/{id}"]);
+        assert_eq!(&*routes.route("/users"), UNMATCHED_ROUTE);
+        assert_eq!(&*routes.route("/users/42/posts"), UNMATCHED_ROUTE);
+        assert_eq!(&*routes.route("/posts/42"), UNMATCHED_ROUTE);
+
+        let routes = RouteMatcher::new(Vec::<String>::new());
+        assert_eq!(&*routes.route("/"), UNMATCHED_ROUTE);
+    }
+
+    #[test]
+    fn templates_are_escaped() {
+        let routes = RouteMatcher::new(&["/say/\"hi\""]);
+        assert_eq!(&*routes.route("/say/\"hi\""), "/say/\\\"hi\\\"");
+    }
+}
@@ -0,0 +1,592 @@
+//! Writers through which scrapes are formatted, each of which rewrites or
+//! limits the metrics written through it.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::{io, mem, thread};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Sink};
+use futures::executor::{self, Notify, NotifyHandle, Spawn};
+use futures::sync::mpsc;
+use hyper::{self, Chunk};
+
+use super::{counter_family, unit_suffix, Family};
+
+/// Options for formatting a scrape.
+#[derive(Copy, Clone, Debug)]
+pub struct ScrapeOptions<'a> {
+    /// If true, only the application metrics are written, so that the
+    /// process metrics don't conflict with those of a federating Prometheus
+    /// server. Set by `federate=1`.
+    pub federate: bool,
+
+    /// If false, histograms are written with only their count and sum, for
+    /// consumers that only need averages. Cleared by `no_buckets=1`.
+    pub histogram_buckets: bool,
+
+    /// If true, each counter is followed by a `_created` sample holding the
+    /// time at which it was created. This is set for OpenMetrics scrapes
+    /// rather than by the query, as older Prometheus scrapers don't expect
+    /// these samples.
+    pub created: bool,
+
+    /// The maximum number of series to write, if any. This is configured on
+    /// `Serve` rather than by the query.
+    pub max_series: Option<usize>,
+
+    /// The query string, whose `name[]=<name>` parameters select the metric
+    /// families to write. If it has none, every family is written.
+    pub query: &'a str,
+
+    /// The prefix with which metric names are written, if any, which the
+    /// names selected by the query may include. This is configured on
+    /// `Serve` rather than by the query.
+    pub name_prefix: Option<&'a str>,
+}
+
+/// Passes metrics through to a writer until a maximum number of series have
+/// been written, after which nothing more is written.
+///
+/// Each call to `write_str` is expected to write whole metrics, as
+/// `fmt_each` does, so that a metric is either written in full or omitted.
+pub struct SeriesLimit<'a, W: 'a> {
+    inner: &'a mut W,
+
+    /// The number of series that may still be written, if limited.
+    remaining: Option<usize>,
+
+    /// Set once a metric has been omitted.
+    pub truncated: bool,
+}
+
+/// Prepends a prefix to the name of each metric written through it.
+///
+/// Each call to `write_str` is expected to write whole lines, as `fmt_each`
+/// does, and the lines are passed on whole, so that it may be followed by
+/// `OpenMetrics`.
+pub struct NamePrefix<'a, W: 'a> {
+    inner: &'a mut W,
+    prefix: &'a str,
+
+    /// Holds the lines of each write as they are prefixed.
+    buf: String,
+}
+
+/// Prepends constant labels to the labels of each series written through
+/// it, or passes every line through unchanged if there are none.
+///
+/// Each call to `write_str` is expected to write whole lines, as `fmt_each`
+/// does, and the lines are passed on whole, so that it may be followed by a
+/// `NamePrefix`.
+pub struct ConstLabels<'a, W: 'a> {
+    inner: &'a mut W,
+    labels: Option<&'a str>,
+
+    /// Holds the lines of each write as they are labeled.
+    buf: String,
+}
+
+/// Buffers each metric as it is formatted, so that it can be written whole,
+/// or in parts of at least `part_size` bytes of whole lines.
+///
+/// A part never ends with a `# HELP` line, so that it is always written
+/// together with the `# TYPE` line after it.
+pub struct MetricParts<'a, W: 'a> {
+    inner: &'a mut W,
+    part_size: Option<usize>,
+    buf: String,
+}
+
+/// Converts each write from the Prometheus text format to the OpenMetrics
+/// text format, as described by `to_openmetrics`, except that it doesn't
+/// end the exposition with `# EOF`.
+///
+/// Each call to `write_str` is expected to write whole metrics, as
+/// `fmt_each` does, so that each counter's `# HELP` line is written with the
+/// `# TYPE` line that follows it.
+pub struct OpenMetrics<'a, W: 'a> {
+    inner: &'a mut W,
+
+    /// Holds each write as it is converted.
+    buf: String,
+}
+
+/// Writes text to an `io::Write`, keeping the first error, which
+/// `fmt::Write` has no way to return.
+pub struct IoWrite<'a, W: 'a> {
+    inner: &'a mut W,
+    error: Option<io::Error>,
+}
+
+/// Sends the bytes written to it as the chunks of a response body.
+///
+/// Bytes are sent once `SCRAPE_CHUNK_SIZE` of them have been written, and
+/// each send waits until the connection has taken the previous chunk, so
+/// that only a couple of chunks are held at once however long the body is.
+/// If the connection doesn't take a chunk within the timeout, the body is
+/// ended with an error, so that a client which stops reading can't block
+/// the sender forever.
+pub struct ChunkSender {
+    /// Taken while a chunk is being sent, and gone once the connection has
+    /// dropped the body.
+    tx: Option<mpsc::Sender<Result<Chunk, hyper::Error>>>,
+    buf: Vec<u8>,
+    timeout: Duration,
+}
+
+/// Wakes the thread blocked in `wait_until` when the future it is polling
+/// can make progress.
+struct ThreadNotify(thread::Thread);
+
+/// The number of bytes in each chunk of a streamed scrape.
+pub const SCRAPE_CHUNK_SIZE: usize = 16 * 1024;
+
+// ===== impl ScrapeOptions =====
+
+impl<'a> ScrapeOptions<'a> {
+    /// Parses the options set by a scrape's query string.
+    pub fn from_query(query: &'a str) -> Self {
+        fn is_set(query: &str, name: &str) -> bool {
+            query.split('&').any(|param| {
+                let mut kv = param.splitn(2, '=');
+                kv.next() == Some(name) && match kv.next() {
+                    Some("1") | Some("true") => true,
+                    _ => false,
+                }
+            })
+        }
+
+        ScrapeOptions {
+            federate: is_set(query, "federate"),
+            histogram_buckets: !is_set(query, "no_buckets"),
+            created: false,
+            max_series: None,
+            query,
+            name_prefix: None,
+        }
+    }
+
+    /// Returns true if `family` should be written, because the query selects
+    /// one of its metrics by name or doesn't select any by name.
+    ///
+    /// Selected names which don't name any metric are ignored.
+    pub fn includes(&self, family: &Family) -> bool {
+        let mut selected = self.query.split('&')
+            .filter_map(|param| {
+                let mut kv = param.splitn(2, '=');
+                let key = kv.next()?;
+                if key == "name[]" || key.eq_ignore_ascii_case("name%5B%5D") {
+                    kv.next()
+                } else {
+                    None
+                }
+            })
+            .peekable();
+        if selected.peek().is_none() {
+            return true;
+        }
+
+        let selected = selected
+            .map(|name| match self.name_prefix {
+                Some(prefix) if name.starts_with(prefix) => &name[prefix.len()..],
+                _ => name,
+            })
+            .collect::<Vec<_>>();
+        family.describe().iter().any(|metric| selected.contains(&metric.name))
+    }
+}
+
+// ===== impl SeriesLimit =====
+
+impl<'a, W: fmt::Write> SeriesLimit<'a, W> {
+    pub fn new(inner: &'a mut W, max_series: Option<usize>) -> Self {
+        SeriesLimit {
+            inner,
+            remaining: max_series,
+            truncated: false,
+        }
+    }
+}
+
+impl<'a, W: fmt::Write> fmt::Write for SeriesLimit<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.truncated {
+            return Ok(());
+        }
+
+        if let Some(ref mut remaining) = self.remaining {
+            let series = s.lines()
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .count();
+            if series > *remaining {
+                self.truncated = true;
+                return Ok(());
+            }
+            *remaining -= series;
+        }
+
+        self.inner.write_str(s)
+    }
+}
+
+// ===== impl NamePrefix =====
+
+impl<'a, W: fmt::Write> NamePrefix<'a, W> {
+    pub fn new(inner: &'a mut W, prefix: &'a str) -> Self {
+        NamePrefix {
+            inner,
+            prefix,
+            buf: String::new(),
+        }
+    }
+}
+
+impl<'a, W: fmt::Write> fmt::Write for NamePrefix<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.buf.clear();
+        for line in s.split_terminator('\n') {
+            if line.starts_with('#') {
+                // `# HELP <name> ...` and `# TYPE <name> ...`
+                let mut parts = line.splitn(3, ' ');
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some(hash), Some(keyword), Some(rest)) => {
+                        write!(self.buf, "{} {} {}{}", hash, keyword, self.prefix, rest)?
+                    },
+                    _ => self.buf.push_str(line),
+                }
+            } else if !line.is_empty() {
+                self.buf.push_str(self.prefix);
+                self.buf.push_str(line);
+            }
+            self.buf.push('\n');
+        }
+        self.inner.write_str(&self.buf)
+    }
+}
+
+// ===== impl MetricParts =====
+
+impl<'a, W: fmt::Write> MetricParts<'a, W> {
+    pub fn new(inner: &'a mut W, part_size: Option<usize>) -> Self {
+        MetricParts {
+            inner,
+            part_size,
+            buf: String::new(),
+        }
+    }
+
+    /// Writes the rest of the metric that has been buffered.
+    pub fn finish(&mut self) -> fmt::Result {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let result = self.inner.write_str(&self.buf);
+        self.buf.clear();
+        result
+    }
+}
+
+impl<'a, W: fmt::Write> fmt::Write for MetricParts<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.buf.push_str(s);
+        let part_size = match self.part_size {
+            Some(part_size) if self.buf.len() >= part_size => part_size,
+            _ => return Ok(()),
+        };
+        let end = match self.buf.rfind('\n') {
+            Some(i) => i + 1,
+            None => return Ok(()),
+        };
+        let last_line = self.buf[..end - 1].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let part = if self.buf[last_line..].starts_with("# HELP ") { last_line } else { end };
+        if part > 0 {
+            trace!("writing {} bytes of a metric longer than {} bytes", part, part_size);
+            self.inner.write_str(&self.buf[..part])?;
+            self.buf.drain(..part);
+        }
+        Ok(())
+    }
+}
+
+// ===== impl OpenMetrics =====
+
+impl<'a, W: fmt::Write> OpenMetrics<'a, W> {
+    pub fn new(inner: &'a mut W) -> Self {
+        OpenMetrics {
+            inner,
+            buf: String::new(),
+        }
+    }
+}
+
+impl<'a, W: fmt::Write> fmt::Write for OpenMetrics<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let counters = s.lines()
+            .filter_map(|line| {
+                let mut parts = line.split(' ');
+                match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                    (Some("#"), Some("TYPE"), Some(name), Some("counter")) => Some(name),
+                    _ => None,
+                }
+            })
+            .collect::<HashSet<_>>();
+
+        self.buf.clear();
+        for line in s.lines().filter(|line| !line.is_empty()) {
+            let mut parts = line.splitn(4, ' ');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some("#"), Some(keyword), Some(name)) if counters.contains(name) => {
+                    self.buf.push_str("# ");
+                    self.buf.push_str(keyword);
+                    self.buf.push(' ');
+                    self.buf.push_str(counter_family(name));
+                    if let Some(rest) = parts.next() {
+                        self.buf.push(' ');
+                        self.buf.push_str(rest);
+                    }
+                },
+                _ => self.buf.push_str(line),
+            }
+            self.buf.push('\n');
+
+            let mut parts = line.split(' ');
+            let type_line = (parts.next(), parts.next(), parts.next());
+            if let (Some("#"), Some("TYPE"), Some(name)) = type_line {
+                let family = if counters.contains(name) { counter_family(name) } else { name };
+                if let Some(unit) = unit_suffix(family) {
+                    self.buf.push_str("# UNIT ");
+                    self.buf.push_str(family);
+                    self.buf.push(' ');
+                    self.buf.push_str(unit);
+                    self.buf.push('\n');
+                }
+            }
+        }
+        self.inner.write_str(&self.buf)
+    }
+}
+
+// ===== impl IoWrite =====
+
+impl<'a, W: io::Write> IoWrite<'a, W> {
+    pub fn new(inner: &'a mut W) -> Self {
+        IoWrite {
+            inner,
+            error: None,
+        }
+    }
+
+    /// Returns the first error that occurred while writing, if any.
+    pub fn into_result(self) -> io::Result<()> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a, W: io::Write> fmt::Write for IoWrite<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.error.is_some() {
+            return Err(fmt::Error);
+        }
+        self.inner.write_all(s.as_bytes()).map_err(|e| {
+            self.error = Some(e);
+            fmt::Error
+        })
+    }
+}
+
+// ===== impl ChunkSender =====
+
+impl ChunkSender {
+    pub fn new(tx: mpsc::Sender<Result<Chunk, hyper::Error>>, timeout: Duration) -> Self {
+        ChunkSender {
+            tx: Some(tx),
+            buf: Vec::with_capacity(SCRAPE_CHUNK_SIZE),
+            timeout,
+        }
+    }
+
+    /// Sends the buffered bytes as a chunk, waiting until the connection
+    /// has taken the previous one, or until the timeout has elapsed.
+    fn send(&mut self) -> io::Result<()> {
+        let closed = || io::Error::new(io::ErrorKind::BrokenPipe, "scrape connection closed");
+        let chunk = mem::replace(&mut self.buf, Vec::with_capacity(SCRAPE_CHUNK_SIZE));
+        let tx = self.tx.take().ok_or_else(&closed)?;
+        let deadline = Instant::now() + self.timeout;
+        let mut send = executor::spawn(tx.send(Ok(Chunk::from(chunk))));
+        match wait_until(&mut send, deadline) {
+            Ok(Some(tx)) => {
+                self.tx = Some(tx);
+                Ok(())
+            },
+            Ok(None) => {
+                // A new sender always has room for one message, so the body
+                // can be failed even though the channel is full. Otherwise,
+                // the connection would end the truncated body as though it
+                // were complete once the sender is dropped.
+                let _ = send.get_ref().get_ref().clone().try_send(Err(hyper::Error::Timeout));
+                Err(io::Error::new(io::ErrorKind::TimedOut, "scrape connection stopped reading"))
+            },
+            Err(_) => Err(closed()),
+        }
+    }
+
+    /// Sends any bytes that are still buffered, ending the body.
+    pub fn finish(mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        self.send()
+    }
+}
+
+impl io::Write for ChunkSender {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(bytes);
+        if self.buf.len() >= SCRAPE_CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        self.send()
+    }
+}
+
+// ===== impl ThreadNotify =====
+
+impl Notify for ThreadNotify {
+    fn notify(&self, _: usize) {
+        self.0.unpark();
+    }
+}
+
+/// Polls `future` on the current thread until it completes, returning
+/// `None` if it hasn't completed by `deadline`.
+fn wait_until<F: Future>(future: &mut Spawn<F>, deadline: Instant)
+    -> Result<Option<F::Item>, F::Error>
+{
+    let notify = NotifyHandle::from(Arc::new(ThreadNotify(thread::current())));
+    loop {
+        if let Async::Ready(item) = future.poll_future_notify(&notify, 0)? {
+            return Ok(Some(item));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        thread::park_timeout(deadline - now);
+    }
+}
+
+// ===== impl ConstLabels =====
+
+impl<'a, W: fmt::Write> ConstLabels<'a, W> {
+    pub fn new(inner: &'a mut W, labels: Option<&'a str>) -> Self {
+        ConstLabels {
+            inner,
+            labels,
+            buf: String::new(),
+        }
+    }
+}
+
+impl<'a, W: fmt::Write> fmt::Write for ConstLabels<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let labels = match self.labels {
+            Some(labels) => labels,
+            None => return self.inner.write_str(s),
+        };
+        self.buf.clear();
+        for line in s.split_terminator('\n') {
+            // A metric name is followed by either its labels or its value.
+            match line.find(|c| c == '{' || c == ' ') {
+                Some(i) if !line.starts_with('#') => {
+                    let (name, rest) = line.split_at(i);
+                    if rest.starts_with("{}") {
+                        write!(self.buf, "{}{{{}{}", name, labels, &rest[1..])?
+                    } else if rest.starts_with('{') {
+                        write!(self.buf, "{}{{{},{}", name, labels, &rest[1..])?
+                    } else {
+                        write!(self.buf, "{}{{{}}}{}", name, labels, rest)?
+                    }
+                },
+                _ => self.buf.push_str(line),
+            }
+            self.buf.push('\n');
+        }
+        self.inner.write_str(&self.buf)
+    }
+}
+
+/// Converts a scrape in the Prometheus text format to the OpenMetrics text
+/// format.
+///
+/// OpenMetrics names each counter family without the `_total` suffix that
+/// its samples carry, doesn't allow blank lines, and requires that the
+/// exposition end with `# EOF`. Every counter is already named with a
+/// `_total` suffix, so the samples themselves are unchanged.
+///
+/// Each family whose name ends with a unit, as `_ms` or `_bytes` do, is
+/// given a `# UNIT` line naming that unit. OpenMetrics requires the unit to
+/// be a suffix of the family's name, so it is written as abbreviated there.
+///
+/// The scrape should have been formatted with `_created` samples, which
+/// OpenMetrics expects and the Prometheus format omits.
+pub fn to_openmetrics(body: &str) -> String {
+    let mut openmetrics = String::with_capacity(body.len());
+    fmt::Write::write_str(&mut OpenMetrics::new(&mut openmetrics), body)
+        .expect("writing to a String cannot fail");
+    openmetrics.push_str("# EOF\n");
+    openmetrics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_metrics_are_written_in_parts_of_whole_lines() {
+        let mut parts = Vec::<String>::new();
+        {
+            struct Parts<'a>(&'a mut Vec<String>);
+            impl<'a> fmt::Write for Parts<'a> {
+                fn write_str(&mut self, s: &str) -> fmt::Result {
+                    self.0.push(s.to_owned());
+                    Ok(())
+                }
+            }
+            let mut w = Parts(&mut parts);
+            let mut buf = MetricParts::new(&mut w, Some(20));
+            let writes = [
+                "# HELP a A",
+                " metric.\n",
+                "# TYPE a counter\n",
+                "a 1\n# HELP b B metric.\n",
+                "# TYPE b counter\nb 2\n",
+                "b{x=\"y\"} 3\n",
+            ];
+            for s in &writes {
+                fmt::Write::write_str(&mut buf, s).unwrap();
+            }
+            buf.finish().unwrap();
+        }
+
+        // Nothing is written until a part is long enough, and a `# HELP`
+        // line waits for the `# TYPE` line after it.
+        assert_eq!(parts, vec![
+            "# HELP a A metric.\n# TYPE a counter\n".to_owned(),
+            "a 1\n".to_owned(),
+            "# HELP b B metric.\n# TYPE b counter\nb 2\n".to_owned(),
+            "b{x=\"y\"} 3\n".to_owned(),
+        ]);
+    }
+}
@@ -0,0 +1,2176 @@
+//! Serves scrapes of the metrics shards over HTTP.
+
+use std::cmp;
+use std::fmt::{self, Write as FmtWrite};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use deflate::{Compression, CompressionOptions};
+use deflate::write::GzEncoder;
+use futures::future::{self, FutureResult};
+use futures::sync::mpsc;
+use hyper::{self, Body, Chunk, Method, StatusCode};
+use hyper::header::{
+    Accept,
+    AcceptEncoding,
+    Allow,
+    Authorization,
+    Bearer,
+    ContentEncoding,
+    ContentType,
+    Encoding,
+    QualityItem,
+};
+use hyper::server::{
+    Response as HyperResponse,
+    Request as HyperRequest,
+    Service as HyperService,
+};
+
+use super::{lock, recent, snapshot, Metrics, MetricDescriptor, Shards, Snapshot};
+use super::labels::{escape_label_value, sanitize_label_key, DstMatcher};
+use super::scrape::{
+    to_openmetrics,
+    ChunkSender,
+    ConstLabels,
+    IoWrite,
+    NamePrefix,
+    OpenMetrics,
+    ScrapeOptions,
+};
+
+/// Serve Prometheues metrics.
+#[derive(Debug, Clone)]
+pub struct Serve {
+    shards: Arc<Shards>,
+
+    /// If true, responses include a `Server-Timing` header reporting how
+    /// long the scrape took to format and compress.
+    server_timing: bool,
+
+    /// If set, scrapes that arrive shortly after another scrape with the
+    /// same query are served the body that it formatted.
+    scrape_cache: Option<Arc<Mutex<ScrapeCache>>>,
+
+    /// The maximum number of series written by a scrape, if any.
+    max_series: Option<usize>,
+
+    /// How hard gzipped scrapes are compressed.
+    gzip_level: Compression,
+
+    /// If set, every request must present this token in an
+    /// `Authorization: Bearer` header.
+    bearer_token: Option<Arc<str>>,
+
+    /// If set, prepended to the name of every metric in a scrape.
+    name_prefix: Option<Arc<str>>,
+
+    /// If set, labels prepended to the labels of every series in a scrape,
+    /// already formatted, such as `pod="web-0",node="n1"`, or written on
+    /// `target_info` in an OpenMetrics scrape.
+    const_labels: Option<Arc<str>>,
+
+    /// The path at which metrics are scraped. The JSON metrics, the process
+    /// metrics alone, the reset endpoint, the recent events, the help text,
+    /// and the metric descriptors are served at `<path>.json`,
+    /// `<path>/system`, `<path>/reset`, `<path>/events`, `<path>/help`, and
+    /// `<path>/describe`.
+    path: Arc<str>,
+
+    /// If true, the metrics may be reset by a `POST` to `<path>/reset`.
+    allow_reset: bool,
+
+    /// The threads on which streamed scrapes are formatted.
+    scrape_workers: Arc<ScrapeWorkers>,
+
+    /// How long a streamed scrape waits for the connection to take each
+    /// chunk before it is abandoned, freeing its worker.
+    scrape_write_timeout: Duration,
+}
+
+/// The most recently formatted scrape body, which is reused by scrapes with
+/// the same query and format until its TTL has elapsed.
+#[derive(Debug)]
+struct ScrapeCache {
+    ttl: Duration,
+    last: Option<CachedScrape>,
+}
+
+#[derive(Debug)]
+struct CachedScrape {
+    query: String,
+
+    /// Whether the body was formatted for an OpenMetrics scrape.
+    openmetrics: bool,
+
+    formatted_at: Instant,
+    body: String,
+}
+
+/// A fixed pool of threads on which streamed scrapes are formatted, with a
+/// bounded queue of scrapes waiting for a thread.
+///
+/// The threads are started by the first streamed scrape, and exit once
+/// every `Serve` using them has been dropped.
+#[derive(Debug, Default)]
+struct ScrapeWorkers {
+    jobs: Mutex<Option<SyncSender<ScrapeJob>>>,
+}
+
+/// A streamed scrape waiting to be formatted by one of the `ScrapeWorkers`.
+struct ScrapeJob {
+    serve: Serve,
+    query: String,
+    openmetrics: bool,
+    gzip_level: Option<Compression>,
+    tx: mpsc::Sender<Result<Chunk, hyper::Error>>,
+}
+
+/// The path at which metrics are served, unless configured otherwise.
+const DEFAULT_METRICS_PATH: &str = "/metrics";
+
+/// The number of threads on which streamed scrapes are formatted.
+const SCRAPE_WORKERS: usize = 2;
+
+/// The number of streamed scrapes which may wait for a thread to format
+/// them. Any more are refused with `503 Service Unavailable`.
+const SCRAPE_QUEUE_CAPACITY: usize = 4;
+
+/// The number of seconds a streamed scrape waits for the connection to take
+/// each chunk, so that a client which stops reading can't hold a worker.
+const SCRAPE_WRITE_TIMEOUT_SECS: u64 = 10;
+
+// ===== impl Serve =====
+
+impl Serve {
+    pub(super) fn new(shards: &Arc<Shards>) -> Self {
+        Serve {
+            shards: shards.clone(),
+            server_timing: false,
+            scrape_cache: None,
+            max_series: None,
+            gzip_level: Compression::Fast,
+            bearer_token: None,
+            name_prefix: None,
+            const_labels: None,
+            path: DEFAULT_METRICS_PATH.into(),
+            allow_reset: false,
+            scrape_workers: Arc::new(ScrapeWorkers::default()),
+            scrape_write_timeout: Duration::from_secs(SCRAPE_WRITE_TIMEOUT_SECS),
+        }
+    }
+
+    /// Configures whether responses include a `Server-Timing` header, for
+    /// diagnosing slow scrapes from the client side.
+    ///
+    /// The header is only known once a scrape has been formatted, so scrapes
+    /// are formatted in full before they are sent, rather than streamed.
+    pub fn with_server_timing(self, server_timing: bool) -> Self {
+        Serve {
+            server_timing,
+            ..self
+        }
+    }
+
+    /// Configures scrapes to share a formatted body with any scrape that has
+    /// the same query and arrives within `ttl` of it, so that concurrent
+    /// scrapes don't each format the metrics.
+    ///
+    /// If `ttl` is `None`, every scrape formats the metrics. Otherwise,
+    /// scrapes are formatted in full to be cached, rather than streamed.
+    pub fn with_scrape_cache_ttl(self, ttl: Option<Duration>) -> Self {
+        Serve {
+            scrape_cache: ttl.map(|ttl| Arc::new(Mutex::new(ScrapeCache::new(ttl)))),
+            ..self
+        }
+    }
+
+    /// Configures the maximum number of series written by each scrape.
+    ///
+    /// Once a scrape would exceed `max_series`, the remaining metrics are
+    /// omitted and `metrics_series_truncated` is set. If `max_series` is
+    /// `None`, scrapes are not limited.
+    pub fn with_max_series(self, max_series: Option<usize>) -> Self {
+        Serve {
+            max_series,
+            ..self
+        }
+    }
+
+    /// Configures how hard gzipped scrapes are compressed.
+    ///
+    /// Defaults to `Compression::Fast`. Infrequently-scraped proxies may
+    /// prefer to spend more CPU time on smaller scrapes.
+    pub fn with_gzip_level(self, gzip_level: Compression) -> Self {
+        Serve {
+            gzip_level,
+            ..self
+        }
+    }
+
+    /// Requires every request to present `token` in an
+    /// `Authorization: Bearer` header.
+    ///
+    /// Requests without the token are refused with `401 Unauthorized`
+    /// before any metrics are read or reset. By default, no token is
+    /// required.
+    pub fn with_bearer_token(self, token: &str) -> Self {
+        Serve {
+            bearer_token: Some(token.into()),
+            ..self
+        }
+    }
+
+    /// Prefixes the name of every metric in a scrape with `namespace`,
+    /// followed by an underscore, such as `conduit_request_total`, so that
+    /// the metrics of different proxies can be told apart.
+    ///
+    /// The namespace should be a valid metric name. The metrics served as
+    /// JSON, which are nested rather than flat, are not prefixed. By
+    /// default, metrics are not prefixed.
+    pub fn with_namespace(self, namespace: &str) -> Self {
+        Serve {
+            name_prefix: Some(format!("{}_", namespace).into()),
+            ..self
+        }
+    }
+
+    /// Adds `labels` to every series in a scrape, ahead of the series' own
+    /// labels, such as to identify the pod and node that a proxy runs on.
+    ///
+    /// Keys are sanitized and values are escaped as they are given, rather
+    /// than on every scrape. The keys must not be any that the metrics are
+    /// labeled with already. The metrics served as JSON and sent to StatsD
+    /// are not labeled. By default, no labels are added.
+    ///
+    /// OpenMetrics scrapes write the labels once instead, on a single
+    /// `target_info` series, and leave them off every other series.
+    pub fn with_const_labels(self, labels: &[(String, String)]) -> Self {
+        let mut formatted = String::new();
+        for &(ref key, ref value) in labels {
+            if !formatted.is_empty() {
+                formatted.push(',');
+            }
+            formatted.push_str(&sanitize_label_key(key));
+            formatted.push_str("=\"");
+            formatted.push_str(&escape_label_value(value));
+            formatted.push('"');
+        }
+        Serve {
+            const_labels: if formatted.is_empty() { None } else { Some(formatted.into()) },
+            ..self
+        }
+    }
+
+    /// Serves metrics at `path` rather than at `/metrics`, such as for an
+    /// ingress which only routes a particular path to the proxy.
+    ///
+    /// The JSON metrics, the process metrics alone, the reset endpoint, the
+    /// recent events, the help text, and the metric descriptors move with
+    /// it, to `<path>.json`, `<path>/system`, `<path>/reset`,
+    /// `<path>/events`, `<path>/help`, and `<path>/describe`.
+    pub fn with_path(self, path: &str) -> Self {
+        Serve {
+            path: path.into(),
+            ..self
+        }
+    }
+
+    /// Configures whether the metrics may be reset by a `POST` to
+    /// `<path>/reset`.
+    ///
+    /// Resetting the metrics discards everything recorded so far, so this is
+    /// disabled by default, and `<path>/reset` is not found. If a bearer
+    /// token is required, resets must present it too.
+    pub fn with_reset(self, allow_reset: bool) -> Self {
+        Serve {
+            allow_reset,
+            ..self
+        }
+    }
+
+    /// Returns true if `req` presents the configured bearer token, or if no
+    /// token is required.
+    fn is_authorized(&self, req: &HyperRequest) -> bool {
+        let token = match self.bearer_token {
+            Some(ref token) => token,
+            None => return true,
+        };
+        match req.headers().get::<Authorization<Bearer>>() {
+            Some(&Authorization(Bearer { token: ref presented })) => {
+                constant_time_eq(presented.as_bytes(), token.as_bytes())
+            },
+            None => false,
+        }
+    }
+
+    /// Serves the recent events as JSON, or `404 Not Found` if they are not
+    /// being kept.
+    fn recent_events(&self, req: &HyperRequest) -> Result<HyperResponse, hyper::Error> {
+        // The events are copied, so that the lock isn't held while they are
+        // serialized.
+        let events = match *lock(&self.shards.recent_events, "recent events") {
+            Some(ref recent) => recent.to_vec(),
+            None => return Ok(HyperResponse::new().with_status(StatusCode::NotFound)),
+        };
+        let body = recent::to_json(&events);
+        metrics_response(req, body, ContentType::json(), self.gzip_level)
+            .map_err(hyper::Error::Io)
+    }
+
+    /// Serves only the metrics describing the proxy process itself, such as
+    /// its start time, for agents which poll often to check that the proxy
+    /// is up.
+    ///
+    /// Only the process shard is locked, and the metrics are formatted
+    /// without copying it, as they are few. This isn't counted as a scrape,
+    /// so that polling doesn't skew the scrape metrics.
+    fn system(&self, req: &HyperRequest) -> Result<HyperResponse, hyper::Error> {
+        let opts = ScrapeOptions {
+            name_prefix: self.name_prefix.as_ref().map(|prefix| &**prefix),
+            ..ScrapeOptions::from_query("")
+        };
+        let labels = self.const_labels.as_ref().map(|labels| &**labels);
+        let mut body = String::new();
+        {
+            let metrics = lock(self.shards.process(), "metrics");
+            let formatted = match self.name_prefix {
+                Some(ref prefix) => {
+                    let mut w = NamePrefix::new(&mut body, prefix);
+                    metrics.fmt_process_metrics(opts, &mut ConstLabels::new(&mut w, labels))
+                },
+                None => metrics.fmt_process_metrics(opts, &mut ConstLabels::new(&mut body, labels)),
+            };
+            formatted.expect("writing to a String cannot fail");
+        }
+        metrics_response(req, body, ContentType::plaintext(), self.gzip_level)
+            .map_err(hyper::Error::Io)
+    }
+
+    /// Handles a request to reset the metrics, which must be a `POST`.
+    fn reset(&self, req: &HyperRequest) -> HyperResponse {
+        if *req.method() != Method::Post {
+            return HyperResponse::new()
+                .with_status(StatusCode::MethodNotAllowed)
+                .with_header(Allow(vec![Method::Post]));
+        }
+
+        // The cache is locked first, as it is while a scrape is formatted,
+        // so that a scrape formatted before the reset isn't served after it.
+        let mut cache = self.scrape_cache.as_ref()
+            .map(|cache| lock(cache, "scrape cache"));
+        self.shards.each(Metrics::reset);
+        if let Some(ref mut cache) = cache {
+            cache.clear();
+        }
+
+        info!("metrics reset");
+        HyperResponse::new()
+    }
+
+    /// Formats a scrape for the given query string, returning the body and
+    /// the time taken to format it.
+    fn format_scrape(&self, query: &str, openmetrics: bool) -> (String, Duration) {
+        let mut body = String::new();
+        let format_elapsed = self.write_scrape(query, openmetrics, &mut body);
+        (body, format_elapsed)
+    }
+
+    /// Responds with a scrape for the given query string which is sent as it
+    /// is formatted, so that the whole scrape is never held in memory.
+    ///
+    /// The scrape is formatted on one of the `ScrapeWorkers`, so that a large
+    /// scrape doesn't hold up the other work of the thread serving it. If
+    /// too many scrapes are already waiting for a worker, this one is
+    /// refused with `503 Service Unavailable`.
+    fn stream_scrape(
+        &self,
+        req: &HyperRequest,
+        query: &str,
+        openmetrics: bool,
+        content_type: ContentType,
+    ) -> HyperResponse {
+        let (tx, body) = Body::pair();
+        let gzip_level = if is_gzip(req) { Some(self.gzip_level) } else { None };
+        let job = ScrapeJob {
+            serve: self.clone(),
+            query: query.to_owned(),
+            openmetrics,
+            gzip_level,
+            tx,
+        };
+        if let Err(status) = self.scrape_workers.submit(job) {
+            return HyperResponse::new().with_status(status);
+        }
+
+        let rsp = HyperResponse::new().with_header(content_type);
+        let rsp = match gzip_level {
+            Some(_) => rsp.with_header(ContentEncoding(vec![Encoding::Gzip])),
+            None => rsp,
+        };
+        rsp.with_body(body)
+    }
+
+    /// Formats a scrape for the given query string, sending it in chunks
+    /// on `tx`, gzipped at `gzip_level` if it is set.
+    ///
+    /// Fails if the connection doesn't take a chunk within the
+    /// `scrape_write_timeout`.
+    fn send_scrape(
+        &self,
+        query: &str,
+        openmetrics: bool,
+        gzip_level: Option<Compression>,
+        tx: mpsc::Sender<Result<Chunk, hyper::Error>>,
+    ) -> io::Result<()> {
+        let chunks = ChunkSender::new(tx, self.scrape_write_timeout);
+        match gzip_level {
+            Some(level) => {
+                let mut writer = GzEncoder::new(chunks, CompressionOptions::from(level));
+                self.write_streamed_scrape(query, openmetrics, &mut writer)?;
+                writer.finish()?.finish()
+            },
+            None => {
+                let mut chunks = chunks;
+                self.write_streamed_scrape(query, openmetrics, &mut chunks)?;
+                chunks.finish()
+            },
+        }
+    }
+
+    fn write_streamed_scrape<W: io::Write>(&self, query: &str, openmetrics: bool, w: &mut W)
+        -> io::Result<()>
+    {
+        let mut w = IoWrite::new(w);
+        if openmetrics {
+            self.write_scrape(query, true, &mut OpenMetrics::new(&mut w));
+            // A failed write has already been recorded by `w`.
+            let _ = fmt::Write::write_str(&mut w, "# EOF\n");
+        } else {
+            self.write_scrape(query, false, &mut w);
+        }
+        w.into_result()
+    }
+
+    /// Writes a scrape for the given query string to `w`, returning the
+    /// time taken to format it.
+    ///
+    /// No metrics lock is held while `w` is written to, so recording is
+    /// never delayed by a slow writer.
+    fn write_scrape<W: fmt::Write>(&self, query: &str, openmetrics: bool, w: &mut W) -> Duration {
+        // The scrape is formatted from a copy of the metrics, so that no
+        // lock is held while it is formatted.
+        let (mut metrics, lock_hold) = self.shards.merged();
+
+        // Scrapes may be scoped to a subset of destinations by passing
+        // `dst_<key>=<value>` query parameters.
+        let dst = DstMatcher::from_query(query);
+        let format_started = Instant::now();
+        let opts = ScrapeOptions {
+            created: openmetrics,
+            max_series: self.max_series,
+            name_prefix: self.name_prefix.as_ref().map(|prefix| &**prefix),
+            ..ScrapeOptions::from_query(query)
+        };
+        let mut labels = self.const_labels.as_ref().map(|labels| &**labels);
+
+        // OpenMetrics scrapes carry the constant labels once, on the
+        // `target_info` series, rather than on every series. Its name isn't
+        // prefixed, as consumers look for it by name. It is written whole,
+        // as `w` expects whole lines.
+        let mut target_info = Ok(());
+        if openmetrics {
+            if let Some(labels) = labels.take() {
+                target_info = w.write_str(&format!(
+                    "# HELP target {help}\n# TYPE target info\ntarget_info{{{labels}}} 1\n\n",
+                    help = TARGET_INFO_HELP,
+                    labels = labels,
+                ));
+            }
+        }
+
+        let formatted = target_info.is_ok() && match self.name_prefix {
+            Some(ref prefix) => {
+                let mut w = NamePrefix::new(w, prefix);
+                metrics.fmt_scrape(&dst, opts, &mut ConstLabels::new(&mut w, labels))
+            },
+            None => metrics.fmt_scrape(&dst, opts, &mut ConstLabels::new(w, labels)),
+        };
+        let format_elapsed = format_started.elapsed();
+
+        // The time spent holding the locks and formatting the scrape is
+        // reported on the next scrape, as this one has already been written.
+        lock(self.shards.process(), "metrics")
+            .record_scrape(&metrics, !formatted, lock_hold, format_elapsed);
+        format_elapsed
+    }
+
+    /// Serializes the metrics as JSON, returning the body and the time taken
+    /// to serialize it.
+    ///
+    /// This counts as a scrape, as the Prometheus format does, but is never
+    /// cached or limited.
+    fn format_json(&self) -> (String, Duration) {
+        let (mut metrics, lock_hold) = self.shards.merged();
+
+        // As in `fmt_scrape`, the scrape includes itself in the count.
+        metrics.scrapes_total.value.incr();
+        metrics.count_series();
+        let format_started = Instant::now();
+        let body = metrics.snapshot().to_json();
+        let format_elapsed = format_started.elapsed();
+
+        lock(self.shards.process(), "metrics")
+            .record_scrape(&metrics, false, lock_hold, format_elapsed);
+        (body, format_elapsed)
+    }
+}
+
+// ===== impl ScrapeWorkers =====
+
+impl ScrapeWorkers {
+    /// Queues `job` to be formatted by the next free worker, starting the
+    /// workers if they haven't been started yet.
+    ///
+    /// Returns the status with which to refuse the scrape if it can't be
+    /// queued.
+    fn submit(&self, job: ScrapeJob) -> Result<(), StatusCode> {
+        let mut jobs = lock(&self.jobs, "scrape workers");
+        if jobs.is_none() {
+            *jobs = Some(ScrapeWorkers::start().map_err(|e| {
+                error!("failed to spawn a thread to format scrapes: {}", e);
+                StatusCode::InternalServerError
+            })?);
+        }
+
+        let result = jobs.as_ref()
+            .expect("scrape workers must have been started")
+            .try_send(job);
+        match result {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                debug!("too many scrapes are waiting to be formatted");
+                Err(StatusCode::ServiceUnavailable)
+            },
+            Err(TrySendError::Disconnected(_)) => {
+                // Every worker has panicked, so they are started again by
+                // the next scrape.
+                error!("no threads are left to format scrapes");
+                *jobs = None;
+                Err(StatusCode::InternalServerError)
+            },
+        }
+    }
+
+    fn start() -> io::Result<SyncSender<ScrapeJob>> {
+        let (tx, rx) = sync_channel(SCRAPE_QUEUE_CAPACITY);
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..SCRAPE_WORKERS {
+            let rx = Arc::clone(&rx);
+            thread::Builder::new()
+                .name("metrics-scrape".into())
+                .spawn(move || ScrapeWorkers::work(&rx))?;
+        }
+        Ok(tx)
+    }
+
+    /// Formats the scrapes received on `jobs` until every sender has been
+    /// dropped.
+    fn work(jobs: &Mutex<Receiver<ScrapeJob>>) {
+        loop {
+            let job = match lock(jobs, "scrape jobs").recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            };
+            let ScrapeJob { serve, query, openmetrics, gzip_level, tx } = job;
+            if let Err(e) = serve.send_scrape(&query, openmetrics, gzip_level, tx) {
+                debug!("failed to send scrape: {}", e);
+            }
+        }
+    }
+}
+
+// ===== impl ScrapeCache =====
+
+impl ScrapeCache {
+    fn new(ttl: Duration) -> Self {
+        ScrapeCache {
+            ttl,
+            last: None,
+        }
+    }
+
+    /// Discards the cached body, so that the next scrape is formatted.
+    fn clear(&mut self) {
+        self.last = None;
+    }
+
+    /// Returns the cached body for `query` and format if it was formatted
+    /// within the TTL, or else formats, caches, and returns a new body.
+    ///
+    /// The time taken to format the body is also returned; it is zero if the
+    /// cached body was used.
+    fn get_or_format<F>(&mut self, query: &str, openmetrics: bool, format: F)
+        -> (String, Duration)
+    where
+        F: FnOnce() -> (String, Duration),
+    {
+        if let Some(ref last) = self.last {
+            let fresh = last.formatted_at.elapsed() < self.ttl;
+            if last.query == query && last.openmetrics == openmetrics && fresh {
+                trace!("serving cached scrape");
+                return (last.body.clone(), Duration::default());
+            }
+        }
+
+        let formatted_at = Instant::now();
+        let (body, format_elapsed) = format();
+        self.last = Some(CachedScrape {
+            query: query.to_owned(),
+            openmetrics,
+            formatted_at,
+            body: body.clone(),
+        });
+        (body, format_elapsed)
+    }
+}
+
+impl Serve {
+    /// Returns the `# HELP` and `# TYPE` lines of every metric, without any
+    /// samples. These are served at `<path>/help`.
+    pub fn help_text(&self) -> String {
+        let mut help = String::new();
+        for metric in self.describe() {
+            write!(
+                help,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n",
+                name = metric.name,
+                help = metric.help,
+                kind = metric.kind,
+            ).expect("writing to a String cannot fail");
+        }
+        help
+    }
+
+    /// Describes every metric that a scrape would include. These are served
+    /// as JSON at `<path>/describe`.
+    ///
+    /// Every shard has the same metrics, so only the process shard is
+    /// described, and its values aren't copied.
+    pub fn describe(&self) -> Vec<MetricDescriptor> {
+        lock(self.shards.process(), "metrics").describe()
+    }
+
+    /// Copies the current value of every metric that a scrape would
+    /// include, for consumers in the same process.
+    ///
+    /// The metrics locks are only held while the metrics are cloned, and not
+    /// while the snapshot is built from the clone.
+    // Not yet used by any consumer in the proxy itself.
+    #[allow(dead_code)]
+    pub fn snapshot(&self) -> Snapshot {
+        let (mut metrics, _) = self.shards.merged();
+        metrics.count_series();
+        metrics.snapshot()
+    }
+}
+
+#[cfg(test)]
+impl Serve {
+    /// Serves `req`, waiting for the response and its body.
+    ///
+    /// Returns the status, body, and headers of the response.
+    pub fn scrape_sync(&self, req: HyperRequest)
+        -> (StatusCode, Vec<u8>, hyper::header::Headers)
+    {
+        use futures::{Future, Stream};
+
+        let rsp = self.call(req).wait().expect("metrics response");
+        let status = rsp.status();
+        let headers = rsp.headers().clone();
+        let body = rsp.body().concat2().wait().expect("metrics response body");
+        (status, body.to_vec(), headers)
+    }
+}
+
+/// Returns true if `a` and `b` are equal, taking the same time to compare
+/// them wherever they first differ, so that the time taken doesn't reveal
+/// how much of a secret was guessed correctly.
+///
+/// Only their lengths are compared in variable time.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Returns true if the client accepts gzip.
+///
+/// An encoding with a q-value of 0 is explicitly not acceptable (RFC 7231,
+/// section 5.3.4), so `gzip;q=0` is not treated as accepting gzip.
+fn is_gzip(req: &HyperRequest) -> bool {
+    if let Some(accept_encodings) = req
+        .headers()
+        .get::<AcceptEncoding>()
+    {
+        return accept_encodings
+            .iter()
+            .any(|&QualityItem { ref item, quality }| {
+                item == &Encoding::Gzip && quality > hyper::header::q(0)
+            })
+    }
+    false
+}
+
+/// Returns the value of a `Server-Timing` header reporting the time taken to
+/// format a scrape and, if it was compressed, to compress it.
+fn server_timing(format: Duration, compress: Option<Duration>) -> String {
+    fn ms(d: Duration) -> f64 {
+        d.as_secs() as f64 * 1_000.0 + f64::from(d.subsec_nanos()) / 1_000_000.0
+    }
+
+    let mut timing = format!("format;dur={:.3}", ms(format));
+    if let Some(compress) = compress {
+        timing.push_str(&format!(", compress;dur={:.3}", ms(compress)));
+    }
+    timing
+}
+
+/// Returns the rest of `path` after `prefix`, if `path` starts with it.
+fn strip_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    if path.starts_with(prefix) {
+        Some(&path[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+/// The help text of the `target_info` series in OpenMetrics scrapes.
+const TARGET_INFO_HELP: &str = "Labels describing the proxy, which apply to every series.";
+
+/// The content type of a scrape in the OpenMetrics text format.
+const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Returns true if the client accepts the OpenMetrics text format, and
+/// prefers it to the Prometheus text format.
+///
+/// The text format is also accepted by `text/*` and `*/*`. If both formats
+/// are accepted with the same quality, OpenMetrics is preferred.
+fn is_openmetrics(req: &HyperRequest) -> bool {
+    if let Some(accept) = req.headers().get::<Accept>() {
+        let mut openmetrics = hyper::header::q(0);
+        let mut text = hyper::header::q(0);
+        for &QualityItem { ref item, quality } in accept.iter() {
+            let (type_, subtype) = (item.type_(), item.subtype());
+            if type_ == "application" && subtype == "openmetrics-text" {
+                openmetrics = cmp::max(openmetrics, quality);
+            } else if type_ == "*" || (type_ == "text" && (subtype == "plain" || subtype == "*")) {
+                text = cmp::max(text, quality);
+            }
+        }
+        return openmetrics > hyper::header::q(0) && openmetrics >= text;
+    }
+    false
+}
+
+/// Returns a response to `req` with the given metrics `body`, compressing
+/// the body at `gzip_level` if the client accepts gzip.
+fn metrics_response(
+    req: &HyperRequest,
+    body: String,
+    content_type: ContentType,
+    gzip_level: Compression,
+) -> io::Result<HyperResponse> {
+    if is_gzip(req) {
+        trace!("gzipping metrics");
+        let mut writer = GzEncoder::new(Vec::<u8>::new(), CompressionOptions::from(gzip_level));
+        writer.write_all(body.as_bytes())
+            .and_then(|_| writer.finish())
+            .map(|body| {
+                HyperResponse::new()
+                    .with_header(ContentEncoding(vec![Encoding::Gzip]))
+                    .with_header(content_type)
+                    .with_body(Body::from(body))
+            })
+    } else {
+        Ok(HyperResponse::new()
+            .with_header(content_type)
+            .with_body(Body::from(body)))
+    }
+}
+
+impl HyperService for Serve {
+    type Request = HyperRequest;
+    type Response = HyperResponse;
+    type Error = hyper::Error;
+    type Future = FutureResult<Self::Response, Self::Error>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        if !self.is_authorized(&req) {
+            let mut rsp = HyperResponse::new().with_status(StatusCode::Unauthorized);
+            rsp.headers_mut().set_raw("WWW-Authenticate", "Bearer realm=\"metrics\"");
+            return future::ok(rsp);
+        }
+
+        // Consumers which can't parse the Prometheus text format may request
+        // the metrics as JSON instead.
+        let json = match strip_prefix(req.path(), &self.path) {
+            Some("") => false,
+            Some(".json") => true,
+            Some("/system") => return future::result(self.system(&req)),
+            Some("/reset") if self.allow_reset => return future::ok(self.reset(&req)),
+            Some("/events") => return future::result(self.recent_events(&req)),
+            Some("/help") => {
+                let body = self.help_text();
+                return future::result(
+                    metrics_response(&req, body, ContentType::plaintext(), self.gzip_level)
+                        .map_err(hyper::Error::Io));
+            },
+            Some("/describe") => {
+                let body = snapshot::describe_to_json(&self.describe());
+                return future::result(
+                    metrics_response(&req, body, ContentType::json(), self.gzip_level)
+                        .map_err(hyper::Error::Io));
+            },
+            _ => return future::ok(HyperResponse::new()
+                .with_status(StatusCode::NotFound)),
+        };
+
+        let openmetrics = !json && is_openmetrics(&req);
+        let content_type = if json {
+            ContentType::json()
+        } else if openmetrics {
+            let content_type = OPENMETRICS_CONTENT_TYPE.parse()
+                .expect("OpenMetrics content type must be valid");
+            ContentType(content_type)
+        } else {
+            ContentType::plaintext()
+        };
+
+        // Agents may check that metrics are being served before scraping
+        // them, so a `HEAD` request gets the headers that a scrape would,
+        // without the cost of formatting one.
+        if *req.method() == Method::Head {
+            let mut rsp = HyperResponse::new().with_header(content_type);
+            if is_gzip(&req) {
+                rsp.headers_mut().set(ContentEncoding(vec![Encoding::Gzip]));
+            }
+            return future::ok(rsp);
+        }
+
+        // Scrapes are streamed unless the whole body is needed first, to be
+        // cached or for the `Server-Timing` header.
+        let query = req.query().unwrap_or("");
+        if !json && self.scrape_cache.is_none() && !self.server_timing {
+            return future::ok(self.stream_scrape(&req, query, openmetrics, content_type));
+        }
+
+        let (body, format_elapsed) = if json {
+            self.format_json()
+        } else {
+            let (body, format_elapsed) = match self.scrape_cache {
+                // The cache stays locked while a scrape is formatted, so that
+                // concurrent scrapes wait for its body rather than formatting
+                // their own.
+                Some(ref cache) => lock(cache, "scrape cache")
+                    .get_or_format(query, openmetrics, || self.format_scrape(query, openmetrics)),
+                None => self.format_scrape(query, openmetrics),
+            };
+
+            // The rest of the OpenMetrics format is derived from the
+            // Prometheus format.
+            if openmetrics {
+                (to_openmetrics(&body), format_elapsed)
+            } else {
+                (body, format_elapsed)
+            }
+        };
+
+        let response_started = Instant::now();
+        let rsp = metrics_response(&req, body, content_type, self.gzip_level).map(|mut rsp| {
+            if self.server_timing {
+                let compress_elapsed = if is_gzip(&req) {
+                    Some(response_started.elapsed())
+                } else {
+                    None
+                };
+                rsp.headers_mut().set_raw(
+                    "Server-Timing",
+                    server_timing(format_elapsed, compress_elapsed),
+                );
+            }
+            rsp
+        });
+
+        future::result(rsp.map_err(hyper::Error::Io))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{Future, Stream};
+
+    use telemetry::event;
+    use super::*;
+    use super::super::*;
+    use super::super::test_util::*;
+
+    #[test]
+    fn scrape_sync_matches_response() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        aggregate.record_event(&request_end(&request("http://foo.test/", &proxy)));
+
+        let rsp = serve.call(get("/metrics")).wait().expect("scrape");
+        let status = rsp.status();
+        let content_type = rsp.headers().get::<ContentType>().cloned();
+        let body = rsp.body().concat2().wait().expect("scrape body");
+
+        let (sync_status, sync_body, sync_headers) = serve.scrape_sync(get("/metrics"));
+        assert_eq!(sync_status, status);
+        assert_eq!(sync_headers.get::<ContentType>().cloned(), content_type);
+
+        // The first scrape is counted, and its lock hold time recorded, in
+        // the second, so compare everything up to those metrics.
+        let cut = |body: &[u8]| {
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            let end = body.find("# HELP metrics_scrapes_total").unwrap();
+            body[..end].to_owned()
+        };
+        assert_eq!(cut(&sync_body), cut(&body));
+    }
+
+    #[test]
+    fn federated_scrape_omits_process_metrics() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        aggregate.record_event(&request_end(&request("http://foo.test/", &proxy)));
+
+        let all = scrape(&serve);
+        assert!(all.contains("process_start_time_seconds"));
+        assert!(all.contains("request_total{"));
+
+        let federated = scrape_uri(&serve, "/metrics?federate=1");
+        assert!(!federated.contains("process_start_time_seconds"));
+        assert!(!federated.contains("build_timestamp_seconds"));
+        assert!(federated.contains("request_total{"));
+        assert!(federated.contains("tcp_open_total"));
+        parse_samples(&federated);
+    }
+
+    #[test]
+    fn histograms_without_buckets_keep_count_and_sum() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let req = request("http://foo.test/", &proxy);
+        let ctx = Arc::new(ctx::transport::Ctx::Server(server(&proxy)));
+        aggregate.record_event(&Event::TransportOpen(Arc::clone(&ctx)));
+        aggregate.record_event(&Event::TransportClose(ctx, event::TransportClose {
+            clean: true,
+            duration: Duration::from_millis(15),
+            rx_bytes: 0,
+            tx_bytes: 0,
+        }));
+        aggregate.record_event(&request_end(&req));
+        aggregate.record_event(&response_end(&req, None));
+
+        let full = scrape(&serve);
+        assert!(full.contains("response_latency_ms_bucket{"));
+        assert!(full.contains("tcp_connection_duration_ms_bucket{"));
+        assert!(full.contains("metrics_scrape_lock_hold_ms_bucket{"));
+
+        let reduced = scrape_uri(&serve, "/metrics?no_buckets=1");
+        assert!(!reduced.contains("_bucket"));
+        assert!(!reduced.contains("# TYPE response_latency_ms histogram\n"));
+        assert!(reduced.contains("# TYPE response_latency_ms summary\n"));
+        for name in &[
+            "response_latency_ms",
+            "tcp_connection_duration_ms",
+        ] {
+            let count = format!("{}_count{{", name);
+            let sum = format!("{}_sum{{", name);
+            assert!(reduced.contains(&count), "{} missing from {}", count, reduced);
+            assert!(reduced.contains(&sum), "{} missing from {}", sum, reduced);
+        }
+        assert!(reduced.contains("metrics_scrape_lock_hold_ms_count 1\n"));
+        parse_samples(&reduced);
+    }
+
+    #[test]
+    fn scrapes_are_truncated_at_max_series() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        for i in 0..50 {
+            let uri = format!("http://foo{}.test/", i);
+            aggregate.record_event(&request_end(&request(&uri, &proxy)));
+        }
+
+        let all = scrape(&serve);
+        assert!(all.contains("metrics_series_truncated 0\n"));
+        assert!(parse_samples(&all).len() > 100);
+
+        let serve = serve.with_max_series(Some(60));
+        let limited = scrape(&serve);
+        let samples = parse_samples(&limited);
+        // The truncation marker is written in addition to the limited
+        // series.
+        assert!(samples.len() <= 61, "{} series were written", samples.len());
+        assert!(limited.contains("request_total{"));
+        assert!(!limited.contains("request_by_authority_total{"));
+        assert!(!limited.contains("process_start_time_seconds"));
+        assert!(limited.ends_with("metrics_series_truncated 1\n\n"));
+    }
+
+    #[test]
+    fn server_timing_is_reported_when_enabled() {
+        let process = ctx::Process::test("test");
+        let (_, serve) = new(&process);
+
+        let (_, _, headers) = serve.scrape_sync(get("/metrics"));
+        assert!(headers.get_raw("Server-Timing").is_none());
+
+        let serve = serve.with_server_timing(true);
+        let (_, _, headers) = serve.scrape_sync(get("/metrics"));
+        let timing = headers.get_raw("Server-Timing")
+            .and_then(|raw| raw.one())
+            .map(|value| String::from_utf8(value.to_vec()).unwrap())
+            .expect("Server-Timing header");
+        assert!(timing.starts_with("format;dur="), "unexpected timing {:?}", timing);
+        assert!(!timing.contains("compress"));
+
+        let mut req = get("/metrics");
+        req.headers_mut().set(AcceptEncoding(vec![QualityItem::new(
+            Encoding::Gzip,
+            hyper::header::q(1000),
+        )]));
+        let (_, _, headers) = serve.scrape_sync(req);
+        let timing = headers.get_raw("Server-Timing")
+            .and_then(|raw| raw.one())
+            .map(|value| String::from_utf8(value.to_vec()).unwrap())
+            .expect("Server-Timing header");
+        assert!(timing.contains("format;dur="));
+        assert!(timing.contains(", compress;dur="));
+    }
+
+    #[test]
+    fn openmetrics_is_negotiated() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let req = request("http://foo.test/", &proxy);
+        aggregate.record_event(&request_end(&req));
+        aggregate.record_event(&response_end(&req, None));
+
+        let accept_openmetrics = |req: &mut HyperRequest| {
+            let openmetrics = "application/openmetrics-text; version=1.0.0".parse().unwrap();
+            req.headers_mut().set(Accept(vec![
+                QualityItem::new(openmetrics, hyper::header::q(1000)),
+            ]));
+        };
+
+        let (_, body, headers) = serve.scrape_sync(get("/metrics"));
+        assert_eq!(headers.get::<ContentType>(), Some(&ContentType::plaintext()));
+        assert!(!String::from_utf8(body).unwrap().contains("# EOF"));
+
+        let mut req = get("/metrics");
+        accept_openmetrics(&mut req);
+        let (status, body, headers) = serve.scrape_sync(req);
+        assert_eq!(status, StatusCode::Ok);
+        let content_type = headers.get::<ContentType>().expect("content type").to_string();
+        assert!(
+            content_type.starts_with("application/openmetrics-text"),
+            "unexpected content type {:?}", content_type
+        );
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.ends_with("\n# EOF\n"));
+        assert!(!body.contains("\n\n"));
+        assert!(body.contains("# TYPE request counter\n"));
+        assert!(body.contains("# HELP request A counter"));
+        assert!(body.contains("request_total{authority=\"foo.test\""));
+        assert!(body.contains("# TYPE response_latency_ms histogram\n"));
+        assert!(body.contains("# TYPE telemetry_events_dropped counter\n"));
+        assert!(body.contains("# UNIT response_latency_ms ms\n"));
+        assert!(body.contains("# UNIT response_body_bytes bytes\n"));
+        assert!(body.contains("# UNIT process_start_time_seconds seconds\n"));
+        assert!(!body.contains("# UNIT request "));
+        assert!(!body.contains("# UNIT telemetry_events_dropped "));
+        assert!(body.contains("telemetry_events_dropped_total 0\n"));
+
+        // The negotiated format is also used when the scrape is compressed.
+        let mut req = get("/metrics");
+        accept_openmetrics(&mut req);
+        req.headers_mut().set(AcceptEncoding(vec![QualityItem::new(
+            Encoding::Gzip,
+            hyper::header::q(1000),
+        )]));
+        let (_, _, headers) = serve.scrape_sync(req);
+        assert!(headers.get::<ContentEncoding>().is_some());
+        assert_eq!(
+            headers.get::<ContentType>().expect("content type").to_string(),
+            content_type
+        );
+    }
+
+    #[test]
+    fn openmetrics_is_negotiated_by_quality() {
+        let process = ctx::Process::test("test");
+        let (_, serve) = new(&process);
+
+        let is_openmetrics = |accept: &[(&str, u16)]| {
+            let mut req = get("/metrics");
+            req.headers_mut().set(Accept(accept.iter()
+                .map(|&(mime, q)| QualityItem::new(mime.parse().unwrap(), hyper::header::q(q)))
+                .collect()));
+            let (status, _, headers) = serve.scrape_sync(req);
+            assert_eq!(status, StatusCode::Ok);
+            headers.get::<ContentType>()
+                .expect("content type")
+                .to_string()
+                .starts_with("application/openmetrics-text")
+        };
+
+        // As sent by Prometheus.
+        assert!(is_openmetrics(&[
+            ("application/openmetrics-text; version=1.0.0", 1000),
+            ("text/plain; version=0.0.4", 500),
+            ("*/*", 100),
+        ]));
+        assert!(is_openmetrics(&[("application/openmetrics-text", 1000), ("*/*", 1000)]));
+
+        // A refused or less preferred OpenMetrics isn't served.
+        assert!(!is_openmetrics(&[("application/openmetrics-text", 0)]));
+        assert!(!is_openmetrics(&[("application/openmetrics-text", 0), ("*/*", 1000)]));
+        assert!(!is_openmetrics(&[
+            ("application/openmetrics-text", 500),
+            ("text/plain", 1000),
+        ]));
+        assert!(!is_openmetrics(&[("application/openmetrics-text", 100), ("text/*", 900)]));
+    }
+
+    #[test]
+    fn counters_are_created_in_openmetrics() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (aggregate, serve) = new(&process);
+        let mut aggregate = aggregate.with_requests_by_authority(true);
+        let serve = serve.with_scrape_cache_ttl(Some(Duration::from_secs(60)));
+        let before = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let req = request("http://foo.test/", &proxy);
+        aggregate.record_event(&request_end(&req));
+
+        // The Prometheus format never includes `_created` samples.
+        let (_, body, _) = serve.scrape_sync(get("/metrics"));
+        assert!(!String::from_utf8(body).unwrap().contains("_created"));
+
+        // The Prometheus scrape was cached, but isn't served to OpenMetrics
+        // scrapers.
+        let mut req = get("/metrics");
+        let openmetrics = "application/openmetrics-text; version=1.0.0".parse().unwrap();
+        req.headers_mut().set(Accept(vec![
+            QualityItem::new(openmetrics, hyper::header::q(1000)),
+        ]));
+        let (_, body, _) = serve.scrape_sync(req);
+        let body = String::from_utf8(body).unwrap();
+        let after = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        // Each `_created` sample directly follows its counter's sample.
+        let lines = body.lines().collect::<Vec<_>>();
+        let total = lines.iter()
+            .position(|line| line.starts_with("request_total{authority=\"foo.test\""))
+            .expect("request_total sample");
+        let labels = &lines[total]["request_total".len()..lines[total].rfind(' ').unwrap()];
+        let created = lines[total + 1];
+        assert!(created.starts_with(&format!("request_created{} ", labels)), "{}", created);
+        let created = created.rsplit(' ').next().unwrap().parse::<f64>().unwrap();
+        assert!(created >= before as f64 && created < (after + 1) as f64, "{}", created);
+
+        assert!(body.contains(
+            "\ntelemetry_events_dropped_total 0\ntelemetry_events_dropped_created "
+        ));
+        assert!(body.contains("\nrequest_by_authority_total{authority=\"foo.test\"} 1\n\
+                               request_by_authority_created{authority=\"foo.test\"} "));
+        assert!(!body.contains("response_latency_ms_created"));
+    }
+
+    #[test]
+    fn large_scrapes_are_streamed_in_chunks() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        for i in 0..50 {
+            let req = request(&format!("http://foo{}.test/", i), &proxy);
+            aggregate.record_event(&request_end(&req));
+            aggregate.record_event(&response_end(&req, None));
+        }
+
+        let chunks = |serve: &Serve| {
+            let rsp = serve.call(get("/metrics")).wait().expect("scrape");
+            rsp.body().collect().wait().expect("scrape body")
+        };
+        let streamed = chunks(&serve);
+        assert!(streamed.len() > 1, "scrape was sent in {} chunk(s)", streamed.len());
+        let body = streamed.iter()
+            .flat_map(|chunk| chunk.iter().cloned())
+            .collect::<Vec<u8>>();
+        let body = String::from_utf8(body).unwrap();
+        for i in 0..50 {
+            assert!(body.contains(&format!("request_total{{authority=\"foo{}.test\"", i)));
+        }
+        parse_samples(&body);
+
+        // Cached scrapes must be formatted in full, so they are sent whole.
+        let cached = serve.with_scrape_cache_ttl(Some(Duration::from_secs(60)));
+        assert_eq!(chunks(&cached).len(), 1);
+    }
+
+    #[test]
+    fn streamed_scrapes_are_refused_when_the_workers_are_saturated() {
+        use std::thread;
+
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        for i in 0..200 {
+            let req = request(&format!("http://foo{}.test/", i), &proxy);
+            aggregate.record_event(&request_end(&req));
+            aggregate.record_event(&response_end(&req, None));
+        }
+
+        // A scrape whose body isn't read blocks its worker once it has
+        // formatted more than a chunk, until the write timeout, so these
+        // occupy every worker and fill the queue.
+        let pending = (0..SCRAPE_WORKERS + SCRAPE_QUEUE_CAPACITY)
+            .map(|_| serve.call(get("/metrics")).wait().expect("scrape"))
+            .collect::<Vec<_>>();
+        let (status, _, _) = serve.scrape_sync(get("/metrics"));
+        assert_eq!(status, StatusCode::ServiceUnavailable);
+
+        // Once their bodies are dropped, the workers are freed.
+        drop(pending);
+        for _ in 0..100 {
+            let rsp = serve.call(get("/metrics")).wait().expect("scrape");
+            if rsp.status() == StatusCode::Ok {
+                let body = rsp.body().concat2().wait().expect("scrape body");
+                let body = String::from_utf8(body.to_vec()).unwrap();
+                assert!(body.contains("request_total{authority=\"foo199.test\""));
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("scrapes were still refused after the workers were freed");
+    }
+
+    #[test]
+    fn streamed_scrapes_free_their_workers_when_their_readers_stall() {
+        use std::thread;
+
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        for i in 0..200 {
+            let req = request(&format!("http://foo{}.test/", i), &proxy);
+            aggregate.record_event(&request_end(&req));
+            aggregate.record_event(&response_end(&req, None));
+        }
+        let serve = Serve {
+            scrape_write_timeout: Duration::from_millis(50),
+            ..serve
+        };
+
+        // These bodies are never read, but are held until the end of the
+        // test, so the workers are only freed by the timeout.
+        let pending = (0..SCRAPE_WORKERS + SCRAPE_QUEUE_CAPACITY)
+            .map(|_| serve.call(get("/metrics")).wait().expect("scrape"))
+            .collect::<Vec<_>>();
+        for _ in 0..100 {
+            let rsp = serve.call(get("/metrics")).wait().expect("scrape");
+            if rsp.status() == StatusCode::Ok {
+                let body = rsp.body().concat2().wait().expect("scrape body");
+                let body = String::from_utf8(body.to_vec()).unwrap();
+                assert!(body.contains("request_total{authority=\"foo199.test\""));
+
+                // The first scrape must have been abandoned before a worker
+                // was free for this one, and its body ends with an error
+                // rather than looking complete.
+                let first = pending.into_iter().next().expect("first scrape");
+                assert_eq!(first.status(), StatusCode::Ok);
+                assert!(first.body().concat2().wait().is_err());
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("scrapes were still refused after their readers stalled");
+    }
+
+    #[test]
+    fn gzip_level_is_configurable() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        for i in 0..50 {
+            let req = request(&format!("http://foo{}.test/", i), &proxy);
+            aggregate.record_event(&request_end(&req));
+            aggregate.record_event(&response_end(&req, None));
+        }
+
+        let gzipped = |serve: &Serve| {
+            let mut req = get("/metrics");
+            req.headers_mut().set(AcceptEncoding(vec![QualityItem::new(
+                Encoding::Gzip,
+                hyper::header::q(1000),
+            )]));
+            let (status, body, headers) = serve.scrape_sync(req);
+            assert_eq!(status, StatusCode::Ok);
+            assert_eq!(
+                headers.get::<ContentEncoding>(),
+                Some(&ContentEncoding(vec![Encoding::Gzip]))
+            );
+            body
+        };
+        let fast = gzipped(&serve);
+        let best = gzipped(&serve.clone().with_gzip_level(Compression::Best));
+        assert!(
+            best.len() < fast.len(),
+            "best ({} bytes) should be smaller than fast ({} bytes)", best.len(), fast.len()
+        );
+    }
+
+    #[test]
+    fn gzip_is_not_used_when_refused() {
+        let process = ctx::Process::test("test");
+        let (_, serve) = new(&process);
+
+        let mut req = get("/metrics");
+        req.headers_mut().set(AcceptEncoding(vec![
+            QualityItem::new(Encoding::Gzip, hyper::header::q(0)),
+            QualityItem::new(Encoding::Identity, hyper::header::q(1000)),
+        ]));
+        let (status, body, headers) = serve.scrape_sync(req);
+        assert_eq!(status, StatusCode::Ok);
+        assert!(headers.get::<ContentEncoding>().is_none());
+        assert!(String::from_utf8(body).unwrap().contains("# TYPE process_start_time"));
+    }
+
+    #[test]
+    fn head_returns_the_scrape_headers() {
+        let process = ctx::Process::test("test");
+        let (_, serve) = new(&process);
+
+        let head = |gzip: bool| {
+            let mut req = HyperRequest::new(Method::Head, "/metrics".parse().unwrap());
+            if gzip {
+                req.headers_mut().set(AcceptEncoding(vec![
+                    QualityItem::new(Encoding::Gzip, hyper::header::q(1000)),
+                ]));
+            }
+            serve.scrape_sync(req)
+        };
+
+        let (status, body, headers) = head(false);
+        assert_eq!(status, StatusCode::Ok);
+        assert!(body.is_empty());
+        assert_eq!(headers.get::<ContentType>(), Some(&ContentType::plaintext()));
+        assert!(headers.get::<ContentEncoding>().is_none());
+
+        let (status, body, headers) = head(true);
+        assert_eq!(status, StatusCode::Ok);
+        assert!(body.is_empty());
+        assert_eq!(headers.get::<ContentEncoding>(), Some(&ContentEncoding(vec![Encoding::Gzip])));
+
+        // Checking the endpoint doesn't count as a scrape.
+        assert!(scrape(&serve).contains("metrics_scrapes_total 1\n"));
+    }
+
+    #[test]
+    fn json_is_nested_by_direction_destination_and_name() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::outbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let req = dst_request("http://foo.test/", &proxy, vec![("service", "web")]);
+        aggregate.record_event(&request_end(&req));
+        aggregate.record_event(&response_end(&req, None));
+
+        let (status, body, headers) = serve.scrape_sync(get("/metrics.json"));
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(headers.get::<ContentType>(), Some(&ContentType::json()));
+        assert!(headers.get::<ContentEncoding>().is_none());
+
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.starts_with(
+            "{\"outbound\":{\"dst_service=web\":{\"request_total\":[{\"labels\":{\
+                \"authority\":\"foo.test\",\"method\":\"GET\"},\"value\":1}],"),
+            "unexpected body {:?}", body);
+        assert!(body.contains(
+            "\"response_latency_ms\":[{\"labels\":{\"authority\":\"foo.test\",\"method\":\"GET\",\
+                \"classification\":\"success\",\"status_code\":\"200\"},\
+                \"value\":{\"count\":1,\"sum\":10,\"buckets\":[[1,0],"));
+        assert!(body.contains(",[\"+Inf\",0]]}}]"));
+        assert!(body.contains("},\"process\":{\"\":{"));
+        assert!(body.contains("\"metrics_scrapes_total\":[{\"labels\":{},\"value\":1}]"));
+        assert!(body.ends_with("}}}"));
+
+        // Serving JSON counts as a scrape.
+        assert!(scrape(&serve).contains("metrics_scrapes_total 2\n"));
+
+        // Gzip is still negotiated.
+        let mut req = get("/metrics.json");
+        req.headers_mut().set(AcceptEncoding(vec![
+            QualityItem::new(Encoding::Gzip, hyper::header::q(1000)),
+        ]));
+        let (status, body, headers) = serve.scrape_sync(req);
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(headers.get::<ContentType>(), Some(&ContentType::json()));
+        assert_eq!(headers.get::<ContentEncoding>(), Some(&ContentEncoding(vec![Encoding::Gzip])));
+        assert_eq!(&body[..2], &[0x1f, 0x8b]);
+    }
+
+    #[test]
+    fn bearer_token_is_required_if_configured() {
+        let process = ctx::Process::test("test");
+        let (_, serve) = new(&process);
+        let serve = serve.with_bearer_token("s3cr3t");
+
+        let with_token = |uri: &str, token: &str| {
+            let mut req = get(uri);
+            req.headers_mut().set(Authorization(Bearer { token: token.to_owned() }));
+            req
+        };
+
+        for req in vec![
+            get("/metrics"),
+            with_token("/metrics", "s3cr3"),
+            with_token("/metrics", "s3cr3t4"),
+            with_token("/metrics", "S3CR3T"),
+            with_token("/metrics.json", ""),
+            HyperRequest::new(Method::Post, "/metrics/reset".parse().unwrap()),
+        ] {
+            let (status, body, headers) = serve.scrape_sync(req);
+            assert_eq!(status, StatusCode::Unauthorized);
+            assert!(body.is_empty());
+            let challenge = headers.get_raw("WWW-Authenticate")
+                .and_then(|raw| raw.one())
+                .expect("WWW-Authenticate header");
+            assert_eq!(challenge, &b"Bearer realm=\"metrics\""[..]);
+        }
+
+        // Refused requests aren't counted as scrapes.
+        let (status, body, _) = serve.scrape_sync(with_token("/metrics", "s3cr3t"));
+        assert_eq!(status, StatusCode::Ok);
+        assert!(String::from_utf8(body).unwrap().contains("metrics_scrapes_total 1\n"));
+
+        let (status, _, _) = serve.scrape_sync(with_token("/metrics.json", "s3cr3t"));
+        assert_eq!(status, StatusCode::Ok);
+    }
+
+    #[test]
+    fn namespace_prefixes_every_metric_name() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let req = request("http://foo.test/", &proxy);
+        aggregate.record_event(&request_end(&req));
+        aggregate.record_event(&response_end(&req, None));
+
+        let unprefixed = scrape(&serve);
+        let prefixed = scrape(&serve.with_namespace("conduit"));
+        assert!(prefixed.contains("# HELP conduit_request_total A counter"));
+        assert!(prefixed.contains("# TYPE conduit_request_total counter\n"));
+        assert!(prefixed.contains("\nconduit_request_total{authority=\"foo.test\""));
+        assert!(prefixed.contains("\nconduit_response_latency_ms_bucket{"));
+        assert!(prefixed.contains("\nconduit_process_start_time_seconds "));
+
+        // Every name in the scrape is prefixed, including in comments.
+        let sample_names = |scrape: &str| {
+            scrape.lines()
+                .filter(|line| !line.is_empty())
+                .map(|line| {
+                    let line = line.trim_left_matches("# HELP ").trim_left_matches("# TYPE ");
+                    line.split(|c| c == '{' || c == ' ').next().unwrap().to_owned()
+                })
+                .collect::<Vec<_>>()
+        };
+        let prefixed_names = sample_names(&prefixed);
+        let unprefixed_names = sample_names(&unprefixed);
+        assert_eq!(prefixed_names.len(), unprefixed_names.len());
+        for (prefixed, unprefixed) in prefixed_names.iter().zip(&unprefixed_names) {
+            assert_eq!(*prefixed, format!("conduit_{}", unprefixed));
+        }
+        parse_samples(&prefixed);
+    }
+
+    #[test]
+    fn const_labels_are_added_to_every_series() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let req = request("http://foo.test/", &proxy);
+        aggregate.record_event(&request_end(&req));
+        aggregate.record_event(&response_end(&req, None));
+
+        let serve = serve
+            .with_namespace("conduit")
+            .with_const_labels(&[
+                ("pod".to_owned(), "web-0".to_owned()),
+                ("k8s.node".to_owned(), "say \"hi\"".to_owned()),
+            ]);
+        let labels = "pod=\"web-0\",k8s_node=\"say \\\"hi\\\"\"";
+        let labeled = scrape(&serve);
+        assert!(labeled.contains("# TYPE conduit_request_total counter\n"));
+        assert!(labeled.contains(&format!(
+            "\nconduit_request_total{{{},authority=\"foo.test\"", labels)));
+        assert!(labeled.contains(&format!(
+            "\nconduit_process_start_time_seconds{{{}}} ", labels)));
+
+        for line in labeled.lines().filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            assert!(line.contains(&format!("{{{}", labels)), "unlabeled series {:?}", line);
+        }
+        parse_samples(&labeled);
+
+        // Without any labels, nothing is added.
+        let serve = serve.with_const_labels(&[]);
+        assert!(scrape(&serve).contains("\nconduit_process_start_time_seconds "));
+    }
+
+    #[test]
+    fn const_labels_are_only_on_target_info_in_openmetrics() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let req = request("http://foo.test/", &proxy);
+        aggregate.record_event(&request_end(&req));
+        aggregate.record_event(&response_end(&req, None));
+
+        let serve = serve
+            .with_namespace("conduit")
+            .with_const_labels(&[
+                ("pod".to_owned(), "web-0".to_owned()),
+                ("node".to_owned(), "n1".to_owned()),
+            ]);
+        let openmetrics_scrape = |serve: &Serve| {
+            let mut req = get("/metrics");
+            let openmetrics = "application/openmetrics-text; version=1.0.0".parse().unwrap();
+            req.headers_mut().set(Accept(vec![
+                QualityItem::new(openmetrics, hyper::header::q(1000)),
+            ]));
+            let (status, body, _) = serve.scrape_sync(req);
+            assert_eq!(status, StatusCode::Ok);
+            String::from_utf8(body).unwrap()
+        };
+
+        // Streamed and formatted scrapes are labeled alike.
+        let cached = serve.clone().with_scrape_cache_ttl(Some(Duration::from_secs(60)));
+        for body in vec![openmetrics_scrape(&serve), openmetrics_scrape(&cached)] {
+            assert!(body.contains(
+                "# TYPE target info\ntarget_info{pod=\"web-0\",node=\"n1\"} 1\n"));
+            assert!(body.contains("\nconduit_request_total{authority=\"foo.test\""));
+            assert!(body.contains("\nconduit_process_start_time_seconds "));
+            assert!(body.ends_with("\n# EOF\n"));
+            assert!(!body.contains("\n\n"));
+
+            let labeled = body.lines()
+                .filter(|line| !line.starts_with("target_info{"))
+                .filter(|line| line.contains("web-0"))
+                .collect::<Vec<_>>();
+            assert!(labeled.is_empty(), "series repeat the target labels: {:?}", labeled);
+        }
+
+        // The Prometheus format still labels every series.
+        let body = scrape(&serve);
+        assert!(!body.contains("target_info"));
+        assert!(body.contains("\nconduit_process_start_time_seconds{pod=\"web-0\",node=\"n1\"} "));
+    }
+
+    #[test]
+    fn constant_time_eq_compares_bytes() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"toke"));
+        assert!(!constant_time_eq(b"", b"token"));
+    }
+
+    #[test]
+    fn scrape_sync_not_found() {
+        let process = ctx::Process::test("test");
+        let (_, serve) = new(&process);
+
+        let (status, body, _) = serve.scrape_sync(get("/nope"));
+        assert_eq!(status, StatusCode::NotFound);
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn metrics_path_is_configurable() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let serve = serve.with_path("/internal/prom").with_reset(true);
+        aggregate.record_event(&request_end(&request("http://foo.test/", &proxy)));
+
+        let body = scrape_uri(&serve, "/internal/prom?federate=1");
+        assert!(body.contains("request_total{"));
+
+        let (status, body, _) = serve.scrape_sync(get("/internal/prom.json"));
+        assert_eq!(status, StatusCode::Ok);
+        assert!(String::from_utf8(body).unwrap().contains("\"request_total\""));
+
+        for path in &["/metrics", "/metrics.json", "/internal/prometheus", "/internal"] {
+            let (status, _, _) = serve.scrape_sync(get(path));
+            assert_eq!(status, StatusCode::NotFound, "{}", path);
+        }
+
+        let reset = HyperRequest::new(Method::Post, "/internal/prom/reset".parse().unwrap());
+        let (status, _, _) = serve.scrape_sync(reset);
+        assert_eq!(status, StatusCode::Ok);
+        assert!(!scrape_uri(&serve, "/internal/prom").contains("request_total{"));
+    }
+
+    #[test]
+    fn recent_events_are_served_if_kept() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (aggregate, serve) = new(&process);
+        let (status, _, _) = serve.scrape_sync(get("/metrics/events"));
+        assert_eq!(status, StatusCode::NotFound);
+
+        let mut aggregate = aggregate.with_recent_events(2);
+        let (status, body, headers) = serve.scrape_sync(get("/metrics/events"));
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(headers.get::<ContentType>(), Some(&ContentType::json()));
+        assert_eq!(body, b"[]");
+
+        let ctx = Arc::new(ctx::transport::Ctx::Server(server(&proxy)));
+        aggregate.record_event(&Event::TransportOpen(ctx));
+        let req = request("http://foo.test/", &proxy);
+        aggregate.record_event(&request_end(&req));
+        aggregate.record_event(&response_end(&req, None));
+
+        // Only the two most recent events are kept, oldest first.
+        let (_, body, _) = serve.scrape_sync(get("/metrics/events"));
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.starts_with("[{\"at\":"), "{}", body);
+        assert!(!body.contains("transport_open"), "{}", body);
+        let request_at = body
+            .find("\"event\":\"stream_request_end\",\"labels\":{\"authority\":\"foo.test\",")
+            .expect("request end");
+        let response_at = body.find("\"event\":\"stream_response_end\"").expect("response end");
+        assert!(request_at < response_at);
+        assert!(body.contains("\"status_code\":\"200\""), "{}", body);
+        assert!(body.contains(",\"bytes_sent\":0"), "{}", body);
+        assert!(body.ends_with("}]"));
+
+        // Repeated events are kept once for each repetition.
+        aggregate.record_repeated_event(&request_end(&req), 2);
+        let (_, body, _) = serve.scrape_sync(get("/metrics/events"));
+        let body = String::from_utf8(body).unwrap();
+        assert_eq!(body.matches("stream_request_end").count(), 2, "{}", body);
+    }
+
+    #[test]
+    fn system_serves_only_process_metrics() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        aggregate.record_event(&request_end(&request("http://foo.test/", &proxy)));
+
+        let (status, body, headers) = serve.scrape_sync(get("/metrics/system"));
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(headers.get::<ContentType>(), Some(&ContentType::plaintext()));
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("process_start_time_seconds "), "{}", body);
+        assert!(!body.contains("request_total"), "{}", body);
+        assert!(!body.contains("tcp_open_total"), "{}", body);
+
+        // Polling the process metrics isn't counted as a scrape.
+        let (_, body, _) = serve.scrape_sync(get("/metrics/system"));
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("metrics_scrapes_total 0\n"), "{}", body);
+        assert!(scrape(&serve).contains("request_total{"));
+    }
+
+    #[test]
+    fn reset_is_not_found_unless_allowed() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        aggregate.record_event(&request_end(&request("http://foo.test/", &proxy)));
+
+        for method in vec![Method::Post, Method::Get] {
+            let reset = HyperRequest::new(method, "/metrics/reset".parse().unwrap());
+            let (status, _, _) = serve.scrape_sync(reset);
+            assert_eq!(status, StatusCode::NotFound);
+        }
+        assert!(scrape(&serve).contains("request_total{"));
+    }
+
+    #[test]
+    fn reset_requires_the_bearer_token_if_configured() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let serve = serve.with_reset(true).with_bearer_token("s3cr3t");
+        aggregate.record_event(&request_end(&request("http://foo.test/", &proxy)));
+        let reset = |token: Option<&str>| {
+            let mut req = HyperRequest::new(Method::Post, "/metrics/reset".parse().unwrap());
+            if let Some(token) = token {
+                req.headers_mut().set(Authorization(Bearer { token: token.to_owned() }));
+            }
+            let (status, _, _) = serve.scrape_sync(req);
+            status
+        };
+        let scrape = || {
+            let mut req = get("/metrics");
+            req.headers_mut().set(Authorization(Bearer { token: "s3cr3t".to_owned() }));
+            let (_, body, _) = serve.scrape_sync(req);
+            String::from_utf8(body).unwrap()
+        };
+
+        assert_eq!(reset(None), StatusCode::Unauthorized);
+        assert_eq!(reset(Some("s3cr3")), StatusCode::Unauthorized);
+        assert!(scrape().contains("request_total{"));
+
+        assert_eq!(reset(Some("s3cr3t")), StatusCode::Ok);
+        assert!(!scrape().contains("request_total{"));
+    }
+
+    #[test]
+    fn reset_clears_recorded_metrics() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let serve = serve
+            .with_scrape_cache_ttl(Some(Duration::from_secs(60)))
+            .with_reset(true);
+        let req = request("http://foo.test/", &proxy);
+        let ctx = Arc::new(ctx::transport::Ctx::Server(server(&proxy)));
+        aggregate.record_event(&Event::TransportOpen(Arc::clone(&ctx)));
+        aggregate.record_event(&request_end(&req));
+        aggregate.record_event(&response_end(&req, None));
+        aggregate.record_events_dropped(3);
+
+        let before = scrape(&serve);
+        assert!(before.contains("request_total{"));
+        assert!(before.contains("response_latency_ms_count{"));
+        assert!(before.contains("telemetry_events_dropped_total 3\n"));
+
+        let (status, _, headers) = serve.scrape_sync(get("/metrics/reset"));
+        assert_eq!(status, StatusCode::MethodNotAllowed);
+        assert_eq!(headers.get::<Allow>(), Some(&Allow(vec![Method::Post])));
+        assert_eq!(scrape(&serve), before);
+
+        let reset = HyperRequest::new(Method::Post, "/metrics/reset".parse().unwrap());
+        let (status, _, _) = serve.scrape_sync(reset);
+        assert_eq!(status, StatusCode::Ok);
+
+        let after = scrape(&serve);
+        assert!(!after.contains("request_total{"));
+        assert!(!after.contains("response_latency_ms_count{"));
+        assert!(!after.contains("tcp_open_total{"));
+        assert!(after.contains("telemetry_events_dropped_total 0\n"));
+        assert!(after.contains("metrics_scrapes_total 1\n"));
+        // Connections which are still open are still counted.
+        assert!(after.contains("tcp_open_connections{direction=\"inbound\",peer=\"src\"} 1\n"));
+        let start_time = |scrape: &str| {
+            scrape.lines()
+                .find(|l| l.starts_with("process_start_time_seconds "))
+                .map(str::to_owned)
+        };
+        assert_eq!(start_time(&after), start_time(&before));
+        parse_samples(&after);
+    }
+
+    #[test]
+    fn snapshot_matches_the_scrape() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::outbound(&process);
+        let (aggregate, serve) = new(&process);
+        let mut aggregate = aggregate
+            .with_request_header_bytes(true)
+            .with_requests_by_authority(true);
+        let req = dst_request("http://foo.test/", &proxy, vec![("service", "foo")]);
+        aggregate.record_event(&Event::StreamRequestOpen(Arc::clone(&req)));
+        aggregate.record_event(&request_end(&req));
+        aggregate.record_event(&request_end(&req));
+        aggregate.record_event(&response_end(&req, None));
+        let ctx = Arc::new(ctx::transport::Ctx::Client(client(&proxy, None)));
+        aggregate.record_event(&Event::TransportOpen(ctx));
+
+        let snapshot = serve.snapshot();
+        let names = snapshot.metrics.iter().map(|m| m.name).collect::<Vec<_>>();
+        let described = serve.describe().into_iter().map(|d| d.name).collect::<Vec<_>>();
+        assert_eq!(names, described);
+
+        let request_total = snapshot.get("request_total").expect("request_total");
+        assert_eq!(request_total.series, vec![SeriesSnapshot {
+            labels: vec![
+                ("authority".to_owned(), "foo.test".to_owned()),
+                ("direction".to_owned(), "outbound".to_owned()),
+                ("method".to_owned(), "GET".to_owned()),
+                ("dst_service".to_owned(), "foo".to_owned()),
+            ],
+            value: Value::Counter(2),
+        }]);
+
+        let by_authority = snapshot.get("request_by_authority_total").expect("by authority");
+        assert_eq!(by_authority.series[0].value, Value::Counter(2));
+
+        let open = snapshot.get("tcp_open_connections").expect("tcp_open_connections");
+        assert_eq!(open.series[0].value, Value::Gauge(1));
+
+        match snapshot.get("response_latency_ms").expect("response_latency_ms").series[0].value {
+            Value::Histogram(ref histogram) => {
+                assert_eq!(histogram.count(), 1);
+                assert_eq!(histogram.sum, 10.0);
+                let (bound, _) = *histogram.buckets.last().unwrap();
+                assert_eq!(bound, ::std::f64::INFINITY);
+                assert!(histogram.buckets.iter().any(|&(le, n)| le == 10.0 && n == 1));
+            },
+            ref value => panic!("unexpected value {:?}", value),
+        }
+
+        match snapshot.get("request_header_bytes").expect("request_header_bytes").series[0].value {
+            Value::Histogram(ref histogram) => assert_eq!(histogram.count(), 1),
+            ref value => panic!("unexpected value {:?}", value),
+        }
+    }
+
+    #[test]
+    fn scrape_selects_metrics_by_name() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let req = request("http://foo.test/", &proxy);
+        let ctx = Arc::new(ctx::transport::Ctx::Server(server(&proxy)));
+        aggregate.record_event(&Event::TransportOpen(ctx));
+        aggregate.record_event(&request_end(&req));
+        aggregate.record_event(&response_end(&req, None));
+
+        let selected = scrape_uri(
+            &serve,
+            "/metrics?name[]=request_total&name%5B%5D=tcp_open_total&name[]=unknown",
+        );
+        let mut names = parse_samples(&selected)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        names.dedup();
+        assert_eq!(names, vec!["request_total", "tcp_open_total"]);
+
+        // Selected names may include the namespace.
+        let serve = serve.with_namespace("conduit");
+        let selected = scrape_uri(&serve, "/metrics?name[]=conduit_response_total");
+        assert!(selected.contains("# TYPE conduit_response_total counter\n"));
+        assert!(!selected.contains("conduit_request_total"));
+
+        // Without any selected names, every metric is written.
+        let all = scrape(&serve);
+        assert!(all.contains("# TYPE conduit_request_total counter\n"));
+        assert!(all.contains("# TYPE conduit_process_start_time_seconds gauge\n"));
+    }
+
+    #[test]
+    fn recording_proceeds_during_a_slow_write() {
+        /// Records a request each time the scrape is written to, as if
+        /// requests completed while a slow client read the scrape.
+        struct SlowWrite {
+            aggregate: Aggregate,
+            req: Arc<ctx::http::Request>,
+            writes: usize,
+        }
+
+        impl fmt::Write for SlowWrite {
+            fn write_str(&mut self, _: &str) -> fmt::Result {
+                assert!(self.aggregate.shards.inbound.try_lock().is_ok());
+                assert!(self.aggregate.shards.outbound.try_lock().is_ok());
+                self.aggregate.record_event(&request_end(&self.req));
+                self.writes += 1;
+                Ok(())
+            }
+        }
+
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (aggregate, serve) = new(&process);
+        let mut w = SlowWrite {
+            aggregate,
+            req: request("http://foo.test/", &proxy),
+            writes: 0,
+        };
+        serve.write_scrape("", false, &mut w);
+        assert!(w.writes > 0);
+
+        let expected = format!(
+            "request_total{{authority=\"foo.test\",direction=\"inbound\",method=\"GET\"}} {}\n",
+            w.writes,
+        );
+        assert!(scrape(&serve).contains(&expected));
+    }
+
+    #[test]
+    fn empty_scrape_is_valid() {
+        let process = ctx::Process::test("test");
+        let (_, serve) = new(&process);
+
+        let mut names = parse_samples(&scrape(&serve))
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        names.dedup();
+        assert_eq!(names, vec![
+            "telemetry_events_dropped_total",
+            "telemetry_queue_depth",
+            "telemetry_queue_capacity",
+            "authority_sanitized_total",
+            "metrics_scrapes_total",
+            "metrics_scrape_errors_total",
+            "metrics_scrape_lock_hold_ms_bucket",
+            "metrics_scrape_lock_hold_ms_count",
+            "metrics_scrape_lock_hold_ms_sum",
+            "metrics_scrape_duration_ms_bucket",
+            "metrics_scrape_duration_ms_count",
+            "metrics_scrape_duration_ms_sum",
+            "metrics_series_total",
+            "shutdown_initiated_time_seconds",
+            "drain_duration_ms_bucket",
+            "drain_duration_ms_count",
+            "drain_duration_ms_sum",
+            "process_start_time_seconds",
+            "build_timestamp_seconds",
+            "proxy_build_info",
+            "metrics_series_truncated",
+        ]);
+    }
+
+    #[test]
+    fn concurrent_scrapes_share_a_formatted_body() {
+        use std::sync::Barrier;
+        use std::thread;
+
+        let process = ctx::Process::test("test");
+        let (_, serve) = new(&process);
+        let serve = serve.with_scrape_cache_ttl(Some(Duration::from_secs(60)));
+
+        let barrier = Arc::new(Barrier::new(2));
+        let scrapes = (0..2)
+            .map(|_| {
+                let serve = serve.clone();
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    barrier.wait();
+                    scrape(&serve)
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|t| t.join().expect("scrape thread panicked"))
+            .collect::<Vec<_>>();
+
+        assert_eq!(scrapes[0], scrapes[1]);
+        assert!(scrapes[0].contains("metrics_scrapes_total 1\n"));
+
+        // Scrapes with a different query are formatted separately.
+        let federated = scrape_uri(&serve, "/metrics?federate=1");
+        assert_ne!(federated, scrapes[0]);
+        let all = serve.with_scrape_cache_ttl(None);
+        assert!(scrape(&all).contains("metrics_scrapes_total 3\n"));
+    }
+
+    #[test]
+    fn format_failure_truncates_scrape() {
+        /// Fails any write that would take it past `limit` bytes.
+        struct FailAfter {
+            buf: String,
+            limit: usize,
+        }
+
+        impl fmt::Write for FailAfter {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                if self.buf.len() + s.len() > self.limit {
+                    return Err(fmt::Error);
+                }
+                self.buf.push_str(s);
+                Ok(())
+            }
+        }
+
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let mut metrics = Metrics::new(&process);
+        metrics.request_total(&RequestLabelsRef::new(&request("http://foo.test/", &proxy)))
+            .incr();
+
+        let mut w = FailAfter { buf: String::new(), limit: 1_000 };
+        metrics.fmt_scrape(&DstMatcher::default(), ScrapeOptions::from_query(""), &mut w);
+
+        assert!(w.buf.contains(
+            "request_total{authority=\"foo.test\",direction=\"inbound\",method=\"GET\"} 1\n"));
+        assert!(!w.buf.contains("process_start_time_seconds"));
+        parse_samples(&w.buf);
+        assert_eq!(Into::<u64>::into(metrics.scrape_errors_total.value), 1);
+
+        let mut body = String::new();
+        metrics.fmt_scrape(&DstMatcher::default(), ScrapeOptions::from_query(""), &mut body);
+        assert!(body.contains("metrics_scrape_errors_total 1\n"));
+        assert!(body.contains("process_start_time_seconds"));
+    }
+
+    #[test]
+    fn help_text_describes_every_metric() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+        let req = request("http://foo.test/", &proxy);
+        let ctx = Arc::new(ctx::transport::Ctx::Server(server(&proxy)));
+        aggregate.record_event(&Event::TransportOpen(Arc::clone(&ctx)));
+        aggregate.record_event(&request_end(&req));
+        aggregate.record_event(&response_end(&req, None));
+
+        let help = serve.help_text();
+        assert!(parse_samples(&help).is_empty());
+
+        let helped = help.lines()
+            .filter(|l| l.starts_with("# HELP "))
+            .map(|l| l.split(' ').nth(2).unwrap())
+            .collect::<Vec<_>>();
+        let typed = help.lines()
+            .filter(|l| l.starts_with("# TYPE "))
+            .map(|l| l.split(' ').nth(2).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(helped, typed);
+
+        let scrape = scrape(&serve);
+        for (name, _) in parse_samples(&scrape) {
+            assert!(
+                typed.iter().any(|t| name.starts_with(t)),
+                "{} is not described by the help text", name
+            );
+        }
+        let scraped_help = scrape.lines()
+            .filter(|l| l.starts_with("# "))
+            .map(|l| format!("{}\n", l))
+            .collect::<String>();
+        assert_eq!(help, scraped_help);
+
+        let (status, body, headers) = serve.scrape_sync(get("/metrics/help"));
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(headers.get::<ContentType>(), Some(&ContentType::plaintext()));
+        assert_eq!(String::from_utf8(body).unwrap(), help);
+    }
+
+    #[test]
+    fn descriptors_are_served_as_json() {
+        let process = ctx::Process::test("test");
+        let (_, serve) = new(&process);
+
+        let (status, body, headers) = serve.scrape_sync(get("/metrics/describe"));
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(headers.get::<ContentType>(), Some(&ContentType::json()));
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.starts_with("[{\"name\":\"request_total\",\"kind\":\"counter\","), "{}", body);
+        assert!(body.contains(
+            "{\"name\":\"response_latency_ms\",\"kind\":\"histogram\",\
+             \"help\":\"A histogram of the total latency of a response. This is \
+             measured from when the request headers are received to when the \
+             response stream has completed.\",\"unit\":\"milliseconds\",\"label_keys\":[\"authority\",\"direction\","),
+            "{}", body);
+        assert!(body.contains("\"unit\":null,"), "{}", body);
+        assert!(body.ends_with("]}]"), "{}", body);
+        assert_eq!(body.matches("\"name\":").count(), serve.describe().len());
+    }
+
+    #[test]
+    fn descriptors_match_the_scrape() {
+        let process = ctx::Process::test("test");
+        let (aggregate, serve) = new(&process);
+        let _aggregate = aggregate
+            .with_request_header_bytes(true)
+            .with_response_byte_latency(true);
+
+        let described = serve.describe().into_iter()
+            .map(|d| (d.name.to_owned(), d.kind.to_owned()))
+            .collect::<Vec<_>>();
+        let typed = scrape(&serve).lines()
+            .filter(|l| l.starts_with("# TYPE "))
+            .map(|l| {
+                let mut parts = l.split(' ').skip(2);
+                let name = parts.next().unwrap().to_owned();
+                let kind = parts.next().unwrap().to_owned();
+                (name, kind)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(described, typed);
+    }
+
+    #[test]
+    fn scrape_records_lock_hold_time() {
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let (mut aggregate, serve) = new(&process);
+
+        aggregate.record_event(&request_end(&request("http://foo.test/", &proxy)));
+
+        let first = scrape(&serve);
+        assert!(first.contains("metrics_scrape_lock_hold_ms_count 0\n"));
+
+        let second = scrape(&serve);
+        assert!(second.contains("metrics_scrape_lock_hold_ms_count 1\n"));
+    }
+
+    #[test]
+    fn scrape_of_a_populated_tree_holds_the_locks() {
+        let process = ctx::Process::test("test");
+        let (mut aggregate, serve) = new(&process);
+        for proxy in &[ctx::Proxy::inbound(&process), ctx::Proxy::outbound(&process)] {
+            let events = (0..500).flat_map(|i| {
+                let req = request(&format!("http://foo{}.test/", i), proxy);
+                vec![request_end(&req), response_end(&req, None)]
+            }).collect::<Vec<_>>();
+            aggregate.record_batch(&events);
+        }
+
+        // A scrape's own lock hold is only recorded after it is formatted.
+        scrape(&serve);
+        let hold = parse_samples(&scrape(&serve)).into_iter()
+            .find(|&(ref name, _)| name == "metrics_scrape_lock_hold_ms_sum")
+            .map(|(_, value)| value.parse::<f64>().expect("sum is a number"))
+            .expect("lock hold is recorded");
+        assert!(hold > 0.0, "lock hold was {}ms", hold);
+    }
+
+    #[test]
+    fn scrape_records_its_duration() {
+        let process = ctx::Process::test("test");
+        let (_, serve) = new(&process);
+        let serve = serve.with_scrape_cache_ttl(Some(Duration::from_secs(60)));
+
+        let first = scrape(&serve);
+        assert!(first.contains("# TYPE metrics_scrape_duration_ms histogram\n"));
+        assert!(first.contains("metrics_scrape_duration_ms_count 0\n"));
+
+        // Cached scrapes aren't formatted, so they aren't timed.
+        assert_eq!(scrape(&serve), first);
+
+        let (_, body, _) = serve.scrape_sync(get("/metrics.json"));
+        assert!(String::from_utf8(body).unwrap().contains("\"metrics_scrape_duration_ms\":[{"));
+
+        let uncached = scrape_uri(&serve, "/metrics?no_buckets=1");
+        assert!(uncached.contains("metrics_scrape_duration_ms_count 2\n"));
+    }
+}
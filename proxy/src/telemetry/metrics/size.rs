@@ -0,0 +1,139 @@
+#![deny(missing_docs)]
+use std::{fmt, iter, ops, slice, u64};
+use std::num::Wrapping;
+use super::Counter;
+
+/// The number of buckets in a size histogram.
+pub const NUM_BUCKETS: usize = 10;
+
+/// The maximum value (inclusive) for each size bucket, in bytes.
+pub const BUCKET_BOUNDS: [Bytes; NUM_BUCKETS] = [
+    Bytes(256),
+    Bytes(512),
+    Bytes(1_024),
+    Bytes(2_048),
+    Bytes(4_096),
+    Bytes(8_192),
+    Bytes(16_384),
+    Bytes(32_768),
+    Bytes(65_536),
+    // As with latencies, the max bucket must be created explicitly.
+    Bytes(u64::MAX),
+];
+
+/// A series of size values and counts.
+#[derive(Debug, Default, Clone)]
+pub struct SizeHistogram {
+
+    /// Buckets in which to count sizes.
+    ///
+    /// The upper bound of a given bucket `i` is given in `BUCKET_BOUNDS[i]`.
+    buckets: [Counter; NUM_BUCKETS],
+
+    /// The total sum of all observed sizes, in bytes.
+    ///
+    /// Like latency histogram sums, this explicitly wraps on overflow.
+    sum: Wrapping<u64>,
+}
+
+/// A size in bytes.
+#[derive(Debug, Default, Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Hash)]
+pub struct Bytes(u64);
+
+// ===== impl SizeHistogram =====
+
+impl SizeHistogram {
+
+    /// Returns the upper bound of each bucket in this histogram.
+    pub fn bounds(&self) -> &'static [Bytes] {
+        &BUCKET_BOUNDS
+    }
+
+    /// Observe a measurement
+    pub fn observe<I>(&mut self, measurement: I)
+    where
+        I: Into<Bytes>,
+    {
+        let measurement = measurement.into();
+        let i = BUCKET_BOUNDS.iter()
+            .position(|max| &measurement <= max)
+            .expect("size value greater than u64::MAX; this shouldn't be \
+                     possible.");
+        self.buckets[i].incr();
+        self.sum += Wrapping(measurement.0);
+    }
+
+    /// Return the sum value of this histogram in bytes.
+    pub fn sum(&self) -> u64 {
+        self.sum.0
+    }
+
+    /// Adds the observations of another histogram to this one.
+    pub fn merge(&mut self, other: &SizeHistogram) {
+        for (count, other) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *count = *count + *other;
+        }
+        self.sum += other.sum;
+    }
+}
+
+impl<I> ops::AddAssign<I> for SizeHistogram
+where
+    I: Into<Bytes>
+{
+    #[inline]
+    fn add_assign(&mut self, measurement: I) {
+        self.observe(measurement)
+    }
+}
+
+impl<'a> IntoIterator for &'a SizeHistogram {
+    type Item = u64;
+    type IntoIter = iter::Map<
+        slice::Iter<'a, Counter>,
+        fn(&'a Counter) -> u64
+    >;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.buckets.iter().map(|&count| count.into())
+    }
+}
+
+// ===== impl Bytes =====
+
+impl From<usize> for Bytes {
+    #[inline]
+    fn from(value: usize) -> Self {
+        Bytes(value as u64)
+    }
+}
+
+impl From<u64> for Bytes {
+    #[inline]
+    fn from(value: u64) -> Self {
+        Bytes(value)
+    }
+}
+
+impl Into<f64> for Bytes {
+    /// Converts the size to a float, with the maximum size converted to
+    /// infinity.
+    fn into(self) -> f64 {
+        if self.0 == u64::MAX {
+            ::std::f64::INFINITY
+        } else {
+            self.0 as f64
+        }
+    }
+}
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == u64::MAX {
+            // As with latencies, the max bound is the infinity bucket.
+            write!(f, "+Inf")
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
@@ -0,0 +1,294 @@
+//! Copies of the current values of the metrics, for consumers in the same
+//! process which would otherwise have to scrape and parse them, and for
+//! consumers which can't parse the Prometheus text format.
+
+use std::fmt::Write;
+
+use indexmap::IndexMap;
+
+use super::MetricDescriptor;
+
+/// The value of every metric that a scrape would include, as of when the
+/// snapshot was taken.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub metrics: Vec<MetricSnapshot>,
+}
+
+/// The value of each series of a metric.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricSnapshot {
+    pub name: &'static str,
+    pub series: Vec<SeriesSnapshot>,
+}
+
+/// The value of a single series, and the labels which identify it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeriesSnapshot {
+    /// The label keys and values of the series, as they would be scraped.
+    pub labels: Vec<(String, String)>,
+
+    pub value: Value,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Counter(u64),
+    Gauge(u64),
+    Histogram(HistogramSnapshot),
+    Summary(SummarySnapshot),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistogramSnapshot {
+    /// The upper bound of each bucket, and the number of values observed in
+    /// that bucket alone.
+    ///
+    /// Bounds are in the histogram's unit, such as milliseconds for
+    /// latencies. The last bucket is bounded by infinity.
+    pub buckets: Vec<(f64, u64)>,
+
+    /// The sum of all observed values, in the histogram's unit.
+    pub sum: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SummarySnapshot {
+    /// Each quantile, and its value among the values observed within the
+    /// summary's window, in the summary's unit.
+    pub quantiles: Vec<(f64, f64)>,
+
+    /// The number of values ever observed.
+    pub count: u64,
+
+    /// The sum of all values ever observed, in the summary's unit.
+    pub sum: f64,
+}
+
+// ===== impl Snapshot =====
+
+impl Snapshot {
+    /// Returns the metric named `name`, if it is included in the snapshot.
+    // Not yet used by any consumer in the proxy itself.
+    #[allow(dead_code)]
+    pub fn get(&self, name: &str) -> Option<&MetricSnapshot> {
+        self.metrics.iter().find(|metric| metric.name == name)
+    }
+
+    /// Serializes the snapshot as a JSON object.
+    ///
+    /// Series are nested by their `direction` label, then by their `dst_*`
+    /// labels, and then by metric name, such as:
+    ///
+    /// ```json
+    /// {"outbound":{"dst_service=web":{"request_total":[
+    ///   {"labels":{"authority":"web.test","method":"GET"},"value":3}
+    /// ]}}}
+    /// ```
+    ///
+    /// Series without a direction, such as the process metrics, are nested
+    /// under `"process"`, and series without destination labels under `""`.
+    /// Destination labels are keyed by each `key=value` pair, in order,
+    /// separated by commas.
+    ///
+    /// Counters and gauges are written as numbers. Histograms are written as
+    /// objects holding their `count`, `sum`, and `buckets`, an array of
+    /// `[upper bound, count]` pairs whose last bound is `"+Inf"`. Summaries
+    /// are written as objects holding their `count`, `sum`, and `quantiles`,
+    /// an array of `[quantile, value]` pairs; a quantile whose value is not
+    /// known is `null`.
+    pub fn to_json(&self) -> String {
+        let mut tree = IndexMap::<&str, IndexMap<String, IndexMap<&str, Vec<_>>>>::new();
+        for metric in &self.metrics {
+            for series in &metric.series {
+                let mut direction = "process";
+                let mut dst = String::new();
+                let mut labels = Vec::with_capacity(series.labels.len());
+                for &(ref key, ref value) in &series.labels {
+                    if key == "direction" {
+                        direction = value.as_str();
+                    } else if key.starts_with("dst_") {
+                        if !dst.is_empty() {
+                            dst.push(',');
+                        }
+                        dst.push_str(key);
+                        dst.push('=');
+                        dst.push_str(value);
+                    } else {
+                        labels.push((key, value));
+                    }
+                }
+
+                tree.entry(direction).or_insert_with(IndexMap::new)
+                    .entry(dst).or_insert_with(IndexMap::new)
+                    .entry(metric.name).or_insert_with(Vec::new)
+                    .push((labels, &series.value));
+            }
+        }
+
+        let mut json = String::new();
+        json.push('{');
+        for (i, (direction, dsts)) in tree.iter().enumerate() {
+            push_key(&mut json, i, direction);
+            json.push('{');
+            for (j, (dst, names)) in dsts.iter().enumerate() {
+                push_key(&mut json, j, dst);
+                json.push('{');
+                for (k, (name, series)) in names.iter().enumerate() {
+                    push_key(&mut json, k, name);
+                    json.push('[');
+                    for (l, &(ref labels, value)) in series.iter().enumerate() {
+                        if l > 0 {
+                            json.push(',');
+                        }
+                        json.push_str("{\"labels\":{");
+                        for (m, &(key, value)) in labels.iter().enumerate() {
+                            push_key(&mut json, m, key);
+                            push_string(&mut json, value);
+                        }
+                        json.push_str("},\"value\":");
+                        value.push_json(&mut json);
+                        json.push('}');
+                    }
+                    json.push(']');
+                }
+                json.push('}');
+            }
+            json.push('}');
+        }
+        json.push('}');
+        json
+    }
+}
+
+// ===== impl Value =====
+
+impl Value {
+    /// Appends the value to `json`, as described by `Snapshot::to_json`.
+    fn push_json(&self, json: &mut String) {
+        match *self {
+            Value::Counter(n) | Value::Gauge(n) => {
+                write!(json, "{}", n).expect("writing to a String cannot fail");
+            },
+            Value::Histogram(ref histogram) => {
+                write!(json, "{{\"count\":{},\"sum\":", histogram.count())
+                    .expect("writing to a String cannot fail");
+                push_number(json, histogram.sum);
+                json.push_str(",\"buckets\":[");
+                for (i, &(bound, count)) in histogram.buckets.iter().enumerate() {
+                    if i > 0 {
+                        json.push(',');
+                    }
+                    json.push('[');
+                    if bound.is_finite() {
+                        push_number(json, bound);
+                    } else {
+                        json.push_str("\"+Inf\"");
+                    }
+                    write!(json, ",{}]", count).expect("writing to a String cannot fail");
+                }
+                json.push_str("]}");
+            },
+            Value::Summary(ref summary) => {
+                write!(json, "{{\"count\":{},\"sum\":", summary.count)
+                    .expect("writing to a String cannot fail");
+                push_number(json, summary.sum);
+                json.push_str(",\"quantiles\":[");
+                for (i, &(quantile, value)) in summary.quantiles.iter().enumerate() {
+                    if i > 0 {
+                        json.push(',');
+                    }
+                    json.push('[');
+                    push_number(json, quantile);
+                    json.push(',');
+                    push_number(json, value);
+                    json.push(']');
+                }
+                json.push_str("]}");
+            },
+        }
+    }
+}
+
+// ===== impl HistogramSnapshot =====
+
+impl HistogramSnapshot {
+    /// Returns the total number of observed values.
+    // Not yet used by any consumer in the proxy itself.
+    #[allow(dead_code)]
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|&(_, count)| count).sum()
+    }
+}
+
+/// Serializes `descriptors` as a JSON array with an object for each metric,
+/// holding its `name`, `kind`, `help`, `unit`, which is `null` if it has
+/// none, and `label_keys`.
+pub fn describe_to_json(descriptors: &[MetricDescriptor]) -> String {
+    let mut json = String::from("[");
+    for (i, metric) in descriptors.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        json.push('{');
+        push_key(&mut json, 0, "name");
+        push_string(&mut json, metric.name);
+        push_key(&mut json, 1, "kind");
+        push_string(&mut json, metric.kind);
+        push_key(&mut json, 2, "help");
+        push_string(&mut json, metric.help);
+        push_key(&mut json, 3, "unit");
+        match metric.unit {
+            Some(unit) => push_string(&mut json, unit),
+            None => json.push_str("null"),
+        }
+        push_key(&mut json, 4, "label_keys");
+        json.push('[');
+        for (i, key) in metric.label_keys.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            push_string(&mut json, key);
+        }
+        json.push_str("]}");
+    }
+    json.push(']');
+    json
+}
+
+/// Appends an object key to `json`, preceded by a comma unless it is the
+/// object's first key.
+pub(super) fn push_key(json: &mut String, index: usize, key: &str) {
+    if index > 0 {
+        json.push(',');
+    }
+    push_string(json, key);
+    json.push(':');
+}
+
+/// Appends `s` to `json` as a JSON string.
+pub(super) fn push_string(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => {
+                write!(json, "\\u{:04x}", c as u32).expect("writing to a String cannot fail");
+            },
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+/// Appends `n` to `json` as a JSON number, or as `null` if it is NaN or
+/// infinite, which JSON numbers can't represent.
+pub(super) fn push_number(json: &mut String, n: f64) {
+    if n.is_finite() {
+        write!(json, "{}", n).expect("writing to a String cannot fail");
+    } else {
+        json.push_str("null");
+    }
+}
@@ -0,0 +1,243 @@
+//! Exports metrics to a StatsD server, as an alternative to serving scrapes.
+//!
+//! Each telemetry event is sent as it is recorded, as a single UDP datagram
+//! in the DogStatsD line protocol. Metrics have the same names as they do in
+//! scrapes, and their labels are sent as tags, formatted from the same label
+//! types.
+
+use std::fmt::{self, Write};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use telemetry::event::Event;
+use super::labels::{
+    label_pairs,
+    AcceptErrorLabels,
+    RequestLabels,
+    ResponseLabels,
+    TransportLabels,
+    TransportCloseLabels,
+};
+
+/// Sends the metrics for each telemetry event to a StatsD server over UDP.
+#[derive(Debug)]
+pub struct StatsdSink {
+    socket: UdpSocket,
+
+    /// The address of the StatsD server.
+    addr: SocketAddr,
+
+    /// Holds the lines for each event as it is formatted, so that the buffer
+    /// is reused between events.
+    buf: String,
+}
+
+// ===== impl StatsdSink =====
+
+impl StatsdSink {
+    /// Binds a UDP socket from which metrics are sent to the StatsD server
+    /// at `addr`.
+    pub fn new(addr: SocketAddr) -> io::Result<Self> {
+        let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let local: SocketAddr = local.parse().expect("unspecified address must be valid");
+        let socket = UdpSocket::bind(local)?;
+        // The telemetry loop must never block on the StatsD server.
+        socket.set_nonblocking(true)?;
+        Ok(StatsdSink {
+            socket,
+            addr,
+            buf: String::new(),
+        })
+    }
+
+    /// Sends the metrics for the given event.
+    ///
+    /// Metrics which cannot be sent immediately are dropped.
+    pub fn record_event(&mut self, event: &Event) {
+        self.buf.clear();
+        fmt_event(&mut self.buf, event).expect("formatting into a String cannot fail");
+        let lines = self.buf.trim_right_matches('\n');
+        if lines.is_empty() {
+            return;
+        }
+
+        if let Err(e) = self.socket.send_to(lines.as_bytes(), &self.addr) {
+            debug!("failed to send metrics to statsd at {}: {}", self.addr, e);
+        }
+    }
+}
+
+/// Writes a line for each metric that the event updates.
+///
+/// Response latency is always measured from when the request was opened.
+fn fmt_event<W: Write>(w: &mut W, event: &Event) -> fmt::Result {
+    match *event {
+        Event::StreamRequestOpen(_) | Event::StreamResponseOpen(_, _) => Ok(()),
+
+        Event::StreamRequestFail(ref req, ref fail) => {
+            let labels = RequestLabels::new(req);
+            fmt_counter(w, "request_total", 1, &labels)?;
+            fmt_timer(w, "request_failure_latency_ms", fail.since_request_open, &labels)?;
+            fmt_counter(w, "request_body_bytes_total", fail.bytes_sent, &labels)
+        },
+
+        Event::StreamRequestEnd(ref req, ref end) => {
+            let labels = RequestLabels::new(req);
+            fmt_counter(w, "request_total", 1, &labels)?;
+            fmt_counter(w, "request_body_bytes_total", end.bytes_sent, &labels)
+        },
+
+        Event::StreamResponseEnd(ref res, ref end) => {
+            let labels = ResponseLabels::new(res, end.grpc_status, None, None);
+            fmt_counter(w, "response_total", 1, &labels)?;
+            fmt_timer(w, "response_latency_ms", end.since_request_open, &labels)?;
+            fmt_counter(w, "response_body_bytes_total", end.bytes_sent, &labels)
+        },
+
+        Event::StreamResponseFail(ref res, ref fail) => {
+            let labels = ResponseLabels::fail(res, fail.error, None, None);
+            fmt_counter(w, "response_total", 1, &labels)?;
+            fmt_timer(w, "response_latency_ms", fail.since_request_open, &labels)?;
+            fmt_counter(w, "response_body_bytes_total", fail.bytes_sent, &labels)
+        },
+
+        Event::TransportOpen(ref ctx) => {
+            let labels = TransportLabels::new(ctx);
+            fmt_counter(w, "tcp_open_total", 1, &labels)?;
+            fmt_gauge_delta(w, "tcp_open_connections", 1, &labels)
+        },
+
+        Event::TransportAcceptFail(_, ref fail) => {
+            fmt_counter(w, "tcp_accept_errors_total", 1, &AcceptErrorLabels::new(fail.reason))
+        },
+
+        Event::TransportClose(ref ctx, ref close) => {
+            let labels = TransportLabels::new(ctx);
+            let close_labels = TransportCloseLabels::new(ctx, close);
+            fmt_counter(w, "tcp_close_total", 1, &close_labels)?;
+            fmt_timer(w, "tcp_connection_duration_ms", close.duration, &close_labels)?;
+            fmt_counter(w, "tcp_write_bytes_total", close.tx_bytes, &labels)?;
+            fmt_counter(w, "tcp_read_bytes_total", close.rx_bytes, &labels)?;
+            fmt_gauge_delta(w, "tcp_open_connections", -1, &labels)
+        },
+    }
+}
+
+fn fmt_counter<W: Write>(w: &mut W, name: &str, n: u64, labels: &fmt::Display) -> fmt::Result {
+    write!(w, "{}:{}|c", name, n)?;
+    fmt_tags(w, labels)
+}
+
+/// Writes a gauge which is incremented or decremented by `delta`, rather
+/// than set to it.
+fn fmt_gauge_delta<W: Write>(w: &mut W, name: &str, delta: i64, labels: &fmt::Display)
+    -> fmt::Result
+{
+    write!(w, "{}:{:+}|g", name, delta)?;
+    fmt_tags(w, labels)
+}
+
+fn fmt_timer<W: Write>(w: &mut W, name: &str, duration: Duration, labels: &fmt::Display)
+    -> fmt::Result
+{
+    let ms = duration.as_secs() as f64 * 1_000.0
+        + f64::from(duration.subsec_nanos()) / 1_000_000.0;
+    write!(w, "{}:{}|ms", name, ms)?;
+    fmt_tags(w, labels)
+}
+
+/// Writes labels as DogStatsD tags, ending the line.
+///
+/// The labels are formatted as they are in scrapes, such as
+/// `direction="inbound",peer="src"`, and then rewritten as tags, such as
+/// `|#direction:inbound,peer:src`. Commas, pipes, and newlines, which would
+/// end a tag, are replaced in tag values.
+fn fmt_tags<W: Write>(w: &mut W, labels: &fmt::Display) -> fmt::Result {
+    let mut sep = "|#";
+    for (key, value) in label_pairs(&labels.to_string()) {
+        write!(w, "{}{}:", sep, key)?;
+        sep = ",";
+        for c in value.chars() {
+            w.write_char(match c {
+                ',' | '|' | '\n' => '_',
+                c => c,
+            })?;
+        }
+    }
+    w.write_char('\n')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use conduit_proxy_controller_grpc::common::Protocol;
+    use ctx;
+    use telemetry::event;
+    use super::*;
+
+    #[test]
+    fn labels_are_formatted_as_tags() {
+        struct Labels(&'static str);
+        impl fmt::Display for Labels {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(self.0)
+            }
+        }
+        let tags = |labels| {
+            let mut tags = String::new();
+            fmt_tags(&mut tags, &Labels(labels)).unwrap();
+            tags
+        };
+
+        assert_eq!(tags(""), "\n");
+        assert_eq!(tags("reason=\"peek\""), "|#reason:peek\n");
+        assert_eq!(
+            tags("authority=\"foo.test:8080\",direction=\"outbound\",dst_a=\"x,y|z\""),
+            "|#authority:foo.test:8080,direction:outbound,dst_a:x_y_z\n"
+        );
+        assert_eq!(tags("a=\"\\\"quoted\\\" \\\\ \\n\",b=\"\""), "|#a:\"quoted\" \\ _,b:\n");
+    }
+
+    #[test]
+    fn transport_events_are_sent() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut sink = StatsdSink::new(server.local_addr().unwrap()).unwrap();
+
+        let process = ctx::Process::test("test");
+        let proxy = ctx::Proxy::inbound(&process);
+        let local: SocketAddr = "127.0.0.1:4143".parse().unwrap();
+        let remote: SocketAddr = "10.1.1.1:41000".parse().unwrap();
+        let transport = ctx::transport::Server::new(&proxy, &local, &remote, &None, Protocol::Http);
+        let ctx = Arc::new(ctx::transport::Ctx::Server(transport));
+
+        sink.record_event(&Event::TransportOpen(Arc::clone(&ctx)));
+        sink.record_event(&Event::TransportClose(ctx, event::TransportClose {
+            clean: true,
+            duration: Duration::from_millis(1500),
+            rx_bytes: 4,
+            tx_bytes: 8,
+        }));
+
+        let mut buf = [0; 1024];
+        let recv = |buf: &mut [u8]| {
+            let n = server.recv(buf).unwrap();
+            String::from_utf8(buf[..n].to_vec()).unwrap()
+        };
+        assert_eq!(
+            recv(&mut buf),
+            "tcp_open_total:1|c|#direction:inbound,peer:src\n\
+             tcp_open_connections:+1|g|#direction:inbound,peer:src"
+        );
+        assert_eq!(
+            recv(&mut buf),
+            "tcp_close_total:1|c|#direction:inbound,peer:src,classification:success\n\
+             tcp_connection_duration_ms:1500|ms|#direction:inbound,peer:src,classification:success\n\
+             tcp_write_bytes_total:8|c|#direction:inbound,peer:src\n\
+             tcp_read_bytes_total:4|c|#direction:inbound,peer:src\n\
+             tcp_open_connections:-1|g|#direction:inbound,peer:src"
+        );
+    }
+}
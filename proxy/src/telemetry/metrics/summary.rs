@@ -0,0 +1,174 @@
+#![deny(missing_docs)]
+use std::collections::VecDeque;
+use std::num::Wrapping;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::Counter;
+use super::latency::{Latency, MS_TO_TENTHS_OF_MS};
+
+/// A summary of latency values, which reports quantiles of the values
+/// observed within a sliding window.
+///
+/// Like a histogram's, the count and sum of a summary include every value
+/// ever observed, while its quantiles only describe the values observed
+/// within the window. Only those values are kept, so a summary's memory use
+/// is bounded by the number of values observed in one window.
+#[derive(Debug, Clone)]
+pub struct Summary {
+    /// How long each observation is included in the quantiles.
+    window: Duration,
+
+    /// The quantiles to report, in increasing order.
+    quantiles: Arc<[f64]>,
+
+    /// The values observed within the window, and when each was observed,
+    /// oldest first.
+    observations: VecDeque<(Instant, Latency)>,
+
+    /// The number of values ever observed.
+    count: Counter,
+
+    /// The sum of all observed values, in tenths of a millisecond.
+    ///
+    /// Like a histogram's sum, this explicitly wraps on overflow.
+    sum: Wrapping<u64>,
+}
+
+// ===== impl Summary =====
+
+impl Summary {
+
+    /// Construct a summary which reports each of `quantiles` over the values
+    /// observed within `window`.
+    ///
+    /// Quantiles are clamped to `[0, 1]`, sorted, and deduplicated.
+    pub fn new(window: Duration, quantiles: &[f64]) -> Self {
+        let mut quantiles = quantiles.iter()
+            .map(|q| q.max(0.0).min(1.0))
+            .collect::<Vec<_>>();
+        quantiles.sort_by(|a, b| a.partial_cmp(b).expect("clamped quantiles are never NaN"));
+        quantiles.dedup();
+        Summary {
+            window,
+            quantiles: Arc::from(quantiles),
+            observations: VecDeque::new(),
+            count: Counter::default(),
+            sum: Wrapping(0),
+        }
+    }
+
+    /// Observe a measurement
+    pub fn observe<I>(&mut self, measurement: I)
+    where
+        I: Into<Latency>,
+    {
+        self.observe_at(measurement, Instant::now())
+    }
+
+    /// Observe a measurement made at `now`, discarding the observations
+    /// which have left the window as of `now`.
+    pub fn observe_at<I>(&mut self, measurement: I, now: Instant)
+    where
+        I: Into<Latency>,
+    {
+        self.expire(now);
+
+        let measurement = measurement.into();
+        self.count.incr();
+        self.sum += Wrapping(u64::from(Into::<u32>::into(measurement)));
+        self.observations.push_back((now, measurement));
+    }
+
+    /// Discards the observations made more than one window before `now`.
+    fn expire(&mut self, now: Instant) {
+        loop {
+            let expired = match self.observations.front() {
+                Some(&(at, _)) => at + self.window < now,
+                None => false,
+            };
+            if !expired {
+                return;
+            }
+            self.observations.pop_front();
+        }
+    }
+
+    /// Returns the number of values ever observed.
+    pub fn count(&self) -> u64 {
+        self.count.into()
+    }
+
+    /// Return the sum value of this summary in milliseconds.
+    pub fn sum_in_ms(&self) -> f64 {
+        self.sum.0 as f64 / f64::from(MS_TO_TENTHS_OF_MS)
+    }
+
+    /// Returns each quantile and its value, in milliseconds, among the values
+    /// observed within the window before `now`.
+    ///
+    /// Each quantile's value is the smallest observed value which is greater
+    /// than or equal to that fraction of the observed values. If nothing was
+    /// observed within the window, each value is NaN.
+    pub fn quantiles_at(&self, now: Instant) -> Vec<(f64, f64)> {
+        let window = self.window;
+        let mut values = self.observations.iter()
+            .filter(|&&(at, _)| at + window >= now)
+            .map(|&(_, value)| value)
+            .collect::<Vec<_>>();
+        values.sort();
+
+        self.quantiles.iter()
+            .map(|&q| {
+                if values.is_empty() {
+                    return (q, ::std::f64::NAN);
+                }
+                let rank = (q * values.len() as f64).ceil() as usize;
+                (q, values[rank.max(1) - 1].into())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn quantiles_are_taken_from_the_window() {
+        let t0 = Instant::now();
+        let mut s = Summary::new(Duration::from_secs(10), &[0.99, 0.5, 1.5, 0.5]);
+        let qs = s.quantiles_at(t0);
+        assert_eq!(qs.iter().map(|&(q, _)| q).collect::<Vec<_>>(), vec![0.5, 0.99, 1.0]);
+        assert!(qs.iter().all(|&(_, value)| value.is_nan()));
+
+        for i in 1..101 {
+            s.observe_at(ms(i), t0);
+        }
+        assert_eq!(s.quantiles_at(t0), vec![(0.5, 50.0), (0.99, 99.0), (1.0, 100.0)]);
+        assert_eq!(s.count(), 100);
+        assert_eq!(s.sum_in_ms(), 5_050.0);
+
+        let t1 = t0 + Duration::from_secs(5);
+        for _ in 0..100 {
+            s.observe_at(ms(1_000), t1);
+        }
+        assert_eq!(s.quantiles_at(t1)[0], (0.5, 100.0));
+        assert_eq!(s.observations.len(), 200);
+
+        // Once the first observations leave the window, only the later ones
+        // are included in the quantiles, but the count and sum still
+        // include every observation.
+        let t2 = t0 + Duration::from_secs(11);
+        assert_eq!(s.quantiles_at(t2), vec![(0.5, 1_000.0), (0.99, 1_000.0), (1.0, 1_000.0)]);
+
+        s.observe_at(ms(1), t2);
+        assert_eq!(s.observations.len(), 101);
+        assert_eq!(s.count(), 201);
+        assert_eq!(s.quantiles_at(t2)[0], (0.5, 1_000.0));
+    }
+}
@@ -2,6 +2,7 @@ use std::sync::Arc;
 
 use futures_mpsc_lossy;
 
+use config::Config;
 use ctx;
 
 mod control;
@@ -23,16 +24,15 @@ pub use self::sensor::Sensors;
 /// aggregation.
 ///
 /// # Arguments
-/// - `capacity`: the size of the event queue.
+/// - `process`: runtime process metadata.
+/// - `config`: the proxy's configuration, from which the size of the event
+///   queue and the `metrics_*` settings are taken.
 ///
 /// [`Sensors`]: struct.Sensors.html
 /// [`Control`]: struct.Control.html
-pub fn new(
-    process: &Arc<ctx::Process>,
-    capacity: usize,
-) -> (Sensors, MakeControl) {
-    let (tx, rx) = futures_mpsc_lossy::channel(capacity);
-    let s = Sensors::new(tx);
-    let c = MakeControl::new(rx, process);
+pub fn new(process: &Arc<ctx::Process>, config: &Config) -> (Sensors, MakeControl) {
+    let (tx, rx) = futures_mpsc_lossy::channel(config.event_buffer_capacity);
+    let s = Sensors::new(tx, config);
+    let c = MakeControl::new(rx, process, config);
     (s, c)
 }
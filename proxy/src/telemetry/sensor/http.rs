@@ -4,7 +4,7 @@ use h2;
 use http;
 use std::default::Default;
 use std::marker::PhantomData;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 use tower::{NewService, Service};
@@ -23,6 +23,27 @@ const GRPC_STATUS: &str = "grpc-status";
 #[derive(Copy, Clone, Debug)]
 pub struct RequestOpen(pub Instant);
 
+/// The time at which a request stream ended, if it has ended.
+///
+/// This is shared between the sensors for the request and the response, so
+/// that response latency may be measured from the end of the request. It is
+/// only tracked if response latency is configured to be measured that way,
+/// so that other requests don't pay for the allocation.
+#[derive(Clone, Debug)]
+struct RequestEnd(Option<Arc<Mutex<Option<Instant>>>>);
+
+/// The optional measurements taken by the HTTP sensors, each of which is
+/// only taken if the metrics use it.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Measure {
+    /// If true, the time at which each request stream ends is tracked, so
+    /// that response latency may be measured from it.
+    pub request_end: bool,
+
+    /// If true, the total size of each request's headers is measured.
+    pub header_bytes: bool,
+}
+
 /// Middleware that adds a `RequestOpen` timestamp to requests.
 ///
 /// This is a separate middleware from `sensor::Http`, because we want
@@ -39,6 +60,7 @@ pub struct NewHttp<N, A, B> {
     new_service: N,
     handle: super::Handle,
     client_ctx: Arc<ctx::transport::Client>,
+    measure: Measure,
     _p: PhantomData<(A, B)>,
 }
 
@@ -47,6 +69,7 @@ pub struct Init<F, A, B> {
     future: F,
     handle: super::Handle,
     client_ctx: Arc<ctx::transport::Client>,
+    measure: Measure,
     _p: PhantomData<(A, B)>,
 }
 
@@ -57,6 +80,7 @@ pub struct Http<S, A, B> {
     service: S,
     handle: super::Handle,
     client_ctx: Arc<ctx::transport::Client>,
+    measure: Measure,
     _p: PhantomData<(A, B)>,
 }
 
@@ -72,6 +96,7 @@ struct RespondInner {
     handle: super::Handle,
     ctx: Arc<ctx::http::Request>,
     request_open: Instant,
+    request_end: RequestEnd,
 }
 
 pub type ResponseBody<B> = MeasuredBody<B, ResponseBodyInner>;
@@ -100,6 +125,7 @@ pub struct ResponseBodyInner {
     bytes_sent: u64,
     frames_sent: u32,
     request_open: Instant,
+    request_end: RequestEnd,
     response_open: Instant,
 }
 
@@ -111,6 +137,7 @@ pub struct RequestBodyInner {
     bytes_sent: u64,
     frames_sent: u32,
     request_open: Instant,
+    request_end: RequestEnd,
 }
 
 // === NewHttp ===
@@ -131,12 +158,14 @@ where
         new_service: N,
         handle: &super::Handle,
         client_ctx: &Arc<ctx::transport::Client>,
+        measure: Measure,
     ) -> Self {
         Self {
             next_id,
             new_service,
             handle: handle.clone(),
             client_ctx: Arc::clone(client_ctx),
+            measure,
             _p: PhantomData,
         }
     }
@@ -166,6 +195,7 @@ where
             future: self.new_service.new_service(),
             handle: self.handle.clone(),
             client_ctx: Arc::clone(&self.client_ctx),
+            measure: self.measure,
             _p: PhantomData,
         }
     }
@@ -194,6 +224,7 @@ where
             handle: self.handle.clone(),
             next_id: self.next_id.clone(),
             client_ctx: self.client_ctx.clone(),
+            measure: self.measure,
             _p: PhantomData,
         }))
     }
@@ -229,23 +260,33 @@ where
         let (inner, body_inner) = match metadata {
             (Some(ctx), Some(RequestOpen(request_open))) => {
                 let id = self.next_id.fetch_add(1, Ordering::SeqCst);
-                let ctx = ctx::http::Request::new(&req, &ctx, &self.client_ctx, id);
+                let ctx = ctx::http::Request::new(
+                    &req,
+                    &ctx,
+                    &self.client_ctx,
+                    id,
+                    self.measure.header_bytes,
+                );
 
                 self.handle
                     .send(|| Event::StreamRequestOpen(Arc::clone(&ctx)));
 
+                let request_end = RequestEnd::new(self.measure.request_end);
                 let respond_inner = Some(RespondInner {
                     ctx: ctx.clone(),
                     handle: self.handle.clone(),
                     request_open,
+                    request_end: request_end.clone(),
                 });
                 let body_inner =
                     if req.body().is_end_stream() {
+                        request_end.end();
                         self.handle.send(|| {
                             Event::StreamRequestEnd(
                                 Arc::clone(&ctx),
                                 event::StreamRequestEnd {
                                     since_request_open: request_open.elapsed(),
+                                    bytes_sent: 0,
                                 },
                             )
                         });
@@ -255,6 +296,7 @@ where
                             ctx,
                             handle: self.handle.clone(),
                             request_open,
+                            request_end,
                             frames_sent: 0,
                             bytes_sent: 0,
                         })
@@ -305,6 +347,7 @@ where
                         ctx,
                         mut handle,
                         request_open,
+                        request_end,
                     } = i;
 
                     let ctx = ctx::http::Response::new(&rsp, &ctx);
@@ -330,6 +373,7 @@ where
                                 event::StreamResponseEnd {
                                     grpc_status,
                                     since_request_open: request_open.elapsed(),
+                                    since_request_end: request_end.elapsed(),
                                     since_response_open: Duration::default(),
                                     bytes_sent: 0,
                                     frames_sent: 0,
@@ -345,6 +389,7 @@ where
                             bytes_sent: 0,
                             frames_sent: 0,
                             request_open,
+                            request_end,
                             response_open: Instant::now(),
                         })
                     }
@@ -366,6 +411,7 @@ where
                             ctx,
                             mut handle,
                             request_open,
+                            ..
                         } = i;
 
                         handle.send(|| {
@@ -374,6 +420,9 @@ where
                                 event::StreamRequestFail {
                                     error,
                                     since_request_open: request_open.elapsed(),
+                                    // The request body is measured by its
+                                    // own sensor, which isn't known here.
+                                    bytes_sent: 0,
                                 },
                             )
                         });
@@ -501,10 +550,10 @@ impl BodySensor for ResponseBodyInner {
             ctx,
             mut handle,
             request_open,
+            request_end,
             response_open,
             bytes_sent,
             frames_sent,
-            ..
         } = self;
 
         handle.send(|| {
@@ -513,6 +562,7 @@ impl BodySensor for ResponseBodyInner {
                 event::StreamResponseFail {
                     error,
                     since_request_open: request_open.elapsed(),
+                    since_request_end: request_end.elapsed(),
                     since_response_open: response_open.elapsed(),
                     bytes_sent,
                     frames_sent,
@@ -526,6 +576,7 @@ impl BodySensor for ResponseBodyInner {
             ctx,
             mut handle,
             request_open,
+            request_end,
             response_open,
             bytes_sent,
             frames_sent,
@@ -537,6 +588,7 @@ impl BodySensor for ResponseBodyInner {
                 event::StreamResponseEnd {
                     grpc_status,
                     since_request_open: request_open.elapsed(),
+                    since_request_end: request_end.elapsed(),
                     since_response_open: response_open.elapsed(),
                     bytes_sent,
                     frames_sent,
@@ -561,6 +613,7 @@ impl BodySensor for RequestBodyInner {
             ctx,
             mut handle,
             request_open,
+            bytes_sent,
             ..
         } = self;
 
@@ -570,6 +623,7 @@ impl BodySensor for RequestBodyInner {
                 event::StreamRequestFail {
                     error,
                     since_request_open: request_open.elapsed(),
+                    bytes_sent,
                 },
             )
         )
@@ -580,14 +634,19 @@ impl BodySensor for RequestBodyInner {
             ctx,
             mut handle,
             request_open,
+            request_end,
+            bytes_sent,
             ..
         } = self;
 
+        request_end.end();
+
         handle.send(||
             event::Event::StreamRequestEnd(
                 Arc::clone(&ctx),
                 event::StreamRequestEnd {
                     since_request_open: request_open.elapsed(),
+                    bytes_sent,
                 },
             )
         )
@@ -602,6 +661,36 @@ impl BodySensor for RequestBodyInner {
     }
 }
 
+// ===== impl RequestEnd =====
+
+impl RequestEnd {
+    /// Returns a `RequestEnd` which records when the request stream ends if
+    /// `track` is true, or else records nothing.
+    fn new(track: bool) -> Self {
+        if track {
+            RequestEnd(Some(Arc::new(Mutex::new(None))))
+        } else {
+            RequestEnd(None)
+        }
+    }
+
+    /// Records that the request stream has ended.
+    fn end(&self) {
+        if let Some(Ok(mut end)) = self.0.as_ref().map(|end| end.lock()) {
+            *end = Some(Instant::now());
+        }
+    }
+
+    /// Returns the time since the request stream ended, if it has ended and
+    /// is tracked.
+    fn elapsed(&self) -> Option<Duration> {
+        self.0.as_ref()
+            .and_then(|end| end.lock().ok())
+            .and_then(|end| *end)
+            .map(|end| end.elapsed())
+    }
+}
+
 impl<S> TimestampRequestOpen<S> {
     pub fn new(inner: S) -> Self {
         Self { inner }
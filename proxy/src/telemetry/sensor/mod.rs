@@ -9,6 +9,7 @@ use tokio_io::{AsyncRead, AsyncWrite};
 use tower::NewService;
 use tower_h2::{client, Body};
 
+use config::Config;
 use ctx;
 use telemetry::event;
 
@@ -24,7 +25,12 @@ struct Handle(Option<Sender<event::Event>>);
 
 /// Supports the creation of telemetry scopes.
 #[derive(Clone, Debug)]
-pub struct Sensors(Handle);
+pub struct Sensors {
+    handle: Handle,
+
+    /// The optional measurements taken by HTTP sensors.
+    measure: http::Measure,
+}
 
 impl Handle {
     fn send<F>(&mut self, mk: F)
@@ -46,12 +52,23 @@ impl Handle {
 }
 
 impl Sensors {
-    pub(super) fn new(h: Sender<event::Event>) -> Self {
-        Sensors(Handle(Some(h)))
+    /// Constructs sensors which send events on `h`, taking the optional
+    /// measurements that `config` enables.
+    pub(super) fn new(h: Sender<event::Event>, config: &Config) -> Self {
+        Sensors {
+            handle: Handle(Some(h)),
+            measure: http::Measure {
+                request_end: config.metrics_latency_from_request_end,
+                header_bytes: config.metrics_request_header_bytes,
+            },
+        }
     }
 
     pub fn null() -> Sensors {
-        Sensors(Handle(None))
+        Sensors {
+            handle: Handle(None),
+            measure: http::Measure::default(),
+        }
     }
 
     pub fn accept<T>(
@@ -65,14 +82,25 @@ impl Sensors {
     {
         debug!("server connection open");
         let ctx = Arc::new(ctx::transport::Ctx::Server(Arc::clone(ctx)));
-        Transport::open(io, opened_at, &self.0, ctx)
+        Transport::open(io, opened_at, &self.handle, ctx)
+    }
+
+    /// Records that a connection accepted by `proxy` failed for `reason`
+    /// before it could be opened as a transport.
+    pub fn accept_fail(&self, proxy: &Arc<ctx::Proxy>, reason: &'static str) {
+        debug!("server connection failed: {}", reason);
+        let mut handle = self.handle.clone();
+        handle.send(|| event::Event::TransportAcceptFail(
+            Arc::clone(proxy),
+            event::TransportAcceptFail { reason },
+        ));
     }
 
     pub fn connect<C>(&self, connect: C, ctx: &Arc<ctx::transport::Client>) -> Connect<C>
     where
         C: tokio_connect::Connect,
     {
-        Connect::new(connect, &self.0, ctx)
+        Connect::new(connect, &self.handle, ctx)
     }
 
     pub fn http<N, A, B>(
@@ -91,6 +119,6 @@ impl Sensors {
         >
             + 'static,
     {
-        NewHttp::new(next_id, new_service, &self.0, client_ctx)
+        NewHttp::new(next_id, new_service, &self.handle, client_ctx, self.measure)
     }
 }
@@ -135,9 +135,14 @@ where
         let tcp = self.tcp.clone();
         let new_service = self.new_service.clone();
         let drain_signal = self.drain_signal.clone();
+        let peek_fail = (self.sensors.clone(), self.proxy_ctx.clone());
         let fut = connection
             .peek_future(sniff)
-            .map_err(|_| ())
+            .map_err(move |e| {
+                debug!("peek error: {}", e);
+                let (sensors, proxy_ctx) = peek_fail;
+                sensors.accept_fail(&proxy_ctx, "peek");
+            })
             .and_then(move |(connection, sniff, n)| -> Box<Future<Item=(), Error=()>> {
                 if let Some(proto) = Protocol::detect(&sniff[..n]) {
                     let srv_ctx = ServerCtx::new(